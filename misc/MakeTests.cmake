@@ -1,5 +1,5 @@
 set(BITS 32)
-set(ARCH rv32ima_zicsr_zifencei)
+set(ARCH rv32ima_zicsr_zifencei_zba_zbb_zbc_zbs)
 set(ABI ilp32)
 set(RVTEST_FOLDER riscv-tests)
 set(TESTBINS_FOLDER testbins)
@@ -46,9 +46,15 @@ else()
     build_asm("${RVTEST_FOLDER}/isa/rv${BITS}ui/*.S" "${TESTBINS_FOLDER}/rv${BITS}ui")
     build_asm("${RVTEST_FOLDER}/isa/rv${BITS}um/*.S" "${TESTBINS_FOLDER}/rv${BITS}um")
     build_asm("${RVTEST_FOLDER}/isa/rv${BITS}ua/*.S" "${TESTBINS_FOLDER}/rv${BITS}ua")
+    build_asm("${RVTEST_FOLDER}/isa/rv${BITS}uzba/*.S" "${TESTBINS_FOLDER}/rv${BITS}uzba")
+    build_asm("${RVTEST_FOLDER}/isa/rv${BITS}uzbb/*.S" "${TESTBINS_FOLDER}/rv${BITS}uzbb")
+    build_asm("${RVTEST_FOLDER}/isa/rv${BITS}uzbc/*.S" "${TESTBINS_FOLDER}/rv${BITS}uzbc")
+    build_asm("${RVTEST_FOLDER}/isa/rv${BITS}uzbs/*.S" "${TESTBINS_FOLDER}/rv${BITS}uzbs")
 
     build_asm("${RVTEST_FOLDER}/isa/rv${BITS}mi/*.S" "${TESTBINS_FOLDER}/rv${BITS}mi")
     build_asm("${RVTEST_FOLDER}/isa/rv${BITS}si/*.S" "${TESTBINS_FOLDER}/rv${BITS}si")
 
+    build_asm("${MISC_FOLDER}/tests/*.S" "${TESTBINS_FOLDER}/misc")
+
     file(REMOVE temp)
 endif()
@@ -3,7 +3,7 @@
 use crate::{
     backend::*,
     bus::{self, BusType},
-    frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_OFFSET_MASK, RV_PAGE_SHIFT},
+    frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_OFFSET_MASK},
     xmem::{PageAllocator, PageState},
 };
 pub use crate::{cpu::*, util::EncodedInsn};
@@ -14,11 +14,11 @@ use std::arch::asm;
 
 pub type PtrT = *mut u8;
 pub type HostInsnT = u8;
-pub const HOST_INSN_MAX_SIZE: usize = 98;
+pub const HOST_INSN_MAX_SIZE: usize = 202;
 pub type HostEncodedInsn = EncodedInsn<HostInsnT, HOST_INSN_MAX_SIZE>;
 pub type DecodeRet = Result<HostEncodedInsn, JitError>;
 
-pub const FASTMEM_BLOCK_SIZE: usize = 88;
+pub const FASTMEM_BLOCK_SIZE: usize = 192;
 
 #[macro_export]
 macro_rules! host_get_return_addr {
@@ -249,6 +249,57 @@ macro_rules! emit_mov_ptr_reg_dword_ptr_rip_relative {
     }};
 }
 
+#[macro_export]
+macro_rules! emit_mov_reg_qword_ptr {
+    ($enc:expr, $dst_reg:expr, $src_reg:expr) => {{
+        assert!($dst_reg < amd64_reg::R8 && $src_reg < amd64_reg::R8);
+        assert!($src_reg != amd64_reg::RSP && $src_reg != amd64_reg::RBP);
+        emit_insn!(
+            $enc,
+            [
+                0x48,
+                0x8B,
+                (0x00 as u8)
+                    .wrapping_add($dst_reg << 3)
+                    .wrapping_add($src_reg)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_mov_reg_dword_ptr_disp8 {
+    ($enc:expr, $dst_reg:expr, $src_reg:expr, $disp:expr) => {{
+        assert!($dst_reg < amd64_reg::R8 && $src_reg < amd64_reg::R8);
+        assert!($src_reg != amd64_reg::RSP);
+        emit_insn!(
+            $enc,
+            [
+                0x8B,
+                (0x40 as u8)
+                    .wrapping_add($dst_reg << 3)
+                    .wrapping_add($src_reg),
+                $disp as u8
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_cmp_reg_dword_ptr {
+    ($enc:expr, $reg:expr, $ptr_reg:expr) => {{
+        assert!($reg < amd64_reg::R8 && $ptr_reg < amd64_reg::R8);
+        assert!($ptr_reg != amd64_reg::RSP && $ptr_reg != amd64_reg::RBP);
+        emit_insn!(
+            $enc,
+            [
+                0x3B,
+                (0x00 as u8).wrapping_add($reg << 3).wrapping_add($ptr_reg)
+            ]
+        );
+    }};
+}
+
 const INSN_MOV_RIP_RELATIVE_SIZE: usize = 6;
 
 #[macro_export]
@@ -585,6 +636,36 @@ macro_rules! emit_add_reg_reg {
     }};
 }
 
+#[macro_export]
+macro_rules! emit_add_reg_reg64 {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x48,
+                0x01,
+                (0xC0 as u8).wrapping_add($reg2 << 3).wrapping_add($reg1)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_test_reg_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        if $reg == amd64_reg::RAX {
+            emit_insn!($enc, [0xA9]);
+        } else if $reg < amd64_reg::R8 {
+            emit_insn!($enc, [0xF7, 0xC0 + $reg as u8]);
+        } else {
+            emit_insn!($enc, [0x41, 0xF7, 0xC0 + $reg as u8 - amd64_reg::R8]);
+        }
+
+        emit_insn!($enc, ($imm as u32).to_le_bytes());
+    }};
+}
+
 #[macro_export]
 macro_rules! emit_xor_reg_imm {
     ($enc:expr, $reg:expr, $imm:expr) => {{
@@ -1079,6 +1160,339 @@ macro_rules! emit_imul32_reg_reg {
     }};
 }
 
+#[macro_export]
+macro_rules! emit_lea_reg32_sib {
+    ($enc:expr, $dst_reg:expr, $base_reg:expr, $index_reg:expr, $scale_shift:expr) => {{
+        // rbp as a base needs a displacement, rsp can't be an index
+        assert!(
+            $dst_reg < amd64_reg::R8 && $base_reg < amd64_reg::R8 && $index_reg < amd64_reg::R8
+        );
+        assert!($base_reg != amd64_reg::RBP && $index_reg != amd64_reg::RSP);
+        emit_insn!(
+            $enc,
+            [
+                0x8D,
+                (0x04 as u8).wrapping_add($dst_reg << 3),
+                (($scale_shift as u8) << 6)
+                    .wrapping_add($index_reg << 3)
+                    .wrapping_add($base_reg)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_not_reg32 {
+    ($enc:expr, $reg:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0xF7, 0xD0 + $reg as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_andn_reg32 {
+    ($enc:expr, $dst_reg:expr, $reg1:expr, $reg2:expr) => {{
+        // dst = !reg1 & reg2
+        assert!($dst_reg < amd64_reg::R8 && $reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0xC4,
+                0xE2,
+                (0x78 as u8).wrapping_sub($reg1 << 3),
+                0xF2,
+                (0xC0 as u8).wrapping_add($dst_reg << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_lzcnt_reg32 {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0xF3,
+                0x0F,
+                0xBD,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_tzcnt_reg32 {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0xF3,
+                0x0F,
+                0xBC,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_popcnt_reg32 {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0xF3,
+                0x0F,
+                0xB8,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_rol_reg32_cl {
+    ($enc:expr, $reg:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0xD3, 0xC0 + $reg as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_ror_reg32_cl {
+    ($enc:expr, $reg:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0xD3, 0xC8 + $reg as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_ror_reg32_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0xC1, 0xC8 + $reg as u8, $imm as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_bswap_reg32 {
+    ($enc:expr, $reg:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0x0F, 0xC8 + $reg as u8]);
+    }};
+}
+
+// Bit tests take the bit index from reg2 modulo 32, CF gets the old bit
+#[macro_export]
+macro_rules! emit_bt_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0xA3,
+                (0xC0 as u8).wrapping_add($reg2 << 3).wrapping_add($reg1)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_bts_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0xAB,
+                (0xC0 as u8).wrapping_add($reg2 << 3).wrapping_add($reg1)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_btr_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0xB3,
+                (0xC0 as u8).wrapping_add($reg2 << 3).wrapping_add($reg1)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_btc_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0xBB,
+                (0xC0 as u8).wrapping_add($reg2 << 3).wrapping_add($reg1)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_bt_reg32_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0x0F, 0xBA, 0xE0 + $reg as u8, $imm as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_bts_reg32_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0x0F, 0xBA, 0xE8 + $reg as u8, $imm as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_btr_reg32_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0x0F, 0xBA, 0xF0 + $reg as u8, $imm as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_btc_reg32_imm {
+    ($enc:expr, $reg:expr, $imm:expr) => {{
+        assert!($reg < amd64_reg::R8);
+        emit_insn!($enc, [0x0F, 0xBA, 0xF8 + $reg as u8, $imm as u8]);
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_cmovl_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0x4C,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_cmovg_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0x4F,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_cmovb_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0x42,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_cmova_reg32_reg {
+    ($enc:expr, $reg1:expr, $reg2:expr) => {{
+        assert!($reg1 < amd64_reg::R8 && $reg2 < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x0F,
+                0x47,
+                (0xC0 as u8).wrapping_add($reg1 << 3).wrapping_add($reg2)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_movq_xmm_reg {
+    ($enc:expr, $xmm:expr, $reg:expr) => {{
+        assert!($xmm < 8 && $reg < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x66,
+                0x48,
+                0x0F,
+                0x6E,
+                (0xC0 as u8).wrapping_add($xmm << 3).wrapping_add($reg)
+            ]
+        );
+    }};
+}
+
+#[macro_export]
+macro_rules! emit_movq_reg_xmm {
+    ($enc:expr, $reg:expr, $xmm:expr) => {{
+        assert!($xmm < 8 && $reg < amd64_reg::R8);
+        emit_insn!(
+            $enc,
+            [
+                0x66,
+                0x48,
+                0x0F,
+                0x7E,
+                (0xC0 as u8).wrapping_add($xmm << 3).wrapping_add($reg)
+            ]
+        );
+    }};
+}
+
+// Carry-less product of the low qwords
+#[macro_export]
+macro_rules! emit_pclmulqdq_xmm_xmm {
+    ($enc:expr, $xmm1:expr, $xmm2:expr) => {{
+        assert!($xmm1 < 8 && $xmm2 < 8);
+        emit_insn!(
+            $enc,
+            [
+                0x66,
+                0x0F,
+                0x3A,
+                0x44,
+                (0xC0 as u8).wrapping_add($xmm1 << 3).wrapping_add($xmm2),
+                0x00
+            ]
+        );
+    }};
+}
+
 // Other
 
 pub fn emit_rel_load(
@@ -1399,10 +1813,6 @@ impl BackendCore for BackendCoreImpl {
         Self::emit_void_call_with_1_arg(fn_ptr, arg1)
     }
 
-    fn fastmem_violation_likely_offset() -> usize {
-        FASTMEM_BLOCK_SIZE - 16
-    }
-
     fn patch_fastmem_violation(
         host_exception_addr: usize,
         guest_exception_addr: BusType,
@@ -1465,10 +1875,14 @@ impl BackendCore for BackendCoreImpl {
 
                 gpfn_state.set_state(PageState::ReadExecute);
 
-                cpu.exception = Exception::InvalidateJitBlock(
-                    dst_addr as CpuReg >> RV_PAGE_SHIFT as CpuReg,
-                    false,
-                );
+                // Data sharing the page with code goes through as is, the exec loop
+                // only has to patch out instructions the store landed on
+                cpu.exception =
+                    if gpfn_state.is_code_write(dst_addr as CpuReg, access_size as usize / 8) {
+                        Exception::InvalidateJitBlock(dst_addr as CpuReg)
+                    } else {
+                        Exception::BookkeepingRet
+                    };
 
                 return FastmemHandleType::Manual;
             }
@@ -1496,8 +1910,11 @@ impl BackendCore for BackendCoreImpl {
 
         let page_size_mask = PageAllocator::get_page_size() - 1;
         let host_insn_begin_aligned = host_insn_begin as usize & !page_size_mask;
+        // The block can straddle a host page boundary
+        let host_insn_end_aligned =
+            (host_insn_begin as usize + FASTMEM_BLOCK_SIZE + page_size_mask) & !page_size_mask;
         let host_insn_begin_npages =
-            (insn.size() + page_size_mask) / PageAllocator::get_page_size();
+            (host_insn_end_aligned - host_insn_begin_aligned) / PageAllocator::get_page_size();
 
         PageAllocator::mark_page(
             host_insn_begin_aligned as *mut u8,
@@ -1544,7 +1961,7 @@ impl BackendCore for BackendCoreImpl {
 
             let diff = host_target as i64 - host_addr as i64 - jmp_insn_offset as i64;
 
-            assert!(diff >= std::i32::MIN as i64 && diff <= std::i32::MAX as i64);
+            assert!(diff >= i32::MIN as i64 && diff <= i32::MAX as i64);
 
             let mut diff = (diff as u32).to_le_bytes();
             unsafe {
@@ -1557,6 +1974,19 @@ impl BackendCore for BackendCoreImpl {
         }
     }
 
+    // Jump placed at host_ptr, patched over the start of a translated instruction
+    fn emit_jump(host_ptr: *mut u8, host_target: *mut u8) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        let diff = host_target as i64 - host_ptr as i64 - JMP_IMM32_SIZE as i64;
+
+        assert!(diff >= i32::MIN as i64 && diff <= i32::MAX as i64);
+
+        emit_jmp_imm32!(insn, diff as i32);
+
+        insn
+    }
+
     #[inline(never)]
     unsafe fn call_jit_ptr(jit_ptr: *mut u8) {
         asm!(
@@ -1,8 +1,11 @@
 pub mod core;
 
+pub mod rvb;
 pub mod rvi;
 pub mod rvm;
 mod test_insn;
+pub mod trace;
 
+pub use rvb::RvbImpl;
 pub use rvi::RviImpl;
 pub use rvm::RvmImpl;
@@ -0,0 +1,538 @@
+use crate::backend::{
+    common, core::amd64_reg, core::emit_mov_reg_guest_to_host, core::emit_mov_reg_host_to_guest,
+};
+use crate::*;
+use common::{DecodeRet, HostEncodedInsn};
+
+pub struct RvbImpl;
+
+impl common::Rvb for RvbImpl {
+    fn emit_sh1add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_lea_reg32_sib!(insn, amd64_reg::RAX, amd64_reg::RBX, amd64_reg::RAX, 1);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_sh2add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_lea_reg32_sib!(insn, amd64_reg::RAX, amd64_reg::RBX, amd64_reg::RAX, 2);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_sh3add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_lea_reg32_sib!(insn, amd64_reg::RAX, amd64_reg::RBX, amd64_reg::RAX, 3);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_andn(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_andn_reg32!(insn, amd64_reg::RAX, amd64_reg::RBX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_orn(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_not_reg32!(insn, amd64_reg::RBX);
+        emit_or_reg_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_xnor(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_xor_reg_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_not_reg32!(insn, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_clz(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_lzcnt_reg32!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_ctz(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_tzcnt_reg32!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_cpop(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_popcnt_reg32!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_max(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_cmp_reg_reg32!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_cmovl_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_maxu(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_cmp_reg_reg32!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_cmovb_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_min(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_cmp_reg_reg32!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_cmovg_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_minu(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_cmp_reg_reg32!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_cmova_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_sext_b(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_movsxd_reg64_reg8!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_sext_h(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_movsxd_reg64_reg16!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_zext_h(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_movzx_reg64_reg16!(insn, amd64_reg::RAX, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_rol(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_rol_reg32_cl!(insn, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_ror(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_ror_reg32_cl!(insn, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_rori(rd: u8, rs1: u8, shamt: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_ror_reg32_imm!(insn, amd64_reg::RAX, shamt);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_orc_b(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        // Top bit of every byte set when the byte isn't zero, then spread it over the byte
+        emit_reg_reg!(insn, amd64_reg::RBX, amd64_reg::RAX);
+        emit_and_reg_imm!(insn, amd64_reg::RBX, 0x7f7f7f7f);
+        emit_add_reg_imm!(insn, amd64_reg::RBX, 0x7f7f7f7f);
+        emit_or_reg_reg!(insn, amd64_reg::RBX, amd64_reg::RAX);
+        emit_shr_reg_imm!(insn, amd64_reg::RBX, 7);
+        emit_and_reg_imm!(insn, amd64_reg::RBX, 0x01010101);
+        emit_reg_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+        emit_shl_reg_imm!(insn, amd64_reg::RAX, 8);
+        emit_sub_reg_reg!(insn, amd64_reg::RAX, amd64_reg::RBX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_rev8(rd: u8, rs1: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_bswap_reg32!(insn, amd64_reg::RAX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_clmul(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_movq_xmm_reg!(insn, 0, amd64_reg::RAX);
+        emit_movq_xmm_reg!(insn, 1, amd64_reg::RBX);
+        emit_pclmulqdq_xmm_xmm!(insn, 0, 1);
+        emit_movq_reg_xmm!(insn, amd64_reg::RAX, 0);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_clmulh(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_movq_xmm_reg!(insn, 0, amd64_reg::RAX);
+        emit_movq_xmm_reg!(insn, 1, amd64_reg::RBX);
+        emit_pclmulqdq_xmm_xmm!(insn, 0, 1);
+        emit_movq_reg_xmm!(insn, amd64_reg::RAX, 0);
+        emit_shr_reg_imm!(insn, amd64_reg::RAX, 32);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_clmulr(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RBX, rs2);
+
+        emit_movq_xmm_reg!(insn, 0, amd64_reg::RAX);
+        emit_movq_xmm_reg!(insn, 1, amd64_reg::RBX);
+        emit_pclmulqdq_xmm_xmm!(insn, 0, 1);
+        emit_movq_reg_xmm!(insn, amd64_reg::RAX, 0);
+        emit_shr_reg_imm!(insn, amd64_reg::RAX, 31);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bclr(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_btr_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RCX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bclri(rd: u8, rs1: u8, shamt: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_btr_reg32_imm!(insn, amd64_reg::RAX, shamt);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bext(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_bt_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RCX);
+        emit_setb_al!(insn);
+        emit_movzx_rax_al!(insn);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bexti(rd: u8, rs1: u8, shamt: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_bt_reg32_imm!(insn, amd64_reg::RAX, shamt);
+        emit_setb_al!(insn);
+        emit_movzx_rax_al!(insn);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_binv(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_btc_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RCX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_binvi(rd: u8, rs1: u8, shamt: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_btc_reg32_imm!(insn, amd64_reg::RAX, shamt);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bset(rd: u8, rs1: u8, rs2: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RCX, rs2);
+
+        emit_bts_reg32_reg!(insn, amd64_reg::RAX, amd64_reg::RCX);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+
+    fn emit_bseti(rd: u8, rs1: u8, shamt: u8) -> DecodeRet {
+        let mut insn = HostEncodedInsn::new();
+        let cpu = cpu::get_cpu();
+
+        emit_check_rd!(insn, rd);
+
+        emit_mov_reg_guest_to_host(&mut insn, cpu, amd64_reg::RAX, rs1);
+
+        emit_bts_reg32_imm!(insn, amd64_reg::RAX, shamt);
+
+        emit_mov_reg_host_to_guest(&mut insn, cpu, amd64_reg::RBX, amd64_reg::RAX, rd);
+
+        Ok(insn)
+    }
+}
@@ -7,10 +7,11 @@ use crate::backend::target::core::{
 };
 use crate::backend::{common, ReturnableHandler, ReturnableImpl};
 use crate::bus::mmu::AccessType;
+use crate::bus::BusType;
 use crate::cpu::{CpuReg, JumpAddrPatch};
-use crate::frontend::exec_core::{INSN_SIZE, RV_PAGE_SHIFT, RV_PAGE_SIZE};
+use crate::frontend::exec_core::{INSN_SIZE, RV_PAGE_MASK, RV_PAGE_SHIFT, RV_PAGE_SIZE};
 use crate::*;
-use bus::tlb::get_current_tlb;
+use bus::tlb::{current_tlb_ptr_addr, get_current_tlb, TLB_ENTRIES, TLB_FLAG_READ, TLB_FLAG_WRITE};
 use common::*;
 use frontend::exec_core::RV_PAGE_OFFSET_MASK;
 
@@ -204,6 +205,43 @@ extern "C" fn c_load_mmu_translate_cb(addr: usize, gpfn_offset: usize) -> usize
     ret.unwrap() as usize
 }
 
+// Translates the virtual address in RAX through the current TLB and jumps
+// over the slow path on a hit. Only RCX and RDX are clobbered
+fn emit_tlb_probe(flag: BusType, slow_path_size: usize) -> HostEncodedInsn {
+    let mut insn = HostEncodedInsn::new();
+
+    // RCX = &tlb[vpn % TLB_ENTRIES], entries are 8 bytes
+    emit_reg_reg!(insn, amd64_reg::RDX, amd64_reg::RAX);
+    emit_shr_reg_imm!(insn, amd64_reg::RDX, RV_PAGE_SHIFT as u8);
+    emit_and_reg_imm!(insn, amd64_reg::RDX, TLB_ENTRIES - 1);
+    emit_shl_reg_imm!(insn, amd64_reg::RDX, 3);
+    emit_movabs_reg_imm!(insn, amd64_reg::RCX, current_tlb_ptr_addr());
+    emit_mov_reg_qword_ptr!(insn, amd64_reg::RCX, amd64_reg::RCX);
+    emit_add_reg_reg64!(insn, amd64_reg::RCX, amd64_reg::RDX);
+
+    let mut hit = HostEncodedInsn::new();
+    emit_and_reg_imm!(hit, amd64_reg::RDX, RV_PAGE_MASK);
+    emit_and_reg_imm!(hit, amd64_reg::RAX, RV_PAGE_OFFSET_MASK);
+    emit_or_reg_reg!(hit, amd64_reg::RAX, amd64_reg::RDX);
+    emit_jmp_imm32!(hit, slow_path_size);
+
+    let mut check = HostEncodedInsn::new();
+    emit_mov_reg_dword_ptr_disp8!(check, amd64_reg::RDX, amd64_reg::RCX, 4);
+    emit_test_reg_imm!(check, amd64_reg::RDX, flag);
+    emit_jz_imm!(check, hit.size());
+
+    // Compare the tag, then the permission bits of the physical entry
+    emit_reg_reg!(insn, amd64_reg::RDX, amd64_reg::RAX);
+    emit_shr_reg_imm!(insn, amd64_reg::RDX, RV_PAGE_SHIFT as u8);
+    emit_cmp_reg_dword_ptr!(insn, amd64_reg::RDX, amd64_reg::RCX);
+    emit_jne_imm!(insn, check.size() + hit.size());
+
+    insn.push_slice(check.as_slice());
+    insn.push_slice(hit.as_slice());
+
+    insn
+}
+
 fn emit_load(
     load_size: usize,
     dest_reg: u8,
@@ -244,8 +282,11 @@ fn emit_load(
     ); // Stack manipulation is left out as it's technically not needed here
     emit_call_reg!(mmu_translate_insn, amd64_reg::R11);
 
+    let tlb_probe_insn = emit_tlb_probe(TLB_FLAG_READ, mmu_translate_insn.size());
+
     emit_cmp_reg_imm!(insn, MMU_IS_ACTIVE_REG, 1);
-    emit_jne_imm!(insn, mmu_translate_insn.size());
+    emit_jne_imm!(insn, tlb_probe_insn.size() + mmu_translate_insn.size());
+    insn.push_slice(tlb_probe_insn.as_slice());
     insn.push_slice(mmu_translate_insn.as_slice());
 
     emit_mov_ptr_reg_dword_ptr!(insn, amd64_reg::RAX, amd64_reg::RAX);
@@ -331,8 +372,11 @@ fn emit_store(store_size: usize, addr_reg: u8, data_reg: u8, imm: i32) -> HostEn
     );
     emit_call_reg!(mmu_translate_insn, amd64_reg::R11);
 
+    let tlb_probe_insn = emit_tlb_probe(TLB_FLAG_WRITE, mmu_translate_insn.size());
+
     emit_cmp_reg_imm!(insn, MMU_IS_ACTIVE_REG, 1);
-    emit_jne_imm!(insn, mmu_translate_insn.size());
+    emit_jne_imm!(insn, tlb_probe_insn.size() + mmu_translate_insn.size());
+    insn.push_slice(tlb_probe_insn.as_slice());
     insn.push_slice(mmu_translate_insn.as_slice());
 
     match store_size {
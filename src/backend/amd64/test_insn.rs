@@ -402,3 +402,121 @@ test_encoded_insn!(
     |enc: &mut HostEncodedInsn| emit_mov_byte_ptr_reg!(enc, amd64_reg::RAX, amd64_reg::RAX),
     [0x88, 0x00]
 );
+
+test_encoded_insn!(
+    test_lea_eax_rbx_rax_4,
+    |enc: &mut HostEncodedInsn| emit_lea_reg32_sib!(
+        enc,
+        amd64_reg::RAX,
+        amd64_reg::RBX,
+        amd64_reg::RAX,
+        2
+    ),
+    [0x8d, 0x04, 0x83]
+);
+
+test_encoded_insn!(
+    test_lea_ecx_rdx_rsi_8,
+    |enc: &mut HostEncodedInsn| emit_lea_reg32_sib!(
+        enc,
+        amd64_reg::RCX,
+        amd64_reg::RDX,
+        amd64_reg::RSI,
+        3
+    ),
+    [0x8d, 0x0c, 0xf2]
+);
+
+test_encoded_insn!(
+    test_andn_eax_ebx_eax,
+    |enc: &mut HostEncodedInsn| emit_andn_reg32!(
+        enc,
+        amd64_reg::RAX,
+        amd64_reg::RBX,
+        amd64_reg::RAX
+    ),
+    [0xc4, 0xe2, 0x60, 0xf2, 0xc0]
+);
+
+test_encoded_insn!(
+    test_andn_ecx_edx_esi,
+    |enc: &mut HostEncodedInsn| emit_andn_reg32!(
+        enc,
+        amd64_reg::RCX,
+        amd64_reg::RDX,
+        amd64_reg::RSI
+    ),
+    [0xc4, 0xe2, 0x68, 0xf2, 0xce]
+);
+
+test_encoded_insn!(
+    test_lzcnt_ecx_ebx,
+    |enc: &mut HostEncodedInsn| emit_lzcnt_reg32!(enc, amd64_reg::RCX, amd64_reg::RBX),
+    [0xf3, 0x0f, 0xbd, 0xcb]
+);
+
+test_encoded_insn!(
+    test_tzcnt_eax_ebx,
+    |enc: &mut HostEncodedInsn| emit_tzcnt_reg32!(enc, amd64_reg::RAX, amd64_reg::RBX),
+    [0xf3, 0x0f, 0xbc, 0xc3]
+);
+
+test_encoded_insn!(
+    test_popcnt_edx_eax,
+    |enc: &mut HostEncodedInsn| emit_popcnt_reg32!(enc, amd64_reg::RDX, amd64_reg::RAX),
+    [0xf3, 0x0f, 0xb8, 0xd0]
+);
+
+test_encoded_insn!(
+    test_ror_ebx_cl,
+    |enc: &mut HostEncodedInsn| emit_ror_reg32_cl!(enc, amd64_reg::RBX),
+    [0xd3, 0xcb]
+);
+
+test_encoded_insn!(
+    test_ror_eax_7,
+    |enc: &mut HostEncodedInsn| emit_ror_reg32_imm!(enc, amd64_reg::RAX, 7),
+    [0xc1, 0xc8, 0x07]
+);
+
+test_encoded_insn!(
+    test_bswap_ebx,
+    |enc: &mut HostEncodedInsn| emit_bswap_reg32!(enc, amd64_reg::RBX),
+    [0x0f, 0xcb]
+);
+
+test_encoded_insn!(
+    test_btr_ebx_edx,
+    |enc: &mut HostEncodedInsn| emit_btr_reg32_reg!(enc, amd64_reg::RBX, amd64_reg::RDX),
+    [0x0f, 0xb3, 0xd3]
+);
+
+test_encoded_insn!(
+    test_bts_ecx_3,
+    |enc: &mut HostEncodedInsn| emit_bts_reg32_imm!(enc, amd64_reg::RCX, 3),
+    [0x0f, 0xba, 0xe9, 0x03]
+);
+
+test_encoded_insn!(
+    test_cmova_ecx_edx,
+    |enc: &mut HostEncodedInsn| emit_cmova_reg32_reg!(enc, amd64_reg::RCX, amd64_reg::RDX),
+    [0x0f, 0x47, 0xca]
+);
+
+test_encoded_insn!(
+    test_movq_xmm1_rbx,
+    |enc: &mut HostEncodedInsn| emit_movq_xmm_reg!(enc, 1, amd64_reg::RBX),
+    [0x66, 0x48, 0x0f, 0x6e, 0xcb]
+);
+
+test_encoded_insn!(
+    test_movq_rax_xmm0,
+    |enc: &mut HostEncodedInsn| emit_movq_reg_xmm!(enc, amd64_reg::RAX, 0),
+    [0x66, 0x48, 0x0f, 0x7e, 0xc0]
+);
+
+test_encoded_insn!(
+    test_pclmulqdq_xmm0_xmm1,
+    |enc: &mut HostEncodedInsn| emit_pclmulqdq_xmm_xmm!(enc, 0, 1),
+    [0x66, 0x0f, 0x3a, 0x44, 0xc1, 0x00]
+);
@@ -0,0 +1,606 @@
+use std::mem::offset_of;
+
+use crate::backend::target::core::{
+    amd64_reg, BackendCoreImpl, CMP_JMP_IMM32_SIZE, MMU_IS_ACTIVE_REG,
+};
+use crate::backend::{BackendCore, HostEncodedInsn, JumpCond};
+use crate::cpu::{self, CpuReg, Exception};
+use crate::frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_OFFSET_MASK, RV_PAGE_SHIFT};
+use crate::frontend::trace::{
+    BranchProfile, Trace, TraceAluOp, TraceExit, TraceOp, TraceOperand, TraceSite,
+    TRACE_INTERRUPT_CHECK_INTERVAL,
+};
+use crate::*;
+
+// Guest registers get one of these for the whole trace. The first three survive
+// the C calls of the first tier code the trace embeds, so the busiest guest
+// registers go there
+const TRACE_HOST_REGS: [u8; 9] = [
+    amd64_reg::R12,
+    amd64_reg::R13,
+    amd64_reg::R14,
+    amd64_reg::RSI,
+    amd64_reg::RDI,
+    amd64_reg::R8,
+    amd64_reg::R9,
+    amd64_reg::R10,
+    amd64_reg::R11,
+];
+
+const TRACE_CALLEE_SAVED_REGS: usize = 3;
+
+// Holds &cpu.regs while a trace runs, guest registers are [rbp + 4 * reg]
+const TRACE_REGS_BASE: u8 = amd64_reg::RBP;
+
+const OPCODE_MOV_RM_REG: u8 = 0x89;
+const OPCODE_MOV_REG_RM: u8 = 0x8B;
+
+// reg, rm form / 0x81 extension / rm, reg form
+const ARITH_ADD: (u8, u8, u8) = (0x01, 0, 0x03);
+const ARITH_SUB: (u8, u8, u8) = (0x29, 5, 0x2B);
+const ARITH_XOR: (u8, u8, u8) = (0x31, 6, 0x33);
+const ARITH_OR: (u8, u8, u8) = (0x09, 1, 0x0B);
+const ARITH_AND: (u8, u8, u8) = (0x21, 4, 0x23);
+const ARITH_CMP: (u8, u8, u8) = (0x39, 7, 0x3B);
+
+const SHIFT_SHL: u8 = 4;
+const SHIFT_SHR: u8 = 5;
+const SHIFT_SAR: u8 = 7;
+
+const CC_EQUAL: u8 = 0x4;
+const CC_NOT_EQUAL: u8 = 0x5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Loc {
+    Host(u8),
+    Mem(u8),
+    Imm(u32),
+}
+
+fn emit_rex(enc: &mut HostEncodedInsn, reg: u8, rm: u8) {
+    let rex = 0x40 | ((reg >> 3) & 1) << 2 | ((rm >> 3) & 1);
+
+    if rex != 0x40 {
+        emit_insn!(enc, [rex]);
+    }
+}
+
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    mode << 6 | (reg & 7) << 3 | (rm & 7)
+}
+
+fn emit_op_reg_reg(enc: &mut HostEncodedInsn, opcode: u8, rm: u8, reg: u8) {
+    emit_rex(enc, reg, rm);
+    emit_insn!(enc, [opcode, modrm(0b11, reg, rm)]);
+}
+
+fn emit_op_reg_guest(enc: &mut HostEncodedInsn, opcode: u8, reg: u8, guest_reg: u8) {
+    emit_rex(enc, reg, TRACE_REGS_BASE);
+    emit_insn!(
+        enc,
+        [
+            opcode,
+            modrm(0b01, reg, TRACE_REGS_BASE),
+            guest_reg * std::mem::size_of::<CpuReg>() as u8
+        ]
+    );
+}
+
+fn emit_op_reg_imm(enc: &mut HostEncodedInsn, ext: u8, reg: u8, imm: u32) {
+    emit_rex(enc, 0, reg);
+    emit_insn!(enc, [0x81, modrm(0b11, ext, reg)]);
+    emit_insn!(enc, imm.to_le_bytes());
+}
+
+fn emit_mov_guest_imm(enc: &mut HostEncodedInsn, guest_reg: u8, imm: u32) {
+    emit_insn!(
+        enc,
+        [
+            0xC7,
+            modrm(0b01, 0, TRACE_REGS_BASE),
+            guest_reg * std::mem::size_of::<CpuReg>() as u8
+        ]
+    );
+    emit_insn!(enc, imm.to_le_bytes());
+}
+
+fn emit_mov_loc(enc: &mut HostEncodedInsn, reg: u8, loc: Loc) {
+    match loc {
+        Loc::Host(host) => emit_op_reg_reg(enc, OPCODE_MOV_RM_REG, reg, host),
+        Loc::Mem(guest_reg) => emit_op_reg_guest(enc, OPCODE_MOV_REG_RM, reg, guest_reg),
+        Loc::Imm(imm) => {
+            emit_rex(enc, 0, reg);
+            emit_insn!(enc, [0xB8 + (reg & 7)]);
+            emit_insn!(enc, imm.to_le_bytes());
+        }
+    }
+}
+
+fn emit_arith(enc: &mut HostEncodedInsn, arith: (u8, u8, u8), reg: u8, loc: Loc) {
+    let (opcode_rm_reg, ext, opcode_reg_rm) = arith;
+
+    match loc {
+        Loc::Host(host) => emit_op_reg_reg(enc, opcode_rm_reg, reg, host),
+        Loc::Mem(guest_reg) => emit_op_reg_guest(enc, opcode_reg_rm, reg, guest_reg),
+        Loc::Imm(imm) => emit_op_reg_imm(enc, ext, reg, imm),
+    }
+}
+
+fn emit_jcc_imm32(enc: &mut HostEncodedInsn, cc: u8, offset: i32) {
+    emit_insn!(enc, [0x0F, 0x80 + cc]);
+    emit_insn!(enc, (offset as u32).to_le_bytes());
+}
+
+fn condition_code(cond: JumpCond) -> u8 {
+    match cond {
+        JumpCond::Equal => CC_EQUAL,
+        JumpCond::NotEqual => CC_NOT_EQUAL,
+        JumpCond::LessThan => 0xC,
+        JumpCond::GreaterThanEqual => 0xD,
+        JumpCond::LessThanUnsigned => 0x2,
+        JumpCond::GreaterThanEqualUnsigned => 0x3,
+        _ => unreachable!(),
+    }
+}
+
+// add qword [reg], insns on the progress counter of the translating hart,
+// nothing unless the counters are on
+fn emit_add_progress(enc: &mut HostEncodedInsn, reg: u8, insns: u32) {
+    if !cpu::count_progress() {
+        return;
+    }
+
+    let progress = cpu::hart_progress(cpu::get_cpu().core_id as usize);
+
+    emit_movabs_reg_imm!(enc, reg, progress.as_ptr() as usize);
+    emit_insn!(enc, [0x48, 0x81, modrm(0b00, 0, reg)]);
+    emit_insn!(enc, insns.to_le_bytes());
+}
+
+fn emit_set_page(enc: &mut HostEncodedInsn, page: CpuReg) {
+    let cpu = cpu::get_cpu();
+
+    let gpfn_addr = &cpu.current_gpfn as *const _ as usize;
+    emit_mov_reg_imm_auto!(enc, amd64_reg::RAX, gpfn_addr);
+    emit_mov_dword_ptr_imm!(enc, amd64_reg::RAX, page >> RV_PAGE_SHIFT);
+
+    let guest_page_addr = &cpu.current_guest_page as *const _ as usize;
+    emit_mov_reg_imm_auto!(enc, amd64_reg::RAX, guest_page_addr);
+    emit_mov_dword_ptr_imm!(enc, amd64_reg::RAX, page);
+
+    // Pages are only chained with the mmu off
+    let phys_page_addr = &cpu.current_phys_page as *const _ as usize;
+    emit_mov_reg_imm_auto!(enc, amd64_reg::RAX, phys_page_addr);
+    emit_mov_dword_ptr_imm!(enc, amd64_reg::RAX, page);
+}
+
+// Second tier code for one trace. Guest registers live in host registers
+// between the first tier instructions the trace embeds and are only written
+// back to cpu.regs when something outside of the trace needs them
+pub struct TraceEmitter {
+    host_regs: [Option<u8>; 32],
+    // Guest registers the trace writes and whose host copy may be newer
+    written: u32,
+    dirty: u32,
+    head_pc: CpuReg,
+    // Guest instructions of one trip around the loop
+    insns: u32,
+    page: CpuReg,
+    multi_page: bool,
+    site: *mut TraceSite,
+    loop_start: usize,
+}
+
+impl TraceEmitter {
+    pub fn new(trace: &Trace, site: *mut TraceSite) -> TraceEmitter {
+        let mut uses = [0usize; 32];
+        let mut written = 0u32;
+
+        let use_operand = |operand: &TraceOperand, uses: &mut [usize; 32]| {
+            if let TraceOperand::Reg(reg) = operand {
+                uses[*reg as usize] += 1;
+            }
+        };
+
+        for op in &trace.ops {
+            match op {
+                TraceOp::Li { rd, .. } => {
+                    uses[*rd as usize] += 1;
+                    written |= 1 << rd;
+                }
+                TraceOp::Alu { rd, rs1, rs2, .. } => {
+                    uses[*rd as usize] += 1;
+                    written |= 1 << rd;
+
+                    use_operand(rs1, &mut uses);
+                    use_operand(rs2, &mut uses);
+                }
+                TraceOp::ExitIf { rs1, rs2, .. } => {
+                    use_operand(rs1, &mut uses);
+                    use_operand(rs2, &mut uses);
+                }
+                _ => {}
+            }
+        }
+
+        let mut by_use: Vec<usize> = (1..32).filter(|reg| uses[*reg] != 0).collect();
+
+        by_use.sort_by_key(|reg| std::cmp::Reverse(uses[*reg]));
+
+        let mut host_regs = [None; 32];
+
+        for (reg, host) in by_use.iter().zip(TRACE_HOST_REGS) {
+            host_regs[*reg] = Some(host);
+        }
+
+        let page = trace.head_pc & RV_PAGE_MASK as CpuReg;
+
+        TraceEmitter {
+            host_regs,
+            written,
+            dirty: 0,
+            head_pc: trace.head_pc,
+            insns: trace.insns,
+            page,
+            multi_page: trace.multi_page,
+            site,
+            loop_start: 0,
+        }
+    }
+
+    // Emitted by the first tier in front of a loop back-edge. Jumps into the
+    // trace once there is one, otherwise counts the loop body as progress,
+    // counts the branch and leaves with HotBlock when it got hot
+    pub fn emit_site_check(
+        site: *mut TraceSite,
+        threshold: u32,
+        body_insns: u32,
+    ) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        emit_movabs_reg_imm!(insn, amd64_reg::RAX, site as usize);
+
+        // mov rdx, [rax + trace_ptr]; test rdx, rdx; jz +2; jmp rdx
+        emit_insn!(
+            insn,
+            [0x48, 0x8B, 0x50, offset_of!(TraceSite, trace_ptr) as u8]
+        );
+        emit_insn!(insn, [0x48, 0x85, 0xD2, 0x74, 0x02]);
+        emit_jmp_reg!(insn, amd64_reg::RDX);
+
+        emit_add_progress(&mut insn, amd64_reg::RDX, body_insns);
+
+        // inc dword [rax]; cmp dword [rax], threshold
+        emit_insn!(insn, [0xFF, 0x00]);
+        emit_insn!(insn, [0x81, 0x38]);
+        emit_insn!(insn, threshold.to_le_bytes());
+
+        let hot = BackendCoreImpl::emit_ret_with_exception(Exception::HotBlock);
+
+        emit_jne_imm!(insn, hot.size());
+        insn.push_slice(hot.as_slice());
+
+        insn
+    }
+
+    // In front of a profiled branch, counts it running
+    pub fn emit_branch_executed(profile: *mut BranchProfile) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        // inc qword [rax]
+        emit_movabs_reg_imm!(insn, amd64_reg::RAX, profile as usize);
+        emit_insn!(insn, [0x48, 0xFF, 0x00]);
+
+        insn
+    }
+
+    // Right behind a profiled branch, only its fallthrough gets here
+    pub fn emit_branch_not_taken(profile: *mut BranchProfile) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        // inc qword [rax]
+        emit_movabs_reg_imm!(
+            insn,
+            amd64_reg::RAX,
+            profile as usize + offset_of!(BranchProfile, not_taken)
+        );
+        emit_insn!(insn, [0x48, 0xFF, 0x00]);
+
+        insn
+    }
+
+    // Stands in for the site check when there is no second tier
+    pub fn emit_progress_count(body_insns: u32) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        emit_add_progress(&mut insn, amd64_reg::RAX, body_insns);
+
+        insn
+    }
+
+    fn loc(&self, operand: TraceOperand) -> Loc {
+        match operand {
+            TraceOperand::Imm(imm) => Loc::Imm(imm),
+            TraceOperand::Reg(0) => Loc::Imm(0),
+            TraceOperand::Reg(reg) => match self.host_regs[reg as usize] {
+                Some(host) => Loc::Host(host),
+                None => Loc::Mem(reg),
+            },
+        }
+    }
+
+    fn emit_store_rd(&mut self, enc: &mut HostEncodedInsn, rd: u8) {
+        match self.host_regs[rd as usize] {
+            Some(host) => {
+                emit_op_reg_reg(enc, OPCODE_MOV_RM_REG, host, amd64_reg::RAX);
+                self.dirty |= 1 << rd;
+            }
+            None => emit_op_reg_guest(enc, OPCODE_MOV_RM_REG, amd64_reg::RAX, rd),
+        }
+    }
+
+    fn emit_writeback(&self, enc: &mut HostEncodedInsn) {
+        for reg in 1..32u8 {
+            if self.dirty & (1 << reg) != 0 {
+                let host = self.host_regs[reg as usize].unwrap();
+
+                emit_op_reg_guest(enc, OPCODE_MOV_RM_REG, host, reg);
+            }
+        }
+    }
+
+    fn emit_exit(&self, enc: &mut HostEncodedInsn, exit: TraceExit) {
+        self.emit_writeback(enc);
+
+        let page = exit.pc & RV_PAGE_MASK as CpuReg;
+
+        if page != self.page {
+            emit_set_page(enc, page);
+        }
+
+        match exit.host_ptr {
+            Some(host_ptr) => {
+                emit_movabs_reg_imm!(enc, amd64_reg::RAX, host_ptr as usize);
+                emit_jmp_reg!(enc, amd64_reg::RAX);
+            }
+            None => {
+                let cpu = cpu::get_cpu();
+
+                emit_set_exception!(
+                    enc,
+                    cpu,
+                    Exception::BlockExit.to_cpu_reg(),
+                    0,
+                    exit.pc & RV_PAGE_OFFSET_MASK as CpuReg
+                );
+            }
+        }
+    }
+
+    // Checks that the trace is entered from the virtual page it was formed in,
+    // and without paging for traces spanning pages, then loads the guest registers
+    pub fn emit_prologue(&mut self, host_ptr: *mut u8, resume_ptr: usize) -> HostEncodedInsn {
+        let cpu = cpu::get_cpu();
+
+        let mut insn = HostEncodedInsn::new();
+
+        emit_movabs_reg_imm!(insn, TRACE_REGS_BASE, cpu.regs.as_ptr() as usize);
+
+        let guest_page_addr = &cpu.current_guest_page as *const _ as usize;
+        emit_mov_reg_imm_auto!(insn, amd64_reg::RAX, guest_page_addr);
+
+        // cmp dword [rax], page
+        emit_insn!(insn, [0x81, 0x38]);
+        emit_insn!(insn, self.page.to_le_bytes());
+
+        let mut resume = HostEncodedInsn::new();
+        emit_movabs_reg_imm!(resume, amd64_reg::RAX, resume_ptr);
+        emit_jmp_reg!(resume, amd64_reg::RAX);
+
+        if self.multi_page {
+            // jne resume; test r15, r15; jnz resume
+            emit_jne_imm!(insn, 11);
+            emit_insn!(
+                insn,
+                [
+                    0x4D,
+                    0x85,
+                    modrm(0b11, MMU_IS_ACTIVE_REG, MMU_IS_ACTIVE_REG)
+                ]
+            );
+            emit_jne_imm!(insn, 2);
+        } else {
+            emit_jne_imm!(insn, 2);
+        }
+
+        emit_insn!(insn, [0xEB, resume.size() as u8]);
+        insn.push_slice(resume.as_slice());
+
+        for reg in 1..32u8 {
+            if let Some(host) = self.host_regs[reg as usize] {
+                emit_op_reg_guest(&mut insn, OPCODE_MOV_REG_RM, host, reg);
+            }
+        }
+
+        self.loop_start = host_ptr as usize + insn.size();
+
+        // Coming around from the previous iteration anything written may be dirty
+        self.dirty = self.written
+            & (1..32)
+                .filter(|reg| self.host_regs[*reg].is_some())
+                .fold(0, |mask, reg| mask | 1 << reg);
+
+        insn
+    }
+
+    pub fn emit_op(&mut self, op: &TraceOp, host_ptr: *mut u8) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        match *op {
+            TraceOp::Li { rd, imm } => match self.host_regs[rd as usize] {
+                Some(host) => {
+                    emit_mov_loc(&mut insn, host, Loc::Imm(imm));
+                    self.dirty |= 1 << rd;
+                }
+                None => emit_mov_guest_imm(&mut insn, rd, imm),
+            },
+            TraceOp::Alu { op, rd, rs1, rs2 } => {
+                let rs2 = self.loc(rs2);
+
+                emit_mov_loc(&mut insn, amd64_reg::RAX, self.loc(rs1));
+
+                match op {
+                    TraceAluOp::Add | TraceAluOp::Sub | TraceAluOp::Xor | TraceAluOp::Or => {
+                        let arith = match op {
+                            TraceAluOp::Add => ARITH_ADD,
+                            TraceAluOp::Sub => ARITH_SUB,
+                            TraceAluOp::Xor => ARITH_XOR,
+                            _ => ARITH_OR,
+                        };
+
+                        if rs2 != Loc::Imm(0) {
+                            emit_arith(&mut insn, arith, amd64_reg::RAX, rs2);
+                        }
+                    }
+                    TraceAluOp::And => emit_arith(&mut insn, ARITH_AND, amd64_reg::RAX, rs2),
+                    TraceAluOp::Sll | TraceAluOp::Srl | TraceAluOp::Sra => {
+                        let ext = match op {
+                            TraceAluOp::Sll => SHIFT_SHL,
+                            TraceAluOp::Srl => SHIFT_SHR,
+                            _ => SHIFT_SAR,
+                        };
+
+                        match rs2 {
+                            Loc::Imm(shamt) if shamt & 0x1f == 0 => {}
+                            Loc::Imm(shamt) => {
+                                emit_insn!(insn, [0xC1, modrm(0b11, ext, amd64_reg::RAX)]);
+                                emit_insn!(insn, [(shamt & 0x1f) as u8]);
+                            }
+                            _ => {
+                                emit_mov_loc(&mut insn, amd64_reg::RCX, rs2);
+                                emit_insn!(insn, [0xD3, modrm(0b11, ext, amd64_reg::RAX)]);
+                            }
+                        }
+                    }
+                    TraceAluOp::Slt | TraceAluOp::Sltu => {
+                        let cond = match op {
+                            TraceAluOp::Slt => JumpCond::LessThan,
+                            _ => JumpCond::LessThanUnsigned,
+                        };
+
+                        emit_arith(&mut insn, ARITH_CMP, amd64_reg::RAX, rs2);
+
+                        // setcc al; movzx eax, al
+                        emit_insn!(insn, [0x0F, 0x90 + condition_code(cond), 0xC0]);
+                        emit_insn!(insn, [0x0F, 0xB6, 0xC0]);
+                    }
+                }
+
+                self.emit_store_rd(&mut insn, rd);
+            }
+            TraceOp::SetPage { page } => {
+                emit_set_page(&mut insn, page);
+                self.page = page;
+            }
+            TraceOp::ExitIf {
+                cond,
+                rs1,
+                rs2,
+                exit,
+            } => {
+                let lhs = match self.loc(rs1) {
+                    Loc::Host(host) => host,
+                    loc => {
+                        emit_mov_loc(&mut insn, amd64_reg::RAX, loc);
+                        amd64_reg::RAX
+                    }
+                };
+
+                emit_arith(&mut insn, ARITH_CMP, lhs, self.loc(rs2));
+
+                let mut exit_insn = HostEncodedInsn::new();
+                self.emit_exit(&mut exit_insn, exit);
+
+                let stay = crate::frontend::trace::inverse_cond(cond);
+
+                emit_jcc_imm32(&mut insn, condition_code(stay), exit_insn.size() as i32);
+                insn.push_slice(exit_insn.as_slice());
+            }
+            TraceOp::Exit(exit) => self.emit_exit(&mut insn, exit),
+            TraceOp::Loop => {
+                let cpu = cpu::get_cpu();
+
+                let countdown_addr =
+                    self.site as usize + offset_of!(TraceSite, interrupt_countdown);
+
+                // dec dword [rax]; jnz loop_start
+                emit_movabs_reg_imm!(insn, amd64_reg::RAX, countdown_addr);
+                emit_insn!(insn, [0xFF, 0x08]);
+                self.emit_jcc_loop_start(&mut insn, CC_NOT_EQUAL, host_ptr);
+
+                emit_mov_dword_ptr_imm!(insn, amd64_reg::RAX, TRACE_INTERRUPT_CHECK_INTERVAL);
+
+                // Counts the iterations since the last check all at once
+                emit_add_progress(
+                    &mut insn,
+                    amd64_reg::RAX,
+                    TRACE_INTERRUPT_CHECK_INTERVAL * self.insns,
+                );
+
+                // Leaves at the head so pending interrupts get taken
+                let pending_addr = &cpu.has_pending_interrupt as *const _ as usize;
+                emit_mov_reg_imm_auto!(insn, amd64_reg::RAX, pending_addr);
+                emit_insn!(insn, [0x83, 0x38, 0x00]);
+                self.emit_jcc_loop_start(&mut insn, CC_EQUAL, host_ptr);
+
+                self.emit_exit(
+                    &mut insn,
+                    TraceExit {
+                        pc: self.head_pc,
+                        host_ptr: None,
+                    },
+                );
+            }
+            TraceOp::Guest { .. } => unreachable!(),
+        }
+
+        insn
+    }
+
+    fn emit_jcc_loop_start(&self, enc: &mut HostEncodedInsn, cc: u8, host_ptr: *mut u8) {
+        let jcc_end = host_ptr as usize + enc.size() + CMP_JMP_IMM32_SIZE;
+        let offset = self.loop_start as i64 - jcc_end as i64;
+
+        assert!(offset >= i32::MIN as i64);
+
+        emit_jcc_imm32(enc, cc, offset as i32);
+    }
+
+    // First tier code embedded in the trace reads and writes cpu.regs
+    pub fn emit_guest_enter(&mut self) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        self.emit_writeback(&mut insn);
+        self.dirty = 0;
+
+        insn
+    }
+
+    // Reloads what the first tier code may have changed, its C calls clobber
+    // all but the callee saved host registers
+    pub fn emit_guest_leave(&mut self, rd: u8) -> HostEncodedInsn {
+        let mut insn = HostEncodedInsn::new();
+
+        for reg in 1..32u8 {
+            let Some(host) = self.host_regs[reg as usize] else {
+                continue;
+            };
+
+            if reg == rd || !TRACE_HOST_REGS[..TRACE_CALLEE_SAVED_REGS].contains(&host) {
+                emit_op_reg_guest(&mut insn, OPCODE_MOV_REG_RM, host, reg);
+            }
+        }
+
+        insn
+    }
+}
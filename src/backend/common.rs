@@ -131,23 +131,18 @@ impl UsizeConversions for BusAccessCond {
 fn do_jump(guest_address: CpuReg, current_guest_pc: CpuReg, rd: *mut CpuReg) -> usize {
     let cpu = cpu::get_cpu();
 
-    let guest_address_phys = if cpu.mmu.is_active() {
+    // A target that lost its mapping or execute permission is left to the exec loop,
+    // the jump completes and the fetch faults with the target as the exception pc
+    let guest_address_phys = if cpu.mmu.is_active() || cpu.pmp.restricts(cpu.mode) {
         let bus = bus::get_bus();
 
-        let guest_address_phys = bus.translate(guest_address, &mut cpu.mmu, AccessType::Fetch);
-
-        if guest_address_phys.is_err() {
-            cpu.set_exception(guest_address_phys.err().unwrap(), current_guest_pc);
-
-            ReturnableImpl::throw();
-        }
-
-        guest_address_phys.unwrap()
+        bus.translate(guest_address, &mut cpu.mmu, AccessType::Fetch)
+            .ok()
     } else {
-        guest_address
+        Some(guest_address)
     };
 
-    let host_addr = cpu.insn_map.get_by_guest_idx(guest_address_phys);
+    let host_addr = guest_address_phys.and_then(|phys| cpu.insn_map.get_by_guest_idx(phys));
 
     if host_addr.is_none() {
         if guest_address % INSN_SIZE as CpuReg == 0 && !rd.is_null() {
@@ -173,6 +168,7 @@ fn do_jump(guest_address: CpuReg, current_guest_pc: CpuReg, rd: *mut CpuReg) ->
     // we need to update the current_gpfn.
     cpu.current_gpfn = guest_address >> RV_PAGE_SHIFT as CpuReg;
     cpu.current_guest_page = guest_address & RV_PAGE_MASK as CpuReg;
+    cpu.current_phys_page = guest_address_phys.unwrap() & RV_PAGE_MASK as BusType;
 
     host_addr.unwrap().host_ptr as usize
 }
@@ -434,9 +430,13 @@ fn do_store(rs1: *mut CpuReg, rs2: *mut CpuReg, imm: i32, guest_pc: CpuReg, stor
         if was_rx {
             gpfn_state.set_state(PageState::ReadExecute);
 
-            if !result.is_err() {
-                cpu.set_exception(Exception::InvalidateJitBlock(gpfn, true), guest_pc);
-                ReturnableImpl::throw();
+            if result.is_ok() {
+                if gpfn_state.is_code_write(addr, store_size as usize / 8) {
+                    cpu.set_exception(Exception::InvalidateJitBlock(addr), guest_pc);
+                    ReturnableImpl::throw();
+                }
+
+                return;
             }
         }
 
@@ -464,6 +464,13 @@ fn do_store(rs1: *mut CpuReg, rs2: *mut CpuReg, imm: i32, guest_pc: CpuReg, stor
 
         ReturnableImpl::throw();
     }
+
+    // The store may have started device DMA over translated code, leave the block so it gets dropped
+    if !cpu.dma_dirty_gpfns.is_empty() {
+        cpu.set_exception(Exception::BookkeepingRet, guest_pc);
+
+        ReturnableImpl::throw();
+    }
 }
 
 pub extern "C" fn c_sb_cb(rd: usize, rs1: usize, imm: usize, guest_pc: usize) {
@@ -593,12 +600,12 @@ pub trait BackendCore {
         fn_ptr: extern "C" fn(usize) -> usize,
         arg1: usize,
     ) -> HostEncodedInsn;
-    fn fastmem_violation_likely_offset() -> usize;
     fn patch_fastmem_violation(
         host_exception_addr: usize,
         guest_exception_addr: BusType,
     ) -> FastmemHandleType;
     fn patch_jump_list(jump_list: &Vec<JumpAddrPatch>);
+    fn emit_jump(host_ptr: PtrT, host_target: PtrT) -> HostEncodedInsn;
     unsafe fn call_jit_ptr(jit_ptr: PtrT);
     unsafe fn call_jit_ptr_nommu(jit_ptr: PtrT);
 }
@@ -661,6 +668,44 @@ pub trait Rvm {
     fn emit_remu(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
 }
 
+pub trait Rvb {
+    fn emit_sh1add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_sh2add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_sh3add(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+
+    fn emit_andn(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_orn(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_xnor(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_clz(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_ctz(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_cpop(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_max(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_maxu(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_min(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_minu(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_sext_b(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_sext_h(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_zext_h(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_rol(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_ror(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_rori(rd: u8, rs1: u8, shamt: u8) -> DecodeRet;
+    fn emit_orc_b(rd: u8, rs1: u8) -> DecodeRet;
+    fn emit_rev8(rd: u8, rs1: u8) -> DecodeRet;
+
+    fn emit_clmul(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_clmulh(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_clmulr(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+
+    fn emit_bclr(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_bclri(rd: u8, rs1: u8, shamt: u8) -> DecodeRet;
+    fn emit_bext(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_bexti(rd: u8, rs1: u8, shamt: u8) -> DecodeRet;
+    fn emit_binv(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_binvi(rd: u8, rs1: u8, shamt: u8) -> DecodeRet;
+    fn emit_bset(rd: u8, rs1: u8, rs2: u8) -> DecodeRet;
+    fn emit_bseti(rd: u8, rs1: u8, shamt: u8) -> DecodeRet;
+}
+
 pub trait Rva {
     fn emit_lr_w(rd: u8, rs1: u8, aq: bool, rl: bool) -> DecodeRet;
 
@@ -695,10 +740,11 @@ pub trait Csr {
 
     fn emit_ecall() -> DecodeRet;
     fn emit_ebreak() -> DecodeRet;
+    fn emit_semihosting_call() -> DecodeRet;
     fn emit_sret() -> DecodeRet;
     fn emit_mret() -> DecodeRet;
 
     fn emit_wfi() -> DecodeRet;
 
-    fn emit_sfence_vma() -> DecodeRet;
+    fn emit_sfence_vma(rs1: u8, rs2: u8) -> DecodeRet;
 }
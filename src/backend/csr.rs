@@ -2,10 +2,11 @@ use common::DecodeRet;
 
 use crate::backend::common;
 use crate::backend::target::core::BackendCoreImpl;
-use crate::bus::mmu::Mmu;
-use crate::bus::BusType;
+use crate::bus::imsic::{self, ImsicLevel};
+use crate::bus::mmu::{AccessType, Mmu};
+use crate::bus::{self, tlb, BusType};
 use crate::cpu::csr::{self, CsrType, MppMode};
-use crate::cpu::{self, CpuReg, Exception};
+use crate::cpu::{self, pmp, CpuReg, Exception};
 use crate::frontend::exec_core::RV_PAGE_SHIFT;
 
 use super::{BackendCore, ReturnableHandler, ReturnableImpl};
@@ -71,12 +72,114 @@ fn csr_satp_handler(csr_reg: usize, csr_val: usize) -> Result<usize, Exception>
     val
 }
 
+fn csr_pmp_handler(csr_reg: usize, csr_val: usize) -> Result<usize, Exception> {
+    let cpu = cpu::get_cpu();
+
+    cpu.pmp.write(csr_reg, csr_val as BusType);
+
+    // Locked and reserved fields keep their value
+    csr_default_handler(csr_reg, cpu.pmp.read(csr_reg) as usize)
+}
+
+fn csr_aia_level(csr_reg: usize) -> ImsicLevel {
+    match csr_reg {
+        csr::register::MISELECT
+        | csr::register::MIREG
+        | csr::register::MTOPEI
+        | csr::register::MTOPI => ImsicLevel::Machine,
+        _ => ImsicLevel::Supervisor,
+    }
+}
+
+fn is_aia_csr(csr_reg: usize) -> bool {
+    matches!(
+        csr_reg,
+        csr::register::MISELECT
+            | csr::register::MIREG
+            | csr::register::MTOPEI
+            | csr::register::MTOPI
+            | csr::register::SISELECT
+            | csr::register::SIREG
+            | csr::register::STOPEI
+            | csr::register::STOPI
+    )
+}
+
+fn csr_aia_check(csr_reg: usize) -> Result<(), Exception> {
+    let cpu = cpu::get_cpu();
+
+    let allowed = match csr_aia_level(csr_reg) {
+        ImsicLevel::Machine => cpu.mode == MppMode::Machine,
+        ImsicLevel::Supervisor => cpu.mode != MppMode::User,
+    };
+
+    if !allowed {
+        return Err(Exception::IllegalInstruction(0));
+    }
+
+    let iselect = match csr_reg {
+        csr::register::MIREG => Some(cpu.csr.read(csr::register::MISELECT)),
+        csr::register::SIREG => Some(cpu.csr.read(csr::register::SISELECT)),
+        csr::register::MTOPEI | csr::register::STOPEI if !imsic::is_enabled() => {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        _ => None,
+    };
+
+    // Without an IMSIC only the major interrupt priorities are accessible
+    if let Some(iselect) = iselect {
+        let file = csr::Csr::get_imsic_file(csr_aia_level(csr_reg));
+
+        if file.read_ireg(iselect).is_none()
+            || (!imsic::is_enabled() && iselect > imsic::ISELECT_IPRIO_END)
+        {
+            return Err(Exception::IllegalInstruction(0));
+        }
+    }
+
+    Ok(())
+}
+
+fn csr_aia_handler(csr_reg: usize, csr_val: usize) -> Result<usize, Exception> {
+    csr_aia_check(csr_reg)?;
+
+    let cpu = cpu::get_cpu();
+    let level = csr_aia_level(csr_reg);
+
+    match csr_reg {
+        csr::register::MIREG | csr::register::SIREG => {
+            let iselect = match level {
+                ImsicLevel::Machine => cpu.csr.read(csr::register::MISELECT),
+                ImsicLevel::Supervisor => cpu.csr.read(csr::register::SISELECT),
+            };
+
+            csr::Csr::get_imsic_file(level).write_ireg(iselect, csr_val as CsrType);
+        }
+        // Any write claims the interrupt reported by the read
+        csr::register::MTOPEI | csr::register::STOPEI => {
+            csr::Csr::get_imsic_file(level).claim_topei();
+        }
+        csr::register::MTOPI | csr::register::STOPI => {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        _ => {
+            return csr_default_handler(csr_reg, csr_val);
+        }
+    }
+
+    Ok(csr_val)
+}
+
 static mut CSR_HANDLERS: [CsrHandler; csr::CSR_COUNT] = [csr_default_handler; csr::CSR_COUNT];
 
 pub fn init_backend_csr() {
     unsafe {
         CSR_HANDLERS[csr::register::SATP] = csr_satp_handler;
 
+        for csr_reg in (0..csr::CSR_COUNT).filter(|csr_reg| pmp::is_pmp_csr(*csr_reg)) {
+            CSR_HANDLERS[csr_reg] = csr_pmp_handler;
+        }
+
         CSR_HANDLERS[csr::register::MISA] = csr_readonly_handler;
         CSR_HANDLERS[csr::register::TDATA1] = csr_readonly_handler;
         CSR_HANDLERS[csr::register::MARCHID] = csr_readonly_handler;
@@ -86,6 +189,15 @@ pub fn init_backend_csr() {
 
         CSR_HANDLERS[csr::register::CYCLE] = csr_enforced_readonly_handler;
         CSR_HANDLERS[csr::register::MSTATUS] = csr_privledged_handler;
+
+        CSR_HANDLERS[csr::register::MISELECT] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::MIREG] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::MTOPEI] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::MTOPI] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::SISELECT] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::SIREG] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::STOPEI] = csr_aia_handler;
+        CSR_HANDLERS[csr::register::STOPI] = csr_aia_handler;
     }
 }
 
@@ -116,7 +228,14 @@ extern "C" fn csr_handler_cb(csr_reg: usize, rd_rhs: usize, op: usize, pc: usize
         }
     };
 
-    let rd_val = unsafe { CSR_HANDLERS[csr_reg](csr_reg, new_csr_val) };
+    // csrrs/csrrc with x0 don't write, which matters for CSRs with write side effects
+    let is_write = matches!(op, CSRRW | CSRRWI) || rhs != 0;
+
+    let rd_val = if !is_write && is_aia_csr(csr_reg) {
+        csr_aia_check(csr_reg).map(|_| new_csr_val)
+    } else {
+        unsafe { CSR_HANDLERS[csr_reg](csr_reg, new_csr_val) }
+    };
 
     if rd_val.is_err() {
         cpu.set_exception(rd_val.err().unwrap(), pc as CpuReg);
@@ -128,13 +247,46 @@ extern "C" fn csr_handler_cb(csr_reg: usize, rd_rhs: usize, op: usize, pc: usize
         cpu.regs[rd] = csr_val as CsrType;
     }
 
-    if csr_reg == csr::register::SATP {
-        cpu.set_exception(Exception::MmuStateUpdate, pc as CpuReg);
+    // Accesses after a PMP change need the TLB refilled, and the JIT may have to
+    // switch between its translated and untranslated memory paths
+    if is_write && pmp::is_pmp_csr(csr_reg) {
+        tlb::asid_tlb_fence(None, None);
+    }
+
+    // Loads and stores switch privilege when MPRV changes, or MPP while it's set
+    let mprv_changed = csr_reg == csr::register::MSTATUS && {
+        let mstatus = cpu.csr.read(csr_reg) as usize;
+        let mask = if mstatus & csr::MPRV != 0 {
+            csr::MPRV | (0b11 << 11)
+        } else {
+            csr::MPRV
+        };
+
+        (mstatus ^ csr_val) & mask != 0
+    };
+
+    if csr_reg == csr::register::SATP || (is_write && pmp::is_pmp_csr(csr_reg)) || mprv_changed {
+        let exception = mmu_state_update(cpu);
+        cpu.set_exception(exception, pc as CpuReg);
 
         ReturnableImpl::throw();
     }
 }
 
+// The block exits after the address translation changed. If the page it runs from
+// no longer fetches from the same physical page, that translation is discarded
+fn mmu_state_update(cpu: &mut cpu::Cpu) -> Exception {
+    let bus = bus::get_bus();
+
+    let phys_page = bus.translate(cpu.current_guest_page, &mut cpu.mmu, AccessType::Fetch);
+
+    if phys_page == Ok(cpu.current_phys_page) {
+        Exception::MmuStateUpdate
+    } else {
+        Exception::DiscardJitBlock(cpu.current_phys_page)
+    }
+}
+
 // Nothing is more permanent than a temporary solution
 extern "C" fn mret_handler_cb(pc: usize) {
     let cpu = cpu::get_cpu();
@@ -193,19 +345,29 @@ extern "C" fn sret_handler_cb(pc: usize) {
     cpu.set_exception(Exception::Sret, pc as CpuReg);
 }
 
-extern "C" fn sfence_vma_cb(pc: usize) {
+extern "C" fn sfence_vma_cb(rs1_rs2: usize, pc: usize) {
     let cpu = cpu::get_cpu();
 
+    let rs1 = (rs1_rs2 >> 8) & 0x1f;
+    let rs2 = rs1_rs2 & 0x1f;
+
     if cpu.csr.read_bit_mstatus(csr::bits::TVM) || cpu.mode == MppMode::User {
-        let sfence_vma: u32 = 0x12000073;
+        let sfence_vma: u32 = 0x12000073 | ((rs2 as u32) << 20) | ((rs1 as u32) << 15);
         cpu.set_exception(Exception::IllegalInstruction(sfence_vma), pc as CpuReg);
 
         ReturnableImpl::throw();
     }
 
+    // x0 in either operand widens the fence to all addresses or all ASIDs
+    let virt = (rs1 != 0).then(|| cpu.regs[rs1] as BusType);
+    let asid = (rs2 != 0).then(|| cpu.regs[rs2] as usize % tlb::ASID_COUNT);
+
+    tlb::asid_tlb_fence(virt, asid);
+
     cpu.mmu.update(cpu.csr.read(csr::register::SATP));
 
-    cpu.set_exception(Exception::MmuStateUpdate, pc as CpuReg);
+    let exception = mmu_state_update(cpu);
+    cpu.set_exception(exception, pc as CpuReg);
 
     ReturnableImpl::throw();
 }
@@ -241,6 +403,12 @@ extern "C" fn ecall_cb(pc: usize) {
     }
 }
 
+extern "C" fn semihosting_cb(_pc: usize) {
+    let cpu = cpu::get_cpu();
+
+    cpu::semihosting::handle_call(cpu);
+}
+
 impl common::Csr for CsrImpl {
     fn emit_csrrw(rd: u8, rs1: u8, csr: u16) -> DecodeRet {
         let rd = rd as usize;
@@ -360,6 +528,15 @@ impl common::Csr for CsrImpl {
         Ok(insn)
     }
 
+    fn emit_semihosting_call() -> DecodeRet {
+        let insn = BackendCoreImpl::emit_void_call_with_1_arg(
+            semihosting_cb,
+            cpu::get_cpu().current_gpfn_offset as usize,
+        );
+
+        Ok(insn)
+    }
+
     fn emit_sret() -> DecodeRet {
         let mut insn = BackendCoreImpl::emit_void_call_with_1_arg(
             sret_handler_cb,
@@ -390,9 +567,10 @@ impl common::Csr for CsrImpl {
         Ok(insn)
     }
 
-    fn emit_sfence_vma() -> DecodeRet {
-        let insn = BackendCoreImpl::emit_void_call_with_1_arg(
+    fn emit_sfence_vma(rs1: u8, rs2: u8) -> DecodeRet {
+        let insn = BackendCoreImpl::emit_void_call_with_2_args(
             sfence_vma_cb,
+            ((rs1 as usize) << 8) | rs2 as usize,
             cpu::get_cpu().current_gpfn_offset as usize,
         );
 
@@ -3,7 +3,7 @@ use std::sync::atomic::{AtomicI32, AtomicU32};
 use super::{core::BackendCoreImpl, BackendCore};
 use crate::backend::{ReturnableHandler, ReturnableImpl};
 use crate::bus::mmu::AccessType;
-use crate::frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_SHIFT};
+use crate::frontend::exec_core::RV_PAGE_MASK;
 use crate::xmem::PageState;
 use crate::{
     backend::common,
@@ -14,8 +14,9 @@ use common::DecodeRet;
 
 pub struct RvaImpl;
 
+// SC and the AMOs translate as stores, so write permission is checked before they run
 macro_rules! fetch_ptr {
-    ($ptr: expr, $addr: expr, $bus: expr, $cpu: expr, $reg: expr, $pc: expr) => {{
+    ($ptr: expr, $addr: expr, $bus: expr, $cpu: expr, $reg: expr, $pc: expr, $access: expr) => {{
         $addr = $cpu.regs[$reg];
 
         if $addr % 4 != 0 {
@@ -24,7 +25,7 @@ macro_rules! fetch_ptr {
             return 1; // 1 is failure, 0 is success
         }
 
-        let phys_addr = $bus.translate($addr, &mut cpu::get_cpu().mmu, AccessType::Load);
+        let phys_addr = $bus.translate($addr, &mut cpu::get_cpu().mmu, $access);
 
         if phys_addr.is_err() {
             $cpu.set_exception(phys_addr.err().unwrap(), $pc as CpuReg);
@@ -84,24 +85,32 @@ fn gpfn_write_check_part_1(addr: CpuReg) -> Option<CpuReg> {
         if gpfn_state.get_state() == PageState::ReadExecute {
             gpfn_state.set_state(PageState::ReadWrite);
 
-            return Option::Some(gpfn >> RV_PAGE_SHIFT as CpuReg);
+            return Option::Some(addr);
         }
     }
 
     Option::None
 }
 
+// Protects the page again, the translation is only patched when the
+// atomic changed one of its instructions
 macro_rules! gpfn_write_check_part_2 {
     ($part_1_result: expr, $guest_pc: expr) => {{
-        if let Some(gpfn) = $part_1_result {
+        if let Some(addr) = $part_1_result {
             let cpu = cpu::get_cpu();
 
-            cpu.set_exception(
-                Exception::InvalidateJitBlock(gpfn, true),
-                $guest_pc as CpuReg,
-            );
+            let gpfn_state = cpu
+                .gpfn_state
+                .get_gpfn_state_mut(addr & RV_PAGE_MASK as CpuReg, AccessType::Store)
+                .unwrap();
 
-            ReturnableImpl::throw();
+            gpfn_state.set_state(PageState::ReadExecute);
+
+            if gpfn_state.is_code_write(addr, std::mem::size_of::<u32>()) {
+                cpu.set_exception(Exception::InvalidateJitBlock(addr), $guest_pc as CpuReg);
+
+                ReturnableImpl::throw();
+            }
         }
     }};
 }
@@ -117,7 +126,7 @@ extern "C" fn lr_w_cb(rd: usize, rs1: usize, aq_rel: usize, pc: usize) -> usize
     let ptr: *mut u8;
     let addr: CpuReg;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Load);
 
     cpu.regs[rd] = atomic_load!(ptr, aq_rel);
 
@@ -136,7 +145,7 @@ extern "C" fn sc_w_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) -> us
     let ptr: *mut u8;
     let addr: CpuReg;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
 
     if cpu.atomic_reservations.contains(&addr) {
         let part_1_res = gpfn_write_check_part_1(addr);
@@ -170,7 +179,7 @@ extern "C" fn amoswapw_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) -
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
 
     unsafe {
         let ptr = ptr as *mut AtomicU32;
@@ -206,7 +215,7 @@ extern "C" fn amoadd_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -243,7 +252,7 @@ extern "C" fn amoxor_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -280,7 +289,7 @@ extern "C" fn amoor_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) -> u
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -317,7 +326,7 @@ extern "C" fn amosub_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -354,7 +363,7 @@ extern "C" fn amoand_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -391,7 +400,7 @@ extern "C" fn amomin_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -428,7 +437,7 @@ extern "C" fn amomax_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -465,7 +474,7 @@ extern "C" fn amominu_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -502,7 +511,7 @@ extern "C" fn amomaxu_cb(rd: usize, rs1_rs2: usize, aq_rel: usize, pc: usize) ->
 
     let ptr: *mut u8;
 
-    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc);
+    fetch_ptr!(ptr, addr, bus, cpu, rs1, pc, AccessType::Store);
     let data = cpu.regs[rs2];
 
     unsafe {
@@ -0,0 +1,263 @@
+use crate::bus::clint::{
+    get_clint, read_u64_half, write_u64_half, Clint, MSIP_SIZE, MTIMECMP_SIZE,
+};
+use crate::bus::*;
+use crate::cpu::*;
+use crate::util;
+
+// Same base as the legacy CLINT so the M-mode register layout stays compatible
+pub const ACLINT_MSWI_ADDR: BusType = 0x2000000;
+const ACLINT_MSWI_SIZE: BusType = 0x4000;
+
+pub const ACLINT_MTIMER_ADDR: BusType = ACLINT_MSWI_ADDR + ACLINT_MSWI_SIZE;
+const ACLINT_MTIMER_SIZE: BusType = 0x8000;
+
+const MTIMECMP: BusType = ACLINT_MTIMER_ADDR;
+const MTIMECMP_SIZE_TOTAL: BusType = 0x7ff8;
+
+const MTIME: BusType = ACLINT_MTIMER_ADDR + MTIMECMP_SIZE_TOTAL;
+const MTIME_SIZE: BusType = 0x8;
+
+pub const ACLINT_SSWI_ADDR: BusType = 0x2f00000;
+const ACLINT_SSWI_SIZE: BusType = 0x4000;
+
+const SETSSIP_SIZE: BusType = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    Clint,
+    Aclint,
+}
+
+impl TimerKind {
+    pub fn parse(arg: &str) -> Result<TimerKind, String> {
+        match arg {
+            "clint" => Ok(TimerKind::Clint),
+            "aclint" => Ok(TimerKind::Aclint),
+            _ => Err(format!("Invalid timer device: {}", arg)),
+        }
+    }
+}
+
+pub struct AclintMswi;
+
+impl AclintMswi {
+    pub fn new() -> AclintMswi {
+        AclintMswi {}
+    }
+}
+
+impl BusDevice for AclintMswi {
+    fn load(&mut self, addr: BusType, _size: BusType) -> Result<BusType, Exception> {
+        let clint = get_clint(((addr - ACLINT_MSWI_ADDR) / MSIP_SIZE) as usize);
+
+        Ok(clint.msip)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
+        let clint = get_clint(((addr - ACLINT_MSWI_ADDR) / MSIP_SIZE) as usize);
+
+        clint.msip = data & 1;
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        ACLINT_MSWI_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        ACLINT_MSWI_ADDR + ACLINT_MSWI_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        let clint = get_clint(cpu.core_id as usize);
+
+        Clint::tick_mswi(clint, cpu)
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let mswi_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("mswi", ACLINT_MSWI_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "riscv,aclint-mswi")
+            .unwrap();
+        fdt.property_array_u32(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, csr::bits::MSIP_BIT as u32],
+        )
+        .unwrap();
+        fdt.property_array_u32("reg", &[0x00, ACLINT_MSWI_ADDR, 0x00, ACLINT_MSWI_SIZE])
+            .unwrap();
+        fdt.property_null("interrupt-controller").unwrap();
+        fdt.property_u32("#interrupt-cells", 0x00).unwrap();
+        fdt.end_node(mswi_node).unwrap();
+    }
+}
+
+pub struct AclintMtimer;
+
+impl AclintMtimer {
+    pub fn new() -> AclintMtimer {
+        AclintMtimer {}
+    }
+}
+
+impl BusDevice for AclintMtimer {
+    fn load(&mut self, addr: BusType, _size: BusType) -> Result<BusType, Exception> {
+        if addr >= MTIME {
+            return Ok(read_u64_half(util::get_mtime(), addr - MTIME));
+        }
+
+        let offset = addr - MTIMECMP;
+        let clint = get_clint((offset / MTIMECMP_SIZE) as usize);
+
+        Ok(read_u64_half(clint.mtimecmp, offset))
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
+        if addr >= MTIME {
+            util::set_mtime(write_u64_half(util::get_mtime(), addr - MTIME, data));
+
+            return Ok(());
+        }
+
+        let offset = addr - MTIMECMP;
+        let clint = get_clint((offset / MTIMECMP_SIZE) as usize);
+
+        clint.mtimecmp = write_u64_half(clint.mtimecmp, offset, data);
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        ACLINT_MTIMER_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        ACLINT_MTIMER_ADDR + ACLINT_MTIMER_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        let clint = get_clint(cpu.core_id as usize);
+
+        Clint::tick_mtimer(clint, cpu)
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let mtimer_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("mtimer", ACLINT_MTIMER_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "riscv,aclint-mtimer")
+            .unwrap();
+        fdt.property_array_u32(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, csr::bits::MTIP_BIT as u32],
+        )
+        .unwrap();
+        fdt.property_array_u32(
+            "reg",
+            &[
+                0x00,
+                MTIME,
+                0x00,
+                MTIME_SIZE,
+                0x00,
+                MTIMECMP,
+                0x00,
+                MTIMECMP_SIZE_TOTAL,
+            ],
+        )
+        .unwrap();
+        fdt.end_node(mtimer_node).unwrap();
+    }
+}
+
+pub struct AclintSswi;
+
+impl AclintSswi {
+    pub fn new() -> AclintSswi {
+        AclintSswi {}
+    }
+}
+
+impl BusDevice for AclintSswi {
+    // SETSSIP always reads as zero
+    fn load(&mut self, _addr: BusType, _size: BusType) -> Result<BusType, Exception> {
+        Ok(0)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
+        if (data & 1) != 0 {
+            let clint = get_clint(((addr - ACLINT_SSWI_ADDR) / SETSSIP_SIZE) as usize);
+
+            clint.setssip = true;
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        ACLINT_SSWI_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        ACLINT_SSWI_ADDR + ACLINT_SSWI_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    // SETSSIP is edge triggered, the guest clears the pending bit through sip
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        let clint = get_clint(cpu.core_id as usize);
+
+        if clint.setssip {
+            clint.setssip = false;
+            cpu.pending_interrupt_number = 0;
+
+            return Some(csr::bits::SSIP_BIT as u32);
+        }
+
+        None
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let sswi_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("sswi", ACLINT_SSWI_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "riscv,aclint-sswi")
+            .unwrap();
+        fdt.property_array_u32(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, csr::bits::SSIP_BIT as u32],
+        )
+        .unwrap();
+        fdt.property_array_u32("reg", &[0x00, ACLINT_SSWI_ADDR, 0x00, ACLINT_SSWI_SIZE])
+            .unwrap();
+        fdt.property_null("interrupt-controller").unwrap();
+        fdt.property_u32("#interrupt-cells", 0x00).unwrap();
+        fdt.end_node(sswi_node).unwrap();
+    }
+}
@@ -0,0 +1,710 @@
+use crate::bus::imsic::{IMSIC_M_ADDR, IMSIC_M_PHANDLE, IMSIC_S_ADDR, IMSIC_S_PHANDLE};
+use crate::bus::*;
+use crate::cpu::*;
+use crate::util;
+
+// Both interrupt domains are served by one device, the gap between them is unmapped
+pub const APLIC_M_ADDR: BusType = 0xc000000;
+pub const APLIC_S_ADDR: BusType = 0xd000000;
+const APLIC_DOMAIN_SIZE: BusType = 0x8000;
+
+pub const APLIC_M_PHANDLE: u32 = 0x05;
+pub const APLIC_S_PHANDLE: u32 = 0x06;
+
+pub const APLIC_NUM_SOURCES: usize = 95;
+const APLIC_WORDS: usize = (APLIC_NUM_SOURCES + 1).div_ceil(32);
+const APLIC_HART_COUNT: usize = 1;
+
+const DOMAINCFG: BusType = 0x0000;
+const SOURCECFG: BusType = 0x0004;
+const SOURCECFG_END: BusType = 0x0ffc;
+const MMSIADDRCFG: BusType = 0x1bc0;
+const MMSIADDRCFGH: BusType = 0x1bc4;
+const SMSIADDRCFG: BusType = 0x1bc8;
+const SMSIADDRCFGH: BusType = 0x1bcc;
+const SETIP: BusType = 0x1c00;
+const SETIP_END: BusType = 0x1c7c;
+const SETIPNUM: BusType = 0x1cdc;
+const IN_CLRIP: BusType = 0x1d00;
+const IN_CLRIP_END: BusType = 0x1d7c;
+const CLRIPNUM: BusType = 0x1ddc;
+const SETIE: BusType = 0x1e00;
+const SETIE_END: BusType = 0x1e7c;
+const SETIENUM: BusType = 0x1edc;
+const CLRIE: BusType = 0x1f00;
+const CLRIE_END: BusType = 0x1f7c;
+const CLRIENUM: BusType = 0x1fdc;
+const SETIPNUM_LE: BusType = 0x2000;
+const SETIPNUM_BE: BusType = 0x2004;
+const GENMSI: BusType = 0x3000;
+const TARGET: BusType = 0x3004;
+const TARGET_END: BusType = 0x3ffc;
+const IDC: BusType = 0x4000;
+const IDC_SIZE: BusType = 0x20;
+
+const IDC_IDELIVERY: BusType = 0x00;
+const IDC_IFORCE: BusType = 0x04;
+const IDC_ITHRESHOLD: BusType = 0x08;
+const IDC_TOPI: BusType = 0x18;
+const IDC_CLAIMI: BusType = 0x1c;
+
+const DOMAINCFG_RO80: u32 = 0x80 << 24;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM_MASK: u32 = 0x7;
+
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+const MSIADDRCFGH_L: u32 = 1 << 31;
+const MSIADDRCFGH_PPN_MASK: u32 = 0xfff;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+const TARGET_IPRIO_MASK: u32 = 0xff;
+
+const GENMSI_BUSY: u32 = 1 << 12;
+
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AiaMode {
+    None,
+    Aplic,
+    AplicImsic,
+}
+
+impl AiaMode {
+    pub fn parse(arg: &str) -> Result<AiaMode, String> {
+        match arg {
+            "none" => Ok(AiaMode::None),
+            "aplic" => Ok(AiaMode::Aplic),
+            "aplic-imsic" => Ok(AiaMode::AplicImsic),
+            _ => Err(format!("Invalid AIA mode: {}", arg)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AplicIdc {
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+}
+
+struct AplicDomain {
+    domaincfg: u32,
+    sourcecfg: [u32; APLIC_NUM_SOURCES + 1],
+    target: [u32; APLIC_NUM_SOURCES + 1],
+    idc: [AplicIdc; APLIC_HART_COUNT],
+}
+
+impl AplicDomain {
+    fn new(msi_mode: bool) -> AplicDomain {
+        let dm = if msi_mode { DOMAINCFG_DM } else { 0 };
+
+        AplicDomain {
+            domaincfg: DOMAINCFG_RO80 | dm,
+            sourcecfg: [0; APLIC_NUM_SOURCES + 1],
+            target: [0; APLIC_NUM_SOURCES + 1],
+            idc: [AplicIdc {
+                idelivery: 0,
+                iforce: 0,
+                ithreshold: 0,
+            }; APLIC_HART_COUNT],
+        }
+    }
+
+    fn is_msi_mode(&self) -> bool {
+        (self.domaincfg & DOMAINCFG_DM) != 0
+    }
+
+    fn is_enabled(&self) -> bool {
+        (self.domaincfg & DOMAINCFG_IE) != 0
+    }
+}
+
+const M_DOMAIN: usize = 0;
+const S_DOMAIN: usize = 1;
+
+pub struct Aplic {
+    domains: [AplicDomain; 2],
+    pending: [u32; APLIC_WORDS],
+    enabled: [u32; APLIC_WORDS],
+    mmsiaddrcfg: u32,
+    mmsiaddrcfgh: u32,
+    smsiaddrcfg: u32,
+    smsiaddrcfgh: u32,
+    msi_mode: bool,
+}
+
+impl Aplic {
+    pub fn new(msi_mode: bool) -> Aplic {
+        Aplic {
+            domains: [AplicDomain::new(msi_mode), AplicDomain::new(msi_mode)],
+            pending: [0; APLIC_WORDS],
+            enabled: [0; APLIC_WORDS],
+            mmsiaddrcfg: IMSIC_M_ADDR >> 12,
+            mmsiaddrcfgh: 0,
+            smsiaddrcfg: IMSIC_S_ADDR >> 12,
+            smsiaddrcfgh: 0,
+            msi_mode,
+        }
+    }
+
+    fn read_bit(bits: &[u32; APLIC_WORDS], irq: usize) -> bool {
+        (bits[irq / 32] >> (irq % 32)) & 1 != 0
+    }
+
+    fn write_bit(bits: &mut [u32; APLIC_WORDS], irq: usize, value: bool) {
+        if value {
+            bits[irq / 32] |= 1 << (irq % 32);
+        } else {
+            bits[irq / 32] &= !(1 << (irq % 32));
+        }
+    }
+
+    fn source_mode(&self, domain: usize, irq: usize) -> u32 {
+        if irq == 0 || irq > APLIC_NUM_SOURCES {
+            return SM_INACTIVE;
+        }
+
+        let root_cfg = self.domains[M_DOMAIN].sourcecfg[irq];
+        let delegated = (root_cfg & SOURCECFG_D) != 0;
+
+        match domain {
+            M_DOMAIN if !delegated => root_cfg & SOURCECFG_SM_MASK,
+            S_DOMAIN if delegated => self.domains[S_DOMAIN].sourcecfg[irq] & SOURCECFG_SM_MASK,
+            _ => SM_INACTIVE,
+        }
+    }
+
+    fn source_domain(&self, irq: usize) -> usize {
+        if (self.domains[M_DOMAIN].sourcecfg[irq] & SOURCECFG_D) != 0 {
+            S_DOMAIN
+        } else {
+            M_DOMAIN
+        }
+    }
+
+    fn is_active(&self, domain: usize, irq: usize) -> bool {
+        self.source_mode(domain, irq) != SM_INACTIVE
+    }
+
+    // Register bitmaps only expose the sources that are active in the accessing domain
+    fn active_mask(&self, domain: usize, word: usize) -> u32 {
+        let mut mask = 0;
+
+        for bit in 0..32 {
+            if self.is_active(domain, word * 32 + bit) {
+                mask |= 1 << bit;
+            }
+        }
+
+        mask
+    }
+
+    fn set_pending(&mut self, domain: usize, irq: usize, value: bool) {
+        if !self.is_active(domain, irq) {
+            return;
+        }
+
+        Self::write_bit(&mut self.pending, irq, value);
+
+        if value {
+            self.forward_msis(domain);
+        }
+    }
+
+    fn set_enabled(&mut self, domain: usize, irq: usize, value: bool) {
+        if !self.is_active(domain, irq) {
+            return;
+        }
+
+        Self::write_bit(&mut self.enabled, irq, value);
+
+        if value {
+            self.forward_msis(domain);
+        }
+    }
+
+    // Called for interrupts coming from the wired device lines
+    pub fn set_pending_wired(&mut self, irq: usize) {
+        if irq == 0 || irq > APLIC_NUM_SOURCES {
+            return;
+        }
+
+        let domain = self.source_domain(irq);
+
+        // Devices only ever signal a raised line, detached and inverted sources ignore it
+        if matches!(self.source_mode(domain, irq), SM_EDGE1 | SM_LEVEL1) {
+            self.set_pending(domain, irq, true);
+        }
+    }
+
+    fn msi_addr(&self, domain: usize, hart: u32) -> BusType {
+        let (ppn, ppnh) = if domain == M_DOMAIN {
+            (self.mmsiaddrcfg, self.mmsiaddrcfgh)
+        } else {
+            (self.smsiaddrcfg, self.smsiaddrcfgh)
+        };
+
+        let lhxs = (ppnh >> 20) & 0x7;
+
+        // Group geometry is always taken from the machine level configuration
+        let hhxs = (self.mmsiaddrcfgh >> 24) & 0x1f;
+        let hhxw = (self.mmsiaddrcfgh >> 16) & 0x7;
+        let lhxw = (self.mmsiaddrcfgh >> 12) & 0xf;
+
+        let group = ((hart >> lhxw) & ((1 << hhxw) - 1)) as u64;
+        let hart = (hart & ((1 << lhxw) - 1)) as u64;
+
+        let ppn = (((ppnh & MSIADDRCFGH_PPN_MASK) as u64) << 32) | ppn as u64;
+        let ppn = ppn | (group << (hhxs + 12)) | (hart << lhxs);
+
+        (ppn << 12) as BusType
+    }
+
+    fn send_msi(&self, domain: usize, hart: u32, eiid: u32) {
+        let addr = self.msi_addr(domain, hart);
+
+        let _ = bus::get_bus().store_nommu(addr, eiid, 32);
+    }
+
+    // In MSI delivery mode pending and enabled sources are turned into MSIs right away
+    fn forward_msis(&mut self, domain: usize) {
+        if !self.domains[domain].is_msi_mode() || !self.domains[domain].is_enabled() {
+            return;
+        }
+
+        for irq in 1..=APLIC_NUM_SOURCES {
+            if !self.is_active(domain, irq)
+                || !Self::read_bit(&self.pending, irq)
+                || !Self::read_bit(&self.enabled, irq)
+            {
+                continue;
+            }
+
+            Self::write_bit(&mut self.pending, irq, false);
+
+            let target = self.domains[domain].target[irq];
+
+            self.send_msi(
+                domain,
+                target >> TARGET_HART_SHIFT,
+                target & TARGET_EIID_MASK,
+            );
+        }
+    }
+
+    // Highest priority pending source for a hart in direct delivery mode, as (irq << 16) | iprio
+    fn topi(&self, domain: usize, hart: usize) -> u32 {
+        let dom = &self.domains[domain];
+        let threshold = dom.idc[hart].ithreshold;
+        let mut best = 0;
+        let mut best_prio = u32::MAX;
+
+        for irq in 1..=APLIC_NUM_SOURCES {
+            if !self.is_active(domain, irq)
+                || !Self::read_bit(&self.pending, irq)
+                || !Self::read_bit(&self.enabled, irq)
+            {
+                continue;
+            }
+
+            let target = dom.target[irq];
+
+            if (target >> TARGET_HART_SHIFT) as usize != hart {
+                continue;
+            }
+
+            let prio = target & TARGET_IPRIO_MASK;
+
+            if threshold != 0 && prio >= threshold {
+                continue;
+            }
+
+            if prio < best_prio {
+                best = irq as u32;
+                best_prio = prio;
+            }
+        }
+
+        if best == 0 {
+            return 0;
+        }
+
+        (best << 16) | best_prio
+    }
+
+    fn claimi(&mut self, domain: usize, hart: usize) -> u32 {
+        let topi = self.topi(domain, hart);
+
+        if topi == 0 {
+            self.domains[domain].idc[hart].iforce = 0;
+
+            return 0;
+        }
+
+        let irq = (topi >> 16) as usize;
+
+        // Level sources stay pending for as long as the device keeps the line raised
+        Self::write_bit(&mut self.pending, irq, false);
+
+        topi
+    }
+
+    fn has_direct_interrupt(&self, domain: usize, hart: usize) -> bool {
+        let dom = &self.domains[domain];
+
+        if dom.is_msi_mode() || !dom.is_enabled() || dom.idc[hart].idelivery == 0 {
+            return false;
+        }
+
+        dom.idc[hart].iforce != 0 || self.topi(domain, hart) != 0
+    }
+
+    fn write_sourcecfg(&mut self, domain: usize, irq: usize, data: u32) {
+        if irq == 0 || irq > APLIC_NUM_SOURCES {
+            return;
+        }
+
+        if domain == S_DOMAIN && self.source_domain(irq) != S_DOMAIN {
+            return;
+        }
+
+        let data = if domain == M_DOMAIN && (data & SOURCECFG_D) != 0 {
+            // The supervisor domain is the only child, so the child index is always zero
+            SOURCECFG_D
+        } else {
+            match data & SOURCECFG_SM_MASK {
+                SM_DETACHED | SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0 => {
+                    data & SOURCECFG_SM_MASK
+                }
+                _ => SM_INACTIVE,
+            }
+        };
+
+        self.domains[domain].sourcecfg[irq] = data;
+
+        // Sources that left this domain lose their pending and enable state
+        if !self.is_active(domain, irq) {
+            Self::write_bit(&mut self.pending, irq, false);
+            Self::write_bit(&mut self.enabled, irq, false);
+        }
+    }
+
+    fn write_target(&mut self, domain: usize, irq: usize, data: u32) {
+        if !self.is_active(domain, irq) {
+            return;
+        }
+
+        let hart = (data >> TARGET_HART_SHIFT) << TARGET_HART_SHIFT;
+
+        self.domains[domain].target[irq] = if self.domains[domain].is_msi_mode() {
+            // Guest interrupt files are not implemented, so the guest index reads as zero
+            hart | (data & TARGET_EIID_MASK)
+        } else {
+            // Priority zero is reserved and reads back as one
+            hart | (data & TARGET_IPRIO_MASK).max(1)
+        };
+    }
+
+    fn decode(&self, addr: BusType) -> Option<(usize, BusType)> {
+        if (APLIC_M_ADDR..APLIC_M_ADDR + APLIC_DOMAIN_SIZE).contains(&addr) {
+            Some((M_DOMAIN, addr - APLIC_M_ADDR))
+        } else if (APLIC_S_ADDR..APLIC_S_ADDR + APLIC_DOMAIN_SIZE).contains(&addr) {
+            Some((S_DOMAIN, addr - APLIC_S_ADDR))
+        } else {
+            None
+        }
+    }
+}
+
+impl BusDevice for Aplic {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        let (domain, offset) = self.decode(addr).ok_or(Exception::LoadAccessFault(addr))?;
+
+        let val = match offset {
+            DOMAINCFG => self.domains[domain].domaincfg,
+            SOURCECFG..=SOURCECFG_END => {
+                let irq = (offset / 4) as usize;
+
+                if irq <= APLIC_NUM_SOURCES && (domain == M_DOMAIN || self.is_active(domain, irq)) {
+                    self.domains[domain].sourcecfg[irq]
+                } else {
+                    0
+                }
+            }
+            MMSIADDRCFG if domain == M_DOMAIN => self.mmsiaddrcfg,
+            MMSIADDRCFGH if domain == M_DOMAIN => self.mmsiaddrcfgh,
+            SMSIADDRCFG if domain == M_DOMAIN => self.smsiaddrcfg,
+            SMSIADDRCFGH if domain == M_DOMAIN => self.smsiaddrcfgh,
+            SETIP..=SETIP_END => {
+                let word = ((offset - SETIP) / 4) as usize;
+
+                if word < APLIC_WORDS {
+                    self.pending[word] & self.active_mask(domain, word)
+                } else {
+                    0
+                }
+            }
+            SETIE..=SETIE_END => {
+                let word = ((offset - SETIE) / 4) as usize;
+
+                if word < APLIC_WORDS {
+                    self.enabled[word] & self.active_mask(domain, word)
+                } else {
+                    0
+                }
+            }
+            // Without input line state tracking the rectified inputs read as zero
+            IN_CLRIP..=IN_CLRIP_END => 0,
+            GENMSI if domain == M_DOMAIN => 0,
+            TARGET..=TARGET_END => {
+                let irq = ((offset - TARGET) / 4 + 1) as usize;
+
+                if self.is_active(domain, irq) {
+                    self.domains[domain].target[irq]
+                } else {
+                    0
+                }
+            }
+            _ if offset >= IDC && !self.domains[domain].is_msi_mode() => {
+                let hart = ((offset - IDC) / IDC_SIZE) as usize;
+
+                if hart >= APLIC_HART_COUNT {
+                    return Ok(0);
+                }
+
+                let idc = &self.domains[domain].idc[hart];
+
+                match (offset - IDC) % IDC_SIZE {
+                    IDC_IDELIVERY => idc.idelivery,
+                    IDC_IFORCE => idc.iforce,
+                    IDC_ITHRESHOLD => idc.ithreshold,
+                    IDC_TOPI => self.topi(domain, hart),
+                    IDC_CLAIMI => self.claimi(domain, hart),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+
+        Ok(val as BusType)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        let (domain, offset) = self.decode(addr).ok_or(Exception::StoreAccessFault(addr))?;
+
+        let locked = (self.mmsiaddrcfgh & MSIADDRCFGH_L) != 0;
+
+        match offset {
+            DOMAINCFG => {
+                // Delivery mode is fixed by the platform configuration
+                let dm = if self.msi_mode { DOMAINCFG_DM } else { 0 };
+
+                self.domains[domain].domaincfg = DOMAINCFG_RO80 | dm | (data & DOMAINCFG_IE);
+
+                self.forward_msis(domain);
+            }
+            SOURCECFG..=SOURCECFG_END => {
+                self.write_sourcecfg(domain, (offset / 4) as usize, data);
+            }
+            MMSIADDRCFG if domain == M_DOMAIN && !locked => self.mmsiaddrcfg = data,
+            MMSIADDRCFGH if domain == M_DOMAIN && !locked => self.mmsiaddrcfgh = data,
+            SMSIADDRCFG if domain == M_DOMAIN && !locked => self.smsiaddrcfg = data,
+            SMSIADDRCFGH if domain == M_DOMAIN && !locked => self.smsiaddrcfgh = data,
+            SETIP..=SETIP_END | SETIE..=SETIE_END | CLRIE..=CLRIE_END | IN_CLRIP..=IN_CLRIP_END => {
+                let (base, set, pending) = match offset {
+                    SETIP..=SETIP_END => (SETIP, true, true),
+                    IN_CLRIP..=IN_CLRIP_END => (IN_CLRIP, false, true),
+                    SETIE..=SETIE_END => (SETIE, true, false),
+                    _ => (CLRIE, false, false),
+                };
+
+                let word = ((offset - base) / 4) as usize;
+
+                for bit in 0..32 {
+                    if (data >> bit) & 1 == 0 {
+                        continue;
+                    }
+
+                    let irq = word * 32 + bit;
+
+                    if pending {
+                        self.set_pending(domain, irq, set);
+                    } else {
+                        self.set_enabled(domain, irq, set);
+                    }
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => self.set_pending(domain, data as usize, true),
+            SETIPNUM_BE => self.set_pending(domain, data.swap_bytes() as usize, true),
+            CLRIPNUM => self.set_pending(domain, data as usize, false),
+            SETIENUM => self.set_enabled(domain, data as usize, true),
+            CLRIENUM => self.set_enabled(domain, data as usize, false),
+            GENMSI if domain == M_DOMAIN && self.domains[domain].is_msi_mode() => {
+                // The MSI is sent immediately so busy never reads as set
+                let data = data & !GENMSI_BUSY;
+
+                self.send_msi(domain, data >> TARGET_HART_SHIFT, data & TARGET_EIID_MASK);
+            }
+            TARGET..=TARGET_END => {
+                self.write_target(domain, ((offset - TARGET) / 4 + 1) as usize, data);
+            }
+            _ if offset >= IDC && !self.domains[domain].is_msi_mode() => {
+                let hart = ((offset - IDC) / IDC_SIZE) as usize;
+
+                if hart >= APLIC_HART_COUNT {
+                    return Ok(());
+                }
+
+                let idc = &mut self.domains[domain].idc[hart];
+
+                match (offset - IDC) % IDC_SIZE {
+                    IDC_IDELIVERY => idc.idelivery = data & 1,
+                    IDC_IFORCE => idc.iforce = data & 1,
+                    IDC_ITHRESHOLD => idc.ithreshold = data & TARGET_IPRIO_MASK,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        APLIC_M_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        APLIC_S_ADDR + APLIC_DOMAIN_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        // With MSI delivery the IMSIC owns the external interrupt lines
+        if self.msi_mode {
+            return None;
+        }
+
+        let hart = cpu.core_id as usize;
+
+        let meip = self.has_direct_interrupt(M_DOMAIN, hart);
+        let seip = self.has_direct_interrupt(S_DOMAIN, hart);
+
+        update_external_irqs(cpu, meip, seip)
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let domains = [
+            (
+                APLIC_M_ADDR,
+                APLIC_M_PHANDLE,
+                IMSIC_M_PHANDLE,
+                csr::bits::MEIP_BIT,
+            ),
+            (
+                APLIC_S_ADDR,
+                APLIC_S_PHANDLE,
+                IMSIC_S_PHANDLE,
+                csr::bits::SEIP_BIT,
+            ),
+        ];
+
+        for (addr, phandle, msi_parent, irq) in domains {
+            let aplic_node = fdt
+                .begin_node(&util::fdt_node_addr_helper("aplic", addr))
+                .unwrap();
+            fdt.property_u32("phandle", phandle).unwrap();
+            fdt.property_string("compatible", "riscv,aplic").unwrap();
+            fdt.property_array_u32("reg", &[0x00, addr, 0x00, APLIC_DOMAIN_SIZE])
+                .unwrap();
+
+            if self.msi_mode {
+                fdt.property_u32("msi-parent", msi_parent).unwrap();
+            } else {
+                fdt.property_array_u32("interrupts-extended", &[CPU_INTC_PHANDLE, irq as u32])
+                    .unwrap();
+            }
+
+            if addr == APLIC_M_ADDR {
+                fdt.property_u32("riscv,children", APLIC_S_PHANDLE).unwrap();
+                fdt.property_array_u32(
+                    "riscv,delegation",
+                    &[APLIC_S_PHANDLE, 1, APLIC_NUM_SOURCES as u32],
+                )
+                .unwrap();
+            }
+
+            fdt.property_u32("riscv,num-sources", APLIC_NUM_SOURCES as u32)
+                .unwrap();
+            fdt.property_null("interrupt-controller").unwrap();
+            fdt.property_u32("#interrupt-cells", 0x02).unwrap();
+            fdt.property_u32("#address-cells", 0x00).unwrap();
+            fdt.end_node(aplic_node).unwrap();
+        }
+    }
+}
+
+// Reflects the external interrupt lines into mip, the higher privilege one is reported first
+pub fn update_external_irqs(cpu: &mut cpu::Cpu, meip: bool, seip: bool) -> Option<u32> {
+    if !meip {
+        cpu.csr.clear_bit_mip_atomic(csr::bits::MEIP_BIT);
+    }
+
+    if !seip {
+        cpu.csr.clear_bit_mip_atomic(csr::bits::SEIP_BIT);
+    }
+
+    if meip && seip {
+        cpu.csr.or_mip_atomic(csr::bits::SEIP as csr::CsrType);
+    }
+
+    if meip {
+        Some(csr::bits::MEIP_BIT as u32)
+    } else if seip {
+        Some(csr::bits::SEIP_BIT as u32)
+    } else {
+        None
+    }
+}
+
+// Wired device interrupts go to the PLIC, or to the supervisor APLIC domain when AIA is in use
+pub fn irq_specifier(irqn: u32) -> (u32, Vec<u32>) {
+    if bus::get_bus().get_aplic().is_some() {
+        (APLIC_S_PHANDLE, vec![irqn, IRQ_TYPE_LEVEL_HIGH])
+    } else {
+        (plic::PLIC_PHANDLE, vec![irqn])
+    }
+}
+
+pub fn describe_fdt_irq(fdt: &mut vm_fdt::FdtWriter, irqn: u32) {
+    let (parent, specifier) = irq_specifier(irqn);
+
+    fdt.property_u32("interrupt-parent", parent).unwrap();
+    fdt.property_array_u32("interrupts", &specifier).unwrap();
+}
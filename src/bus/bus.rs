@@ -1,9 +1,12 @@
 use crate::bus::mmu::*;
 use crate::cpu::*;
 
-use super::plic::{Plic, PLIC_BASE};
+use super::aplic::Aplic;
+use super::plic::Plic;
 use super::ram::RAM_BEGIN_ADDR;
 use super::tlb::{tlb_fetch_instr, tlb_fetch_load, tlb_fetch_store};
+use crate::frontend::parse_core::{RV_PAGE_MASK, RV_PAGE_SIZE};
+use crate::xmem::PageState;
 
 pub type BusType = u32;
 
@@ -65,6 +68,7 @@ pub struct Bus {
     fb_end_addr: usize,
 
     plic_ptr: *mut Plic,
+    aplic_ptr: *mut Aplic,
 }
 
 impl Bus {
@@ -78,17 +82,29 @@ impl Bus {
             fb_end_addr: 0,
 
             plic_ptr: std::ptr::null_mut(),
+            aplic_ptr: std::ptr::null_mut(),
         }
     }
 
     pub fn add_device(&mut self, device: Box<dyn BusDevice>) {
-        if device.get_begin_addr() == PLIC_BASE {
-            self.plic_ptr = device.as_ref() as *const dyn BusDevice as *mut Plic;
-        }
-
         self.devices.push(device);
     }
 
+    pub fn add_plic(&mut self, plic: Plic) {
+        let mut plic = Box::new(plic);
+
+        // The box keeps the controller at the same address once it's in the device list
+        self.plic_ptr = plic.as_mut() as *mut Plic;
+        self.devices.push(plic);
+    }
+
+    pub fn add_aplic(&mut self, aplic: Aplic) {
+        let mut aplic = Box::new(aplic);
+
+        self.aplic_ptr = aplic.as_mut() as *mut Aplic;
+        self.devices.push(aplic);
+    }
+
     pub fn set_ram_ptr(&mut self, ptr: *mut u8, end_addr: usize) {
         self.ram_ptr = ptr;
         self.ram_end_addr = end_addr;
@@ -105,7 +121,28 @@ impl Bus {
         mmu: &mut Sv32Mmu,
         access_type: AccessType,
     ) -> Result<BusType, Exception> {
-        return mmu.translate(addr, access_type);
+        let phys = mmu.translate(addr, access_type)?;
+
+        self.pmp_check(phys, 1, access_type)
+            .map_err(|_| pmp::access_fault(addr, access_type))?;
+
+        Ok(phys)
+    }
+
+    fn pmp_check(
+        &self,
+        phys: BusType,
+        size: BusType,
+        access_type: AccessType,
+    ) -> Result<(), Exception> {
+        let cpu = cpu::get_cpu();
+        let mode = access_mode(cpu, access_type);
+
+        if cpu.pmp.check(phys, size as usize, access_type, mode) {
+            Ok(())
+        } else {
+            Err(pmp::access_fault(phys, access_type))
+        }
     }
 
     pub fn load(
@@ -115,6 +152,8 @@ impl Bus {
         mmu: &mut Sv32Mmu,
     ) -> Result<BusType, Exception> {
         if !mmu.is_active() {
+            self.pmp_check(addr, size / 8, AccessType::Load)?;
+
             return self.load_nommu(addr, size);
         }
 
@@ -140,6 +179,8 @@ impl Bus {
         mmu: &mut Sv32Mmu,
     ) -> Result<BusType, Exception> {
         if !mmu.is_active() {
+            self.pmp_check(addr, size / 8, AccessType::Fetch)?;
+
             return self.fetch_nommu(addr, size);
         }
 
@@ -170,6 +211,8 @@ impl Bus {
         mmu: &mut Sv32Mmu,
     ) -> Result<(), Exception> {
         if !mmu.is_active() {
+            self.pmp_check(addr, size / 8, AccessType::Store)?;
+
             return self.store_nommu(addr, data, size);
         }
 
@@ -254,8 +297,22 @@ impl Bus {
     pub fn tick_async(&mut self, cpu: &mut cpu::Cpu) {
         let mut new_mip: CsrType = 0;
 
+        let aplic_ptr = self.aplic_ptr;
+
         for device in &mut self.devices {
             if let Some(irq) = device.tick_async(cpu) {
+                // With an APLIC present, wired interrupts are routed through it instead of mip
+                if !aplic_ptr.is_null()
+                    && irq == csr::bits::SEIP_BIT as u32
+                    && cpu.pending_interrupt_number != 0
+                {
+                    unsafe { &mut *aplic_ptr }
+                        .set_pending_wired(cpu.pending_interrupt_number as usize);
+                    cpu.pending_interrupt_number = 0;
+
+                    continue;
+                }
+
                 new_mip |= 1 << irq;
                 break;
             }
@@ -278,8 +335,80 @@ impl Bus {
         Err(Exception::LoadAccessFault(addr))
     }
 
-    pub fn get_plic(&mut self) -> &'static mut Plic {
-        return unsafe { &mut *self.plic_ptr };
+    // Zero-copy views of guest RAM for device DMA. A mutable view is about to be written,
+    // so pages holding translated code are made writable and queued for invalidation first.
+    // Only the exec thread may call these, the queue lives in its cpu
+    pub fn dma_slice_mut(
+        &mut self,
+        addr: BusType,
+        len: usize,
+    ) -> Result<&'static mut [u8], Exception> {
+        if !self.is_dma_range(addr, len) {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        let cpu = cpu::get_cpu();
+        let end_addr = addr as usize + len;
+        let mut page = addr as usize & RV_PAGE_MASK;
+
+        while page < end_addr {
+            let gpfn = page as CpuReg;
+
+            if let Some(state) = cpu.gpfn_state.get_gpfn_state(gpfn) {
+                if state.get_state() == PageState::ReadExecute {
+                    cpu.gpfn_state.set_gpfn_state(gpfn, PageState::ReadWrite);
+                    cpu.dma_dirty_gpfns.push(gpfn);
+                }
+            }
+
+            page += RV_PAGE_SIZE;
+        }
+
+        let ptr = self.get_ptr(addr)?;
+
+        Ok(unsafe { std::slice::from_raw_parts_mut(ptr, len) })
+    }
+
+    pub fn dma_slice(&mut self, addr: BusType, len: usize) -> Result<&'static [u8], Exception> {
+        if !self.is_dma_range(addr, len) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        let ptr = self.get_ptr(addr)?;
+
+        Ok(unsafe { std::slice::from_raw_parts(ptr, len) })
+    }
+
+    pub fn dma_write(&mut self, addr: BusType, data: &[u8]) -> Result<(), Exception> {
+        self.dma_slice_mut(addr, data.len())?.copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn dma_read(&mut self, addr: BusType, data: &mut [u8]) -> Result<(), Exception> {
+        data.copy_from_slice(self.dma_slice(addr, data.len())?);
+
+        Ok(())
+    }
+
+    fn is_dma_range(&self, addr: BusType, len: usize) -> bool {
+        self.is_dram_addr(addr) && addr as usize + len <= self.ram_end_addr
+    }
+
+    pub fn get_plic(&mut self) -> Option<&'static mut Plic> {
+        if self.plic_ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { &mut *self.plic_ptr })
+        }
+    }
+
+    pub fn get_aplic(&mut self) -> Option<&'static mut Aplic> {
+        if self.aplic_ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { &mut *self.aplic_ptr })
+        }
     }
 
     pub fn get_ram_end_addr(&self) -> usize {
@@ -1,10 +1,8 @@
 use crate::bus::*;
 use crate::cpu::*;
-use crate::frontend::exec_core::INSN_SIZE;
 use crate::util;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
 use std::sync::Mutex;
 
 use self::plic::PLIC_PHANDLE;
@@ -14,19 +12,23 @@ const CLINT_SIZE: BusType = 0x10000;
 const CLINT_END: BusType = CLINT_ADDR + CLINT_SIZE;
 
 const MSIP: BusType = CLINT_ADDR;
-const MSIP_END: BusType = MSIP + INSN_SIZE as BusType;
+const MSIP_END: BusType = MSIP + 0x3fff;
 
 const MTIMECMP: BusType = CLINT_ADDR + 0x4000;
-const MTIMECMP_END: BusType = MTIMECMP + INSN_SIZE as BusType;
+const MTIMECMP_END: BusType = MTIMECMP + 0x7ff7;
 
 const MTIME: BusType = CLINT_ADDR + 0xbff8;
-const MTIME_END: BusType = MTIME + INSN_SIZE as BusType;
+const MTIME_END: BusType = MTIME + 0x7;
+
+pub const MSIP_SIZE: BusType = 4;
+pub const MTIMECMP_SIZE: BusType = 8;
 
 pub const CLINT_IRQN: u32 = 0;
 
 pub struct ClintData {
     pub msip: BusType,
-    pub mtimecmp: BusType,
+    pub mtimecmp: u64,
+    pub setssip: bool,
 }
 
 impl ClintData {
@@ -34,6 +36,7 @@ impl ClintData {
         ClintData {
             msip: 0,
             mtimecmp: 0,
+            setssip: false,
         }
     }
 }
@@ -42,28 +45,35 @@ lazy_static! {
     static ref CLINTS: Mutex<HashMap<usize, ClintData>> = Mutex::new(HashMap::new());
 }
 
-fn get_clint(thread_id: usize) -> &'static mut ClintData {
+// Per hart state, shared between the legacy CLINT and the ACLINT devices
+pub fn get_clint(thread_id: usize) -> &'static mut ClintData {
     let mut map = CLINTS.lock().unwrap();
 
-    if !map.contains_key(&thread_id) {
-        let clint = ClintData::new();
-        map.insert(thread_id, clint);
-    }
-
     unsafe {
-        let clint = map.get_mut(&thread_id).unwrap();
+        let clint = map.entry(thread_id).or_insert_with(ClintData::new);
         let clint = clint as *mut ClintData;
 
         &mut *clint
     }
 }
-static mut ATOMIC_CNT: AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
-fn get_time() -> BusType {
-    let mtime = util::timebase_since_program_start();
+// 64-bit registers are accessed as two 32-bit halves on rv32
+pub fn read_u64_half(val: u64, offset: BusType) -> BusType {
+    if (offset & 0x4) != 0 {
+        (val >> 32) as BusType
+    } else {
+        val as BusType
+    }
+}
 
-    mtime as BusType
+pub fn write_u64_half(val: u64, offset: BusType, data: BusType) -> u64 {
+    if (offset & 0x4) != 0 {
+        (val & 0xffff_ffff) | ((data as u64) << 32)
+    } else {
+        (val & !0xffff_ffff) | data as u64
+    }
 }
+
 pub struct Clint;
 
 impl Clint {
@@ -71,16 +81,18 @@ impl Clint {
         Clint {}
     }
 
-    pub fn tick(clint_data: &mut ClintData, cpu: &mut cpu::Cpu) -> Option<u32> {
-        let mtime = get_time();
-
+    pub fn tick_mswi(clint_data: &mut ClintData, cpu: &mut cpu::Cpu) -> Option<u32> {
         if (clint_data.msip & 1) != 0 {
             cpu.pending_interrupt_number = CLINT_IRQN as CpuReg;
 
             return Some(csr::bits::MSIP_BIT as u32);
         }
 
-        if mtime >= clint_data.mtimecmp {
+        None
+    }
+
+    pub fn tick_mtimer(clint_data: &mut ClintData, cpu: &mut cpu::Cpu) -> Option<u32> {
+        if util::get_mtime() >= clint_data.mtimecmp {
             cpu.pending_interrupt_number = CLINT_IRQN as CpuReg;
 
             return Some(csr::bits::MTIP_BIT as u32);
@@ -90,38 +102,50 @@ impl Clint {
 
         None
     }
+
+    pub fn tick(clint_data: &mut ClintData, cpu: &mut cpu::Cpu) -> Option<u32> {
+        if let Some(irq) = Self::tick_mswi(clint_data, cpu) {
+            return Some(irq);
+        }
+
+        Self::tick_mtimer(clint_data, cpu)
+    }
 }
 
 impl BusDevice for Clint {
     fn load(&mut self, addr: BusType, _size: BusType) -> Result<BusType, Exception> {
-        let clint = get_clint(cpu::get_cpu().core_id as usize);
-
         match addr {
             MSIP..=MSIP_END => {
-                return Ok(clint.msip);
+                let clint = get_clint(((addr - MSIP) / MSIP_SIZE) as usize);
+
+                Ok(clint.msip)
             }
             MTIMECMP..=MTIMECMP_END => {
-                return Ok(clint.mtimecmp);
-            }
-            MTIME..=MTIME_END => {
-                return Ok(get_time());
+                let offset = addr - MTIMECMP;
+                let clint = get_clint((offset / MTIMECMP_SIZE) as usize);
+
+                Ok(read_u64_half(clint.mtimecmp, offset))
             }
-            _ => return Err(Exception::LoadAccessFault(addr)),
-        };
+            MTIME..=MTIME_END => Ok(read_u64_half(util::get_mtime(), addr - MTIME)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
     }
 
     fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
-        let clint = get_clint(cpu::get_cpu().core_id as usize);
-
         match addr {
             MSIP..=MSIP_END => {
-                clint.msip = data;
+                let clint = get_clint(((addr - MSIP) / MSIP_SIZE) as usize);
+
+                clint.msip = data & 1;
             }
             MTIMECMP..=MTIMECMP_END => {
-                clint.mtimecmp = data;
+                let offset = addr - MTIMECMP;
+                let clint = get_clint((offset / MTIMECMP_SIZE) as usize);
+
+                clint.mtimecmp = write_u64_half(clint.mtimecmp, offset, data);
             }
             MTIME..=MTIME_END => {
-                println!("mtime store\n\n\n")
+                util::set_mtime(write_u64_half(util::get_mtime(), addr - MTIME, data));
             }
             _ => return Err(Exception::StoreAccessFault(addr)),
         };
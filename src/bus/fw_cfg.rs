@@ -0,0 +1,425 @@
+use crate::{
+    bus::{self, bus::*, ramfb},
+    cpu::{self, Exception},
+    util,
+};
+
+pub const FW_CFG_ADDR: BusType = 0x10100000;
+pub const FW_CFG_SIZE: BusType = 0x18;
+
+const FW_CFG_DATA_REG: BusType = 0x00;
+const FW_CFG_SELECTOR_REG: BusType = 0x08;
+const FW_CFG_DMA_REG_HIGH: BusType = 0x10;
+const FW_CFG_DMA_REG_LOW: BusType = 0x14;
+
+// "QEMU CFG", read back from the DMA address register
+const FW_CFG_DMA_SIGNATURE: u64 = 0x51454d5520434647;
+
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_ID: u16 = 0x01;
+const FW_CFG_RAM_SIZE: u16 = 0x03;
+const FW_CFG_NB_CPUS: u16 = 0x05;
+const FW_CFG_KERNEL_SIZE: u16 = 0x08;
+const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+const FW_CFG_KERNEL_DATA: u16 = 0x11;
+const FW_CFG_INITRD_DATA: u16 = 0x12;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+const FW_CFG_WRITE_CHANNEL: u16 = 0x4000;
+const FW_CFG_INVALID: u16 = 0xffff;
+
+const FW_CFG_VERSION: u32 = 1 << 0;
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SKIP: u32 = 1 << 2;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+const FW_CFG_DMA_CTL_WRITE: u32 = 1 << 4;
+
+// struct FWCfgDmaAccess, all fields are big endian
+const FW_CFG_DMA_ACCESS_SIZE: usize = 16;
+
+// struct FWCfgFile, the name includes the terminating NUL
+const FW_CFG_MAX_FILE_NAME: usize = 56;
+
+// Called with the whole entry after the guest wrote into it
+type FwCfgWriteHook = fn(&[u8]);
+
+struct FwCfgEntry {
+    key: u16,
+    data: Vec<u8>,
+    write_hook: Option<FwCfgWriteHook>,
+}
+
+struct FwCfgFile {
+    name: String,
+    key: u16,
+}
+
+pub struct FwCfgBlob {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl FwCfgBlob {
+    // Accepts name=<name>,file=<path> or name=<name>,string=<value>
+    pub fn parse(arg: &str) -> Result<FwCfgBlob, String> {
+        let mut name = None;
+        let mut data = None;
+
+        for opt in arg.split(',') {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or(format!("Invalid fw_cfg option: {}", opt))?;
+
+            match key {
+                "name" => name = Some(value.to_string()),
+                "file" => {
+                    let file = util::read_file(value)
+                        .map_err(|err| format!("Failed to read fw_cfg file {}: {}", value, err))?;
+
+                    data = Some(file);
+                }
+                "string" => data = Some(value.as_bytes().to_vec()),
+                _ => return Err(format!("Invalid fw_cfg option: {}", key)),
+            }
+        }
+
+        let name = name.ok_or("fw_cfg entries require a name".to_string())?;
+        let data = data.ok_or("fw_cfg entries require a file or a string".to_string())?;
+
+        if name.is_empty() || name.len() >= FW_CFG_MAX_FILE_NAME {
+            return Err(format!("Invalid fw_cfg name: {}", name));
+        }
+
+        // Everything outside of opt/ is reserved for the emulator itself
+        if !name.starts_with("opt/") {
+            return Err(format!("fw_cfg names must start with opt/: {}", name));
+        }
+
+        Ok(FwCfgBlob { name, data })
+    }
+}
+
+pub struct FwCfg {
+    entries: Vec<FwCfgEntry>,
+    files: Vec<FwCfgFile>,
+    selected: Option<usize>,
+    offset: usize,
+    dma_addr: u64,
+}
+
+impl FwCfg {
+    pub fn new(ram_size: usize) -> FwCfg {
+        let mut fw_cfg = FwCfg {
+            entries: Vec::new(),
+            files: Vec::new(),
+            selected: None,
+            offset: 0,
+            dma_addr: 0,
+        };
+
+        fw_cfg.add_bytes(FW_CFG_SIGNATURE, b"QEMU".to_vec());
+        fw_cfg.add_u32(FW_CFG_ID, FW_CFG_VERSION | FW_CFG_VERSION_DMA);
+        fw_cfg.add_bytes(FW_CFG_RAM_SIZE, (ram_size as u64).to_le_bytes().to_vec());
+        fw_cfg.add_bytes(FW_CFG_NB_CPUS, 1u16.to_le_bytes().to_vec());
+        fw_cfg.add_bytes(FW_CFG_FILE_DIR, Vec::new());
+
+        fw_cfg.update_file_dir();
+
+        fw_cfg
+    }
+
+    fn add_bytes(&mut self, key: u16, data: Vec<u8>) {
+        self.entries.push(FwCfgEntry {
+            key,
+            data,
+            write_hook: None,
+        });
+    }
+
+    fn add_u32(&mut self, key: u16, value: u32) {
+        self.add_bytes(key, value.to_le_bytes().to_vec());
+    }
+
+    pub fn add_kernel(&mut self, kernel: Vec<u8>) {
+        self.add_u32(FW_CFG_KERNEL_SIZE, kernel.len() as u32);
+        self.add_bytes(FW_CFG_KERNEL_DATA, kernel);
+    }
+
+    pub fn add_initrd(&mut self, initrd: Vec<u8>) {
+        self.add_u32(FW_CFG_INITRD_SIZE, initrd.len() as u32);
+        self.add_bytes(FW_CFG_INITRD_DATA, initrd);
+    }
+
+    pub fn add_cmdline(&mut self, cmdline: &str) {
+        let mut data = cmdline.as_bytes().to_vec();
+
+        data.push(0);
+
+        self.add_u32(FW_CFG_CMDLINE_SIZE, data.len() as u32);
+        self.add_bytes(FW_CFG_CMDLINE_DATA, data);
+    }
+
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        data: Vec<u8>,
+        write_hook: Option<FwCfgWriteHook>,
+    ) -> Result<(), String> {
+        if self.files.iter().any(|file| file.name == name) {
+            return Err(format!("Duplicate fw_cfg file: {}", name));
+        }
+
+        let key = FW_CFG_FILE_FIRST + self.files.len() as u16;
+
+        self.entries.push(FwCfgEntry {
+            key,
+            data,
+            write_hook,
+        });
+
+        self.files.push(FwCfgFile {
+            name: name.to_string(),
+            key,
+        });
+
+        self.update_file_dir();
+
+        Ok(())
+    }
+
+    // Lets the guest place and size the framebuffer itself, like QEMU's ramfb
+    pub fn add_ramfb(&mut self) {
+        self.add_file(
+            "etc/ramfb",
+            vec![0; ramfb::RAMFB_CFG_SIZE],
+            Some(ramfb::fw_cfg_write),
+        )
+        .unwrap();
+    }
+
+    // struct FWCfgFiles: a big endian count followed by the file entries
+    fn update_file_dir(&mut self) {
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+
+        for file in &self.files {
+            let entry = self.find_entry(file.key).unwrap();
+            let size = self.entries[entry].data.len() as u32;
+
+            let mut name = [0u8; FW_CFG_MAX_FILE_NAME];
+            name[..file.name.len()].copy_from_slice(file.name.as_bytes());
+
+            dir.extend_from_slice(&size.to_be_bytes());
+            dir.extend_from_slice(&file.key.to_be_bytes());
+            dir.extend_from_slice(&0u16.to_be_bytes());
+            dir.extend_from_slice(&name);
+        }
+
+        let entry = self.find_entry(FW_CFG_FILE_DIR).unwrap();
+
+        self.entries[entry].data = dir;
+    }
+
+    fn find_entry(&self, key: u16) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.key == key)
+    }
+
+    fn select(&mut self, key: u16) {
+        self.selected = self.find_entry(key & !FW_CFG_WRITE_CHANNEL);
+        self.offset = 0;
+    }
+
+    fn read_data(&mut self, size: BusType) -> BusType {
+        let mut bytes = [0u8; 4];
+
+        if let Some(entry) = self.selected {
+            let data = &self.entries[entry].data;
+
+            for byte in bytes.iter_mut().take((size / 8) as usize) {
+                if self.offset < data.len() {
+                    *byte = data[self.offset];
+                    self.offset += 1;
+                }
+            }
+        }
+
+        // The data register streams bytes in order regardless of the access width
+        BusType::from_le_bytes(bytes)
+    }
+
+    fn dma_transfer(&mut self, control: u32, mut addr: BusType, mut len: usize) -> u32 {
+        let bus = bus::get_bus();
+        let mut control = control;
+
+        let read = control & FW_CFG_DMA_CTL_READ != 0;
+        let write = control & FW_CFG_DMA_CTL_WRITE != 0;
+
+        while len > 0 {
+            let avail = match self.selected {
+                Some(entry) => self.entries[entry].data.len().saturating_sub(self.offset),
+                None => 0,
+            };
+
+            // Reads past the end of an item are zero filled and writes fail
+            if avail == 0 {
+                if read {
+                    let zeros = vec![0u8; len];
+
+                    if bus.dma_write(addr, &zeros).is_err() {
+                        control |= FW_CFG_DMA_CTL_ERROR;
+                    }
+                }
+
+                if write {
+                    control |= FW_CFG_DMA_CTL_ERROR;
+                }
+
+                break;
+            }
+
+            let entry = self.selected.unwrap();
+            let chunk = len.min(avail);
+            let range = self.offset..self.offset + chunk;
+
+            if read {
+                if bus
+                    .dma_write(addr, &self.entries[entry].data[range])
+                    .is_err()
+                {
+                    control |= FW_CFG_DMA_CTL_ERROR;
+                }
+            } else if write {
+                let entry = &mut self.entries[entry];
+
+                match entry.write_hook {
+                    Some(hook)
+                        if chunk == len && bus.dma_read(addr, &mut entry.data[range]).is_ok() =>
+                    {
+                        hook(&entry.data)
+                    }
+                    _ => control |= FW_CFG_DMA_CTL_ERROR,
+                }
+            }
+
+            self.offset += chunk;
+            addr += chunk as BusType;
+            len -= chunk;
+        }
+
+        control
+    }
+
+    fn dma_access(&mut self, access_addr: BusType) {
+        let bus = bus::get_bus();
+        let mut access = [0u8; FW_CFG_DMA_ACCESS_SIZE];
+
+        if bus.dma_read(access_addr, &mut access).is_err() {
+            return;
+        }
+
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let len = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let addr = u64::from_be_bytes(access[8..16].try_into().unwrap());
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+
+        let control = if addr > BusType::MAX as u64 {
+            FW_CFG_DMA_CTL_ERROR
+        } else if control & (FW_CFG_DMA_CTL_READ | FW_CFG_DMA_CTL_WRITE) != 0 {
+            self.dma_transfer(control, addr as BusType, len)
+        } else if control & FW_CFG_DMA_CTL_SKIP != 0 {
+            self.offset += len;
+
+            0
+        } else {
+            0
+        };
+
+        // The guest polls the control field, only the error bit is left behind
+        let control = control & FW_CFG_DMA_CTL_ERROR;
+
+        let _ = bus.dma_write(access_addr, &control.to_be_bytes());
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, Exception> {
+        let data = match addr - FW_CFG_ADDR {
+            FW_CFG_DATA_REG..=0x07 => self.read_data(size),
+            FW_CFG_DMA_REG_HIGH => ((FW_CFG_DMA_SIGNATURE >> 32) as u32).swap_bytes(),
+            FW_CFG_DMA_REG_LOW => (FW_CFG_DMA_SIGNATURE as u32).swap_bytes(),
+            _ => 0,
+        };
+
+        Ok(data)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
+        // The selector and the DMA address are big endian registers
+        match addr - FW_CFG_ADDR {
+            FW_CFG_SELECTOR_REG => {
+                let key = (data as u16).swap_bytes();
+
+                if key == FW_CFG_INVALID {
+                    self.selected = None;
+                } else {
+                    self.select(key);
+                }
+            }
+            FW_CFG_DMA_REG_HIGH => {
+                self.dma_addr = (data.swap_bytes() as u64) << 32;
+            }
+            FW_CFG_DMA_REG_LOW => {
+                let access_addr = self.dma_addr | data.swap_bytes() as u64;
+
+                self.dma_addr = 0;
+
+                if access_addr <= BusType::MAX as u64 {
+                    self.dma_access(access_addr as BusType);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        FW_CFG_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        FW_CFG_ADDR + FW_CFG_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, _cpu: &mut cpu::Cpu) -> Option<u32> {
+        None
+    }
+
+    fn get_ptr(&mut self, addr: BusType) -> Result<*mut u8, Exception> {
+        Err(Exception::LoadAccessFault(addr))
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let fw_cfg_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("fw-cfg", FW_CFG_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "qemu,fw-cfg-mmio")
+            .unwrap();
+        fdt.property_array_u32("reg", &[0x0, FW_CFG_ADDR, 0x0, FW_CFG_SIZE])
+            .unwrap();
+        fdt.property_null("dma-coherent").unwrap();
+        fdt.end_node(fw_cfg_node).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    bus::bus::*,
+    cpu::{self, csr, Exception, CPU_TIMEBASE_FREQ},
+    util,
+};
+
+use super::aplic;
+
+pub const RTC_ADDR: BusType = 0x101000;
+const RTC_SIZE: BusType = 0x1000;
+const RTC_END_ADDR: BusType = RTC_ADDR + RTC_SIZE;
+pub const RTC_IRQN: BusType = 11;
+
+const TIME_LOW: BusType = 0x00;
+const TIME_HIGH: BusType = 0x04;
+const ALARM_LOW: BusType = 0x08;
+const ALARM_HIGH: BusType = 0x0c;
+const IRQ_ENABLED: BusType = 0x10;
+const CLEAR_ALARM: BusType = 0x14;
+const ALARM_STATUS: BusType = 0x18;
+const CLEAR_INTERRUPT: BusType = 0x1c;
+
+const NSEC_PER_SEC: i64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcBase {
+    Utc,
+    LocalTime,
+    // Seconds since the Unix epoch
+    Fixed(i64),
+}
+
+impl RtcBase {
+    // Accepts the same forms as QEMU: base=utc, base=localtime or base=<iso8601>
+    pub fn parse(arg: &str) -> Result<RtcBase, String> {
+        let base = arg.strip_prefix("base=").unwrap_or(arg);
+
+        match base {
+            "utc" => Ok(RtcBase::Utc),
+            "localtime" => Ok(RtcBase::LocalTime),
+            _ => parse_iso8601(base)
+                .map(RtcBase::Fixed)
+                .ok_or(format!("Invalid RTC base: {}", base)),
+        }
+    }
+
+    fn start_time_ns(&self) -> i64 {
+        let host_now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_else(|_| std::time::Duration::new(0, 0))
+            .as_nanos() as i64;
+
+        match self {
+            RtcBase::Utc => host_now,
+            RtcBase::LocalTime => host_now + util::host_utc_offset_secs() * NSEC_PER_SEC,
+            RtcBase::Fixed(secs) => secs * NSEC_PER_SEC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcClock {
+    // Host time passing since the emulator started
+    Host,
+    // The guest's mtime, including whatever the guest wrote to it. With a fixed
+    // base every boot starts at the same date and the RTC only moves with the
+    // guest's own timer
+    Vm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcConfig {
+    pub base: RtcBase,
+    pub clock: RtcClock,
+}
+
+impl RtcConfig {
+    // [base=utc|localtime|<iso8601>][,clock=host|vm], like QEMU's -rtc
+    pub fn parse(arg: &str) -> Result<RtcConfig, String> {
+        let mut config = RtcConfig {
+            base: RtcBase::Utc,
+            clock: RtcClock::Host,
+        };
+
+        for part in arg.split(',') {
+            if let Some(clock) = part.strip_prefix("clock=") {
+                config.clock = match clock {
+                    "host" => RtcClock::Host,
+                    "vm" => RtcClock::Vm,
+                    _ => return Err(format!("Invalid RTC clock: {}", clock)),
+                };
+            } else {
+                config.base = RtcBase::parse(part)?;
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn parse_number(s: &str, range: std::ops::RangeInclusive<i64>) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let value = s.parse::<i64>().ok()?;
+
+    if range.contains(&value) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// Parses YYYY-MM-DD[THH:MM[:SS]][Z|+HH:MM|-HH:MM] into seconds since the Unix epoch
+fn parse_iso8601(s: &str) -> Option<i64> {
+    let (date, time) = match s.find(['T', 't', ' ']) {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, ""),
+    };
+
+    let mut date_parts = date.split('-');
+    let year = parse_number(date_parts.next()?, 0..=9999)?;
+    let month = parse_number(date_parts.next()?, 1..=12)?;
+    let day = parse_number(date_parts.next()?, 1..=31)?;
+
+    if date_parts.next().is_some() {
+        return None;
+    }
+
+    let (time, tz_offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(idx) = time.rfind(['+', '-']) {
+        let sign = if time.as_bytes()[idx] == b'-' { -1 } else { 1 };
+        let (hours, minutes) = time[idx + 1..].split_once(':')?;
+        let offset = parse_number(hours, 0..=23)? * 3600 + parse_number(minutes, 0..=59)? * 60;
+
+        (&time[..idx], sign * offset)
+    } else {
+        (time, 0)
+    };
+
+    let mut secs = 0;
+
+    if !time.is_empty() {
+        let mut time_parts = time.split(':');
+        let hours = parse_number(time_parts.next()?, 0..=23)?;
+        let minutes = parse_number(time_parts.next()?, 0..=59)?;
+        let seconds = match time_parts.next() {
+            Some(seconds) => parse_number(seconds, 0..=60)?,
+            None => 0,
+        };
+
+        if time_parts.next().is_some() {
+            return None;
+        }
+
+        secs = hours * 3600 + minutes * 60 + seconds;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + secs - tz_offset)
+}
+
+pub struct GoldfishRtc {
+    clock: RtcClock,
+    offset_ns: i64,
+    time_high: u32,
+    alarm_ns: u64,
+    alarm_high: u32,
+    alarm_armed: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl GoldfishRtc {
+    pub fn new(config: RtcConfig) -> Self {
+        Self {
+            clock: config.clock,
+            offset_ns: config.base.start_time_ns(),
+            time_high: 0,
+            alarm_ns: 0,
+            alarm_high: 0,
+            alarm_armed: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    // The RTC advances with the same timebase the CPU observes through mtime, so
+    // the guest wall clock stays consistent with its timers
+    fn get_virtual_ns(&self) -> i64 {
+        let timebase = match self.clock {
+            RtcClock::Host => util::timebase_since_program_start(),
+            RtcClock::Vm => util::get_mtime(),
+        } as i64;
+
+        timebase * (NSEC_PER_SEC / CPU_TIMEBASE_FREQ as i64)
+    }
+
+    fn get_time_ns(&self) -> u64 {
+        (self.offset_ns + self.get_virtual_ns()) as u64
+    }
+
+    fn set_time_ns(&mut self, time_ns: u64) {
+        self.offset_ns = time_ns as i64 - self.get_virtual_ns();
+    }
+}
+
+impl BusDevice for GoldfishRtc {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        match addr - RTC_ADDR {
+            TIME_LOW => {
+                let time = self.get_time_ns();
+
+                self.time_high = (time >> 32) as u32;
+
+                Ok(time as BusType)
+            }
+            TIME_HIGH => Ok(self.time_high as BusType),
+            ALARM_LOW => Ok(self.alarm_ns as BusType),
+            ALARM_HIGH => Ok((self.alarm_ns >> 32) as BusType),
+            IRQ_ENABLED => Ok(self.irq_enabled as BusType),
+            ALARM_STATUS => Ok(self.alarm_armed as BusType),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        match addr - RTC_ADDR {
+            TIME_LOW => {
+                let time = ((self.time_high as u64) << 32) | data as u64;
+
+                self.set_time_ns(time);
+            }
+            TIME_HIGH => {
+                self.time_high = data;
+            }
+            ALARM_LOW => {
+                self.alarm_ns = ((self.alarm_high as u64) << 32) | data as u64;
+                self.alarm_armed = true;
+            }
+            ALARM_HIGH => {
+                self.alarm_high = data;
+            }
+            IRQ_ENABLED => {
+                self.irq_enabled = (data & 1) != 0;
+            }
+            CLEAR_ALARM => {
+                self.alarm_armed = false;
+            }
+            CLEAR_INTERRUPT => {
+                self.irq_pending = false;
+            }
+            _ => return Err(Exception::StoreAccessFault(addr)),
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        RTC_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        RTC_END_ADDR
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        if self.alarm_armed && self.get_time_ns() >= self.alarm_ns {
+            self.alarm_armed = false;
+            self.irq_pending = true;
+        }
+
+        if self.irq_pending && self.irq_enabled {
+            cpu.pending_interrupt_number = RTC_IRQN;
+
+            return Some(csr::bits::SEIP_BIT as u32);
+        }
+
+        None
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let rtc_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("rtc", RTC_ADDR))
+            .unwrap();
+        aplic::describe_fdt_irq(fdt, RTC_IRQN);
+        fdt.property_array_u32("reg", &[0x00, RTC_ADDR, 0x00, RTC_SIZE])
+            .unwrap();
+        fdt.property_string("compatible", "google,goldfish-rtc")
+            .unwrap();
+        fdt.end_node(rtc_node).unwrap();
+    }
+}
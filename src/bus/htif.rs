@@ -0,0 +1,378 @@
+use std::io::Write;
+
+use crate::{
+    backend::{ReturnableHandler, ReturnableImpl},
+    bus::{self, bus::*},
+    cpu::{self, Exception},
+    util,
+};
+
+use super::{ns16550::charbuf_read_data, syscon};
+
+// Matches the .tohost section placement in misc/link.ld
+pub const HTIF_DEFAULT_TOHOST_ADDR: BusType = 0x01000000;
+// riscv-tests aligns fromhost to the next 64 byte boundary after tohost
+const HTIF_DEFAULT_FROMHOST_OFFSET: BusType = 0x40;
+const HTIF_REG_SIZE: BusType = 8;
+
+const HTIF_DEV_SYSCALL: u64 = 0;
+const HTIF_DEV_CONSOLE: u64 = 1;
+
+const HTIF_CONSOLE_CMD_GETCHAR: u64 = 0;
+const HTIF_CONSOLE_CMD_PUTCHAR: u64 = 1;
+
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+const ENOSYS: i64 = 38;
+const EBADF: i64 = 9;
+
+// The syscall payload points at 8 doublewords: the syscall number followed by its arguments
+const MAGIC_MEM_WORDS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HtifConfig {
+    pub tohost: BusType,
+    pub fromhost: BusType,
+}
+
+impl HtifConfig {
+    pub fn new(tohost: BusType, fromhost: Option<BusType>) -> HtifConfig {
+        HtifConfig {
+            tohost,
+            fromhost: fromhost.unwrap_or(tohost + HTIF_DEFAULT_FROMHOST_OFFSET),
+        }
+    }
+
+    pub fn from_elf(elf: &util::Elf) -> Option<HtifConfig> {
+        let tohost = elf.get_symbol("tohost")?;
+
+        Some(HtifConfig::new(tohost, elf.get_symbol("fromhost")))
+    }
+
+    // Accepts tohost=<addr>[,fromhost=<addr>], addresses can be decimal or 0x prefixed hex
+    pub fn parse(arg: &str) -> Result<HtifConfig, String> {
+        let mut tohost = None;
+        let mut fromhost = None;
+
+        for opt in arg.split(',') {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or(format!("Invalid HTIF option: {}", opt))?;
+
+            let value = if let Some(hex) = value.strip_prefix("0x") {
+                BusType::from_str_radix(hex, 16)
+            } else {
+                value.parse::<BusType>()
+            }
+            .map_err(|_| format!("Invalid HTIF address: {}", value))?;
+
+            match key {
+                "tohost" => tohost = Some(value),
+                "fromhost" => fromhost = Some(value),
+                _ => return Err(format!("Invalid HTIF option: {}", key)),
+            }
+        }
+
+        let tohost = tohost.ok_or("HTIF tohost address is required".to_string())?;
+
+        Ok(HtifConfig::new(tohost, fromhost))
+    }
+}
+
+pub struct Htif {
+    config: HtifConfig,
+    tohost: u64,
+    fromhost: u64,
+    pending_getchar: bool,
+    // If the guest placed tohost/fromhost in RAM, stores bypass the bus
+    // so the registers are polled from memory instead, like Spike does
+    polled: bool,
+}
+
+impl Htif {
+    pub fn new(config: HtifConfig) -> Self {
+        let bus = bus::get_bus();
+        let polled = bus.is_dram_addr(config.tohost) || bus.is_dram_addr(config.fromhost);
+
+        Self {
+            config,
+            tohost: 0,
+            fromhost: 0,
+            pending_getchar: false,
+            polled,
+        }
+    }
+
+    fn read_guest_u64(addr: BusType) -> u64 {
+        let bus = bus::get_bus();
+
+        let low = bus.load_nommu(addr, 32).unwrap_or(0) as u64;
+        let high = bus.load_nommu(addr + 4, 32).unwrap_or(0) as u64;
+
+        (high << 32) | low
+    }
+
+    fn write_guest_u64(addr: BusType, data: u64) {
+        let bus = bus::get_bus();
+
+        let _ = bus.store_nommu(addr, data as BusType, 32);
+        let _ = bus.store_nommu(addr + 4, (data >> 32) as BusType, 32);
+    }
+
+    fn respond(&mut self, device: u64, cmd: u64, payload: u64) {
+        self.fromhost = (device << 56) | (cmd << 48) | (payload & 0xffff_ffff_ffff);
+
+        if self.polled {
+            Self::write_guest_u64(self.config.fromhost, self.fromhost);
+        }
+    }
+
+    fn exit(&mut self, cpu: &mut cpu::Cpu, exit_code: i32) {
+        std::io::stdout().flush().unwrap();
+
+        syscon::request_poweroff(exit_code);
+
+        if self.polled {
+            // We're on the tick thread here and the guest is spinning inside a jit block,
+            // kick it out so the exec loop sees the poweroff and winds down
+            cpu.has_pending_interrupt
+                .store(1, std::sync::atomic::Ordering::Release);
+
+            return;
+        }
+
+        cpu.exception = Exception::Poweroff;
+        ReturnableImpl::throw();
+    }
+
+    fn handle_syscall(&mut self, cpu: &mut cpu::Cpu, magic_mem: BusType) {
+        let mut args = [0u64; MAGIC_MEM_WORDS];
+
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = Self::read_guest_u64(magic_mem + (i * 8) as BusType);
+        }
+
+        let ret: i64 = match args[0] {
+            SYS_WRITE => {
+                let (fd, buf, len) = (args[1], args[2] as BusType, args[3] as BusType);
+
+                if fd == 1 || fd == 2 {
+                    let bus = bus::get_bus();
+                    let data = (0..len)
+                        .map(|i| bus.load_nommu(buf + i, 8).unwrap_or(0) as u8)
+                        .collect::<Vec<u8>>();
+
+                    std::io::stdout().write_all(&data).unwrap();
+
+                    len as i64
+                } else {
+                    -EBADF
+                }
+            }
+            SYS_READ => {
+                let (fd, buf, len) = (args[1], args[2] as BusType, args[3] as BusType);
+
+                if fd == 0 {
+                    let bus = bus::get_bus();
+                    let mut count = 0;
+
+                    while count < len {
+                        if let Some(c) = charbuf_read_data() {
+                            let _ = bus.store_nommu(buf + count, c as BusType, 8);
+                            count += 1;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    count as i64
+                } else {
+                    -EBADF
+                }
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit(cpu, args[1] as i32);
+
+                return;
+            }
+            _ => -ENOSYS,
+        };
+
+        Self::write_guest_u64(magic_mem, ret as u64);
+
+        self.respond(HTIF_DEV_SYSCALL, 0, 1);
+    }
+
+    fn handle_tohost(&mut self, cpu: &mut cpu::Cpu, tohost: u64) {
+        let device = tohost >> 56;
+        let cmd = (tohost >> 48) & 0xff;
+        let payload = tohost & 0xffff_ffff_ffff;
+
+        match (device, cmd) {
+            (HTIF_DEV_SYSCALL, 0) => {
+                if (payload & 1) != 0 {
+                    self.exit(cpu, (payload >> 1) as i32);
+
+                    return;
+                }
+
+                self.handle_syscall(cpu, payload as BusType);
+            }
+            (HTIF_DEV_CONSOLE, HTIF_CONSOLE_CMD_PUTCHAR) => {
+                std::io::stdout().write_all(&[payload as u8]).unwrap();
+            }
+            (HTIF_DEV_CONSOLE, HTIF_CONSOLE_CMD_GETCHAR) => {
+                self.pending_getchar = true;
+            }
+            _ => {
+                println!("HTIF: unknown command {:#x}", tohost);
+            }
+        }
+    }
+
+    fn poll_getchar(&mut self) {
+        if !self.pending_getchar || self.fromhost != 0 {
+            return;
+        }
+
+        if let Some(c) = charbuf_read_data() {
+            self.pending_getchar = false;
+            self.respond(HTIF_DEV_CONSOLE, HTIF_CONSOLE_CMD_GETCHAR, 0x100 | c as u64);
+        }
+    }
+
+    fn poll_memory(&mut self, cpu: &mut cpu::Cpu) {
+        self.fromhost = Self::read_guest_u64(self.config.fromhost);
+
+        let tohost = Self::read_guest_u64(self.config.tohost);
+
+        if tohost != 0 {
+            Self::write_guest_u64(self.config.tohost, 0);
+
+            self.handle_tohost(cpu, tohost);
+        }
+    }
+
+    fn reg_offset(&self, addr: BusType) -> Option<(BusType, BusType)> {
+        if addr >= self.config.tohost && addr < self.config.tohost + HTIF_REG_SIZE {
+            Some((self.config.tohost, addr - self.config.tohost))
+        } else if addr >= self.config.fromhost && addr < self.config.fromhost + HTIF_REG_SIZE {
+            Some((self.config.fromhost, addr - self.config.fromhost))
+        } else {
+            None
+        }
+    }
+}
+
+impl BusDevice for Htif {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, Exception> {
+        let reg = match self.reg_offset(addr) {
+            Some((reg, offset)) => (reg, offset),
+            None => return Ok(0),
+        };
+
+        let data = if reg.0 == self.config.tohost {
+            self.tohost
+        } else {
+            self.poll_getchar();
+            self.fromhost
+        };
+
+        let data = data >> (reg.1 * 8);
+
+        Ok(match size {
+            8 => data as u8 as BusType,
+            16 => data as u16 as BusType,
+            _ => data as BusType,
+        })
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), Exception> {
+        let (reg, offset) = match self.reg_offset(addr) {
+            Some(reg) => reg,
+            None => return Ok(()),
+        };
+
+        if size != 32 {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        let shift = offset * 8;
+        let mask = !(0xffff_ffffu64 << shift);
+
+        if reg == self.config.fromhost {
+            self.fromhost = (self.fromhost & mask) | ((data as u64) << shift);
+
+            return Ok(());
+        }
+
+        self.tohost = (self.tohost & mask) | ((data as u64) << shift);
+
+        // On RV32 the command is complete once the upper word has been written
+        if offset == 4 && self.tohost != 0 {
+            let tohost = self.tohost;
+
+            self.tohost = 0;
+            self.handle_tohost(cpu::get_cpu(), tohost);
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        if self.polled {
+            return 0;
+        }
+
+        self.config.tohost.min(self.config.fromhost)
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        if self.polled {
+            return 0;
+        }
+
+        self.config.tohost.max(self.config.fromhost) + HTIF_REG_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        if self.polled {
+            self.poll_memory(cpu);
+        }
+
+        self.poll_getchar();
+
+        None
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let htif_node = fdt.begin_node("htif").unwrap();
+        fdt.property_string("compatible", "ucb,htif0").unwrap();
+        fdt.property_array_u32(
+            "reg",
+            &[
+                0x00,
+                self.config.fromhost,
+                0x00,
+                HTIF_REG_SIZE,
+                0x00,
+                self.config.tohost,
+                0x00,
+                HTIF_REG_SIZE,
+            ],
+        )
+        .unwrap();
+        fdt.end_node(htif_node).unwrap();
+    }
+}
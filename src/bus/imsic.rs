@@ -0,0 +1,286 @@
+use crate::bus::*;
+use crate::cpu::*;
+use crate::util;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+pub const IMSIC_M_ADDR: BusType = 0x24000000;
+pub const IMSIC_S_ADDR: BusType = 0x28000000;
+pub const IMSIC_HART_SIZE: BusType = 0x1000;
+const IMSIC_HART_COUNT: BusType = 1;
+
+pub const IMSIC_M_PHANDLE: u32 = 0x07;
+pub const IMSIC_S_PHANDLE: u32 = 0x08;
+
+const SETEIPNUM_LE: BusType = 0x0;
+const SETEIPNUM_BE: BusType = 0x4;
+
+// Identity 0 is reserved, so 255 usable identities fit into 8 eip/eie words
+pub const IMSIC_NUM_IDS: u32 = 255;
+const IMSIC_WORDS: usize = (IMSIC_NUM_IDS as usize + 1) / 32;
+
+// Indirectly accessed registers, selected through miselect/siselect
+const ISELECT_IPRIO: u32 = 0x30;
+pub const ISELECT_IPRIO_END: u32 = 0x3f;
+const ISELECT_EIDELIVERY: u32 = 0x70;
+const ISELECT_EITHRESHOLD: u32 = 0x72;
+const ISELECT_EIP: u32 = 0x80;
+const ISELECT_EIP_END: u32 = 0xbf;
+const ISELECT_EIE: u32 = 0xc0;
+const ISELECT_EIE_END: u32 = 0xff;
+
+static IMSIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImsicLevel {
+    Machine,
+    Supervisor,
+}
+
+pub struct ImsicFile {
+    eidelivery: u32,
+    eithreshold: u32,
+    eip: [u32; IMSIC_WORDS],
+    eie: [u32; IMSIC_WORDS],
+}
+
+impl ImsicFile {
+    pub fn new() -> ImsicFile {
+        ImsicFile {
+            eidelivery: 0,
+            eithreshold: 0,
+            eip: [0; IMSIC_WORDS],
+            eie: [0; IMSIC_WORDS],
+        }
+    }
+
+    pub fn set_pending(&mut self, id: u32) {
+        if id == 0 || id > IMSIC_NUM_IDS {
+            return;
+        }
+
+        self.eip[(id / 32) as usize] |= 1 << (id % 32);
+    }
+
+    // Lower identities have higher priority, the value is reported as (id << 16) | id
+    pub fn topei(&self) -> u32 {
+        for (i, (eip, eie)) in self.eip.iter().zip(self.eie.iter()).enumerate() {
+            let active = eip & eie;
+
+            if active == 0 {
+                continue;
+            }
+
+            let id = i as u32 * 32 + active.trailing_zeros();
+
+            if self.eithreshold != 0 && id >= self.eithreshold {
+                return 0;
+            }
+
+            return (id << 16) | id;
+        }
+
+        0
+    }
+
+    pub fn claim_topei(&mut self) {
+        let id = self.topei() >> 16;
+
+        if id != 0 {
+            self.eip[(id / 32) as usize] &= !(1 << (id % 32));
+        }
+    }
+
+    pub fn has_interrupt(&self) -> bool {
+        (self.eidelivery & 1) != 0 && self.topei() != 0
+    }
+
+    pub fn read_ireg(&self, iselect: u32) -> Option<u32> {
+        match iselect {
+            ISELECT_IPRIO..=ISELECT_IPRIO_END => Some(0),
+            ISELECT_EIDELIVERY => Some(self.eidelivery),
+            ISELECT_EITHRESHOLD => Some(self.eithreshold),
+            ISELECT_EIP..=ISELECT_EIP_END => {
+                Some(*self.eip.get((iselect - ISELECT_EIP) as usize).unwrap_or(&0))
+            }
+            ISELECT_EIE..=ISELECT_EIE_END => {
+                Some(*self.eie.get((iselect - ISELECT_EIE) as usize).unwrap_or(&0))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn write_ireg(&mut self, iselect: u32, data: u32) -> bool {
+        match iselect {
+            // Major interrupt priorities are hardwired to zero
+            ISELECT_IPRIO..=ISELECT_IPRIO_END => {}
+            ISELECT_EIDELIVERY => self.eidelivery = data & 1,
+            ISELECT_EITHRESHOLD => self.eithreshold = data.min(IMSIC_NUM_IDS),
+            ISELECT_EIP..=ISELECT_EIP_END => {
+                let index = (iselect - ISELECT_EIP) as usize;
+
+                if index < IMSIC_WORDS {
+                    // Identity 0 is not implemented
+                    self.eip[index] = if index == 0 { data & !1 } else { data };
+                }
+            }
+            ISELECT_EIE..=ISELECT_EIE_END => {
+                let index = (iselect - ISELECT_EIE) as usize;
+
+                if index < IMSIC_WORDS {
+                    self.eie[index] = if index == 0 { data & !1 } else { data };
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+pub struct ImsicHart {
+    pub m: ImsicFile,
+    pub s: ImsicFile,
+}
+
+impl ImsicHart {
+    pub fn new() -> ImsicHart {
+        ImsicHart {
+            m: ImsicFile::new(),
+            s: ImsicFile::new(),
+        }
+    }
+
+    pub fn get_file(&mut self, level: ImsicLevel) -> &mut ImsicFile {
+        match level {
+            ImsicLevel::Machine => &mut self.m,
+            ImsicLevel::Supervisor => &mut self.s,
+        }
+    }
+}
+
+lazy_static! {
+    static ref IMSICS: Mutex<HashMap<usize, ImsicHart>> = Mutex::new(HashMap::new());
+}
+
+// Interrupt files are shared between the MMIO pages and the hart's CSRs
+pub fn get_imsic(hart: usize) -> &'static mut ImsicHart {
+    let mut map = IMSICS.lock().unwrap();
+
+    unsafe {
+        let imsic = map.entry(hart).or_insert_with(ImsicHart::new);
+        let imsic = imsic as *mut ImsicHart;
+
+        &mut *imsic
+    }
+}
+
+pub fn is_enabled() -> bool {
+    IMSIC_ENABLED.load(Ordering::Acquire)
+}
+
+pub struct Imsic {
+    level: ImsicLevel,
+    base: BusType,
+}
+
+impl Imsic {
+    pub fn new(level: ImsicLevel) -> Imsic {
+        IMSIC_ENABLED.store(true, Ordering::Release);
+
+        let base = match level {
+            ImsicLevel::Machine => IMSIC_M_ADDR,
+            ImsicLevel::Supervisor => IMSIC_S_ADDR,
+        };
+
+        Imsic { level, base }
+    }
+}
+
+impl BusDevice for Imsic {
+    // seteipnum registers read as zero
+    fn load(&mut self, _addr: BusType, _size: BusType) -> Result<BusType, Exception> {
+        Ok(0)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        let hart = ((addr - self.base) / IMSIC_HART_SIZE) as usize;
+        let offset = (addr - self.base) % IMSIC_HART_SIZE;
+
+        let id = match offset {
+            SETEIPNUM_LE => data,
+            SETEIPNUM_BE => data.swap_bytes(),
+            _ => return Ok(()),
+        };
+
+        get_imsic(hart).get_file(self.level).set_pending(id);
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        self.base
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        self.base + IMSIC_HART_SIZE * IMSIC_HART_COUNT
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        let file = get_imsic(cpu.core_id as usize).get_file(self.level);
+        let pending = file.has_interrupt();
+
+        let bit = match self.level {
+            ImsicLevel::Machine => csr::bits::MEIP_BIT,
+            ImsicLevel::Supervisor => csr::bits::SEIP_BIT,
+        };
+
+        if pending {
+            return Some(bit as u32);
+        }
+
+        cpu.csr.clear_bit_mip_atomic(bit);
+
+        None
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let (phandle, irq) = match self.level {
+            ImsicLevel::Machine => (IMSIC_M_PHANDLE, csr::bits::MEIP_BIT),
+            ImsicLevel::Supervisor => (IMSIC_S_PHANDLE, csr::bits::SEIP_BIT),
+        };
+
+        let imsic_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("imsics", self.base))
+            .unwrap();
+        fdt.property_u32("phandle", phandle).unwrap();
+        fdt.property_string("compatible", "riscv,imsics").unwrap();
+        fdt.property_array_u32(
+            "reg",
+            &[0x00, self.base, 0x00, IMSIC_HART_SIZE * IMSIC_HART_COUNT],
+        )
+        .unwrap();
+        fdt.property_array_u32("interrupts-extended", &[CPU_INTC_PHANDLE, irq as u32])
+            .unwrap();
+        fdt.property_u32("riscv,num-ids", IMSIC_NUM_IDS).unwrap();
+        fdt.property_null("interrupt-controller").unwrap();
+        fdt.property_u32("#interrupt-cells", 0x00).unwrap();
+        fdt.property_null("msi-controller").unwrap();
+        fdt.property_u32("#msi-cells", 0x00).unwrap();
+        fdt.end_node(imsic_node).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
+use crate::cpu::pmp;
 use crate::cpu::*;
-use crate::frontend::exec_core::{RV_PAGE_OFFSET_MASK, RV_PAGE_SIZE};
+use crate::frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_OFFSET_MASK, RV_PAGE_SIZE};
 use crate::util::read_bits;
 use crate::{cpu::csr::*, util::read_bit};
 
-use super::tlb::{asid_tlb_set, get_current_tlb};
+use super::tlb::{
+    asid_tlb_set, get_asid_tlb, get_identity_tlb, set_global_entry, TLB_FLAG_EXECUTE,
+    TLB_FLAG_GLOBAL, TLB_FLAG_READ, TLB_FLAG_WRITE,
+};
 use super::{bus, BusType};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -53,6 +57,15 @@ macro_rules! PteBitTest {
     };
 }
 
+// Loads and stores are made with MPP's privilege while MPRV is set
+pub fn access_mode(cpu: &cpu::Cpu, access_type: AccessType) -> MppMode {
+    if access_type != AccessType::Fetch && cpu.csr.read_bit_mstatus(csr::bits::MPRV) {
+        cpu.csr.read_mpp_mode()
+    } else {
+        cpu.mode
+    }
+}
+
 pub trait Mmu {
     fn new() -> Self;
 
@@ -68,31 +81,20 @@ pub trait Mmu {
     fn get_ppn(&self, pte: BusType, level: BusType) -> Self::PnArr;
 
     fn translate(&mut self, addr: BusType, access_type: AccessType) -> Result<BusType, Exception> {
-        if !self.is_active() {
-            return Ok(addr);
-        }
-
         let cpu_instance = cpu::get_cpu();
 
-        let mut mode = cpu_instance.mode;
+        let mode = access_mode(cpu_instance, access_type);
 
-        if access_type != AccessType::Fetch && cpu_instance.csr.read_bit_mstatus(csr::bits::MPRV) {
-            mode = cpu_instance.csr.read_mpp_mode();
-        }
+        if !self.is_active() || mode == MppMode::Machine {
+            let page = addr & RV_PAGE_MASK as BusType;
+            let flags = cpu_instance.pmp.page_flags(page, mode);
+
+            get_identity_tlb(mode).set_phys_entry(addr, page | flags);
 
-        if mode == MppMode::Machine {
             return Ok(addr);
         }
 
-        let mut pte = {
-            let pte = self.get_pte(addr, access_type);
-
-            if pte.is_err() {
-                return Self::create_exeption(addr, access_type);
-            }
-
-            pte.unwrap()
-        };
+        let mut pte = self.get_pte(addr, access_type)?;
 
         let mxr = cpu_instance.csr.read_bit_mstatus(csr::bits::MXR);
         let sum = cpu_instance.csr.read_bit_mstatus(csr::bits::SUM);
@@ -137,6 +139,14 @@ pub trait Mmu {
         let mut dirty = PteBitTest!(pte.pte, PteBitVal::Dirty);
 
         if !accessed || (access_type == AccessType::Store && !dirty) {
+            // Without Svadu enabled the OS sets the bits itself from the page fault
+            if !cpu_instance
+                .csr
+                .read_bit(csr::register::MENVCFGH, csr::bits::ADUE)
+            {
+                return Self::create_exeption(addr, access_type);
+            }
+
             pte.pte |= PteBit!(PteBitVal::Accessed);
 
             if access_type == AccessType::Store {
@@ -144,23 +154,44 @@ pub trait Mmu {
                 dirty = true;
             }
 
+            // The walk writes the PTE back with S-mode's PMP permissions
+            if !cpu_instance
+                .pmp
+                .check(pte.pte_addr, 4, AccessType::Store, MppMode::Supervisor)
+            {
+                return Err(pmp::access_fault(addr, access_type));
+            }
+
             let pte_atomic: &std::sync::atomic::AtomicU32 =
                 unsafe { std::mem::transmute(pte.pte_addr as u64) };
 
             pte_atomic.store(pte.pte, std::sync::atomic::Ordering::Release);
         }
 
-        let mut phys_flags = pte.phys_base;
+        let mut phys_flags = 0;
+
+        if read || (execute && mxr) {
+            phys_flags |= TLB_FLAG_READ;
+        }
 
         if write && dirty {
-            phys_flags |= 1;
+            phys_flags |= TLB_FLAG_WRITE;
         }
 
         if execute {
-            phys_flags |= 2;
+            phys_flags |= TLB_FLAG_EXECUTE;
         }
 
-        get_current_tlb().set_phys_entry(addr, phys_flags);
+        phys_flags &= cpu_instance.pmp.page_flags(pte.phys_base, mode);
+        phys_flags |= pte.phys_base;
+
+        if PteBitTest!(pte.pte, PteBitVal::Global) {
+            phys_flags |= TLB_FLAG_GLOBAL;
+
+            set_global_entry(addr, phys_flags);
+        }
+
+        get_asid_tlb().set_phys_entry(addr, phys_flags);
 
         Ok(pte.phys_base | (addr & RV_PAGE_OFFSET_MASK as BusType))
     }
@@ -199,6 +230,7 @@ impl Mmu for Sv32Mmu {
 
         let mut pte = Pte::default();
         let bus = bus::get_bus();
+        let cpu_instance = cpu::get_cpu();
 
         while i >= 0 {
             pte.pte_addr = a + vpn[i as usize] * pte_size;
@@ -207,6 +239,13 @@ impl Mmu for Sv32Mmu {
                 return Err(Self::create_exeption(addr, access_type).err().unwrap());
             }
 
+            if !cpu_instance
+                .pmp
+                .check(pte.pte_addr, 4, AccessType::Load, MppMode::Supervisor)
+            {
+                return Err(pmp::access_fault(addr, access_type));
+            }
+
             let pte_atomic: &std::sync::atomic::AtomicU32 =
                 unsafe { std::mem::transmute(pte.pte_addr as u64) };
 
@@ -268,7 +307,7 @@ impl Mmu for Sv32Mmu {
 
         self.enabled = read_bit(satp, 31);
 
-        let asid = read_bits(satp, 30, 22);
+        let asid = read_bits(satp, 22, 30);
 
         asid_tlb_set(asid as usize);
     }
@@ -1,12 +1,24 @@
+pub mod aclint;
+pub mod aplic;
 pub mod bus;
 pub mod clint;
 pub mod dtb;
+pub mod fw_cfg;
+pub mod goldfish_rtc;
+pub mod htif;
+pub mod imsic;
 pub mod mmu;
 pub mod ns16550;
+pub mod nvme;
+pub mod pci;
+pub mod pflash;
 pub mod plic;
 pub mod ram;
 pub mod ramfb;
 pub mod syscon;
 pub mod tlb;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_rng;
 
 pub use bus::*;
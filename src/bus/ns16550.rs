@@ -1,4 +1,9 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crossbeam::queue::ArrayQueue;
 use lazy_static::lazy_static;
@@ -9,13 +14,19 @@ use crate::{
     util,
 };
 
-use super::plic::PLIC_PHANDLE;
+use super::{aplic, pci};
 
-const UART_ADDR: BusType = 0x10000000;
+pub const UART_ADDR: BusType = 0x10000000;
 const UART_SIZE: BusType = 10;
-const UART_END_ADDR: BusType = UART_ADDR + UART_SIZE;
 pub const UART_IRQN: BusType = 10;
 
+// Extra ports default to the following pages and the IRQs after the RTC
+const UART_EXTRA_STRIDE: BusType = 0x1000;
+const UART_EXTRA_IRQN: BusType = 12;
+const UART_MAX_IRQN: BusType = 31;
+
+const UART_RX_QUEUE_SIZE: usize = 1024;
+
 const RHR: BusType = 0;
 const THR: BusType = 0;
 const DLL: BusType = 0;
@@ -34,27 +45,279 @@ const SCR: BusType = 7;
 const DOOM: BusType = 8;
 const DOOM_FLUSH: BusType = 8;
 
-const LSR_DR: u8 = 0x1;
-const LSR_THRE: u8 = 0x20;
-const LSR_TEMT: u8 = 0x40;
+const UART_CLOCK_FREQ: u64 = 0x384000;
+const UART_FIFO_SIZE: usize = 16;
 
 const IER_RDI: u8 = 0x01;
 const IER_THRI: u8 = 0x02;
+const IER_RLSI: u8 = 0x04;
+const IER_MSI: u8 = 0x08;
+const IER_MASK: u8 = 0x0f;
 
+// Interrupt identification, highest priority first
+const ISR_RLSI: u8 = 0x06;
+const ISR_RDI: u8 = 0x04;
+const ISR_CTI: u8 = 0x0c;
+const ISR_THRI: u8 = 0x02;
+const ISR_MSI: u8 = 0x00;
+const ISR_NO_INT: u8 = 0x01;
+const ISR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const FCR_CLEAR_TX: u8 = 0x04;
+const FCR_DMA_MODE: u8 = 0x08;
+const FCR_TRIGGER_MASK: u8 = 0xc0;
+const FCR_TRIGGER_SHIFT: u8 = 6;
+const FCR_TRIGGER_LEVELS: [usize; 4] = [1, 4, 8, 14];
+
+const LCR_WORD_LENGTH_MASK: u8 = 0x03;
+const LCR_STOP_BITS: u8 = 0x04;
+const LCR_PARITY: u8 = 0x08;
+const LCR_BREAK: u8 = 0x40;
 const LCR_DLAB: u8 = 0x80;
 
-const ISR_NO_INT: u8 = 0x01;
-const ISR_THRI: u8 = 0x02;
-const ISR_RDI: u8 = 0x04;
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOP: u8 = 0x10;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_PE: u8 = 0x04;
+const LSR_FE: u8 = 0x08;
+const LSR_BI: u8 = 0x10;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+const LSR_RXFE: u8 = 0x80;
+const LSR_ERRORS: u8 = LSR_OE | LSR_PE | LSR_FE | LSR_BI;
+
+const MSR_DCTS: u8 = 0x01;
+const MSR_DDSR: u8 = 0x02;
+const MSR_TERI: u8 = 0x04;
+const MSR_DDCD: u8 = 0x08;
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+const MSR_DELTAS: u8 = MSR_DCTS | MSR_DDSR | MSR_TERI | MSR_DDCD;
+
+// The host side always looks like a connected modem
+const MSR_HOST_INPUTS: u8 = MSR_CTS | MSR_DSR | MSR_DCD;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UartBackend {
+    // The console, shared with the window, HTIF and semihosting
+    Stdio,
+    Null,
+    File(String),
+    Tcp(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UartConfig {
+    pub addr: BusType,
+    pub irqn: BusType,
+    pub backend: UartBackend,
+}
+
+impl UartConfig {
+    pub fn console() -> UartConfig {
+        UartConfig {
+            addr: UART_ADDR,
+            irqn: UART_IRQN,
+            backend: UartBackend::Stdio,
+        }
+    }
+
+    // Accepts <null|file:<path>|tcp:<port>>[,addr=<addr>][,irq=<irq>], index counts
+    // the extra ports from 1 and picks the default address and IRQ
+    pub fn parse(arg: &str, index: usize) -> Result<UartConfig, String> {
+        let mut opts = arg.split(',');
+
+        let backend = opts.next().unwrap_or("");
+
+        let backend = if backend == "null" {
+            UartBackend::Null
+        } else if let Some(path) = backend.strip_prefix("file:") {
+            UartBackend::File(path.to_string())
+        } else if let Some(port) = backend.strip_prefix("tcp:") {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid serial port number: {}", port))?;
+
+            UartBackend::Tcp(port)
+        } else {
+            return Err(format!("Invalid serial backend: {}", backend));
+        };
+
+        let mut config = UartConfig {
+            addr: UART_ADDR + UART_EXTRA_STRIDE * index as BusType,
+            irqn: UART_EXTRA_IRQN + index as BusType - 1,
+            backend,
+        };
+
+        for opt in opts {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or(format!("Invalid serial option: {}", opt))?;
+
+            let value = if let Some(hex) = value.strip_prefix("0x") {
+                BusType::from_str_radix(hex, 16)
+            } else {
+                value.parse::<BusType>()
+            }
+            .map_err(|_| format!("Invalid serial option value: {}", value))?;
+
+            match key {
+                "addr" => config.addr = value,
+                "irq" => config.irqn = value,
+                _ => return Err(format!("Invalid serial option: {}", key)),
+            }
+        }
+
+        if config.irqn == 0 || config.irqn > UART_MAX_IRQN {
+            return Err(format!(
+                "Serial IRQ must be between 1 and {}",
+                UART_MAX_IRQN
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+// A TCP port accepts one client at a time, what it sends ends up in the receive queue.
+// Ports stay bound across reboots so the client does not have to reconnect
+struct TcpBackend {
+    rx: ArrayQueue<u8>,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+lazy_static! {
+    static ref TCP_BACKENDS: Mutex<HashMap<u16, Arc<TcpBackend>>> = Mutex::new(HashMap::new());
+}
+
+fn tcp_accept_thread(listener: TcpListener, backend: Arc<TcpBackend>) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+
+        *backend.stream.lock().unwrap() = stream.try_clone().ok();
+
+        let mut input = [0u8];
+
+        while let Ok(1) = stream.read(&mut input) {
+            let _ = backend.rx.push(input[0]);
+        }
+
+        *backend.stream.lock().unwrap() = None;
+    }
+}
+
+fn get_tcp_backend(port: u16) -> Result<Arc<TcpBackend>, String> {
+    let mut backends = TCP_BACKENDS.lock().unwrap();
+
+    if let Some(backend) = backends.get(&port) {
+        return Ok(backend.clone());
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| format!("Failed to listen on serial port {}: {}", port, err))?;
+
+    let backend = Arc::new(TcpBackend {
+        rx: ArrayQueue::new(UART_RX_QUEUE_SIZE),
+        stream: Mutex::new(None),
+    });
+
+    let thread_backend = backend.clone();
+
+    let _ = std::thread::spawn(move || tcp_accept_thread(listener, thread_backend));
+
+    backends.insert(port, backend.clone());
+
+    Ok(backend)
+}
+
+enum UartHost {
+    Stdio,
+    Null,
+    File(File),
+    Tcp(Arc<TcpBackend>),
+}
+
+impl UartHost {
+    fn open(backend: &UartBackend) -> Result<UartHost, String> {
+        let host = match backend {
+            UartBackend::Stdio => {
+                init_threads_once();
+
+                UartHost::Stdio
+            }
+            UartBackend::Null => UartHost::Null,
+            UartBackend::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| format!("Failed to open serial file {}: {}", path, err))?;
+
+                UartHost::File(file)
+            }
+            UartBackend::Tcp(port) => UartHost::Tcp(get_tcp_backend(*port)?),
+        };
+
+        Ok(host)
+    }
+
+    fn read(&self) -> Option<u8> {
+        match self {
+            UartHost::Stdio => charbuf_read_data(),
+            UartHost::Tcp(backend) => backend.rx.pop(),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, c: u8) {
+        match self {
+            UartHost::Stdio => {
+                if c.is_ascii() {
+                    std::io::stdout().write_all(&[c]).unwrap();
+                }
+            }
+            UartHost::Null => {}
+            UartHost::File(file) => {
+                let _ = file.write_all(&[c]);
+            }
+            UartHost::Tcp(backend) => {
+                if let Some(stream) = backend.stream.lock().unwrap().as_mut() {
+                    let _ = stream.write_all(&[c]);
+                }
+            }
+        }
+    }
+}
 
 pub struct Ns16550 {
+    addr: BusType,
+    irqn: BusType,
+    host: UartHost,
+    rx_fifo: VecDeque<u8>,
+    // Characters leave the transmitter as soon as they are written, so only
+    // the THR empty interrupt is kept around
+    thr_ipending: bool,
+    timeout_ipending: bool,
+    last_rx_activity: Instant,
     dll: u8,
     dlm: u8,
-    isr: u8,
     ier: u8,
     fcr: u8,
     lcr: u8,
     mcr: u8,
+    // Only the latched error bits, the rest is derived from the FIFOs
     lsr: u8,
     msr: u8,
     scr: u8,
@@ -84,7 +347,7 @@ fn charbuf_kbd_read_data() -> Option<u8> {
 }
 
 fn charkbd_flush() {
-    while let Some(_) = CHARBUF_KBD.pop() {}
+    while CHARBUF_KBD.pop().is_some() {}
 }
 
 fn charbuf_has_data() -> bool {
@@ -96,14 +359,25 @@ fn read_thread() {
         static mut CTRL_A_PRESSED: bool = false;
 
         let mut input = [0u8];
-        std::io::stdin().read(&mut input).unwrap();
+        if std::io::stdin().read(&mut input).unwrap() == 0 {
+            // stdin closed, nothing more will ever arrive
+            return;
+        }
 
         unsafe {
             if input[0] == 1 {
                 CTRL_A_PRESSED = true;
                 continue;
-            } else if (input[0] == 'X' as u8 || input[0] == 'x' as u8) && CTRL_A_PRESSED {
+            } else if (input[0] == b'X' || input[0] == b'x') && CTRL_A_PRESSED {
                 std::process::exit(0);
+            } else if (input[0] == b'P' || input[0] == b'p') && CTRL_A_PRESSED {
+                CTRL_A_PRESSED = false;
+                pci::request_hotplug();
+                continue;
+            } else if (input[0] == b'U' || input[0] == b'u') && CTRL_A_PRESSED {
+                CTRL_A_PRESSED = false;
+                pci::request_unplug();
+                continue;
             } else {
                 CTRL_A_PRESSED = false;
             }
@@ -130,122 +404,345 @@ fn init_threads_once() {
 }
 
 impl Ns16550 {
-    pub fn new() -> Self {
-        let this = Self {
+    pub fn new(config: &UartConfig) -> Result<Self, String> {
+        let host = UartHost::open(&config.backend)?;
+
+        Ok(Self {
+            addr: config.addr,
+            irqn: config.irqn,
+            host,
+            rx_fifo: VecDeque::with_capacity(UART_FIFO_SIZE),
+            thr_ipending: false,
+            timeout_ipending: false,
+            last_rx_activity: Instant::now(),
             dll: 0,
             dlm: 0,
-            isr: 0,
             ier: 0,
             fcr: 0,
             lcr: 0,
             mcr: 0,
-            lsr: LSR_TEMT | LSR_THRE,
-            msr: 0,
+            lsr: 0,
+            msr: MSR_HOST_INPUTS,
             scr: 0,
             val: 0,
             lol: false,
+        })
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_FIFO_ENABLE != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr & MCR_LOOP != 0
+    }
+
+    // Without FIFOs the receiver only has the holding register
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger(&self) -> usize {
+        if self.fifo_enabled() {
+            FCR_TRIGGER_LEVELS[(self.fcr >> FCR_TRIGGER_SHIFT) as usize]
+        } else {
+            1
+        }
+    }
+
+    fn receive(&mut self, c: u8) {
+        if self.rx_fifo.len() >= self.rx_capacity() {
+            // The FIFO keeps its contents, a full holding register is overwritten
+            self.lsr |= LSR_OE;
+
+            if self.fifo_enabled() {
+                return;
+            }
+
+            self.rx_fifo.clear();
+        }
+
+        self.rx_fifo.push_back(c);
+        self.last_rx_activity = Instant::now();
+    }
+
+    // Host input is pulled only while there is room, so it never overruns. In loopback
+    // mode the receiver is disconnected from the host and the input stays queued
+    fn fill_rx(&mut self) {
+        if self.loopback() || self.lol {
+            return;
+        }
+
+        while self.rx_fifo.len() < self.rx_capacity() {
+            match self.host.read() {
+                Some(c) => self.receive(c),
+                None => break,
+            }
+        }
+    }
+
+    fn read_rbr(&mut self) -> u8 {
+        let c = self.rx_fifo.pop_front().unwrap_or(0);
+
+        self.timeout_ipending = false;
+        self.last_rx_activity = Instant::now();
+
+        self.fill_rx();
+
+        c
+    }
+
+    fn transmit(&mut self, c: u8) {
+        if self.loopback() {
+            self.receive(c);
+        } else if !self.lol {
+            self.host.write(c);
+        }
+
+        self.thr_ipending = true;
+    }
+
+    fn char_time(&self) -> Duration {
+        let divisor = ((self.dlm as u64) << 8 | self.dll as u64).max(1);
+
+        let data_bits = 5 + (self.lcr & LCR_WORD_LENGTH_MASK) as u64;
+        let stop_bits = if self.lcr & LCR_STOP_BITS != 0 { 2 } else { 1 };
+        let parity_bits = if self.lcr & LCR_PARITY != 0 { 1 } else { 0 };
+
+        let bits = 1 + data_bits + stop_bits + parity_bits;
+
+        Duration::from_nanos(bits * 16 * divisor * 1_000_000_000 / UART_CLOCK_FREQ)
+    }
+
+    // The character timeout fires when data sits below the trigger level
+    // and nothing was received or read for four character times
+    fn update_timeout(&mut self) {
+        if !self.fifo_enabled() || self.rx_fifo.is_empty() || self.timeout_ipending {
+            return;
+        }
+
+        if self.last_rx_activity.elapsed() >= self.char_time() * 4 {
+            self.timeout_ipending = true;
+        }
+    }
+
+    fn update_msr(&mut self) {
+        let inputs = if self.loopback() {
+            let mut inputs = 0;
+
+            if self.mcr & MCR_RTS != 0 {
+                inputs |= MSR_CTS;
+            }
+
+            if self.mcr & MCR_DTR != 0 {
+                inputs |= MSR_DSR;
+            }
+
+            if self.mcr & MCR_OUT1 != 0 {
+                inputs |= MSR_RI;
+            }
+
+            if self.mcr & MCR_OUT2 != 0 {
+                inputs |= MSR_DCD;
+            }
+
+            inputs
+        } else {
+            MSR_HOST_INPUTS
         };
 
-        init_threads_once();
+        let changed = (self.msr ^ inputs) & !MSR_DELTAS;
+        let mut deltas = self.msr & MSR_DELTAS;
+
+        if changed & MSR_CTS != 0 {
+            deltas |= MSR_DCTS;
+        }
+
+        if changed & MSR_DSR != 0 {
+            deltas |= MSR_DDSR;
+        }
+
+        // Only the trailing edge of ring indicator counts
+        if changed & MSR_RI != 0 && inputs & MSR_RI == 0 {
+            deltas |= MSR_TERI;
+        }
+
+        if changed & MSR_DCD != 0 {
+            deltas |= MSR_DDCD;
+        }
 
-        this
+        self.msr = inputs | deltas;
     }
-}
 
-fn dispatch_irq(uart: &mut Ns16550) -> bool {
-    uart.isr |= 0xc0;
+    fn read_lsr(&mut self) -> u8 {
+        let mut lsr = self.lsr | LSR_THRE | LSR_TEMT;
+
+        if !self.rx_fifo.is_empty() {
+            lsr |= LSR_DR;
+        }
+
+        if self.fifo_enabled() && self.lsr & (LSR_PE | LSR_FE | LSR_BI) != 0 {
+            lsr |= LSR_RXFE;
+        }
+
+        lsr
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RLSI != 0 && self.lsr & LSR_ERRORS != 0 {
+            ISR_RLSI
+        } else if self.ier & IER_RDI != 0 && self.rx_fifo.len() >= self.rx_trigger() {
+            ISR_RDI
+        } else if self.ier & IER_RDI != 0 && self.timeout_ipending {
+            ISR_CTI
+        } else if self.ier & IER_THRI != 0 && self.thr_ipending {
+            ISR_THRI
+        } else if self.ier & IER_MSI != 0 && self.msr & MSR_DELTAS != 0 {
+            ISR_MSI
+        } else {
+            ISR_NO_INT
+        }
+    }
+
+    fn write_fcr(&mut self, data: u8) {
+        // Toggling the FIFO enable resets both FIFOs
+        if (data ^ self.fcr) & FCR_FIFO_ENABLE != 0 || data & FCR_CLEAR_RX != 0 {
+            self.rx_fifo.clear();
+            self.timeout_ipending = false;
+        }
+
+        if data & FCR_CLEAR_TX != 0 {
+            self.thr_ipending = self.ier & IER_THRI != 0;
+        }
+
+        self.fcr = if data & FCR_FIFO_ENABLE != 0 {
+            data & (FCR_FIFO_ENABLE | FCR_DMA_MODE | FCR_TRIGGER_MASK)
+        } else {
+            0
+        };
+    }
+
+    fn write_ier(&mut self, data: u8) {
+        let changed = (self.ier ^ data) & IER_THRI;
+
+        self.ier = data & IER_MASK;
 
-    if ((uart.ier & IER_RDI) != 0) && ((uart.lsr & LSR_DR) != 0) {
-        return true;
-    } else if ((uart.ier & IER_THRI) != 0) && ((uart.lsr & LSR_TEMT) != 0) {
-        return true;
+        // Enabling the THR empty interrupt while the transmitter is idle raises it right away
+        if changed != 0 {
+            self.thr_ipending = self.ier & IER_THRI != 0;
+        }
     }
 
-    false
+    fn write_lcr(&mut self, data: u8) {
+        // A break sent in loopback mode comes back as a received break
+        if data & LCR_BREAK != 0 && self.lcr & LCR_BREAK == 0 && self.loopback() {
+            self.receive(0);
+            self.lsr |= LSR_BI;
+        }
+
+        self.lcr = data;
+    }
 }
 
 impl BusDevice for Ns16550 {
     fn load(&mut self, addr: BusType, _size: BusType) -> Result<BusType, Exception> {
         let adj_addr = (addr as usize) - (self.get_begin_addr() as usize);
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let data = match adj_addr as BusType {
+            DLL if dlab => self.dll,
+            DLM if dlab => self.dlm,
+            RHR => self.read_rbr(),
+            IER => self.ier,
+            ISR => {
+                self.fill_rx();
+                self.update_timeout();
+
+                let id = self.interrupt_id();
+
+                // Reading the identification acknowledges a THR empty interrupt
+                if id == ISR_THRI {
+                    self.thr_ipending = false;
+                }
 
-        match adj_addr as BusType {
-            THR => {
-                let c = charbuf_read_data();
+                if self.fifo_enabled() {
+                    id | ISR_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.fill_rx();
+
+                let lsr = self.read_lsr();
 
-                let c = if let Some(c) = c { c } else { 0 };
+                self.lsr &= !LSR_ERRORS;
 
-                self.lsr &= !LSR_DR;
+                lsr
+            }
+            MSR => {
+                let msr = self.msr;
+
+                self.msr &= !MSR_DELTAS;
 
-                Ok(c as BusType)
+                msr
             }
-            IER => return Ok(self.ier as BusType),
-            ISR => return Ok(self.isr as BusType),
-            LCR => return Ok(self.lcr as BusType),
-            MCR => return Ok(self.mcr as BusType),
-            LSR => return Ok(self.lsr as BusType),
-            MSR => return Ok(self.msr as BusType),
-            SCR => return Ok(self.scr as BusType),
+            SCR => self.scr,
             DOOM => {
                 let c = charbuf_kbd_read_data();
 
                 self.val = if let Some(c) = c { c } else { self.val };
 
-                Ok(self.val as BusType)
+                self.val
             }
 
-            _ => Err(Exception::LoadAccessFault(addr)),
-        }
+            _ => return Err(Exception::LoadAccessFault(addr)),
+        };
+
+        Ok(data as BusType)
     }
 
     fn store(&mut self, addr: BusType, data: BusType, _size: BusType) -> Result<(), Exception> {
         let adj_addr = (addr as usize) - (self.get_begin_addr() as usize);
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let data = data as u8;
 
         match adj_addr as BusType {
-            THR => {
-                let c = data as u8 as char;
-
-                if c.is_ascii() && !self.lol {
-                    std::io::stdout().write_all(&[c as u8]).unwrap();
-                }
-
-                Ok(())
-            }
-            IER => {
-                self.ier = data as u8;
-                Ok(())
-            }
-            FCR => {
-                self.fcr = data as u8;
-                Ok(())
-            }
-            LCR => {
-                self.lcr = data as u8;
-                Ok(())
-            }
+            DLL if dlab => self.dll = data,
+            DLM if dlab => self.dlm = data,
+            THR => self.transmit(data),
+            IER => self.write_ier(data),
+            FCR => self.write_fcr(data),
+            LCR => self.write_lcr(data),
             MCR => {
-                self.mcr = data as u8;
-                Ok(())
-            }
-            SCR => {
-                self.scr = data as u8;
-                Ok(())
+                self.mcr = data & MCR_MASK;
+                self.update_msr();
             }
+            // LSR and MSR are read only
+            LSR | MSR => {}
+            SCR => self.scr = data,
             DOOM_FLUSH => {
                 charkbd_flush();
                 self.lol = true;
-
-                Ok(())
             }
-            _ => Err(Exception::StoreAccessFault(addr)),
+            _ => return Err(Exception::StoreAccessFault(addr)),
         }
+
+        Ok(())
     }
 
     fn get_begin_addr(&self) -> BusType {
-        UART_ADDR
+        self.addr
     }
 
     fn get_end_addr(&self) -> BusType {
-        UART_END_ADDR
+        self.addr + UART_SIZE
     }
 
     fn tick_core_local(&mut self) {}
@@ -259,15 +756,11 @@ impl BusDevice for Ns16550 {
     }
 
     fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
-        if charbuf_has_data() && !self.lol {
-            self.lsr |= LSR_DR;
-            cpu.pending_interrupt_number = UART_IRQN;
-
-            return Some(csr::bits::SEIP_BIT as u32);
-        }
+        self.fill_rx();
+        self.update_timeout();
 
-        if dispatch_irq(self) {
-            cpu.pending_interrupt_number = UART_IRQN;
+        if self.interrupt_id() != ISR_NO_INT {
+            cpu.pending_interrupt_number = self.irqn;
 
             return Some(csr::bits::SEIP_BIT as u32);
         }
@@ -277,12 +770,11 @@ impl BusDevice for Ns16550 {
 
     fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
         let serial_node = fdt
-            .begin_node(&util::fdt_node_addr_helper("serial", UART_ADDR))
+            .begin_node(&util::fdt_node_addr_helper("serial", self.addr))
             .unwrap();
-        fdt.property_u32("interrupts", UART_IRQN).unwrap();
-        fdt.property_u32("interrupt-parent", PLIC_PHANDLE).unwrap();
+        aplic::describe_fdt_irq(fdt, self.irqn);
         fdt.property_u32("clock-frequency", 0x384000).unwrap();
-        fdt.property_array_u32("reg", &[0x00, UART_ADDR, 0x00, UART_SIZE])
+        fdt.property_array_u32("reg", &[0x00, self.addr, 0x00, UART_SIZE])
             .unwrap();
         fdt.property_string("compatible", "ns16550a").unwrap();
         fdt.end_node(serial_node).unwrap();
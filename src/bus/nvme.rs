@@ -0,0 +1,794 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::bus::pci::*;
+use crate::bus::*;
+
+const PCI_DEVICE_ID_REDHAT_NVME: u16 = 0x0010;
+const PCI_CLASS_STORAGE_EXPRESS: u32 = 0x010802;
+
+// BAR 0 holds the controller registers, the doorbells and the MSI-X table
+const NVME_BAR: usize = 0;
+const NVME_BAR_SIZE: BusType = 0x4000;
+const MSIX_TABLE_OFFSET: BusType = 0x2000;
+const MSIX_PBA_OFFSET: BusType = 0x3000;
+
+const REG_CAP: BusType = 0x00;
+const REG_CAP_HI: BusType = 0x04;
+const REG_VS: BusType = 0x08;
+const REG_INTMS: BusType = 0x0c;
+const REG_INTMC: BusType = 0x10;
+const REG_CC: BusType = 0x14;
+const REG_CSTS: BusType = 0x1c;
+const REG_AQA: BusType = 0x24;
+const REG_ASQ: BusType = 0x28;
+const REG_ASQ_HI: BusType = 0x2c;
+const REG_ACQ: BusType = 0x30;
+const REG_ACQ_HI: BusType = 0x34;
+const REG_DOORBELL: BusType = 0x1000;
+const REG_DOORBELL_END: BusType = 0x1fff;
+
+// MQES is zero based, CQR set, 7.5s ready timeout, NVM command set, 4 KiB pages
+const CAP_MQES: u64 = 0xff;
+const CAP_CQR: u64 = 1 << 16;
+const CAP_TO: u64 = 0xf << 24;
+const CAP_CSS_NVM: u64 = 1 << 37;
+const NVME_VERSION: u32 = 0x00010400;
+
+const CC_EN: u32 = 1 << 0;
+const CC_SHN_SHIFT: u32 = 14;
+const CC_SHN_MASK: u32 = 0x3;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+const NVME_PAGE_SIZE: u64 = 4096;
+const NVME_SQ_ENTRY_SIZE: u64 = 64;
+const NVME_CQ_ENTRY_SIZE: u64 = 16;
+const NVME_IO_QUEUES: usize = 16;
+const NVME_QUEUES: usize = NVME_IO_QUEUES + 1;
+const NVME_NSID: u32 = 1;
+const NVME_MDTS: u8 = 5;
+
+const LBA_SHIFT: u32 = 9;
+const LBA_SIZE: u64 = 1 << LBA_SHIFT;
+
+const ADMIN_DELETE_SQ: u8 = 0x00;
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT: u8 = 0x0c;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NS_LIST: u32 = 0x02;
+const CNS_NS_DESCRIPTORS: u32 = 0x03;
+
+const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+// Status is (SCT << 8) | SC
+const SC_SUCCESS: u16 = 0x00;
+const SC_INVALID_OPCODE: u16 = 0x01;
+const SC_INVALID_FIELD: u16 = 0x02;
+const SC_DATA_TRANSFER_ERROR: u16 = 0x04;
+const SC_INTERNAL: u16 = 0x06;
+const SC_INVALID_NAMESPACE: u16 = 0x0b;
+const SC_NS_WRITE_PROTECTED: u16 = 0x20;
+const SC_LBA_RANGE: u16 = 0x80;
+const SC_INVALID_CQ: u16 = 0x100;
+const SC_INVALID_QID: u16 = 0x101;
+const SC_INVALID_QSIZE: u16 = 0x102;
+const SC_INVALID_QUEUE_DELETION: u16 = 0x10c;
+
+struct SubmissionQueue {
+    addr: u64,
+    size: u16,
+    head: u16,
+    tail: u16,
+    cqid: u16,
+}
+
+struct CompletionQueue {
+    addr: u64,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    irq_enabled: bool,
+}
+
+struct NvmeCommand {
+    opcode: u8,
+    cid: u16,
+    nsid: u32,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+}
+
+impl NvmeCommand {
+    fn parse(bytes: &[u8]) -> NvmeCommand {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        NvmeCommand {
+            opcode: bytes[0],
+            cid: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+            nsid: u32_at(4),
+            prp1: u64_at(24),
+            prp2: u64_at(32),
+            cdw10: u32_at(40),
+            cdw11: u32_at(44),
+            cdw12: u32_at(48),
+        }
+    }
+}
+
+fn dma_addr(addr: u64) -> Result<BusType, u16> {
+    if addr > BusType::MAX as u64 {
+        return Err(SC_DATA_TRANSFER_ERROR);
+    }
+
+    Ok(addr as BusType)
+}
+
+fn set_u64_half(value: &mut u64, data: BusType, high: bool) {
+    if high {
+        *value = (*value & 0xffffffff) | ((data as u64) << 32);
+    } else {
+        *value = (*value & !0xffffffff) | data as u64;
+    }
+}
+
+// Splits a PRP1/PRP2 pair into the guest memory segments it describes
+fn prp_segments(prp1: u64, prp2: u64, len: usize) -> Result<Vec<(BusType, usize)>, u16> {
+    let mut segments = Vec::new();
+    let mut remaining = len as u64;
+
+    let first = remaining.min(NVME_PAGE_SIZE - (prp1 % NVME_PAGE_SIZE));
+
+    segments.push((dma_addr(prp1)?, first as usize));
+    remaining -= first;
+
+    if remaining == 0 {
+        return Ok(segments);
+    }
+
+    if remaining <= NVME_PAGE_SIZE {
+        segments.push((dma_addr(prp2)?, remaining as usize));
+
+        return Ok(segments);
+    }
+
+    let bus = bus::get_bus();
+    let mut list = prp2;
+
+    // The last entry of a full list page points to the next list page
+    while remaining > 0 {
+        let entries = ((NVME_PAGE_SIZE - (list % NVME_PAGE_SIZE)) / 8) as usize;
+        let list_bytes = bus
+            .dma_slice(dma_addr(list)?, entries * 8)
+            .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+
+        for i in 0..entries {
+            let entry = u64::from_le_bytes(list_bytes[i * 8..i * 8 + 8].try_into().unwrap());
+
+            if i == entries - 1 && remaining > NVME_PAGE_SIZE {
+                list = entry;
+                break;
+            }
+
+            let count = remaining.min(NVME_PAGE_SIZE);
+
+            segments.push((dma_addr(entry)?, count as usize));
+            remaining -= count;
+
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+fn copy_to_guest(prp1: u64, prp2: u64, data: &[u8]) -> Result<(), u16> {
+    let bus = bus::get_bus();
+    let mut done = 0;
+
+    for (addr, len) in prp_segments(prp1, prp2, data.len())? {
+        bus.dma_write(addr, &data[done..done + len])
+            .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+
+        done += len;
+    }
+
+    Ok(())
+}
+
+fn copy_ascii(dst: &mut [u8], src: &str) {
+    dst.fill(b' ');
+    dst[..src.len()].copy_from_slice(src.as_bytes());
+}
+
+pub struct Nvme {
+    config: PciConfig,
+    msix: Msix,
+    file: File,
+    readonly: bool,
+    nlbas: u64,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    intms: u32,
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+}
+
+impl Nvme {
+    pub fn new(path: &str, readonly: bool) -> Result<Nvme, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .open(path)
+            .map_err(|err| format!("Failed to open NVMe namespace image {}: {}", path, err))?;
+
+        let size = file.metadata().map_err(|err| err.to_string())?.len();
+
+        let mut config = PciConfig::new(
+            PCI_VENDOR_ID_REDHAT,
+            PCI_DEVICE_ID_REDHAT_NVME,
+            PCI_CLASS_STORAGE_EXPRESS,
+            2,
+        );
+
+        config.set_subsystem(PCI_VENDOR_ID_REDHAT, 0);
+        config.set_interrupt_pin(1);
+        config.add_bar(NVME_BAR, NVME_BAR_SIZE);
+
+        let msix = Msix::new(
+            &mut config,
+            NVME_QUEUES as u16,
+            NVME_BAR,
+            MSIX_TABLE_OFFSET,
+            MSIX_PBA_OFFSET,
+        );
+
+        Ok(Nvme {
+            config,
+            msix,
+            file,
+            readonly,
+            nlbas: size >> LBA_SHIFT,
+            cc: 0,
+            csts: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            intms: 0,
+            sqs: (0..NVME_QUEUES).map(|_| None).collect(),
+            cqs: (0..NVME_QUEUES).map(|_| None).collect(),
+        })
+    }
+
+    fn cap(&self) -> u64 {
+        CAP_MQES | CAP_CQR | CAP_TO | CAP_CSS_NVM
+    }
+
+    fn reset(&mut self) {
+        self.csts = 0;
+        self.intms = 0;
+
+        self.sqs.iter_mut().for_each(|sq| *sq = None);
+        self.cqs.iter_mut().for_each(|cq| *cq = None);
+    }
+
+    fn enable(&mut self) {
+        let sq_size = (self.aqa & 0xfff) as u16 + 1;
+        let cq_size = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+
+        if sq_size < 2 || cq_size < 2 {
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        self.sqs[0] = Some(SubmissionQueue {
+            addr: self.asq,
+            size: sq_size,
+            head: 0,
+            tail: 0,
+            cqid: 0,
+        });
+
+        self.cqs[0] = Some(CompletionQueue {
+            addr: self.acq,
+            size: cq_size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector: 0,
+            irq_enabled: true,
+        });
+
+        self.csts |= CSTS_RDY;
+    }
+
+    fn write_cc(&mut self, data: u32) {
+        let was_enabled = (self.cc & CC_EN) != 0;
+
+        self.cc = data;
+
+        if !was_enabled && (data & CC_EN) != 0 {
+            self.enable();
+        } else if was_enabled && (data & CC_EN) == 0 {
+            self.reset();
+        }
+
+        if ((data >> CC_SHN_SHIFT) & CC_SHN_MASK) != 0 {
+            let _ = self.file.flush();
+
+            self.csts |= CSTS_SHST_COMPLETE;
+        } else {
+            self.csts &= !CSTS_SHST_COMPLETE;
+        }
+    }
+
+    fn write_doorbell(&mut self, offset: BusType, data: BusType) {
+        if (self.csts & CSTS_RDY) == 0 {
+            return;
+        }
+
+        let qid = (offset / 8) as usize;
+        let is_cq = (offset / 4) % 2 == 1;
+
+        if qid >= NVME_QUEUES {
+            return;
+        }
+
+        if is_cq {
+            if let Some(cq) = self.cqs[qid].as_mut() {
+                if (data as u16) < cq.size {
+                    cq.head = data as u16;
+                }
+            }
+
+            return;
+        }
+
+        if let Some(sq) = self.sqs[qid].as_mut() {
+            if (data as u16) < sq.size {
+                sq.tail = data as u16;
+            }
+        }
+
+        self.process_sq(qid);
+    }
+
+    fn process_sq(&mut self, qid: usize) {
+        if !self.config.bus_master_enabled() {
+            return;
+        }
+
+        let mut used_cqs = Vec::new();
+
+        while let Some(sq) = self.sqs[qid].as_mut() {
+            if sq.head == sq.tail {
+                break;
+            }
+
+            let entry_addr = sq.addr + sq.head as u64 * NVME_SQ_ENTRY_SIZE;
+
+            sq.head = (sq.head + 1) % sq.size;
+
+            let sq_head = sq.head;
+            let cqid = sq.cqid as usize;
+
+            let bytes = match dma_addr(entry_addr)
+                .ok()
+                .and_then(|addr| bus::get_bus().dma_slice(addr, 64).ok())
+            {
+                Some(bytes) => bytes,
+                None => {
+                    self.csts |= CSTS_CFS;
+                    break;
+                }
+            };
+
+            let cmd = NvmeCommand::parse(bytes);
+
+            // Asynchronous event requests stay outstanding, there are no events to report
+            if qid == 0 && cmd.opcode == ADMIN_ASYNC_EVENT {
+                continue;
+            }
+
+            let (status, result) = if qid == 0 {
+                self.execute_admin(&cmd)
+            } else {
+                self.execute_io(&cmd)
+            };
+
+            self.post_completion(cqid, qid as u16, sq_head, cmd.cid, status, result);
+
+            if !used_cqs.contains(&cqid) {
+                used_cqs.push(cqid);
+            }
+        }
+
+        for cqid in used_cqs {
+            self.send_irq(cqid);
+        }
+    }
+
+    fn post_completion(
+        &mut self,
+        cqid: usize,
+        sqid: u16,
+        sq_head: u16,
+        cid: u16,
+        status: u16,
+        result: u32,
+    ) {
+        let cq = if let Some(cq) = self.cqs[cqid].as_mut() {
+            cq
+        } else {
+            return;
+        };
+
+        let mut entry = [0u8; NVME_CQ_ENTRY_SIZE as usize];
+        let dw3 = cid as u32 | ((cq.phase as u32) << 16) | ((status as u32) << 17);
+
+        entry[0..4].copy_from_slice(&result.to_le_bytes());
+        entry[8..12].copy_from_slice(&(sq_head as u32 | ((sqid as u32) << 16)).to_le_bytes());
+        entry[12..16].copy_from_slice(&dw3.to_le_bytes());
+
+        let addr = cq.addr + cq.tail as u64 * NVME_CQ_ENTRY_SIZE;
+
+        let written = dma_addr(addr)
+            .ok()
+            .map(|addr| bus::get_bus().dma_write(addr, &entry).is_ok());
+
+        if written != Some(true) {
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        cq.tail = (cq.tail + 1) % cq.size;
+
+        if cq.tail == 0 {
+            cq.phase = !cq.phase;
+        }
+    }
+
+    fn send_irq(&mut self, cqid: usize) {
+        let cq = if let Some(cq) = self.cqs[cqid].as_ref() {
+            cq
+        } else {
+            return;
+        };
+
+        if cq.irq_enabled && self.msix.is_enabled(&self.config) {
+            let vector = cq.vector;
+
+            self.msix.notify(&self.config, vector);
+        }
+    }
+
+    fn execute_admin(&mut self, cmd: &NvmeCommand) -> (u16, u32) {
+        match cmd.opcode {
+            ADMIN_CREATE_CQ => (self.create_cq(cmd), 0),
+            ADMIN_CREATE_SQ => (self.create_sq(cmd), 0),
+            ADMIN_DELETE_SQ => {
+                let qid = (cmd.cdw10 & 0xffff) as usize;
+
+                if qid == 0 || qid >= NVME_QUEUES || self.sqs[qid].is_none() {
+                    return (SC_INVALID_QID, 0);
+                }
+
+                self.sqs[qid] = None;
+
+                (SC_SUCCESS, 0)
+            }
+            ADMIN_DELETE_CQ => {
+                let qid = (cmd.cdw10 & 0xffff) as usize;
+
+                if qid == 0 || qid >= NVME_QUEUES || self.cqs[qid].is_none() {
+                    return (SC_INVALID_QID, 0);
+                }
+
+                let in_use = self.sqs.iter().flatten().any(|sq| sq.cqid as usize == qid);
+
+                if in_use {
+                    return (SC_INVALID_QUEUE_DELETION, 0);
+                }
+
+                self.cqs[qid] = None;
+
+                (SC_SUCCESS, 0)
+            }
+            ADMIN_IDENTIFY => (self.identify(cmd), 0),
+            ADMIN_GET_LOG_PAGE => {
+                let dwords = ((cmd.cdw10 >> 16) & 0xfff) as usize + 1;
+                let data = vec![0u8; dwords * 4];
+
+                match copy_to_guest(cmd.prp1, cmd.prp2, &data) {
+                    Ok(()) => (SC_SUCCESS, 0),
+                    Err(status) => (status, 0),
+                }
+            }
+            ADMIN_SET_FEATURES | ADMIN_GET_FEATURES => {
+                if (cmd.cdw10 & 0xff) == FEATURE_NUM_QUEUES {
+                    let count = NVME_IO_QUEUES as u32 - 1;
+
+                    return (SC_SUCCESS, (count << 16) | count);
+                }
+
+                (SC_SUCCESS, 0)
+            }
+            ADMIN_ABORT => (SC_SUCCESS, 1),
+            _ => (SC_INVALID_OPCODE, 0),
+        }
+    }
+
+    fn create_cq(&mut self, cmd: &NvmeCommand) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) as u64 + 1;
+        let vector = (cmd.cdw11 >> 16) as u16;
+
+        if qid == 0 || qid >= NVME_QUEUES || self.cqs[qid].is_some() {
+            return SC_INVALID_QID;
+        }
+
+        if !(2..=CAP_MQES + 1).contains(&size) {
+            return SC_INVALID_QSIZE;
+        }
+
+        // Only physically contiguous queues are supported (CAP.CQR)
+        if (cmd.cdw11 & 1) == 0 || vector as usize >= NVME_QUEUES {
+            return SC_INVALID_FIELD;
+        }
+
+        self.cqs[qid] = Some(CompletionQueue {
+            addr: cmd.prp1,
+            size: size as u16,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            irq_enabled: (cmd.cdw11 & 2) != 0,
+        });
+
+        SC_SUCCESS
+    }
+
+    fn create_sq(&mut self, cmd: &NvmeCommand) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) as u64 + 1;
+        let cqid = (cmd.cdw11 >> 16) as usize;
+
+        if qid == 0 || qid >= NVME_QUEUES || self.sqs[qid].is_some() {
+            return SC_INVALID_QID;
+        }
+
+        if cqid == 0 || cqid >= NVME_QUEUES || self.cqs[cqid].is_none() {
+            return SC_INVALID_CQ;
+        }
+
+        if !(2..=CAP_MQES + 1).contains(&size) {
+            return SC_INVALID_QSIZE;
+        }
+
+        if (cmd.cdw11 & 1) == 0 {
+            return SC_INVALID_FIELD;
+        }
+
+        self.sqs[qid] = Some(SubmissionQueue {
+            addr: cmd.prp1,
+            size: size as u16,
+            head: 0,
+            tail: 0,
+            cqid: cqid as u16,
+        });
+
+        SC_SUCCESS
+    }
+
+    fn identify(&mut self, cmd: &NvmeCommand) -> u16 {
+        let mut data = [0u8; NVME_PAGE_SIZE as usize];
+
+        match cmd.cdw10 & 0xff {
+            CNS_CONTROLLER => {
+                data[0..2].copy_from_slice(&PCI_VENDOR_ID_REDHAT.to_le_bytes());
+                data[2..4].copy_from_slice(&PCI_VENDOR_ID_REDHAT.to_le_bytes());
+                copy_ascii(&mut data[4..24], "RISCVBOX0001");
+                copy_ascii(&mut data[24..64], "RISCVBox NVMe Ctrl");
+                copy_ascii(&mut data[64..72], env!("CARGO_PKG_VERSION"));
+                data[72] = 6;
+                data[77] = NVME_MDTS;
+                data[80..84].copy_from_slice(&NVME_VERSION.to_le_bytes());
+                data[258] = 3;
+                data[259] = 3;
+                data[512] = 0x66;
+                data[513] = 0x44;
+                data[516..520].copy_from_slice(&NVME_NSID.to_le_bytes());
+                data[525] = 1;
+            }
+            CNS_NAMESPACE => {
+                if cmd.nsid != NVME_NSID {
+                    return SC_INVALID_NAMESPACE;
+                }
+
+                data[0..8].copy_from_slice(&self.nlbas.to_le_bytes());
+                data[8..16].copy_from_slice(&self.nlbas.to_le_bytes());
+                data[16..24].copy_from_slice(&self.nlbas.to_le_bytes());
+                data[130] = LBA_SHIFT as u8;
+            }
+            CNS_ACTIVE_NS_LIST => {
+                if cmd.nsid < NVME_NSID {
+                    data[0..4].copy_from_slice(&NVME_NSID.to_le_bytes());
+                }
+            }
+            CNS_NS_DESCRIPTORS => {
+                if cmd.nsid != NVME_NSID {
+                    return SC_INVALID_NAMESPACE;
+                }
+            }
+            _ => return SC_INVALID_FIELD,
+        }
+
+        match copy_to_guest(cmd.prp1, cmd.prp2, &data) {
+            Ok(()) => SC_SUCCESS,
+            Err(status) => status,
+        }
+    }
+
+    fn execute_io(&mut self, cmd: &NvmeCommand) -> (u16, u32) {
+        if cmd.nsid != NVME_NSID {
+            return (SC_INVALID_NAMESPACE, 0);
+        }
+
+        let status = match cmd.opcode {
+            IO_FLUSH => {
+                if !self.readonly && self.file.sync_all().is_err() {
+                    SC_INTERNAL
+                } else {
+                    SC_SUCCESS
+                }
+            }
+            IO_READ | IO_WRITE => match self.read_write(cmd) {
+                Ok(()) => SC_SUCCESS,
+                Err(status) => status,
+            },
+            _ => SC_INVALID_OPCODE,
+        };
+
+        (status, 0)
+    }
+
+    // Data moves straight between the image and guest RAM, without a bounce buffer
+    fn read_write(&mut self, cmd: &NvmeCommand) -> Result<(), u16> {
+        let slba = cmd.cdw10 as u64 | ((cmd.cdw11 as u64) << 32);
+        let nlb = (cmd.cdw12 & 0xffff) as u64 + 1;
+        let len = nlb * LBA_SIZE;
+
+        if slba.checked_add(nlb).map_or(true, |end| end > self.nlbas) {
+            return Err(SC_LBA_RANGE);
+        }
+
+        if len > (NVME_PAGE_SIZE << NVME_MDTS) {
+            return Err(SC_INVALID_FIELD);
+        }
+
+        if cmd.opcode == IO_WRITE && self.readonly {
+            return Err(SC_NS_WRITE_PROTECTED);
+        }
+
+        let segments = prp_segments(cmd.prp1, cmd.prp2, len as usize)?;
+
+        self.file
+            .seek(SeekFrom::Start(slba * LBA_SIZE))
+            .map_err(|_| SC_INTERNAL)?;
+
+        let bus = bus::get_bus();
+
+        for (addr, len) in segments {
+            if cmd.opcode == IO_READ {
+                let guest = bus
+                    .dma_slice_mut(addr, len)
+                    .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+
+                self.file.read_exact(guest).map_err(|_| SC_INTERNAL)?;
+            } else {
+                let guest = bus
+                    .dma_slice(addr, len)
+                    .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+
+                self.file.write_all(guest).map_err(|_| SC_INTERNAL)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PciDevice for Nvme {
+    fn name(&self) -> String {
+        "nvme".to_string()
+    }
+
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_load(&mut self, bar: usize, offset: BusType, size: BusType) -> BusType {
+        if self.msix.contains(bar, offset) {
+            return self.msix.read(offset, size);
+        }
+
+        match offset {
+            REG_CAP => self.cap() as BusType,
+            REG_CAP_HI => (self.cap() >> 32) as BusType,
+            REG_VS => NVME_VERSION,
+            REG_INTMS | REG_INTMC => self.intms,
+            REG_CC => self.cc,
+            REG_CSTS => self.csts,
+            REG_AQA => self.aqa,
+            REG_ASQ => self.asq as BusType,
+            REG_ASQ_HI => (self.asq >> 32) as BusType,
+            REG_ACQ => self.acq as BusType,
+            REG_ACQ_HI => (self.acq >> 32) as BusType,
+            _ => 0,
+        }
+    }
+
+    fn bar_store(&mut self, bar: usize, offset: BusType, data: BusType, size: BusType) {
+        if self.msix.contains(bar, offset) {
+            self.msix.write(&self.config, offset, data, size);
+            return;
+        }
+
+        match offset {
+            REG_INTMS => self.intms |= data,
+            REG_INTMC => self.intms &= !data,
+            REG_CC => self.write_cc(data),
+            REG_AQA => self.aqa = data,
+            REG_ASQ => set_u64_half(&mut self.asq, data, false),
+            REG_ASQ_HI => set_u64_half(&mut self.asq, data, true),
+            REG_ACQ => set_u64_half(&mut self.acq, data, false),
+            REG_ACQ_HI => set_u64_half(&mut self.acq, data, true),
+            REG_DOORBELL..=REG_DOORBELL_END => self.write_doorbell(offset - REG_DOORBELL, data),
+            _ => {}
+        }
+    }
+
+    // INTx stays asserted while an interrupt enabled completion queue has unconsumed entries
+    fn irq_level(&self) -> bool {
+        if self.msix.is_enabled(&self.config) || (self.intms & 1) != 0 {
+            return false;
+        }
+
+        self.cqs
+            .iter()
+            .flatten()
+            .any(|cq| cq.irq_enabled && cq.head != cq.tail)
+    }
+}
@@ -0,0 +1,749 @@
+use crate::bus::*;
+use crate::cpu::*;
+use crate::util;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::{aplic, imsic, nvme, virtio, virtio_blk, virtio_rng};
+
+pub const PCI_ECAM_ADDR: BusType = 0x30000000;
+const PCI_ECAM_SIZE: BusType = 0x1000000;
+pub const PCI_MMIO_ADDR: BusType = 0x40000000;
+const PCI_MMIO_SIZE: BusType = 0x40000000;
+
+const PCI_BUS_COUNT: u32 = PCI_ECAM_SIZE >> 20;
+const PCI_SLOT_COUNT: usize = 32;
+
+// Same INTx lines as QEMU's virt machine, swizzled by slot number
+const PCI_INTX_BASE_IRQ: u32 = 32;
+const PCI_INTX_COUNT: u32 = 4;
+
+pub const PCI_CONFIG_SIZE: usize = 0x1000;
+pub const PCI_NUM_BARS: usize = 6;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_REVISION_ID: usize = 0x08;
+pub const PCI_CLASS_PROG: usize = 0x09;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_BASE_ADDRESS_0: usize = 0x10;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+const PCI_CAP_START: usize = 0x40;
+const PCI_CAP_END: usize = 0x100;
+
+pub const PCI_COMMAND_MEMORY: u16 = 0x2;
+pub const PCI_COMMAND_MASTER: u16 = 0x4;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+
+const PCI_STATUS_INTERRUPT: u16 = 0x8;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+
+pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const PCI_MSIX_FLAGS: usize = 2;
+const PCI_MSIX_TABLE: usize = 4;
+const PCI_MSIX_PBA: usize = 8;
+const PCI_MSIX_FLAGS_ENABLE: u16 = 0x8000;
+const PCI_MSIX_FLAGS_MASKALL: u16 = 0x4000;
+const PCI_MSIX_ENTRY_SIZE: BusType = 16;
+const PCI_MSIX_ENTRY_CTRL_MASKBIT: u32 = 1;
+
+pub const PCI_MSIX_NO_VECTOR: u16 = 0xffff;
+
+pub const PCI_VENDOR_ID_REDHAT: u16 = 0x1b36;
+const PCI_DEVICE_ID_REDHAT_PCIE_HOST: u16 = 0x0008;
+const PCI_CLASS_BRIDGE_HOST: u32 = 0x060000;
+
+static HOTPLUG_REQUESTS: AtomicU32 = AtomicU32::new(0);
+static UNPLUG_REQUESTS: AtomicU32 = AtomicU32::new(0);
+
+// Configuration space with per-byte write masks, which also gives BARs their sizing behaviour
+pub struct PciConfig {
+    data: [u8; PCI_CONFIG_SIZE],
+    wmask: [u8; PCI_CONFIG_SIZE],
+    bar_sizes: [BusType; PCI_NUM_BARS],
+    next_cap: usize,
+}
+
+impl PciConfig {
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u32, revision: u8) -> PciConfig {
+        let mut config = PciConfig {
+            data: [0; PCI_CONFIG_SIZE],
+            wmask: [0; PCI_CONFIG_SIZE],
+            bar_sizes: [0; PCI_NUM_BARS],
+            next_cap: PCI_CAP_START,
+        };
+
+        config.set_u16(PCI_VENDOR_ID, vendor_id);
+        config.set_u16(PCI_DEVICE_ID, device_id);
+        config.set_u8(PCI_REVISION_ID, revision);
+        config.set_u8(PCI_CLASS_PROG, class_code as u8);
+        config.set_u16(PCI_CLASS_PROG + 1, (class_code >> 8) as u16);
+        config.set_u8(PCI_HEADER_TYPE, 0);
+
+        config.set_wmask_u16(
+            PCI_COMMAND,
+            PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE,
+        );
+        config.set_wmask_u8(PCI_INTERRUPT_LINE, 0xff);
+
+        config
+    }
+
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set_u16(PCI_SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(PCI_SUBSYSTEM_ID, id);
+    }
+
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.set_u8(PCI_INTERRUPT_PIN, pin);
+    }
+
+    pub fn get_interrupt_pin(&self) -> u8 {
+        self.get_u8(PCI_INTERRUPT_PIN)
+    }
+
+    // Only 32-bit non-prefetchable memory BARs are implemented
+    pub fn add_bar(&mut self, bar: usize, size: BusType) {
+        assert!(size.is_power_of_two() && size >= 16);
+
+        self.bar_sizes[bar] = size;
+        self.set_wmask_u32(PCI_BASE_ADDRESS_0 + bar * 4, !(size - 1));
+    }
+
+    pub fn get_bar_size(&self, bar: usize) -> BusType {
+        self.bar_sizes[bar]
+    }
+
+    pub fn get_bar_addr(&self, bar: usize) -> BusType {
+        self.get_u32(PCI_BASE_ADDRESS_0 + bar * 4) & !0xf
+    }
+
+    pub fn set_bar_addr(&mut self, bar: usize, addr: BusType) {
+        self.set_u32(PCI_BASE_ADDRESS_0 + bar * 4, addr & !0xf);
+    }
+
+    // Returns the offset of the new capability, linked at the end of the list
+    pub fn add_capability(&mut self, id: u8, len: usize) -> usize {
+        let offset = self.next_cap;
+
+        assert!(offset + len <= PCI_CAP_END);
+
+        self.set_u8(offset, id);
+        self.set_u8(offset + 1, 0);
+
+        if offset == PCI_CAP_START {
+            self.set_u8(PCI_CAPABILITY_LIST, offset as u8);
+            self.set_u16(PCI_STATUS, self.get_u16(PCI_STATUS) | PCI_STATUS_CAP_LIST);
+        } else {
+            let mut prev = self.get_u8(PCI_CAPABILITY_LIST) as usize;
+
+            while self.get_u8(prev + 1) != 0 {
+                prev = self.get_u8(prev + 1) as usize;
+            }
+
+            self.set_u8(prev + 1, offset as u8);
+        }
+
+        self.next_cap = util::align_up(offset + len, 4);
+
+        offset
+    }
+
+    pub fn get_command(&self) -> u16 {
+        self.get_u16(PCI_COMMAND)
+    }
+
+    pub fn memory_enabled(&self) -> bool {
+        (self.get_command() & PCI_COMMAND_MEMORY) != 0
+    }
+
+    pub fn bus_master_enabled(&self) -> bool {
+        (self.get_command() & PCI_COMMAND_MASTER) != 0
+    }
+
+    pub fn intx_disabled(&self) -> bool {
+        (self.get_command() & PCI_COMMAND_INTX_DISABLE) != 0
+    }
+
+    pub fn set_interrupt_status(&mut self, asserted: bool) {
+        let status = self.get_u16(PCI_STATUS) & !PCI_STATUS_INTERRUPT;
+        let status = if asserted {
+            status | PCI_STATUS_INTERRUPT
+        } else {
+            status
+        };
+
+        self.set_u16(PCI_STATUS, status);
+    }
+
+    // Accesses are aligned down to their size, so one near the end of the
+    // space can't run past it
+    pub fn read(&self, offset: usize, size: BusType) -> BusType {
+        match size {
+            8 => self.get_u8(offset) as BusType,
+            16 => self.get_u16(offset & !1) as BusType,
+            32 => self.get_u32(offset & !3),
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: BusType, size: BusType) {
+        let len = match size {
+            8 | 16 | 32 => (size / 8) as usize,
+            _ => return,
+        };
+        let offset = offset & !(len - 1);
+
+        for i in 0..len {
+            let byte = (data >> (i * 8)) as u8;
+            let mask = self.wmask[offset + i];
+
+            self.data[offset + i] = (self.data[offset + i] & !mask) | (byte & mask);
+        }
+    }
+
+    pub fn get_u8(&self, offset: usize) -> u8 {
+        self.data[offset]
+    }
+
+    pub fn get_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes(self.data[offset..offset + 2].try_into().unwrap())
+    }
+
+    pub fn get_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn set_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn set_wmask_u8(&mut self, offset: usize, mask: u8) {
+        self.wmask[offset] = mask;
+    }
+
+    pub fn set_wmask_u16(&mut self, offset: usize, mask: u16) {
+        self.wmask[offset..offset + 2].copy_from_slice(&mask.to_le_bytes());
+    }
+
+    pub fn set_wmask_u32(&mut self, offset: usize, mask: u32) {
+        self.wmask[offset..offset + 4].copy_from_slice(&mask.to_le_bytes());
+    }
+}
+
+// MSI-X capability with its vector table and pending bit array placed inside one BAR
+pub struct Msix {
+    cap: usize,
+    bar: usize,
+    table_offset: BusType,
+    pba_offset: BusType,
+    table: Vec<[u32; 4]>,
+    pba: Vec<u32>,
+}
+
+impl Msix {
+    pub fn new(
+        config: &mut PciConfig,
+        vectors: u16,
+        bar: usize,
+        table_offset: BusType,
+        pba_offset: BusType,
+    ) -> Msix {
+        let cap = config.add_capability(PCI_CAP_ID_MSIX, 12);
+
+        config.set_u16(cap + PCI_MSIX_FLAGS, vectors - 1);
+        config.set_wmask_u16(
+            cap + PCI_MSIX_FLAGS,
+            PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL,
+        );
+        config.set_u32(cap + PCI_MSIX_TABLE, table_offset | bar as u32);
+        config.set_u32(cap + PCI_MSIX_PBA, pba_offset | bar as u32);
+
+        Msix {
+            cap,
+            bar,
+            table_offset,
+            pba_offset,
+            table: vec![[0, 0, 0, PCI_MSIX_ENTRY_CTRL_MASKBIT]; vectors as usize],
+            pba: vec![0; (vectors as usize).div_ceil(32)],
+        }
+    }
+
+    pub fn is_enabled(&self, config: &PciConfig) -> bool {
+        (config.get_u16(self.cap + PCI_MSIX_FLAGS) & PCI_MSIX_FLAGS_ENABLE) != 0
+    }
+
+    fn is_masked(&self, config: &PciConfig, vector: usize) -> bool {
+        (config.get_u16(self.cap + PCI_MSIX_FLAGS) & PCI_MSIX_FLAGS_MASKALL) != 0
+            || (self.table[vector][3] & PCI_MSIX_ENTRY_CTRL_MASKBIT) != 0
+    }
+
+    fn table_size(&self) -> BusType {
+        self.table.len() as BusType * PCI_MSIX_ENTRY_SIZE
+    }
+
+    pub fn contains(&self, bar: usize, offset: BusType) -> bool {
+        bar == self.bar
+            && ((offset >= self.table_offset && offset < self.table_offset + self.table_size())
+                || (offset >= self.pba_offset
+                    && offset < self.pba_offset + self.pba.len() as BusType * 4))
+    }
+
+    pub fn read(&self, offset: BusType, size: BusType) -> BusType {
+        if size != 32 {
+            return 0;
+        }
+
+        if offset >= self.pba_offset && offset < self.pba_offset + self.pba.len() as BusType * 4 {
+            return self.pba[((offset - self.pba_offset) / 4) as usize];
+        }
+
+        let offset = offset - self.table_offset;
+
+        self.table[(offset / PCI_MSIX_ENTRY_SIZE) as usize][((offset % 16) / 4) as usize]
+    }
+
+    pub fn write(&mut self, config: &PciConfig, offset: BusType, data: BusType, size: BusType) {
+        if size != 32
+            || offset < self.table_offset
+            || offset >= self.table_offset + self.table_size()
+        {
+            return;
+        }
+
+        let offset = offset - self.table_offset;
+        let vector = (offset / PCI_MSIX_ENTRY_SIZE) as usize;
+
+        self.table[vector][((offset % 16) / 4) as usize] = data;
+
+        // Unmasking a vector delivers the message that was held back while it was masked
+        if self.is_pending(vector) && !self.is_masked(config, vector) {
+            self.set_pending(vector, false);
+            self.send(vector);
+        }
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        (self.pba[vector / 32] & (1 << (vector % 32))) != 0
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        if pending {
+            self.pba[vector / 32] |= 1 << (vector % 32);
+        } else {
+            self.pba[vector / 32] &= !(1 << (vector % 32));
+        }
+    }
+
+    fn send(&self, vector: usize) {
+        let [addr_lo, addr_hi, data, _] = self.table[vector];
+
+        if addr_hi != 0 {
+            return;
+        }
+
+        let _ = bus::get_bus().store_nommu(addr_lo, data, 32);
+    }
+
+    pub fn notify(&mut self, config: &PciConfig, vector: u16) {
+        let vector = vector as usize;
+
+        if vector >= self.table.len() || !self.is_enabled(config) {
+            return;
+        }
+
+        if self.is_masked(config, vector) {
+            self.set_pending(vector, true);
+        } else {
+            self.send(vector);
+        }
+    }
+}
+
+pub trait PciDevice {
+    fn name(&self) -> String;
+    fn config(&self) -> &PciConfig;
+    fn config_mut(&mut self) -> &mut PciConfig;
+    fn bar_load(&mut self, bar: usize, offset: BusType, size: BusType) -> BusType;
+    fn bar_store(&mut self, bar: usize, offset: BusType, data: BusType, size: BusType);
+    fn irq_level(&self) -> bool;
+
+    fn config_write(&mut self, offset: usize, data: BusType, size: BusType) {
+        self.config_mut().write(offset, data, size);
+    }
+}
+
+struct PciHostBridge {
+    config: PciConfig,
+}
+
+impl PciHostBridge {
+    fn new() -> PciHostBridge {
+        PciHostBridge {
+            config: PciConfig::new(
+                PCI_VENDOR_ID_REDHAT,
+                PCI_DEVICE_ID_REDHAT_PCIE_HOST,
+                PCI_CLASS_BRIDGE_HOST,
+                0,
+            ),
+        }
+    }
+}
+
+impl PciDevice for PciHostBridge {
+    fn name(&self) -> String {
+        "host-bridge".to_string()
+    }
+
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_load(&mut self, _bar: usize, _offset: BusType, _size: BusType) -> BusType {
+        0
+    }
+
+    fn bar_store(&mut self, _bar: usize, _offset: BusType, _data: BusType, _size: BusType) {}
+
+    fn irq_level(&self) -> bool {
+        false
+    }
+}
+
+// Parses a --device argument, e.g. nvme,file=disk.img,readonly or virtio-rng,hotplug.
+// The returned flag tells whether the device is held back for hotplug
+pub fn parse_device(arg: &str) -> Result<(Box<dyn PciDevice>, bool), String> {
+    let mut parts = arg.split(',');
+    let kind = parts.next().unwrap_or("");
+
+    let mut file = None;
+    let mut readonly = false;
+    let mut hotplug = false;
+
+    for part in parts {
+        match part.split_once('=') {
+            Some(("file", path)) => file = Some(path.to_string()),
+            None if part == "readonly" => readonly = true,
+            None if part == "hotplug" => hotplug = true,
+            _ => return Err(format!("Invalid device option: {}", part)),
+        }
+    }
+
+    let device: Box<dyn PciDevice> = match kind {
+        "virtio-blk" => {
+            let file = file.ok_or("virtio-blk requires file=<path>".to_string())?;
+            let blk = virtio_blk::VirtioBlk::new(&file, readonly)?;
+
+            Box::new(virtio::VirtioPci::new(Box::new(blk)))
+        }
+        "nvme" => {
+            let file = file.ok_or("nvme requires file=<path>".to_string())?;
+
+            Box::new(nvme::Nvme::new(&file, readonly)?)
+        }
+        "virtio-rng" => Box::new(virtio::VirtioPci::new(Box::new(
+            virtio_rng::VirtioRng::new(),
+        ))),
+        _ => return Err(format!("Invalid device: {}", kind)),
+    };
+
+    Ok((device, hotplug))
+}
+
+// Hotplug requests come from the console thread, the host picks them up on its next
+// configuration access so the slot table is only ever touched by the exec thread
+pub fn request_hotplug() {
+    HOTPLUG_REQUESTS.fetch_add(1, Ordering::AcqRel);
+}
+
+pub fn request_unplug() {
+    UNPLUG_REQUESTS.fetch_add(1, Ordering::AcqRel);
+}
+
+pub struct PciHost {
+    slots: Vec<Option<Box<dyn PciDevice>>>,
+    hotplug_queue: Vec<Box<dyn PciDevice>>,
+    hotplugged: Vec<usize>,
+    mmio_next: BusType,
+}
+
+impl PciHost {
+    pub fn new(
+        devices: Vec<Box<dyn PciDevice>>,
+        hotplug_devices: Vec<Box<dyn PciDevice>>,
+    ) -> PciHost {
+        let mut host = PciHost {
+            slots: (0..PCI_SLOT_COUNT).map(|_| None).collect(),
+            hotplug_queue: hotplug_devices,
+            hotplugged: Vec::new(),
+            mmio_next: PCI_MMIO_ADDR,
+        };
+
+        host.plug(Box::new(PciHostBridge::new()));
+
+        for device in devices {
+            if host.plug(device).is_none() {
+                println!("pci: no free slot left");
+                std::process::exit(1);
+            }
+        }
+
+        host.hotplug_queue.reverse();
+
+        host
+    }
+
+    // BARs are assigned from a bump allocator, the guest is free to move them afterwards
+    fn plug(&mut self, mut device: Box<dyn PciDevice>) -> Option<usize> {
+        let slot = self.slots.iter().position(|slot| slot.is_none())?;
+
+        device.config_mut().set_u16(PCI_COMMAND, 0);
+
+        for bar in 0..PCI_NUM_BARS {
+            let size = device.config().get_bar_size(bar);
+
+            if size == 0 {
+                continue;
+            }
+
+            let addr = util::align_up(self.mmio_next as usize, size as usize) as BusType;
+
+            if addr as u64 + size as u64 > PCI_MMIO_ADDR as u64 + PCI_MMIO_SIZE as u64 {
+                println!("pci: out of MMIO space for {}", device.name());
+                return None;
+            }
+
+            device.config_mut().set_bar_addr(bar, addr);
+            self.mmio_next = addr + size;
+        }
+
+        self.slots[slot] = Some(device);
+
+        Some(slot)
+    }
+
+    fn process_hotplug(&mut self) {
+        while HOTPLUG_REQUESTS.load(Ordering::Acquire) != 0 {
+            HOTPLUG_REQUESTS.fetch_sub(1, Ordering::AcqRel);
+
+            let device = if let Some(device) = self.hotplug_queue.pop() {
+                device
+            } else {
+                println!("pci: no device left to hotplug");
+                continue;
+            };
+
+            let name = device.name();
+
+            if let Some(slot) = self.plug(device) {
+                self.hotplugged.push(slot);
+                println!("pci: hotplugged {} at 00:{:02x}.0", name, slot);
+            }
+        }
+
+        while UNPLUG_REQUESTS.load(Ordering::Acquire) != 0 {
+            UNPLUG_REQUESTS.fetch_sub(1, Ordering::AcqRel);
+
+            let slot = if let Some(slot) = self.hotplugged.pop() {
+                slot
+            } else {
+                println!("pci: no hotplugged device to remove");
+                continue;
+            };
+
+            // Unplugged devices go back to the queue so they can be plugged in again
+            let device = self.slots[slot].take().unwrap();
+
+            println!("pci: unplugged {} from 00:{:02x}.0", device.name(), slot);
+
+            self.hotplug_queue.push(device);
+        }
+    }
+
+    fn ecam_device(&mut self, offset: BusType) -> Option<(&mut Box<dyn PciDevice>, usize)> {
+        let bus = offset >> 20;
+        let slot = ((offset >> 15) & 0x1f) as usize;
+        let function = (offset >> 12) & 0x7;
+        let reg = (offset & 0xfff) as usize;
+
+        if bus != 0 || function != 0 {
+            return None;
+        }
+
+        self.slots[slot].as_mut().map(|device| (device, reg))
+    }
+
+    fn bar_device(&mut self, addr: BusType) -> Option<(&mut Box<dyn PciDevice>, usize, BusType)> {
+        for device in self.slots.iter_mut().flatten() {
+            let config = device.config();
+
+            if !config.memory_enabled() {
+                continue;
+            }
+
+            for bar in 0..PCI_NUM_BARS {
+                let size = config.get_bar_size(bar);
+                let base = config.get_bar_addr(bar);
+
+                if size != 0 && addr >= base && addr - base < size {
+                    return Some((device, bar, addr - base));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl BusDevice for PciHost {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, Exception> {
+        if addr < PCI_ECAM_ADDR + PCI_ECAM_SIZE {
+            self.process_hotplug();
+
+            return match self.ecam_device(addr - PCI_ECAM_ADDR) {
+                Some((device, reg)) => Ok(device.config().read(reg, size)),
+                None => Ok(BusType::MAX >> (32 - size)),
+            };
+        }
+
+        match self.bar_device(addr) {
+            Some((device, bar, offset)) => Ok(device.bar_load(bar, offset, size)),
+            None => Ok(0),
+        }
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), Exception> {
+        if addr < PCI_ECAM_ADDR + PCI_ECAM_SIZE {
+            self.process_hotplug();
+
+            if let Some((device, reg)) = self.ecam_device(addr - PCI_ECAM_ADDR) {
+                device.config_write(reg, data, size);
+            }
+
+            return Ok(());
+        }
+
+        if let Some((device, bar, offset)) = self.bar_device(addr) {
+            device.bar_store(bar, offset, data, size);
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        PCI_ECAM_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        PCI_MMIO_ADDR + PCI_MMIO_SIZE
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
+        Ok(std::ptr::null_mut())
+    }
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, cpu: &mut cpu::Cpu) -> Option<u32> {
+        for (slot, device) in self.slots.iter_mut().enumerate() {
+            let device = if let Some(device) = device {
+                device
+            } else {
+                continue;
+            };
+
+            let asserted = device.irq_level();
+
+            device.config_mut().set_interrupt_status(asserted);
+
+            let pin = device.config().get_interrupt_pin() as u32;
+
+            if !asserted || pin == 0 || device.config().intx_disabled() {
+                continue;
+            }
+
+            cpu.pending_interrupt_number =
+                PCI_INTX_BASE_IRQ + (slot as u32 + pin - 1) % PCI_INTX_COUNT;
+
+            return Some(csr::bits::SEIP_BIT as u32);
+        }
+
+        None
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let mut interrupt_map = Vec::new();
+
+        for slot in 0..PCI_INTX_COUNT {
+            for pin in 1..=PCI_INTX_COUNT {
+                let irq = PCI_INTX_BASE_IRQ + (slot + pin - 1) % PCI_INTX_COUNT;
+                let (parent, specifier) = aplic::irq_specifier(irq);
+
+                interrupt_map.extend_from_slice(&[slot << 11, 0, 0, pin, parent]);
+                interrupt_map.extend_from_slice(&specifier);
+            }
+        }
+
+        let pci_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("pci", PCI_ECAM_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "pci-host-ecam-generic")
+            .unwrap();
+        fdt.property_string("device_type", "pci").unwrap();
+        fdt.property_u32("#address-cells", 0x03).unwrap();
+        fdt.property_u32("#size-cells", 0x02).unwrap();
+        fdt.property_u32("#interrupt-cells", 0x01).unwrap();
+        fdt.property_u32("linux,pci-domain", 0x00).unwrap();
+        fdt.property_array_u32("bus-range", &[0x00, PCI_BUS_COUNT - 1])
+            .unwrap();
+        fdt.property_array_u32("reg", &[0x00, PCI_ECAM_ADDR, 0x00, PCI_ECAM_SIZE])
+            .unwrap();
+        fdt.property_array_u32(
+            "ranges",
+            &[
+                0x02000000,
+                0x00,
+                PCI_MMIO_ADDR,
+                0x00,
+                PCI_MMIO_ADDR,
+                0x00,
+                PCI_MMIO_SIZE,
+            ],
+        )
+        .unwrap();
+        fdt.property_array_u32("interrupt-map-mask", &[0x1800, 0x00, 0x00, 0x07])
+            .unwrap();
+        fdt.property_array_u32("interrupt-map", &interrupt_map)
+            .unwrap();
+
+        if imsic::is_enabled() {
+            fdt.property_u32("msi-parent", imsic::IMSIC_S_PHANDLE)
+                .unwrap();
+        }
+
+        fdt.property_null("dma-coherent").unwrap();
+        fdt.end_node(pci_node).unwrap();
+    }
+}
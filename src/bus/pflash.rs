@@ -0,0 +1,564 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bus::bus::*;
+use crate::cpu;
+use crate::util;
+use crate::xmem::{PageAllocator, PageState};
+
+pub const PFLASH_ADDR: BusType = 0x20000000;
+pub const PFLASH_MAX_SIZE: usize = util::size_mib(32);
+
+// A single x32 device on a 32-bit bus, so every command and query address is
+// scaled by 4 and values are returned zero extended
+const PFLASH_BANK_WIDTH: usize = 4;
+const PFLASH_SECTOR_SIZE: usize = util::size_kib(256);
+const PFLASH_WRITE_BUFFER_SIZE: usize = 64;
+
+const CFI_TABLE_SIZE: usize = 0x100;
+const CFI_QUERY_ADDR: usize = 0x55;
+
+const INTEL_MANUFACTURER_ID: u32 = 0x89;
+const INTEL_DEVICE_ID: u32 = 0x18;
+const AMD_MANUFACTURER_ID: u32 = 0x01;
+const AMD_DEVICE_ID: u32 = 0x7e;
+
+const AMD_UNLOCK_ADDR1: usize = 0x555;
+const AMD_UNLOCK_ADDR2: usize = 0x2aa;
+
+const STATUS_READY: u8 = 1 << 7;
+const STATUS_ERASE_ERROR: u8 = 1 << 5;
+const STATUS_PROGRAM_ERROR: u8 = 1 << 4;
+const STATUS_BLOCK_LOCKED: u8 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PflashCmdSet {
+    Intel,
+    Amd,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PflashConfig {
+    pub path: String,
+    pub readonly: bool,
+    pub cmdset: PflashCmdSet,
+}
+
+impl PflashConfig {
+    // Accepts <path>[,readonly][,cmdset=intel|amd]
+    pub fn parse(arg: &str) -> Result<PflashConfig, String> {
+        let mut opts = arg.split(',');
+
+        let path = opts.next().unwrap_or("");
+
+        if path.is_empty() {
+            return Err("pflash requires a file path".to_string());
+        }
+
+        let mut config = PflashConfig {
+            path: path.to_string(),
+            readonly: false,
+            cmdset: PflashCmdSet::Intel,
+        };
+
+        for opt in opts {
+            match opt {
+                "readonly" => config.readonly = true,
+                "cmdset=intel" => config.cmdset = PflashCmdSet::Intel,
+                "cmdset=amd" => config.cmdset = PflashCmdSet::Amd,
+                _ => return Err(format!("Invalid pflash option: {}", opt)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PflashMode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    Query,
+    Program,
+    WriteBufferCount,
+    WriteBufferData(usize),
+    WriteBufferConfirm,
+    EraseSetup,
+    LockSetup,
+}
+
+// Number of host pages mapped at PFLASH_ADDR, the mapping is kept across reboots
+static SHADOW_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// Code executed in place is translated from the array like any other page, which
+// marks the backing host page as read-execute. So the flash gets a host mapping at
+// its guest address that mirrors the array, and it is left inaccessible otherwise
+// so that loads and stores keep reaching the device
+fn init_shadow_once(npages: usize) {
+    let mapped = SHADOW_PAGES.load(Ordering::Acquire);
+
+    if mapped < npages {
+        PageAllocator::allocate_pages_at(PFLASH_ADDR as usize, npages).unwrap();
+
+        SHADOW_PAGES.store(npages, Ordering::Release);
+    }
+}
+
+pub struct Pflash {
+    file: File,
+    readonly: bool,
+    cmdset: PflashCmdSet,
+    data: Vec<u8>,
+    cfi_table: [u8; CFI_TABLE_SIZE],
+    mode: PflashMode,
+    status: u8,
+    amd_cycle: u8,
+    amd_erase_armed: bool,
+}
+
+impl Pflash {
+    pub fn new(config: &PflashConfig) -> Result<Pflash, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!config.readonly)
+            .open(&config.path)
+            .map_err(|err| format!("Failed to open pflash image {}: {}", config.path, err))?;
+
+        let mut data = Vec::new();
+
+        file.read_to_end(&mut data)
+            .map_err(|err| format!("Failed to read pflash image {}: {}", config.path, err))?;
+
+        if data.len() > PFLASH_MAX_SIZE {
+            return Err(format!(
+                "pflash image {} is larger than {} MiB",
+                config.path,
+                PFLASH_MAX_SIZE / util::size_mib(1)
+            ));
+        }
+
+        // Erased flash reads as all ones, the image is padded up to a whole sector
+        let size = util::align_up(data.len().max(1), PFLASH_SECTOR_SIZE);
+
+        data.resize(size, 0xff);
+
+        let mut pflash = Pflash {
+            file,
+            readonly: config.readonly,
+            cmdset: config.cmdset,
+            data,
+            cfi_table: [0; CFI_TABLE_SIZE],
+            mode: PflashMode::ReadArray,
+            status: STATUS_READY,
+            amd_cycle: 0,
+            amd_erase_armed: false,
+        };
+
+        pflash.build_cfi_table();
+
+        init_shadow_once(size / PageAllocator::get_page_size());
+
+        for page in (0..size).step_by(PageAllocator::get_page_size()) {
+            pflash.write_shadow_page(page, PageState::Invalid);
+        }
+
+        Ok(pflash)
+    }
+
+    fn build_cfi_table(&mut self) {
+        let table = &mut self.cfi_table;
+        let sectors = self.data.len() / PFLASH_SECTOR_SIZE;
+
+        table[0x10] = b'Q';
+        table[0x11] = b'R';
+        table[0x12] = b'Y';
+
+        // Primary vendor command set and its extended query table
+        let (cmdset, ext_addr) = match self.cmdset {
+            PflashCmdSet::Intel => (0x0001, 0x31),
+            PflashCmdSet::Amd => (0x0002, 0x40),
+        };
+
+        table[0x13] = cmdset as u8;
+        table[0x15] = ext_addr;
+
+        // Voltages and typical/maximum timeouts
+        table[0x1b] = 0x45;
+        table[0x1c] = 0x55;
+        table[0x1f] = 0x07;
+        table[0x21] = 0x0a;
+        table[0x23] = 0x04;
+        table[0x25] = 0x04;
+
+        if self.cmdset == PflashCmdSet::Intel {
+            table[0x20] = 0x07;
+            table[0x24] = 0x04;
+        }
+
+        table[0x27] = self.data.len().trailing_zeros() as u8;
+
+        // x32 device interface
+        table[0x28] = 0x03;
+
+        if self.cmdset == PflashCmdSet::Intel {
+            table[0x2a] = PFLASH_WRITE_BUFFER_SIZE.trailing_zeros() as u8;
+        }
+
+        // One uniform erase block region
+        table[0x2c] = 0x01;
+        table[0x2d] = (sectors - 1) as u8;
+        table[0x2e] = ((sectors - 1) >> 8) as u8;
+        table[0x2f] = ((PFLASH_SECTOR_SIZE >> 8) & 0xff) as u8;
+        table[0x30] = (PFLASH_SECTOR_SIZE >> 16) as u8;
+
+        let ext = ext_addr as usize;
+
+        table[ext] = b'P';
+        table[ext + 1] = b'R';
+        table[ext + 2] = b'I';
+
+        match self.cmdset {
+            PflashCmdSet::Intel => {
+                table[ext + 3] = b'1';
+                table[ext + 4] = b'0';
+                // Number of protection register fields
+                table[ext + 14] = 0x01;
+            }
+            PflashCmdSet::Amd => {
+                table[ext + 3] = b'1';
+                table[ext + 4] = b'3';
+                // Erase suspend supported for read only
+                table[ext + 6] = 0x01;
+                // Bottom boot, all sectors are uniform anyway
+                table[ext + 15] = 0x02;
+            }
+        }
+    }
+
+    fn write_shadow_page(&self, page: usize, state: PageState) {
+        let addr = (PFLASH_ADDR as usize + page) as *mut u8;
+
+        PageAllocator::mark_page(addr, 1, PageState::ReadWrite).unwrap();
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.data.as_ptr().add(page),
+                addr,
+                PageAllocator::get_page_size(),
+            );
+        }
+
+        PageAllocator::mark_page(addr, 1, state).unwrap();
+    }
+
+    // Pages holding translated code go back to the state the JIT left them in
+    fn sync_shadow(&self, offset: usize, len: usize) {
+        let page_size = PageAllocator::get_page_size();
+        let cpu = cpu::get_cpu();
+
+        let mut page = offset & !(page_size - 1);
+
+        while page < offset + len {
+            let state = cpu
+                .gpfn_state
+                .get_gpfn_state(PFLASH_ADDR + page as BusType)
+                .map_or(PageState::Invalid, |state| state.get_state());
+
+            self.write_shadow_page(page, state);
+
+            page += page_size;
+        }
+    }
+
+    // Programs and erases write through to the backing file right away
+    fn commit(&mut self, offset: usize, len: usize) {
+        self.sync_shadow(offset, len);
+
+        let result = self
+            .file
+            .seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(&self.data[offset..offset + len]));
+
+        if let Err(err) = result {
+            println!("Failed to write back pflash image: {}", err);
+        }
+    }
+
+    // Programming can only clear bits, setting them back takes an erase
+    fn program(&mut self, offset: usize, data: BusType, size: BusType) -> bool {
+        if self.readonly {
+            return false;
+        }
+
+        let len = (size / 8) as usize;
+
+        if offset + len > self.data.len() {
+            return false;
+        }
+
+        let bytes = data.to_le_bytes();
+
+        for (cell, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+
+        self.commit(offset, len);
+
+        true
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> bool {
+        if self.readonly {
+            return false;
+        }
+
+        self.data[offset..offset + len].fill(0xff);
+
+        self.commit(offset, len);
+
+        true
+    }
+
+    fn erase_sector(&mut self, offset: usize) -> bool {
+        let sector = offset & !(PFLASH_SECTOR_SIZE - 1);
+
+        self.erase(sector, PFLASH_SECTOR_SIZE)
+    }
+
+    fn read_array(&self, offset: usize, size: BusType) -> BusType {
+        let mut bytes = [0u8; 4];
+        let len = (size / 8) as usize;
+
+        bytes[..len].copy_from_slice(&self.data[offset..offset + len]);
+
+        BusType::from_le_bytes(bytes)
+    }
+
+    // Only the low address lines are decoded, so the table shows up in every block
+    fn read_query(&self, offset: usize) -> BusType {
+        let index = (offset / PFLASH_BANK_WIDTH) & 0xff;
+
+        if index < CFI_TABLE_SIZE {
+            self.cfi_table[index] as BusType
+        } else {
+            0
+        }
+    }
+
+    fn read_id(&self, offset: usize) -> BusType {
+        let (manufacturer, device) = match self.cmdset {
+            PflashCmdSet::Intel => (INTEL_MANUFACTURER_ID, INTEL_DEVICE_ID),
+            PflashCmdSet::Amd => (AMD_MANUFACTURER_ID, AMD_DEVICE_ID),
+        };
+
+        // Identifier codes repeat at the start of every block, the block
+        // lock status (offset 2) always reads as unlocked
+        match (offset % PFLASH_SECTOR_SIZE) / PFLASH_BANK_WIDTH {
+            0 => manufacturer,
+            1 => device,
+            _ => 0,
+        }
+    }
+
+    fn intel_store(&mut self, offset: usize, data: BusType, size: BusType) {
+        let cmd = data as u8;
+
+        match self.mode {
+            PflashMode::Program => {
+                if !self.program(offset, data, size) {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_BLOCK_LOCKED;
+                }
+
+                self.mode = PflashMode::ReadStatus;
+            }
+            PflashMode::WriteBufferCount => {
+                let words = (data & 0xff) as usize + 1;
+
+                if words * PFLASH_BANK_WIDTH > PFLASH_WRITE_BUFFER_SIZE {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                    self.mode = PflashMode::ReadStatus;
+                } else {
+                    self.mode = PflashMode::WriteBufferData(words);
+                }
+            }
+            PflashMode::WriteBufferData(remaining) => {
+                // Words go straight to the array, confirming only ends the sequence
+                if !self.program(offset, data, size) {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_BLOCK_LOCKED;
+                }
+
+                self.mode = if remaining > 1 {
+                    PflashMode::WriteBufferData(remaining - 1)
+                } else {
+                    PflashMode::WriteBufferConfirm
+                };
+            }
+            PflashMode::WriteBufferConfirm => {
+                if cmd != 0xd0 {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                }
+
+                self.mode = PflashMode::ReadStatus;
+            }
+            PflashMode::EraseSetup => {
+                if cmd != 0xd0 {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                } else if !self.erase_sector(offset) {
+                    self.status |= STATUS_ERASE_ERROR | STATUS_BLOCK_LOCKED;
+                }
+
+                self.mode = PflashMode::ReadStatus;
+            }
+            PflashMode::LockSetup => {
+                // Block locking is accepted but has no effect
+                self.mode = PflashMode::ReadStatus;
+            }
+            _ => match cmd {
+                0xff => self.mode = PflashMode::ReadArray,
+                0x90 => self.mode = PflashMode::ReadId,
+                0x98 => self.mode = PflashMode::Query,
+                0x70 => self.mode = PflashMode::ReadStatus,
+                0x50 => self.status = STATUS_READY,
+                0x10 | 0x40 => self.mode = PflashMode::Program,
+                0xe8 => self.mode = PflashMode::WriteBufferCount,
+                0x20 => self.mode = PflashMode::EraseSetup,
+                0x60 => self.mode = PflashMode::LockSetup,
+                // Suspend and resume, nothing is ever in progress
+                0xb0 | 0xd0 => self.mode = PflashMode::ReadStatus,
+                _ => self.mode = PflashMode::ReadArray,
+            },
+        }
+    }
+
+    fn amd_store(&mut self, offset: usize, data: BusType, size: BusType) {
+        let cmd = data as u8;
+        // Unlock cycles only decode the low 11 address lines
+        let index = (offset / PFLASH_BANK_WIDTH) & 0x7ff;
+
+        if self.mode == PflashMode::Program {
+            self.program(offset, data, size);
+            self.mode = PflashMode::ReadArray;
+
+            return;
+        }
+
+        if cmd == 0xf0 {
+            self.mode = PflashMode::ReadArray;
+            self.amd_cycle = 0;
+            self.amd_erase_armed = false;
+
+            return;
+        }
+
+        if cmd == 0x98 && index == CFI_QUERY_ADDR && self.amd_cycle == 0 {
+            self.mode = PflashMode::Query;
+
+            return;
+        }
+
+        match self.amd_cycle {
+            0 if index == AMD_UNLOCK_ADDR1 && cmd == 0xaa => self.amd_cycle = 1,
+            1 if index == AMD_UNLOCK_ADDR2 && cmd == 0x55 => self.amd_cycle = 2,
+            2 if self.amd_erase_armed => {
+                self.amd_cycle = 0;
+                self.amd_erase_armed = false;
+
+                match cmd {
+                    0x30 => {
+                        self.erase_sector(offset);
+                    }
+                    0x10 if index == AMD_UNLOCK_ADDR1 => {
+                        self.erase(0, self.data.len());
+                    }
+                    _ => {}
+                }
+
+                self.mode = PflashMode::ReadArray;
+            }
+            2 if index == AMD_UNLOCK_ADDR1 => {
+                self.amd_cycle = 0;
+
+                match cmd {
+                    0xa0 => self.mode = PflashMode::Program,
+                    0x90 => self.mode = PflashMode::ReadId,
+                    0x80 => self.amd_erase_armed = true,
+                    _ => {}
+                }
+            }
+            _ => self.amd_cycle = 0,
+        }
+    }
+}
+
+impl BusDevice for Pflash {
+    fn load(&mut self, addr: BusType, size: BusType) -> Result<BusType, cpu::Exception> {
+        let offset = (addr - PFLASH_ADDR) as usize;
+
+        if offset + (size / 8) as usize > self.data.len() {
+            return Err(cpu::Exception::LoadAccessFault(addr));
+        }
+
+        // Pages the CPU already executes from are read through the shadow mapping,
+        // those keep returning array data while the device is in a command mode
+        let data = match self.mode {
+            PflashMode::ReadArray => self.read_array(offset, size),
+            PflashMode::Program if self.cmdset == PflashCmdSet::Amd => {
+                self.read_array(offset, size)
+            }
+            PflashMode::ReadId => self.read_id(offset),
+            PflashMode::Query => self.read_query(offset),
+            _ => self.status as BusType,
+        };
+
+        Ok(data)
+    }
+
+    fn store(&mut self, addr: BusType, data: BusType, size: BusType) -> Result<(), cpu::Exception> {
+        let offset = (addr - PFLASH_ADDR) as usize;
+
+        if offset + (size / 8) as usize > self.data.len() {
+            return Err(cpu::Exception::StoreAccessFault(addr));
+        }
+
+        match self.cmdset {
+            PflashCmdSet::Intel => self.intel_store(offset, data, size),
+            PflashCmdSet::Amd => self.amd_store(offset, data, size),
+        }
+
+        Ok(())
+    }
+
+    fn get_begin_addr(&self) -> BusType {
+        PFLASH_ADDR
+    }
+
+    fn get_end_addr(&self) -> BusType {
+        PFLASH_ADDR + self.data.len() as BusType
+    }
+
+    fn tick_core_local(&mut self) {}
+
+    fn tick_from_main_thread(&mut self) {}
+
+    fn tick_async(&mut self, _cpu: &mut cpu::Cpu) -> Option<u32> {
+        None
+    }
+
+    fn get_ptr(&mut self, addr: BusType) -> Result<*mut u8, cpu::Exception> {
+        Err(cpu::Exception::LoadAccessFault(addr))
+    }
+
+    fn describe_fdt(&self, fdt: &mut vm_fdt::FdtWriter) {
+        let flash_node = fdt
+            .begin_node(&util::fdt_node_addr_helper("flash", PFLASH_ADDR))
+            .unwrap();
+        fdt.property_string("compatible", "cfi-flash").unwrap();
+        fdt.property_array_u32("reg", &[0x0, PFLASH_ADDR, 0x0, self.data.len() as u32])
+            .unwrap();
+        fdt.property_u32("bank-width", PFLASH_BANK_WIDTH as u32)
+            .unwrap();
+        fdt.end_node(flash_node).unwrap();
+    }
+}
@@ -44,15 +44,15 @@ impl Plic {
     }
 
     pub fn update_pending(&mut self, irq: u64) {
-        let index = irq / WORD_SIZE as u64;
-        self.pending[index as usize] = self.pending[index as usize] | (1 << irq);
+        let index = irq / 32;
+        self.pending[index as usize] |= 1 << (irq % 32);
 
         self.update_claim(irq);
     }
 
     fn clear_pending(&mut self, irq: u64) {
-        let index = irq / WORD_SIZE as u64;
-        self.pending[index as usize] = self.pending[index as usize] & !(1 << irq);
+        let index = irq / 32;
+        self.pending[index as usize] &= !(1 << (irq % 32));
 
         self.update_claim(0);
     }
@@ -180,3 +180,24 @@ impl BusDevice for Plic {
         fdt.end_node(plic_node).unwrap();
     }
 }
+
+#[test]
+fn test_pending_above_first_word() {
+    let mut plic = Plic::new();
+    let irq = 34;
+    let claim = THRESHOLD_AND_CLAIM + CONTEXT_OFFSET + 4;
+
+    // Supervisor context enables start at word 32
+    plic.store(ENABLE + 32 * WORD_SIZE + WORD_SIZE, 1 << (irq % 32), 32)
+        .unwrap();
+    plic.update_pending(irq as u64);
+
+    assert_eq!(plic.load(PENDING, 32).unwrap(), 0);
+    assert_eq!(plic.load(PENDING + WORD_SIZE, 32).unwrap(), 1 << (irq % 32));
+    assert_eq!(plic.load(claim, 32).unwrap(), irq);
+
+    plic.store(claim, irq, 32).unwrap();
+
+    assert_eq!(plic.load(PENDING + WORD_SIZE, 32).unwrap(), 0);
+    assert_eq!(plic.load(claim, 32).unwrap(), 0);
+}
@@ -3,6 +3,70 @@ use crate::{bus::bus::*, xmem::PageAllocator};
 
 pub const RAMFB_BEGIN_ADDR: BusType = 0x1d380000;
 
+// struct RAMFBCfg written through fw_cfg, all fields are big endian
+pub const RAMFB_CFG_SIZE: usize = 28;
+
+pub const DRM_FORMAT_XRGB8888: u32 = 0x34325258;
+pub const DRM_FORMAT_ARGB8888: u32 = 0x34325241;
+pub const DRM_FORMAT_XBGR8888: u32 = 0x34324258;
+pub const DRM_FORMAT_ABGR8888: u32 = 0x34324241;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RamFBGuestConfig {
+    pub ptr: usize,
+    pub fourcc: u32,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+}
+
+static GUEST_CONFIG: std::sync::Mutex<Option<RamFBGuestConfig>> = std::sync::Mutex::new(None);
+
+// Called when the guest writes etc/ramfb, the framebuffer then lives in guest RAM
+pub fn fw_cfg_write(data: &[u8]) {
+    let be_u32 = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    let addr = u64::from_be_bytes(data[0..8].try_into().unwrap());
+    let fourcc = be_u32(8);
+    let width = be_u32(16) as usize;
+    let height = be_u32(20) as usize;
+    let stride = match be_u32(24) as usize {
+        0 => width * 4,
+        stride => stride,
+    };
+
+    let supported = matches!(
+        fourcc,
+        DRM_FORMAT_XRGB8888 | DRM_FORMAT_ARGB8888 | DRM_FORMAT_XBGR8888 | DRM_FORMAT_ABGR8888
+    );
+
+    if !supported || width == 0 || height == 0 || stride < width * 4 || addr > BusType::MAX as u64 {
+        println!("ramfb: unsupported configuration from the guest");
+
+        return;
+    }
+
+    let fb = bus::get_bus().dma_slice(addr as BusType, stride * height);
+
+    if fb.is_err() {
+        println!("ramfb: framebuffer at {:#x} is outside of RAM", addr);
+
+        return;
+    }
+
+    *GUEST_CONFIG.lock().unwrap() = Some(RamFBGuestConfig {
+        ptr: fb.unwrap().as_ptr() as usize,
+        fourcc,
+        width,
+        height,
+        stride,
+    });
+}
+
+pub fn get_guest_config() -> Option<RamFBGuestConfig> {
+    *GUEST_CONFIG.lock().unwrap()
+}
+
 pub struct RamFB {
     pub mem: *mut u8,
     len: usize,
@@ -41,6 +105,8 @@ impl RamFB {
 
         init_ramfb_once(width, height, bpp);
 
+        *GUEST_CONFIG.lock().unwrap() = None;
+
         Self {
             mem: unsafe { RAMFB },
             len: fb_len,
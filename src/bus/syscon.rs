@@ -7,9 +7,13 @@ use crate::{
 pub const SYSCON_ADDR: BusType = 0x11100000;
 pub const SYSCON_SIZE: BusType = 0x1000;
 
+pub const SYSCON_FAIL: BusType = 0x3333;
 pub const SYSCON_POWEROFF: BusType = 0x5555;
 pub const SYSCON_REBOOT: BusType = 0x7777;
 
+const SYSCON_CODE_MASK: BusType = 0xffff;
+const SYSCON_EXIT_STATUS_SHIFT: BusType = 16;
+
 pub struct Syscon;
 
 impl Syscon {
@@ -36,6 +40,28 @@ pub fn should_reboot() -> bool {
     unsafe { SHOULD_REBOOT.load(std::sync::atomic::Ordering::Acquire) }
 }
 
+static SHOULD_POWEROFF: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+static EXIT_CODE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+pub fn request_poweroff(exit_code: i32) {
+    EXIT_CODE.store(exit_code, std::sync::atomic::Ordering::Release);
+    SHOULD_POWEROFF.store(true, std::sync::atomic::Ordering::Release);
+}
+
+pub fn should_poweroff() -> bool {
+    SHOULD_POWEROFF.load(std::sync::atomic::Ordering::Acquire)
+}
+
+pub fn get_exit_code() -> i32 {
+    EXIT_CODE.load(std::sync::atomic::Ordering::Acquire)
+}
+
+// Either a reboot or a poweroff was requested, the emulator should wind down
+// its threads and return to main
+pub fn should_stop() -> bool {
+    should_reboot() || should_poweroff()
+}
+
 impl BusDevice for Syscon {
     fn load(&mut self, _addr: BusType, _size: BusType) -> Result<BusType, cpu::Exception> {
         Ok(0)
@@ -43,16 +69,35 @@ impl BusDevice for Syscon {
 
     fn store(
         &mut self,
-        _addr: BusType,
+        addr: BusType,
         data: BusType,
         _size: BusType,
     ) -> Result<(), cpu::Exception> {
-        if data == SYSCON_POWEROFF {
-            std::process::exit(0);
-        } else if data == SYSCON_REBOOT {
-            set_should_reboot();
-            cpu::get_cpu().exception = cpu::Exception::Reboot;
-            ReturnableImpl::throw();
+        if addr != SYSCON_ADDR {
+            return Ok(());
+        }
+
+        // Same layout as the sifive,test0 finisher: the command lives in the lower
+        // 16 bits and FAIL carries the exit status in the upper 16 bits
+        let exit_status = (data >> SYSCON_EXIT_STATUS_SHIFT) as i32;
+
+        match data & SYSCON_CODE_MASK {
+            SYSCON_FAIL => {
+                request_poweroff(exit_status);
+                cpu::get_cpu().exception = cpu::Exception::Poweroff;
+                ReturnableImpl::throw();
+            }
+            SYSCON_POWEROFF => {
+                request_poweroff(0);
+                cpu::get_cpu().exception = cpu::Exception::Poweroff;
+                ReturnableImpl::throw();
+            }
+            SYSCON_REBOOT => {
+                set_should_reboot();
+                cpu::get_cpu().exception = cpu::Exception::Reboot;
+                ReturnableImpl::throw();
+            }
+            _ => {}
         }
 
         Ok(())
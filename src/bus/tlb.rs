@@ -1,16 +1,32 @@
 use crate::bus::bus::*;
+use crate::cpu::csr::MppMode;
 use crate::cpu::CpuReg;
-use crate::frontend::exec_core::{RV_PAGE_OFFSET_MASK, RV_PAGE_SHIFT};
+use crate::frontend::exec_core::{RV_PAGE_MASK, RV_PAGE_OFFSET_MASK, RV_PAGE_SHIFT};
 
-const TLB_ENTRIES: usize = 256;
+pub const TLB_ENTRIES: usize = 256;
 const MAX_ASID_ENTRIES: usize = 16;
-
+// Sv32 satp carries a 9 bit ASID
+pub const ASID_COUNT: usize = 1 << 9;
+const NO_SET: u8 = u8::MAX;
+
+// Permission flags kept in the low bits of the page aligned physical address
+pub const TLB_FLAG_WRITE: BusType = 1;
+pub const TLB_FLAG_EXECUTE: BusType = 2;
+pub const TLB_FLAG_READ: BusType = 4;
+// The mapping came from a global PTE and is valid in every address space
+pub const TLB_FLAG_GLOBAL: BusType = 8;
+
+// The layout is read by the inline TLB probe the JIT emits
+#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct TlbEntry {
     pub virt: BusType,
     pub phys: BusType,
 }
 
+const _: () = assert!(std::mem::size_of::<TlbEntry>() == 8);
+
+#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct TLBAsidEntry {
     tlb: [TlbEntry; TLB_ENTRIES],
@@ -21,8 +37,8 @@ macro_rules! tlb_fetch_load {
     ($addr:expr) => {
         let phys = get_current_tlb().get_phys_entry($addr as CpuReg) as usize;
 
-        if phys != 0 {
-            let phys = phys & !3;
+        if phys & TLB_FLAG_READ as usize != 0 {
+            let phys = phys & RV_PAGE_MASK;
 
             return (phys | ($addr & RV_PAGE_OFFSET_MASK)) as usize;
         }
@@ -34,54 +50,46 @@ macro_rules! tlb_fetch_store {
     ($addr:expr) => {
         let phys = get_current_tlb().get_phys_entry($addr as CpuReg) as usize;
 
-        let is_write = (phys & 1) != 0;
-        if phys != 0 && is_write {
-            let phys = phys & !3;
+        if phys & TLB_FLAG_WRITE as usize != 0 {
+            let phys = phys & RV_PAGE_MASK;
 
             return (phys | ($addr & RV_PAGE_OFFSET_MASK)) as usize;
         }
     };
 }
 
-pub fn tlb_fetch_instr(addr: BusType) -> Option<BusType> {
-    let phys = get_current_tlb().get_phys_entry(addr as CpuReg) as usize;
+fn tlb_fetch(addr: BusType, flag: BusType) -> Option<BusType> {
+    let mut phys = get_current_tlb().get_phys_entry(addr as CpuReg);
 
-    let is_exec = (phys & 2) != 0;
-    if phys != 0 && is_exec {
-        let phys = phys & !3;
+    if phys & flag == 0 && asid_allocator().translated {
+        // Global mappings filled under another ASID don't need a page walk
+        phys = asid_allocator().global.get_phys_entry(addr as CpuReg);
 
-        let offset = addr & (RV_PAGE_OFFSET_MASK as BusType);
-        return Some(phys as BusType | offset);
+        if phys & flag != 0 {
+            get_current_tlb().set_phys_entry(addr, phys);
+        }
     }
 
-    None
-}
-
-pub fn tlb_fetch_load(addr: BusType) -> Option<BusType> {
-    let phys = get_current_tlb().get_phys_entry(addr as CpuReg) as usize;
-
-    if phys != 0 {
-        let phys = phys & !3;
+    if phys & flag != 0 {
+        let phys = phys & RV_PAGE_MASK as BusType;
 
         let offset = addr & (RV_PAGE_OFFSET_MASK as BusType);
-        return Some(phys as BusType | offset);
+        return Some(phys | offset);
     }
 
     None
 }
 
-pub fn tlb_fetch_store(addr: BusType) -> Option<BusType> {
-    let phys = get_current_tlb().get_phys_entry(addr as CpuReg) as usize;
-
-    let is_write = (phys & 1) != 0;
-    if phys != 0 && is_write {
-        let phys = phys & !3;
+pub fn tlb_fetch_instr(addr: BusType) -> Option<BusType> {
+    tlb_fetch(addr, TLB_FLAG_EXECUTE)
+}
 
-        let offset = addr & (RV_PAGE_OFFSET_MASK as BusType);
-        return Some(phys as BusType | offset);
-    }
+pub fn tlb_fetch_load(addr: BusType) -> Option<BusType> {
+    tlb_fetch(addr, TLB_FLAG_READ)
+}
 
-    None
+pub fn tlb_fetch_store(addr: BusType) -> Option<BusType> {
+    tlb_fetch(addr, TLB_FLAG_WRITE)
 }
 
 impl TLBAsidEntry {
@@ -117,6 +125,24 @@ impl TLBAsidEntry {
             self.tlb[i] = TlbEntry { virt: 0, phys: 0 };
         }
     }
+
+    pub fn flush_non_global(&mut self) {
+        for entry in self.tlb.iter_mut() {
+            if entry.phys & TLB_FLAG_GLOBAL == 0 {
+                *entry = TlbEntry { virt: 0, phys: 0 };
+            }
+        }
+    }
+
+    pub fn flush_addr(&mut self, virt: BusType, keep_global: bool) {
+        let vpn = (virt >> RV_PAGE_SHIFT) as BusType;
+
+        let entry = &mut self.tlb[vpn as usize % TLB_ENTRIES];
+
+        if entry.virt == vpn && !(keep_global && entry.phys & TLB_FLAG_GLOBAL != 0) {
+            *entry = TlbEntry { virt: 0, phys: 0 };
+        }
+    }
 }
 
 static mut TLB: *mut TLBAsidEntry = std::ptr::null_mut();
@@ -125,70 +151,171 @@ pub fn get_current_tlb() -> &'static mut TLBAsidEntry {
     unsafe { &mut *TLB }
 }
 
+// Where the pointer to the current TLB lives, so JIT code can follow ASID switches
+pub fn current_tlb_ptr_addr() -> usize {
+    std::ptr::addr_of!(TLB) as usize
+}
+
+// Maps every ASID onto one of the MAX_ASID_ENTRIES TLB sets. When they run out,
+// the least recently used set is flushed and handed to the new ASID
 pub struct AsidAllocator {
-    asid: [usize; MAX_ASID_ENTRIES],
+    asid_set: [u8; ASID_COUNT],
+    owner: [Option<usize>; MAX_ASID_ENTRIES],
     lru: [usize; MAX_ASID_ENTRIES],
     tlb_cache: Vec<TLBAsidEntry>,
+    global: TLBAsidEntry,
+    // Identity mappings, one set per privilege mode, for accesses without translation
+    // that PMP still has to check
+    identity: Vec<TLBAsidEntry>,
+    current: usize,
+    translated: bool,
     counter: usize,
 }
 
 impl AsidAllocator {
     pub fn new() -> AsidAllocator {
         AsidAllocator {
-            asid: [0; MAX_ASID_ENTRIES],
+            asid_set: [NO_SET; ASID_COUNT],
+            owner: [None; MAX_ASID_ENTRIES],
             lru: [0; MAX_ASID_ENTRIES],
             tlb_cache: vec![TLBAsidEntry::new(); MAX_ASID_ENTRIES],
+            global: TLBAsidEntry::new(),
+            identity: vec![TLBAsidEntry::new(); 4],
+            current: 0,
+            translated: true,
             counter: 0,
         }
     }
 
+    fn recycle_set(&mut self) -> usize {
+        let index = match self.owner.iter().position(|owner| owner.is_none()) {
+            Some(index) => index,
+            None => (0..MAX_ASID_ENTRIES).min_by_key(|&i| self.lru[i]).unwrap(),
+        };
+
+        if let Some(old_asid) = self.owner[index].take() {
+            self.asid_set[old_asid] = NO_SET;
+            self.tlb_cache[index].flush();
+        }
+
+        index
+    }
+
     pub fn set_asid(&mut self, asid: usize) {
-        let mut empty_index: Option<usize> = None;
-        let mut lru_index = 0;
-
-        for i in 0..MAX_ASID_ENTRIES {
-            if self.asid[i] == asid {
-                self.lru[i] = self.counter;
-                self.counter += 1;
-                return;
-            }
+        let asid = asid % ASID_COUNT;
 
-            if self.asid[i] == 0 && empty_index.is_none() {
-                empty_index = Some(i);
-            }
+        let index = match self.asid_set[asid] {
+            NO_SET => {
+                let index = self.recycle_set();
 
-            if self.lru[i] < self.lru[lru_index] {
-                lru_index = i;
-            }
-        }
+                self.asid_set[asid] = index as u8;
+                self.owner[index] = Some(asid);
 
-        let target_index = if let Some(index) = empty_index {
-            index
-        } else {
-            lru_index
+                index
+            }
+            index => index as usize,
         };
 
-        self.asid[target_index] = asid;
-        self.lru[target_index] = self.counter;
+        self.lru[index] = self.counter;
         self.counter += 1;
+
+        self.current = index;
+        self.select_asid();
+    }
+
+    pub fn select_asid(&mut self) {
+        self.translated = true;
+
         unsafe {
-            TLB = &mut self.tlb_cache[target_index] as *mut TLBAsidEntry;
+            TLB = &mut self.tlb_cache[self.current] as *mut TLBAsidEntry;
+        }
+    }
+
+    pub fn select_identity(&mut self, mode: MppMode) {
+        self.translated = false;
+
+        unsafe {
+            TLB = &mut self.identity[mode as usize] as *mut TLBAsidEntry;
+        }
+    }
+
+    // sfence.vma, a None operand stands for x0. Global entries are only
+    // dropped by fences that don't name an ASID
+    pub fn fence(&mut self, virt: Option<BusType>, asid: Option<usize>) {
+        match (virt, asid) {
+            (None, None) => {
+                self.tlb_cache.iter_mut().for_each(|tlb| tlb.flush());
+                self.identity.iter_mut().for_each(|tlb| tlb.flush());
+                self.global.flush();
+            }
+            (Some(virt), None) => {
+                self.tlb_cache
+                    .iter_mut()
+                    .for_each(|tlb| tlb.flush_addr(virt, false));
+                self.global.flush_addr(virt, false);
+            }
+            (virt, Some(asid)) => {
+                let index = self.asid_set[asid % ASID_COUNT];
+
+                if index == NO_SET {
+                    return;
+                }
+
+                let tlb = &mut self.tlb_cache[index as usize];
+
+                match virt {
+                    Some(virt) => tlb.flush_addr(virt, true),
+                    None => tlb.flush_non_global(),
+                }
+            }
         }
     }
 }
 
 static mut ASID_ALLOCATOR: *mut AsidAllocator = std::ptr::null_mut();
 
+fn asid_allocator() -> &'static mut AsidAllocator {
+    unsafe { &mut *ASID_ALLOCATOR }
+}
+
 pub fn asid_tlb_init() {
     unsafe {
         ASID_ALLOCATOR = Box::into_raw(Box::new(AsidAllocator::new()));
-        TLB = &mut (*ASID_ALLOCATOR).tlb_cache[0];
     }
+
+    asid_tlb_set(0);
 }
 
 pub fn asid_tlb_set(asid: usize) {
-    unsafe {
-        (*ASID_ALLOCATOR).set_asid(asid);
+    asid_allocator().set_asid(asid);
+}
+
+pub fn asid_tlb_fence(virt: Option<BusType>, asid: Option<usize>) {
+    asid_allocator().fence(virt, asid);
+}
+
+pub fn set_global_entry(virt: BusType, phys: BusType) {
+    asid_allocator().global.set_phys_entry(virt, phys);
+}
+
+// The set of the current ASID, whichever set the JIT probes
+pub fn get_asid_tlb() -> &'static mut TLBAsidEntry {
+    let allocator = asid_allocator();
+
+    &mut allocator.tlb_cache[allocator.current]
+}
+
+pub fn get_identity_tlb(mode: MppMode) -> &'static mut TLBAsidEntry {
+    &mut asid_allocator().identity[mode as usize]
+}
+
+// Points the JIT at the ASID set when loads and stores are translated, otherwise at
+// the identity set of the privilege mode they are made in
+pub fn select_tlb(translated: bool, mode: MppMode) {
+    if translated {
+        asid_allocator().select_asid();
+    } else {
+        asid_allocator().select_identity(mode);
     }
 }
 
@@ -0,0 +1,619 @@
+use crate::bus::pci::*;
+use crate::bus::*;
+use crate::cpu::*;
+
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x1100;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTIO_STATUS_FEATURES_OK: u8 = 0x08;
+const VIRTIO_STATUS_DRIVER_OK: u8 = 0x04;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const VIRTIO_ISR_QUEUE: u8 = 0x1;
+const VIRTIO_ISR_CONFIG: u8 = 0x2;
+
+// BAR 0 holds the virtio structures, BAR 1 the MSI-X table and PBA
+const VIRTIO_BAR: usize = 0;
+const VIRTIO_BAR_SIZE: BusType = 0x4000;
+const COMMON_CFG_OFFSET: BusType = 0x0000;
+const COMMON_CFG_SIZE: BusType = 0x38;
+const ISR_CFG_OFFSET: BusType = 0x1000;
+const ISR_CFG_SIZE: BusType = 0x4;
+const DEVICE_CFG_OFFSET: BusType = 0x2000;
+const DEVICE_CFG_SIZE: BusType = 0x1000;
+const NOTIFY_CFG_OFFSET: BusType = 0x3000;
+const NOTIFY_CFG_SIZE: BusType = 0x1000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+const MSIX_BAR: usize = 1;
+const MSIX_BAR_SIZE: BusType = 0x1000;
+const MSIX_TABLE_OFFSET: BusType = 0x000;
+const MSIX_PBA_OFFSET: BusType = 0x800;
+
+// struct virtio_pci_common_cfg
+const DEVICE_FEATURE_SELECT: BusType = 0x00;
+const DEVICE_FEATURE: BusType = 0x04;
+const DRIVER_FEATURE_SELECT: BusType = 0x08;
+const DRIVER_FEATURE: BusType = 0x0c;
+const CONFIG_MSIX_VECTOR: BusType = 0x10;
+const NUM_QUEUES: BusType = 0x12;
+const DEVICE_STATUS: BusType = 0x14;
+const CONFIG_GENERATION: BusType = 0x15;
+const QUEUE_SELECT: BusType = 0x16;
+const QUEUE_SIZE: BusType = 0x18;
+const QUEUE_MSIX_VECTOR: BusType = 0x1a;
+const QUEUE_ENABLE: BusType = 0x1c;
+const QUEUE_NOTIFY_OFF: BusType = 0x1e;
+const QUEUE_DESC_LO: BusType = 0x20;
+const QUEUE_DESC_HI: BusType = 0x24;
+const QUEUE_DRIVER_LO: BusType = 0x28;
+const QUEUE_DRIVER_HI: BusType = 0x2c;
+const QUEUE_DEVICE_LO: BusType = 0x30;
+const QUEUE_DEVICE_HI: BusType = 0x34;
+
+pub const VIRTQ_MAX_SIZE: u16 = 256;
+
+const VIRTQ_DESC_SIZE: u64 = 16;
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+pub trait VirtioDevice {
+    fn name(&self) -> String;
+    fn device_id(&self) -> u16;
+    fn class_code(&self) -> u32;
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    fn read_config(&self, offset: BusType, size: BusType) -> BusType;
+    // Handles one request and returns the number of bytes written into the chain
+    fn process(&mut self, queue: usize, chain: &DescChain) -> u32;
+
+    fn write_config(&mut self, _offset: BusType, _data: BusType, _size: BusType) {}
+    fn reset(&mut self) {}
+}
+
+// Little-endian access into a device configuration structure
+pub fn read_config_bytes(bytes: &[u8], offset: BusType, size: BusType) -> BusType {
+    let offset = offset as usize;
+    let mut data = 0;
+
+    for i in 0..(size / 8) as usize {
+        data |= (*bytes.get(offset + i).unwrap_or(&0) as BusType) << (i * 8);
+    }
+
+    data
+}
+
+fn dma_u64_addr(addr: u64) -> Result<BusType, Exception> {
+    if addr > BusType::MAX as u64 {
+        return Err(Exception::LoadAccessFault(addr as BusType));
+    }
+
+    Ok(addr as BusType)
+}
+
+fn dma_read_u16(addr: u64) -> Result<u16, Exception> {
+    let mut data = [0u8; 2];
+
+    bus::get_bus().dma_read(dma_u64_addr(addr)?, &mut data)?;
+
+    Ok(u16::from_le_bytes(data))
+}
+
+// A descriptor chain split into the parts the device reads and the parts it writes
+pub struct DescChain {
+    pub head: u16,
+    readable: Vec<(BusType, u32)>,
+    writable: Vec<(BusType, u32)>,
+}
+
+impl DescChain {
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    // Both return the number of bytes copied, which stops short at the end of the chain
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, Exception> {
+        let bus = bus::get_bus();
+
+        Self::for_each_segment(&self.readable, offset, buf.len(), |addr, range| {
+            bus.dma_read(addr, &mut buf[range])
+        })
+    }
+
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, Exception> {
+        let bus = bus::get_bus();
+
+        Self::for_each_segment(&self.writable, offset, buf.len(), |addr, range| {
+            bus.dma_write(addr, &buf[range])
+        })
+    }
+
+    fn for_each_segment<F>(
+        segments: &[(BusType, u32)],
+        mut offset: usize,
+        len: usize,
+        mut f: F,
+    ) -> Result<usize, Exception>
+    where
+        F: FnMut(BusType, std::ops::Range<usize>) -> Result<(), Exception>,
+    {
+        let mut done = 0;
+
+        for (addr, seg_len) in segments {
+            let seg_len = *seg_len as usize;
+
+            if offset >= seg_len {
+                offset -= seg_len;
+                continue;
+            }
+
+            let count = (seg_len - offset).min(len - done);
+
+            f(addr + offset as BusType, done..done + count)?;
+
+            done += count;
+            offset = 0;
+
+            if done == len {
+                break;
+            }
+        }
+
+        Ok(done)
+    }
+}
+
+// Split virtqueue living in guest memory
+pub struct Virtqueue {
+    size: u16,
+    ready: bool,
+    msix_vector: u16,
+    desc_addr: u64,
+    driver_addr: u64,
+    device_addr: u64,
+    last_avail_idx: u16,
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    fn new() -> Virtqueue {
+        Virtqueue {
+            size: VIRTQ_MAX_SIZE,
+            ready: false,
+            msix_vector: PCI_MSIX_NO_VECTOR,
+            desc_addr: 0,
+            driver_addr: 0,
+            device_addr: 0,
+            last_avail_idx: 0,
+            used_idx: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Result<Option<DescChain>, Exception> {
+        let avail_idx = dma_read_u16(self.driver_addr + 2)?;
+
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+
+        let ring_offset = 4 + (self.last_avail_idx % self.size) as u64 * 2;
+        let head = dma_read_u16(self.driver_addr + ring_offset)?;
+
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let mut chain = DescChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        let mut index = head;
+
+        // A chain can't be longer than the ring, anything else is a loop
+        for _ in 0..self.size {
+            let mut desc = [0u8; VIRTQ_DESC_SIZE as usize];
+            let desc_addr = self.desc_addr + (index % self.size) as u64 * VIRTQ_DESC_SIZE;
+
+            bus::get_bus().dma_read(dma_u64_addr(desc_addr)?, &mut desc)?;
+
+            let addr = dma_u64_addr(u64::from_le_bytes(desc[0..8].try_into().unwrap()))?;
+            let len = u32::from_le_bytes(desc[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+
+            if (flags & VIRTQ_DESC_F_WRITE) != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                chain.readable.push((addr, len));
+            }
+
+            if (flags & VIRTQ_DESC_F_NEXT) == 0 {
+                break;
+            }
+
+            index = next;
+        }
+
+        Ok(Some(chain))
+    }
+
+    fn push(&mut self, head: u16, len: u32) -> Result<(), Exception> {
+        let bus = bus::get_bus();
+
+        let elem_offset = 4 + (self.used_idx % self.size) as u64 * 8;
+        let mut elem = [0u8; 8];
+
+        elem[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+
+        bus.dma_write(dma_u64_addr(self.device_addr + elem_offset)?, &elem)?;
+
+        self.used_idx = self.used_idx.wrapping_add(1);
+
+        bus.dma_write(
+            dma_u64_addr(self.device_addr + 2)?,
+            &self.used_idx.to_le_bytes(),
+        )
+    }
+
+    fn interrupts_suppressed(&self) -> bool {
+        dma_read_u16(self.driver_addr).unwrap_or(0) & VIRTQ_AVAIL_F_NO_INTERRUPT != 0
+    }
+}
+
+fn set_u64_half(value: &mut u64, data: BusType, high: bool) {
+    if high {
+        *value = (*value & 0xffffffff) | ((data as u64) << 32);
+    } else {
+        *value = (*value & !0xffffffff) | data as u64;
+    }
+}
+
+// Virtio 1.x PCI transport, the structures are found through vendor capabilities
+pub struct VirtioPci {
+    config: PciConfig,
+    msix: Msix,
+    device: Box<dyn VirtioDevice>,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    config_msix_vector: u16,
+    status: u8,
+    config_generation: u8,
+    isr: u8,
+    queue_select: u16,
+    queues: Vec<Virtqueue>,
+}
+
+impl VirtioPci {
+    pub fn new(device: Box<dyn VirtioDevice>) -> VirtioPci {
+        let mut config = PciConfig::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device.device_id(),
+            device.class_code(),
+            1,
+        );
+
+        config.set_subsystem(VIRTIO_PCI_VENDOR_ID, VIRTIO_PCI_SUBSYSTEM_ID);
+        config.set_interrupt_pin(1);
+        config.add_bar(VIRTIO_BAR, VIRTIO_BAR_SIZE);
+        config.add_bar(MSIX_BAR, MSIX_BAR_SIZE);
+
+        let caps = [
+            (
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CFG_OFFSET,
+                COMMON_CFG_SIZE,
+            ),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG_OFFSET, ISR_CFG_SIZE),
+            (
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_CFG_OFFSET,
+                DEVICE_CFG_SIZE,
+            ),
+            (
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                NOTIFY_CFG_OFFSET,
+                NOTIFY_CFG_SIZE,
+            ),
+        ];
+
+        for (cfg_type, offset, length) in caps {
+            let cap_len = if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                20
+            } else {
+                16
+            };
+
+            let cap = config.add_capability(PCI_CAP_ID_VNDR, cap_len);
+
+            config.set_u8(cap + 2, cap_len as u8);
+            config.set_u8(cap + 3, cfg_type);
+            config.set_u8(cap + 4, VIRTIO_BAR as u8);
+            config.set_u32(cap + 8, offset);
+            config.set_u32(cap + 12, length);
+
+            if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                config.set_u32(cap + 16, NOTIFY_OFF_MULTIPLIER);
+            }
+        }
+
+        let num_queues = device.num_queues();
+
+        let msix = Msix::new(
+            &mut config,
+            num_queues as u16 + 1,
+            MSIX_BAR,
+            MSIX_TABLE_OFFSET,
+            MSIX_PBA_OFFSET,
+        );
+
+        VirtioPci {
+            config,
+            msix,
+            device,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            config_msix_vector: PCI_MSIX_NO_VECTOR,
+            status: 0,
+            config_generation: 0,
+            isr: 0,
+            queue_select: 0,
+            queues: (0..num_queues).map(|_| Virtqueue::new()).collect(),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.config_msix_vector = PCI_MSIX_NO_VECTOR;
+        self.status = 0;
+        self.isr = 0;
+        self.queue_select = 0;
+
+        for queue in self.queues.iter_mut() {
+            *queue = Virtqueue::new();
+        }
+
+        self.device.reset();
+    }
+
+    fn read_common(&self, offset: BusType) -> BusType {
+        let queue = self.queues.get(self.queue_select as usize);
+
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_feature_select,
+            DEVICE_FEATURE => match self.device_feature_select {
+                0 => self.device_features() as BusType,
+                1 => (self.device_features() >> 32) as BusType,
+                _ => 0,
+            },
+            DRIVER_FEATURE_SELECT => self.driver_feature_select,
+            DRIVER_FEATURE => match self.driver_feature_select {
+                0 => self.driver_features as BusType,
+                1 => (self.driver_features >> 32) as BusType,
+                _ => 0,
+            },
+            CONFIG_MSIX_VECTOR => self.config_msix_vector as BusType,
+            NUM_QUEUES => self.queues.len() as BusType,
+            DEVICE_STATUS => self.status as BusType,
+            CONFIG_GENERATION => self.config_generation as BusType,
+            QUEUE_SELECT => self.queue_select as BusType,
+            _ => {
+                let queue = if let Some(queue) = queue {
+                    queue
+                } else {
+                    return 0;
+                };
+
+                match offset {
+                    QUEUE_SIZE => queue.size as BusType,
+                    QUEUE_MSIX_VECTOR => queue.msix_vector as BusType,
+                    QUEUE_ENABLE => queue.ready as BusType,
+                    QUEUE_NOTIFY_OFF => self.queue_select as BusType,
+                    QUEUE_DESC_LO => queue.desc_addr as BusType,
+                    QUEUE_DESC_HI => (queue.desc_addr >> 32) as BusType,
+                    QUEUE_DRIVER_LO => queue.driver_addr as BusType,
+                    QUEUE_DRIVER_HI => (queue.driver_addr >> 32) as BusType,
+                    QUEUE_DEVICE_LO => queue.device_addr as BusType,
+                    QUEUE_DEVICE_HI => (queue.device_addr >> 32) as BusType,
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write_common(&mut self, offset: BusType, data: BusType) {
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_feature_select = data,
+            DRIVER_FEATURE_SELECT => self.driver_feature_select = data,
+            DRIVER_FEATURE => match self.driver_feature_select {
+                0 => set_u64_half(&mut self.driver_features, data, false),
+                1 => set_u64_half(&mut self.driver_features, data, true),
+                _ => {}
+            },
+            CONFIG_MSIX_VECTOR => self.config_msix_vector = data as u16,
+            DEVICE_STATUS => {
+                let status = data as u8;
+
+                if status == 0 {
+                    self.reset();
+                    return;
+                }
+
+                // Features the device never offered can't be accepted
+                if (status & VIRTIO_STATUS_FEATURES_OK) != 0
+                    && (self.driver_features & !self.device_features()) != 0
+                {
+                    self.status = status & !VIRTIO_STATUS_FEATURES_OK;
+                    return;
+                }
+
+                self.status = status;
+            }
+            QUEUE_SELECT => self.queue_select = data as u16,
+            _ => {
+                let queue = if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+                    queue
+                } else {
+                    return;
+                };
+
+                match offset {
+                    QUEUE_SIZE => {
+                        let size = data as u16;
+
+                        if size.is_power_of_two() && size <= VIRTQ_MAX_SIZE {
+                            queue.size = size;
+                        }
+                    }
+                    QUEUE_MSIX_VECTOR => queue.msix_vector = data as u16,
+                    QUEUE_ENABLE => queue.ready = (data & 1) != 0,
+                    QUEUE_DESC_LO => set_u64_half(&mut queue.desc_addr, data, false),
+                    QUEUE_DESC_HI => set_u64_half(&mut queue.desc_addr, data, true),
+                    QUEUE_DRIVER_LO => set_u64_half(&mut queue.driver_addr, data, false),
+                    QUEUE_DRIVER_HI => set_u64_half(&mut queue.driver_addr, data, true),
+                    QUEUE_DEVICE_LO => set_u64_half(&mut queue.device_addr, data, false),
+                    QUEUE_DEVICE_HI => set_u64_half(&mut queue.device_addr, data, true),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn notify_queue(&mut self, index: usize) {
+        if (self.status & VIRTIO_STATUS_DRIVER_OK) == 0 || !self.config.bus_master_enabled() {
+            return;
+        }
+
+        let queue = if let Some(queue) = self.queues.get_mut(index) {
+            queue
+        } else {
+            return;
+        };
+
+        if !queue.ready {
+            return;
+        }
+
+        let mut used = false;
+
+        while let Ok(Some(chain)) = queue.pop() {
+            let len = self.device.process(index, &chain);
+
+            if queue.push(chain.head, len).is_err() {
+                break;
+            }
+
+            used = true;
+        }
+
+        if used && !queue.interrupts_suppressed() {
+            let vector = queue.msix_vector;
+
+            self.send_irq(VIRTIO_ISR_QUEUE, vector);
+        }
+    }
+
+    fn send_irq(&mut self, isr: u8, vector: u16) {
+        if self.msix.is_enabled(&self.config) {
+            if vector != PCI_MSIX_NO_VECTOR {
+                self.msix.notify(&self.config, vector);
+            }
+        } else {
+            self.isr |= isr;
+        }
+    }
+
+    // Lets a device report a configuration change, such as a resized backing file
+    pub fn notify_config_change(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+
+        self.send_irq(VIRTIO_ISR_CONFIG, self.config_msix_vector);
+    }
+}
+
+impl PciDevice for VirtioPci {
+    fn name(&self) -> String {
+        self.device.name()
+    }
+
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_load(&mut self, bar: usize, offset: BusType, size: BusType) -> BusType {
+        if self.msix.contains(bar, offset) {
+            return self.msix.read(offset, size);
+        }
+
+        if bar != VIRTIO_BAR {
+            return 0;
+        }
+
+        match offset {
+            COMMON_CFG_OFFSET..=0x0fff => self.read_common(offset) & (BusType::MAX >> (32 - size)),
+            ISR_CFG_OFFSET..=0x1fff => {
+                // Reading the ISR acknowledges the interrupt
+                let isr = self.isr;
+
+                self.isr = 0;
+
+                isr as BusType
+            }
+            DEVICE_CFG_OFFSET..=0x2fff => self.device.read_config(offset - DEVICE_CFG_OFFSET, size),
+            _ => 0,
+        }
+    }
+
+    fn bar_store(&mut self, bar: usize, offset: BusType, data: BusType, size: BusType) {
+        if self.msix.contains(bar, offset) {
+            self.msix.write(&self.config, offset, data, size);
+            return;
+        }
+
+        if bar != VIRTIO_BAR {
+            return;
+        }
+
+        match offset {
+            COMMON_CFG_OFFSET..=0x0fff => self.write_common(offset, data),
+            DEVICE_CFG_OFFSET..=0x2fff => {
+                self.device
+                    .write_config(offset - DEVICE_CFG_OFFSET, data, size)
+            }
+            NOTIFY_CFG_OFFSET..=0x3fff => {
+                let index = (offset - NOTIFY_CFG_OFFSET) / NOTIFY_OFF_MULTIPLIER;
+
+                self.notify_queue(index as usize);
+            }
+            _ => {}
+        }
+    }
+
+    fn irq_level(&self) -> bool {
+        self.isr != 0
+    }
+}
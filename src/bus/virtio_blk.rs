@@ -0,0 +1,189 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::bus::virtio::*;
+use crate::bus::*;
+
+const VIRTIO_ID_BLOCK: u16 = 2;
+const PCI_CLASS_STORAGE_SCSI: u32 = 0x010000;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+const SECTOR_SIZE: u64 = 512;
+const REQ_HEADER_SIZE: usize = 16;
+
+// struct virtio_blk_config, up to blk_size
+const CONFIG_CAPACITY: usize = 0x00;
+const CONFIG_BLK_SIZE: usize = 0x14;
+const CONFIG_SIZE: usize = 0x18;
+
+pub struct VirtioBlk {
+    file: File,
+    readonly: bool,
+    size: u64,
+    config: [u8; CONFIG_SIZE],
+}
+
+impl VirtioBlk {
+    pub fn new(path: &str, readonly: bool) -> Result<VirtioBlk, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .open(path)
+            .map_err(|err| format!("Failed to open block device image {}: {}", path, err))?;
+
+        let size = file.metadata().map_err(|err| err.to_string())?.len();
+
+        let mut config = [0u8; CONFIG_SIZE];
+
+        config[CONFIG_CAPACITY..CONFIG_CAPACITY + 8]
+            .copy_from_slice(&(size / SECTOR_SIZE).to_le_bytes());
+        config[CONFIG_BLK_SIZE..CONFIG_BLK_SIZE + 4]
+            .copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+
+        Ok(VirtioBlk {
+            file,
+            readonly,
+            size,
+            config,
+        })
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.write_all(buf)
+    }
+
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|offset| offset.checked_add(len as u64))
+            .is_some_and(|end| end <= self.size)
+    }
+
+    // Returns the status and the number of data bytes written into the chain
+    fn handle_request(&mut self, chain: &DescChain, req_type: u32, sector: u64) -> (u8, usize) {
+        let data_len = chain.writable_len().saturating_sub(1);
+
+        match req_type {
+            VIRTIO_BLK_T_IN => {
+                let mut buf = vec![0u8; data_len];
+
+                if !self.in_range(sector, data_len) || self.read_sectors(sector, &mut buf).is_err()
+                {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                match chain.write(0, &buf) {
+                    Ok(written) => (VIRTIO_BLK_S_OK, written),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_OUT => {
+                let mut buf = vec![0u8; chain.readable_len().saturating_sub(REQ_HEADER_SIZE)];
+
+                if self.readonly
+                    || !self.in_range(sector, buf.len())
+                    || chain.read(REQ_HEADER_SIZE, &mut buf).is_err()
+                    || self.write_sectors(sector, &buf).is_err()
+                {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, 0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                if !self.readonly && self.file.sync_all().is_err() {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+
+                (VIRTIO_BLK_S_OK, 0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+                let name = b"riscvbox-virtio-blk";
+
+                id[..name.len()].copy_from_slice(name);
+
+                let len = data_len.min(VIRTIO_BLK_ID_BYTES);
+
+                match chain.write(0, &id[..len]) {
+                    Ok(written) => (VIRTIO_BLK_S_OK, written),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn name(&self) -> String {
+        "virtio-blk".to_string()
+    }
+
+    fn device_id(&self) -> u16 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn class_code(&self) -> u32 {
+        PCI_CLASS_STORAGE_SCSI
+    }
+
+    fn features(&self) -> u64 {
+        let ro = if self.readonly { VIRTIO_BLK_F_RO } else { 0 };
+
+        VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH | ro
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: BusType, size: BusType) -> BusType {
+        read_config_bytes(&self.config, offset, size)
+    }
+
+    fn process(&mut self, _queue: usize, chain: &DescChain) -> u32 {
+        let mut header = [0u8; REQ_HEADER_SIZE];
+
+        let writable_len = chain.writable_len();
+
+        if writable_len == 0 {
+            return 0;
+        }
+
+        let (status, written) = match chain.read(0, &mut header) {
+            Ok(REQ_HEADER_SIZE) => {
+                let req_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+                self.handle_request(chain, req_type, sector)
+            }
+            _ => (VIRTIO_BLK_S_IOERR, 0),
+        };
+
+        // The status byte is always the last writable byte of the chain
+        let _ = chain.write(writable_len - 1, &[status]);
+
+        written as u32 + 1
+    }
+}
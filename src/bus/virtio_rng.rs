@@ -0,0 +1,59 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::bus::virtio::*;
+use crate::bus::*;
+
+const VIRTIO_ID_RNG: u16 = 4;
+const PCI_CLASS_OTHERS: u32 = 0xff0000;
+
+pub struct VirtioRng {}
+
+impl VirtioRng {
+    pub fn new() -> VirtioRng {
+        VirtioRng {}
+    }
+}
+
+// Every RandomState gets fresh keys derived from the host's random source
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+impl VirtioDevice for VirtioRng {
+    fn name(&self) -> String {
+        "virtio-rng".to_string()
+    }
+
+    fn device_id(&self) -> u16 {
+        VIRTIO_ID_RNG
+    }
+
+    fn class_code(&self) -> u32 {
+        PCI_CLASS_OTHERS
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: BusType, _size: BusType) -> BusType {
+        0
+    }
+
+    fn process(&mut self, _queue: usize, chain: &DescChain) -> u32 {
+        let mut buf = vec![0u8; chain.writable_len()];
+
+        for chunk in buf.chunks_mut(8) {
+            let len = chunk.len();
+
+            chunk.copy_from_slice(&random_u64().to_le_bytes()[..len]);
+        }
+
+        chain.write(0, &buf).unwrap_or(0) as u32
+    }
+}
@@ -1,14 +1,51 @@
 use crate::bus::bus::BusType;
 use crate::bus::mmu::{Mmu, Sv32Mmu};
 use crate::cpu::csr;
+use crate::cpu::pmp::Pmp;
 use crate::frontend::gpfn_state::GpfnStateSet;
 use crate::frontend::insn_lookup::InsnData;
-use std::collections::HashSet;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
 
 pub type CpuReg = BusType;
 
 pub const CPU_INTC_PHANDLE: u32 = 0x2;
 pub const CPU_TIMEBASE_FREQ: u32 = 1000000;
+// Rate the progress counters turn into guest time at, a hart getting through
+// one instruction per cycle at 100 MHz
+pub const CPU_NOMINAL_IPS: u64 = 100000000;
+
+static COUNT_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref HART_PROGRESS: Mutex<HashMap<usize, Box<AtomicU64>>> = Mutex::new(HashMap::new());
+}
+
+// Only pays for the counters when something paces itself by them, has to be
+// set before the harts translate anything
+pub fn set_count_progress(enabled: bool) {
+    COUNT_PROGRESS.store(enabled, Ordering::Release);
+}
+
+pub fn count_progress() -> bool {
+    COUNT_PROGRESS.load(Ordering::Acquire)
+}
+
+// How far a hart got since the emulator started, in guest instructions. It is
+// an estimate rather than instret: the jit adds whole loop bodies at back-edges
+// and idling in wfi counts at CPU_NOMINAL_IPS. Only the hart itself writes it,
+// the jit bumps it without a lock, and it carries on across guest reboots
+pub fn hart_progress(hart: usize) -> &'static AtomicU64 {
+    let mut map = HART_PROGRESS.lock().unwrap();
+
+    let progress = map
+        .entry(hart)
+        .or_insert_with(|| Box::new(AtomicU64::new(0)));
+
+    unsafe { &*(progress.as_ref() as *const AtomicU64) }
+}
 
 pub enum RegName {
     Zero = 0,
@@ -181,13 +218,17 @@ pub enum Exception {
     BlockExit = 0x101,
     Mret = 0x102,
     Sret = 0x103,
-    InvalidateJitBlock(CpuReg, bool) = 0x104,
+    // Carries the guest address of a store that landed on translated code
+    InvalidateJitBlock(CpuReg) = 0x104,
     DiscardJitBlock(CpuReg) = 0x105,
     MmuStateUpdate = 0x106,
     Wfi = 0x107,
     BookkeepingRet = 0x108,
     FastmemViolation = 0x109,
     Reboot = 0x10a,
+    Poweroff = 0x10b,
+    HotBlock = 0x10c,
+    StaleInsn = 0x10d,
 }
 
 impl Exception {
@@ -212,13 +253,16 @@ impl Exception {
             0x101 => Exception::BlockExit,
             0x102 => Exception::Mret,
             0x103 => Exception::Sret,
-            0x104 => Exception::InvalidateJitBlock(data, true),
+            0x104 => Exception::InvalidateJitBlock(data),
             0x105 => Exception::DiscardJitBlock(data),
             0x106 => Exception::MmuStateUpdate,
             0x107 => Exception::Wfi,
             0x108 => Exception::BookkeepingRet,
             0x109 => Exception::FastmemViolation,
             0x10a => Exception::Reboot,
+            0x10b => Exception::Poweroff,
+            0x10c => Exception::HotBlock,
+            0x10d => Exception::StaleInsn,
             _ => Exception::None,
         }
     }
@@ -244,13 +288,16 @@ impl Exception {
             Exception::BlockExit => 0x101,
             Exception::Mret => 0x102,
             Exception::Sret => 0x103,
-            Exception::InvalidateJitBlock(_, _) => 0x104,
+            Exception::InvalidateJitBlock(_) => 0x104,
             Exception::DiscardJitBlock(_) => 0x105,
             Exception::MmuStateUpdate => 0x106,
             Exception::Wfi => 0x107,
             Exception::BookkeepingRet => 0x108,
             Exception::FastmemViolation => 0x109,
             Exception::Reboot => 0x10a,
+            Exception::Poweroff => 0x10b,
+            Exception::HotBlock => 0x10c,
+            Exception::StaleInsn => 0x10d,
         }
     }
 
@@ -275,13 +322,16 @@ impl Exception {
             Exception::BlockExit => 0,
             Exception::Mret => 0,
             Exception::Sret => 0,
-            Exception::InvalidateJitBlock(data, _) => *data,
+            Exception::InvalidateJitBlock(data) => *data,
             Exception::DiscardJitBlock(data) => *data,
             Exception::MmuStateUpdate => 0,
             Exception::Wfi => 0,
             Exception::BookkeepingRet => 0,
             Exception::FastmemViolation => 0,
             Exception::Reboot => 0,
+            Exception::Poweroff => 0,
+            Exception::HotBlock => 0,
+            Exception::StaleInsn => 0,
         };
 
         data
@@ -326,6 +376,8 @@ pub struct Cpu {
     pub current_gpfn: CpuReg,
     pub current_guest_page: CpuReg,
     pub current_gpfn_offset: CpuReg,
+    // Physical page behind current_guest_page, as last fetched from
+    pub current_phys_page: BusType,
     pub regs: [CpuReg; 32],
     pub insn_map: InsnData,
     pub insn_patch_list: Vec<JumpAddrPatch>,
@@ -337,8 +389,10 @@ pub struct Cpu {
     pub jump_count: usize,
     pub mode: csr::MppMode,
     pub gpfn_state: GpfnStateSet,
+    pub dma_dirty_gpfns: Vec<CpuReg>,
     pub atomic_reservations: HashSet<BusType>, // TODO: this probably isn't core local, check later
     pub mmu: Sv32Mmu,
+    pub pmp: Pmp,
     pub csr: &'static mut csr::Csr,
     pub has_pending_interrupt: std::sync::atomic::AtomicU32,
     pub pending_interrupt_number: CpuReg,
@@ -352,6 +406,7 @@ impl Cpu {
             current_gpfn: 0,
             current_guest_page: 0,
             current_gpfn_offset: 0,
+            current_phys_page: 0,
             regs: [0; 32],
             insn_map: InsnData::new(),
             insn_patch_list: Vec::new(),
@@ -363,8 +418,10 @@ impl Cpu {
             jump_count: 0,
             mode: csr::MppMode::Machine,
             gpfn_state: GpfnStateSet::new(),
+            dma_dirty_gpfns: Vec::new(),
             atomic_reservations: HashSet::new(),
             mmu: Sv32Mmu::new(),
+            pmp: Pmp::new(),
             csr: csr::get_csr(),
             has_pending_interrupt: std::sync::atomic::AtomicU32::new(0),
             pending_interrupt_number: 0,
@@ -1,4 +1,6 @@
 use crate::bus::bus::BusType;
+use crate::bus::imsic::{self, ImsicFile, ImsicLevel};
+use crate::cpu::cpu;
 use crate::util::util;
 use std::cell::RefCell;
 use std::sync::atomic::AtomicU32;
@@ -28,6 +30,9 @@ pub mod register {
     pub const SCAUSE: usize = 0x142;
     pub const STVAL: usize = 0x143;
     pub const SIP: usize = 0x144;
+    pub const SISELECT: usize = 0x150;
+    pub const SIREG: usize = 0x151;
+    pub const STOPEI: usize = 0x15c;
     pub const SATP: usize = 0x180;
     pub const MSTATUS: usize = 0x300;
     pub const MISA: usize = 0x301;
@@ -36,20 +41,33 @@ pub mod register {
     pub const MIE: usize = 0x304;
     pub const MTVEC: usize = 0x305;
     pub const MCOUNTEREN: usize = 0x306;
+    pub const MENVCFG: usize = 0x30a;
+    pub const MENVCFGH: usize = 0x31a;
+    pub const PMPCFG0: usize = 0x3a0;
+    pub const PMPCFG15: usize = 0x3af;
+    pub const PMPADDR0: usize = 0x3b0;
+    pub const PMPADDR63: usize = 0x3ef;
+    pub const MSECCFG: usize = 0x747;
+    pub const MSECCFGH: usize = 0x757;
     pub const MSCRATCH: usize = 0x340;
     pub const MEPC: usize = 0x341;
     pub const MCAUSE: usize = 0x342;
     pub const MTVAL: usize = 0x343;
     pub const MIP: usize = 0x344;
+    pub const MISELECT: usize = 0x350;
+    pub const MIREG: usize = 0x351;
+    pub const MTOPEI: usize = 0x35c;
     pub const CYCLE: usize = 0xc00;
     pub const CYCLEH: usize = 0xc80;
     pub const TIME: usize = 0xc01;
     pub const TIMEH: usize = 0xc81;
     pub const TDATA1: usize = 0x7a1;
+    pub const STOPI: usize = 0xdb0;
     pub const MVENDORID: usize = 0xf11;
     pub const MARCHID: usize = 0xf12;
     pub const MIMPID: usize = 0xf13;
     pub const MHARTID: usize = 0xf14;
+    pub const MTOPI: usize = 0xfb0;
 }
 
 pub const SIE: usize = 1 << 1;
@@ -70,6 +88,13 @@ pub const MPRV: usize = 1 << 17;
 pub const TVM: usize = 1 << 20;
 pub const TSR: usize = 1 << 22;
 
+// menvcfg is WARL, these are the fields that stick. FIOM only makes fences
+// stronger, which order everything here already. CBIE, CBCFE and CBZE need
+// Zicbom and Zicboz, PBMTE belongs to Svpbmt which only exists on RV64 and
+// STCE needs Sstc, so they read as zero
+pub const MENVCFG: usize = 1 << bits::FIOM;
+pub const MENVCFGH: usize = 1 << bits::ADUE;
+
 pub const A_EXT: usize = 1 << 0;
 pub const C_EXT: usize = 1 << 2;
 pub const D_EXT: usize = 1 << 3;
@@ -99,6 +124,10 @@ pub mod bits {
     pub const MXR: usize = 19;
     pub const TVM: usize = 20;
     pub const TSR: usize = 22;
+    // menvcfg bit 0, fences on I/O order memory as well
+    pub const FIOM: usize = 0;
+    // menvcfg bit 61, Svadu hardware A/D updates
+    pub const ADUE: usize = 29;
     pub const SSIP_BIT: usize = 1;
     pub const MSIP_BIT: usize = 3;
     pub const STIP_BIT: usize = 5;
@@ -112,6 +141,9 @@ pub mod bits {
     pub const MTIP: usize = 1 << MTIP_BIT;
     pub const SEIP: usize = 1 << SEIP_BIT;
     pub const MEIP: usize = 1 << MEIP_BIT;
+
+    // Major interrupts in their default priority order
+    pub const TOPI_ORDER: [usize; 6] = [MEIP_BIT, MSIP_BIT, MTIP_BIT, SEIP_BIT, SSIP_BIT, STIP_BIT];
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -134,6 +166,9 @@ impl Csr {
         regs[register::MISA] =
             (XLEN_32 | RV32I_64I_128I | A_EXT | M_EXT | SUPERVISOR | USER) as u32;
 
+        // Firmware that predates Svadu never sets ADUE and expects the hardware updates
+        regs[register::MENVCFGH] = MENVCFGH as CsrType;
+
         let csr = Self { regs };
 
         csr
@@ -146,8 +181,14 @@ impl Csr {
             register::SIP => self.fetch_mip_atomic() & self.regs[register::MIDELEG],
             register::CYCLE => util::timebase_estimate_cycles() as CsrType,
             register::CYCLEH => (util::timebase_estimate_cycles() >> 32) as CsrType,
-            register::TIME => util::timebase_since_program_start() as CsrType,
-            register::TIMEH => (util::timebase_since_program_start() >> 32) as CsrType,
+            register::TIME => util::get_mtime() as CsrType,
+            register::TIMEH => (util::get_mtime() >> 32) as CsrType,
+            register::MIREG => self.read_ireg(ImsicLevel::Machine, register::MISELECT),
+            register::SIREG => self.read_ireg(ImsicLevel::Supervisor, register::SISELECT),
+            register::MTOPEI => Self::get_imsic_file(ImsicLevel::Machine).topei(),
+            register::STOPEI => Self::get_imsic_file(ImsicLevel::Supervisor).topei(),
+            register::MTOPI => self.read_topi(false),
+            register::STOPI => self.read_topi(true),
             _ => self.regs[addr],
         }
     }
@@ -169,12 +210,43 @@ impl Csr {
                 let val = (self.regs[register::MIP as usize] & !mask) | (data & mask);
                 self.store_mip_atomic(val);
             }
+            register::MENVCFG => {
+                self.regs[register::MENVCFG] = data & MENVCFG as CsrType;
+            }
+            register::MENVCFGH => {
+                self.regs[register::MENVCFGH] = data & MENVCFGH as CsrType;
+            }
             _ => {
                 self.regs[addr as usize] = data;
             }
         }
     }
 
+    pub fn get_imsic_file(level: ImsicLevel) -> &'static mut ImsicFile {
+        imsic::get_imsic(cpu::get_cpu().core_id as usize).get_file(level)
+    }
+
+    fn read_ireg(&self, level: ImsicLevel, iselect: usize) -> CsrType {
+        Self::get_imsic_file(level)
+            .read_ireg(self.regs[iselect])
+            .unwrap_or(0)
+    }
+
+    // Interrupt priorities are hardwired, so the reported priority is always 1
+    fn read_topi(&self, supervisor: bool) -> CsrType {
+        let mideleg = self.regs[register::MIDELEG];
+        let delegated = if supervisor { mideleg } else { !mideleg };
+        let pending = self.fetch_mip_atomic() & self.regs[register::MIE] & delegated;
+
+        for bit in bits::TOPI_ORDER {
+            if (pending >> bit) & 1 != 0 {
+                return ((bit as CsrType) << 16) | 1;
+            }
+        }
+
+        0
+    }
+
     pub fn read_bit(&self, addr: usize, bit: usize) -> bool {
         let val = self.read(addr);
         util::read_bit(val, bit)
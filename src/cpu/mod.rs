@@ -2,4 +2,6 @@ pub mod cpu;
 pub use cpu::*;
 
 pub mod csr;
+pub mod pmp;
+pub mod semihosting;
 pub mod trap;
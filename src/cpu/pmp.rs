@@ -0,0 +1,314 @@
+use crate::bus::bus::BusType;
+use crate::bus::mmu::AccessType;
+use crate::bus::tlb::{TLB_FLAG_EXECUTE, TLB_FLAG_READ, TLB_FLAG_WRITE};
+use crate::cpu::csr::{register, MppMode};
+use crate::cpu::Exception;
+use crate::frontend::exec_core::RV_PAGE_SIZE;
+
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields, one byte per entry
+const CFG_R: u8 = 1 << 0;
+const CFG_W: u8 = 1 << 1;
+const CFG_X: u8 = 1 << 2;
+const CFG_RWX: u8 = CFG_R | CFG_W | CFG_X;
+const CFG_A_SHIFT: u8 = 3;
+const CFG_L: u8 = 1 << 7;
+const CFG_WRITABLE: u8 = CFG_L | (0b11 << CFG_A_SHIFT) | CFG_RWX;
+
+const A_OFF: u8 = 0;
+const A_TOR: u8 = 1;
+const A_NA4: u8 = 2;
+const A_NAPOT: u8 = 3;
+
+// Smepmp
+pub const MSECCFG_MML: BusType = 1 << 0;
+pub const MSECCFG_MMWP: BusType = 1 << 1;
+pub const MSECCFG_RLB: BusType = 1 << 2;
+
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [BusType; PMP_ENTRIES],
+    // Byte range [start, end) each entry matches, empty when it's off
+    range: [(u64, u64); PMP_ENTRIES],
+    mseccfg: BusType,
+    active: bool,
+    restricts_machine: bool,
+}
+
+pub fn is_pmp_csr(csr_reg: usize) -> bool {
+    matches!(
+        csr_reg,
+        register::PMPCFG0..=register::PMPCFG15
+            | register::PMPADDR0..=register::PMPADDR63
+            | register::MSECCFG
+            | register::MSECCFGH
+    )
+}
+
+pub fn access_fault(addr: BusType, access_type: AccessType) -> Exception {
+    match access_type {
+        AccessType::Load => Exception::LoadAccessFault(addr),
+        AccessType::Store => Exception::StoreAccessFault(addr),
+        AccessType::Fetch => Exception::InstructionAccessFault(addr),
+    }
+}
+
+fn access_flag(access_type: AccessType) -> u8 {
+    match access_type {
+        AccessType::Load => CFG_R,
+        AccessType::Store => CFG_W,
+        AccessType::Fetch => CFG_X,
+    }
+}
+
+// What M-mode and S/U-mode may do in a region once mseccfg.MML is set
+fn mml_perms(cfg: u8) -> (u8, u8) {
+    let rwx = cfg & CFG_RWX;
+
+    match (cfg & CFG_L != 0, rwx) {
+        (false, CFG_W) => (CFG_R | CFG_W, CFG_R),
+        (false, 0b110) => (CFG_R | CFG_W, CFG_R | CFG_W),
+        (false, _) => (0, rwx),
+        (true, CFG_W) => (CFG_X, CFG_X),
+        (true, 0b110) => (CFG_R | CFG_X, CFG_X),
+        (true, CFG_RWX) => (CFG_R, CFG_R),
+        (true, _) => (rwx, 0),
+    }
+}
+
+impl Pmp {
+    pub fn new() -> Pmp {
+        Pmp {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+            range: [(0, 0); PMP_ENTRIES],
+            mseccfg: 0,
+            active: false,
+            restricts_machine: false,
+        }
+    }
+
+    fn is_locked(&self, index: usize) -> bool {
+        self.cfg[index] & CFG_L != 0 && self.mseccfg & MSECCFG_RLB == 0
+    }
+
+    fn addr_mode(&self, index: usize) -> u8 {
+        (self.cfg[index] >> CFG_A_SHIFT) & 0b11
+    }
+
+    pub fn read(&self, csr_reg: usize) -> BusType {
+        match csr_reg {
+            register::PMPCFG0..=register::PMPCFG15 => {
+                let first = (csr_reg - register::PMPCFG0) * 4;
+
+                (0..4)
+                    .filter(|i| first + i < PMP_ENTRIES)
+                    .fold(0, |val, i| {
+                        val | (self.cfg[first + i] as BusType) << (i * 8)
+                    })
+            }
+            register::PMPADDR0..=register::PMPADDR63 => {
+                let index = csr_reg - register::PMPADDR0;
+
+                if index < PMP_ENTRIES {
+                    self.addr[index]
+                } else {
+                    0
+                }
+            }
+            register::MSECCFG => self.mseccfg,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, csr_reg: usize, val: BusType) {
+        match csr_reg {
+            register::PMPCFG0..=register::PMPCFG15 => {
+                let first = (csr_reg - register::PMPCFG0) * 4;
+
+                for i in 0..4 {
+                    if first + i < PMP_ENTRIES {
+                        self.write_cfg(first + i, (val >> (i * 8)) as u8);
+                    }
+                }
+            }
+            register::PMPADDR0..=register::PMPADDR63 => {
+                let index = csr_reg - register::PMPADDR0;
+
+                // A locked TOR entry locks the address below it too
+                let tor_locked = index + 1 < PMP_ENTRIES
+                    && self.is_locked(index + 1)
+                    && self.addr_mode(index + 1) == A_TOR;
+
+                if index < PMP_ENTRIES && !self.is_locked(index) && !tor_locked {
+                    self.addr[index] = val;
+                }
+            }
+            register::MSECCFG => {
+                // MML and MMWP stick until reset, RLB can't be set once a rule is locked
+                let mut rlb = val & MSECCFG_RLB;
+
+                if self.mseccfg & MSECCFG_RLB == 0 && self.cfg.iter().any(|cfg| cfg & CFG_L != 0) {
+                    rlb = 0;
+                }
+
+                self.mseccfg = (self.mseccfg & (MSECCFG_MML | MSECCFG_MMWP))
+                    | (val & (MSECCFG_MML | MSECCFG_MMWP))
+                    | rlb;
+            }
+            _ => {}
+        }
+
+        self.update();
+    }
+
+    fn write_cfg(&mut self, index: usize, cfg: u8) {
+        if self.is_locked(index) {
+            return;
+        }
+
+        let cfg = cfg & CFG_WRITABLE;
+        let mml = self.mseccfg & MSECCFG_MML != 0;
+
+        // W without R is reserved unless MML gives it a meaning
+        if !mml && cfg & (CFG_R | CFG_W) == CFG_W {
+            return;
+        }
+
+        // New rules M-mode can execute from need RLB under MML
+        if mml
+            && self.mseccfg & MSECCFG_RLB == 0
+            && cfg & CFG_L != 0
+            && mml_perms(cfg).0 & CFG_X != 0
+        {
+            return;
+        }
+
+        self.cfg[index] = cfg;
+    }
+
+    fn update(&mut self) {
+        for i in 0..PMP_ENTRIES {
+            let addr = (self.addr[i] as u64) << 2;
+
+            self.range[i] = match self.addr_mode(i) {
+                A_OFF => (0, 0),
+                A_TOR => {
+                    let start = if i == 0 {
+                        0
+                    } else {
+                        (self.addr[i - 1] as u64) << 2
+                    };
+
+                    (start, addr)
+                }
+                A_NA4 => (addr, addr + 4),
+                A_NAPOT => {
+                    let ones = self.addr[i].trailing_ones() as u64;
+                    let size = 1u64 << (ones + 3);
+
+                    let start = addr & !(size - 1);
+
+                    (start, start + size)
+                }
+                _ => unreachable!(),
+            };
+        }
+
+        self.active = self
+            .cfg
+            .iter()
+            .any(|cfg| (cfg >> CFG_A_SHIFT) & 0b11 != A_OFF);
+
+        self.restricts_machine = self.mseccfg & (MSECCFG_MML | MSECCFG_MMWP) != 0
+            || (0..PMP_ENTRIES).any(|i| self.cfg[i] & CFG_L != 0 && self.addr_mode(i) != A_OFF);
+    }
+
+    // Whether any access made in this mode can be denied. Until firmware turns on
+    // an entry S and U-mode accesses go through unchecked
+    pub fn restricts(&self, mode: MppMode) -> bool {
+        match mode {
+            MppMode::Machine => self.restricts_machine,
+            _ => self.active,
+        }
+    }
+
+    fn allows(&self, cfg: u8, access_type: AccessType, mode: MppMode) -> bool {
+        let flag = access_flag(access_type);
+
+        if self.mseccfg & MSECCFG_MML != 0 {
+            let (machine, user) = mml_perms(cfg);
+
+            return match mode {
+                MppMode::Machine => machine & flag != 0,
+                _ => user & flag != 0,
+            };
+        }
+
+        if mode == MppMode::Machine && cfg & CFG_L == 0 {
+            return true;
+        }
+
+        cfg & flag != 0
+    }
+
+    pub fn check(
+        &self,
+        addr: BusType,
+        size: usize,
+        access_type: AccessType,
+        mode: MppMode,
+    ) -> bool {
+        if !self.restricts(mode) {
+            return true;
+        }
+
+        let start = addr as u64;
+        let end = start + size as u64;
+
+        // The lowest numbered entry that matches any byte decides, and it has to
+        // match all of them
+        for i in 0..PMP_ENTRIES {
+            let (lo, hi) = self.range[i];
+
+            if lo >= hi || end <= lo || start >= hi {
+                continue;
+            }
+
+            if start < lo || end > hi {
+                return false;
+            }
+
+            return self.allows(self.cfg[i], access_type, mode);
+        }
+
+        match mode {
+            MppMode::Machine => {
+                self.mseccfg & MSECCFG_MMWP == 0
+                    && !(self.mseccfg & MSECCFG_MML != 0 && access_type == AccessType::Fetch)
+            }
+            _ => false,
+        }
+    }
+
+    // TLB flags for the accesses allowed on the whole page, anything else has to
+    // take the slow path and be checked on its own
+    pub fn page_flags(&self, page: BusType, mode: MppMode) -> BusType {
+        let mut flags = 0;
+
+        if self.check(page, RV_PAGE_SIZE, AccessType::Load, mode) {
+            flags |= TLB_FLAG_READ;
+        }
+
+        if self.check(page, RV_PAGE_SIZE, AccessType::Store, mode) {
+            flags |= TLB_FLAG_WRITE;
+        }
+
+        if self.check(page, RV_PAGE_SIZE, AccessType::Fetch, mode) {
+            flags |= TLB_FLAG_EXECUTE;
+        }
+
+        flags
+    }
+}
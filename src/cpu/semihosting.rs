@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::backend::{ReturnableHandler, ReturnableImpl};
+use crate::bus::{self, ns16550::charbuf_read_data, syscon, BusType};
+use crate::cpu::{self, CpuReg, Exception, RegName, CPU_TIMEBASE_FREQ};
+use crate::util;
+
+// slli x0, x0, 0x1f
+pub const SEMIHOSTING_ENTRY_INSN: u32 = 0x01f01013;
+// ebreak
+pub const SEMIHOSTING_EBREAK_INSN: u32 = 0x00100073;
+// srai x0, x0, 7
+pub const SEMIHOSTING_EXIT_INSN: u32 = 0x40705013;
+
+const SYS_OPEN: CpuReg = 0x01;
+const SYS_CLOSE: CpuReg = 0x02;
+const SYS_WRITEC: CpuReg = 0x03;
+const SYS_WRITE0: CpuReg = 0x04;
+const SYS_WRITE: CpuReg = 0x05;
+const SYS_READ: CpuReg = 0x06;
+const SYS_READC: CpuReg = 0x07;
+const SYS_ISERROR: CpuReg = 0x08;
+const SYS_ISTTY: CpuReg = 0x09;
+const SYS_SEEK: CpuReg = 0x0a;
+const SYS_FLEN: CpuReg = 0x0c;
+const SYS_TMPNAM: CpuReg = 0x0d;
+const SYS_REMOVE: CpuReg = 0x0e;
+const SYS_RENAME: CpuReg = 0x0f;
+const SYS_CLOCK: CpuReg = 0x10;
+const SYS_TIME: CpuReg = 0x11;
+const SYS_SYSTEM: CpuReg = 0x12;
+const SYS_ERRNO: CpuReg = 0x13;
+const SYS_GET_CMDLINE: CpuReg = 0x15;
+const SYS_HEAPINFO: CpuReg = 0x16;
+const SYS_EXIT: CpuReg = 0x18;
+const SYS_EXIT_EXTENDED: CpuReg = 0x20;
+const SYS_ELAPSED: CpuReg = 0x30;
+const SYS_TICKFREQ: CpuReg = 0x31;
+
+const ADP_STOPPED_APPLICATION_EXIT: CpuReg = 0x20026;
+
+const SEMIHOSTING_ERR: CpuReg = -1i32 as CpuReg;
+
+// Host buffer SYS_READ and SYS_WRITE copy guest memory through a chunk at a time
+const CHUNK_SIZE: usize = 0x10000;
+
+// errno values reported through SYS_ERRNO when the host doesn't give us one
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EINVAL: i32 = 22;
+
+// The special ":tt" file opens the console, the open mode picks the stream
+const TT_NAME: &str = ":tt";
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+struct Semihosting {
+    root: PathBuf,
+    cmdline: String,
+    handles: HashMap<CpuReg, Handle>,
+    next_handle: CpuReg,
+    errno: i32,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref SEMIHOSTING: Mutex<Option<Semihosting>> = Mutex::new(None);
+}
+
+pub fn init(root: &str, cmdline: &str) -> Result<(), String> {
+    let root = Path::new(root)
+        .canonicalize()
+        .map_err(|err| format!("Invalid semihosting root {}: {}", root, err))?;
+
+    *SEMIHOSTING.lock().unwrap() = Some(Semihosting {
+        root,
+        cmdline: cmdline.to_string(),
+        handles: HashMap::new(),
+        next_handle: 1,
+        errno: 0,
+    });
+
+    ENABLED.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+fn read_guest_u32(cpu: &mut cpu::Cpu, addr: CpuReg) -> Option<CpuReg> {
+    bus::get_bus().load(addr as BusType, 32, &mut cpu.mmu).ok()
+}
+
+fn write_guest_u32(cpu: &mut cpu::Cpu, addr: CpuReg, data: CpuReg) -> Option<()> {
+    bus::get_bus()
+        .store(addr as BusType, data as BusType, 32, &mut cpu.mmu)
+        .ok()
+}
+
+fn read_guest_bytes(cpu: &mut cpu::Cpu, addr: CpuReg, len: CpuReg) -> Option<Vec<u8>> {
+    let bus = bus::get_bus();
+
+    (0..len)
+        .map(|i| {
+            bus.load((addr + i) as BusType, 8, &mut cpu.mmu)
+                .ok()
+                .map(|c| c as u8)
+        })
+        .collect()
+}
+
+fn write_guest_bytes(cpu: &mut cpu::Cpu, addr: CpuReg, data: &[u8]) -> Option<()> {
+    let bus = bus::get_bus();
+
+    for (i, c) in data.iter().enumerate() {
+        bus.store(addr + i as CpuReg, *c as BusType, 8, &mut cpu.mmu)
+            .ok()?;
+    }
+
+    Some(())
+}
+
+fn read_guest_cstr(cpu: &mut cpu::Cpu, addr: CpuReg) -> Option<Vec<u8>> {
+    let bus = bus::get_bus();
+    let mut data = Vec::new();
+
+    loop {
+        let c = bus
+            .load(addr + data.len() as CpuReg, 8, &mut cpu.mmu)
+            .ok()? as u8;
+
+        if c == 0 {
+            return Some(data);
+        }
+
+        data.push(c);
+    }
+}
+
+fn read_console_byte() -> u8 {
+    loop {
+        if let Some(c) = charbuf_read_data() {
+            return c;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
+
+fn io_errno(err: &std::io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(EINVAL)
+}
+
+impl Semihosting {
+    // Resolves a guest path against the root directory. Absolute guest paths are
+    // treated as relative to the root and anything that would escape it is rejected.
+    fn resolve_path(&self, name: &[u8]) -> Result<PathBuf, i32> {
+        let name = std::str::from_utf8(name).map_err(|_| EINVAL)?;
+        let mut path = self.root.clone();
+
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir | Component::RootDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(EACCES),
+            }
+        }
+
+        // A symlink as the last component could point outside of the root even when
+        // it dangles, and opening a missing file would follow it to create the target
+        if path
+            .symlink_metadata()
+            .is_ok_and(|meta| meta.file_type().is_symlink())
+        {
+            return Err(EACCES);
+        }
+
+        // Symlinks further up could still point outside of the root, so check the
+        // canonical location of whatever already exists on the host
+        let existing = if path.exists() {
+            path.canonicalize()
+        } else {
+            path.parent().unwrap_or(&self.root).canonicalize()
+        };
+
+        match existing {
+            Ok(existing) if existing.starts_with(&self.root) => Ok(path),
+            Ok(_) => Err(EACCES),
+            Err(err) => Err(io_errno(&err)),
+        }
+    }
+
+    fn add_handle(&mut self, handle: Handle) -> CpuReg {
+        let fd = self.next_handle;
+
+        self.next_handle += 1;
+        self.handles.insert(fd, handle);
+
+        fd
+    }
+
+    fn set_errno(&mut self, errno: i32) -> CpuReg {
+        self.errno = errno;
+
+        SEMIHOSTING_ERR
+    }
+
+    fn sys_open(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let name_ptr = read_guest_u32(cpu, args)?;
+        let mode = read_guest_u32(cpu, args + 4)?;
+        let name_len = read_guest_u32(cpu, args + 8)?;
+
+        let name = read_guest_bytes(cpu, name_ptr, name_len)?;
+
+        if name == TT_NAME.as_bytes() {
+            let handle = match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            };
+
+            return Some(self.add_handle(handle));
+        }
+
+        let path = match self.resolve_path(&name) {
+            Ok(path) => path,
+            Err(errno) => return Some(self.set_errno(errno)),
+        };
+
+        // Modes map to fopen's r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+        let mut options = OpenOptions::new();
+
+        match mode / 2 {
+            0 => options.read(true),
+            1 => options.read(true).write(true),
+            2 => options.write(true).create(true).truncate(true),
+            3 => options.read(true).write(true).create(true).truncate(true),
+            4 => options.append(true).create(true),
+            5 => options.read(true).append(true).create(true),
+            _ => return Some(self.set_errno(EINVAL)),
+        };
+
+        match options.open(path) {
+            Ok(file) => Some(self.add_handle(Handle::File(file))),
+            Err(err) => Some(self.set_errno(io_errno(&err))),
+        }
+    }
+
+    fn sys_close(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+
+        if self.handles.remove(&fd).is_none() {
+            return Some(self.set_errno(EBADF));
+        }
+
+        Some(0)
+    }
+
+    // Returns the number of bytes that were NOT written
+    fn sys_write(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+        let buf = read_guest_u32(cpu, args + 4)?;
+        let len = read_guest_u32(cpu, args + 8)?;
+
+        // The guest picks len, so the host side only ever holds one chunk
+        let mut total: CpuReg = 0;
+
+        while total < len {
+            let count = (len - total).min(CHUNK_SIZE as CpuReg);
+            let data = read_guest_bytes(cpu, buf + total, count)?;
+
+            let res = match self.handles.get_mut(&fd) {
+                Some(Handle::Stdout) => std::io::stdout().write_all(&data),
+                Some(Handle::Stderr) => std::io::stderr().write_all(&data),
+                Some(Handle::File(file)) => file.write_all(&data),
+                _ => {
+                    self.set_errno(EBADF);
+                    return Some(len);
+                }
+            };
+
+            if let Err(err) = res {
+                self.set_errno(io_errno(&err));
+                return Some(len - total);
+            }
+
+            total += count;
+        }
+
+        Some(0)
+    }
+
+    // Returns the number of bytes that were NOT read
+    fn sys_read(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+        let buf = read_guest_u32(cpu, args + 4)?;
+        let len = read_guest_u32(cpu, args + 8)?;
+
+        // The guest picks len, so the host side only ever holds one chunk
+        let mut data = vec![0u8; (len as usize).min(CHUNK_SIZE)];
+        let mut total: usize = 0;
+
+        while total < len as usize {
+            let want = (len as usize - total).min(data.len());
+
+            let res = match self.handles.get_mut(&fd) {
+                Some(Handle::Stdin) => {
+                    let mut count = 0;
+                    let mut line_end = false;
+
+                    while count < want && !line_end {
+                        data[count] = read_console_byte();
+                        line_end = data[count] == b'\n' || data[count] == b'\r';
+                        count += 1;
+                    }
+
+                    // A line is all one read returns from the console
+                    Ok((count, line_end))
+                }
+                Some(Handle::File(file)) => {
+                    file.read(&mut data[..want]).map(|count| (count, false))
+                }
+                _ => {
+                    self.set_errno(EBADF);
+                    return Some(len);
+                }
+            };
+
+            match res {
+                Ok((count, done)) => {
+                    write_guest_bytes(cpu, buf + total as CpuReg, &data[..count])?;
+                    total += count;
+
+                    if count < want || done {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    self.set_errno(io_errno(&err));
+
+                    if total == 0 {
+                        return Some(len);
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        Some(len - total as CpuReg)
+    }
+
+    fn sys_seek(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+        let pos = read_guest_u32(cpu, args + 4)?;
+
+        let res = match self.handles.get_mut(&fd) {
+            Some(Handle::File(file)) => file.seek(SeekFrom::Start(pos as u64)),
+            _ => return Some(self.set_errno(EBADF)),
+        };
+
+        match res {
+            Ok(_) => Some(0),
+            Err(err) => Some(self.set_errno(io_errno(&err))),
+        }
+    }
+
+    fn sys_flen(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+
+        let res = match self.handles.get(&fd) {
+            Some(Handle::File(file)) => file.metadata(),
+            _ => return Some(self.set_errno(EBADF)),
+        };
+
+        match res {
+            Ok(metadata) => Some(metadata.len() as CpuReg),
+            Err(err) => Some(self.set_errno(io_errno(&err))),
+        }
+    }
+
+    fn sys_istty(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let fd = read_guest_u32(cpu, args)?;
+
+        match self.handles.get(&fd) {
+            Some(Handle::File(_)) => Some(0),
+            Some(_) => Some(1),
+            None => Some(self.set_errno(EBADF)),
+        }
+    }
+
+    fn sys_tmpnam(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let buf = read_guest_u32(cpu, args)?;
+        let id = read_guest_u32(cpu, args + 4)?;
+        let len = read_guest_u32(cpu, args + 8)?;
+
+        let mut name = format!("tmp{:03}", id & 0xff).into_bytes();
+        name.push(0);
+
+        if name.len() > len as usize {
+            return Some(self.set_errno(EINVAL));
+        }
+
+        write_guest_bytes(cpu, buf, &name)?;
+
+        Some(0)
+    }
+
+    fn sys_remove(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let name_ptr = read_guest_u32(cpu, args)?;
+        let name_len = read_guest_u32(cpu, args + 4)?;
+
+        let name = read_guest_bytes(cpu, name_ptr, name_len)?;
+
+        let path = match self.resolve_path(&name) {
+            Ok(path) => path,
+            Err(errno) => return Some(self.set_errno(errno)),
+        };
+
+        match std::fs::remove_file(path) {
+            Ok(_) => Some(0),
+            Err(err) => Some(self.set_errno(io_errno(&err))),
+        }
+    }
+
+    fn sys_rename(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let old_ptr = read_guest_u32(cpu, args)?;
+        let old_len = read_guest_u32(cpu, args + 4)?;
+        let new_ptr = read_guest_u32(cpu, args + 8)?;
+        let new_len = read_guest_u32(cpu, args + 12)?;
+
+        let old_name = read_guest_bytes(cpu, old_ptr, old_len)?;
+        let new_name = read_guest_bytes(cpu, new_ptr, new_len)?;
+
+        let paths = self
+            .resolve_path(&old_name)
+            .and_then(|old| Ok((old, self.resolve_path(&new_name)?)));
+
+        let (old_path, new_path) = match paths {
+            Ok(paths) => paths,
+            Err(errno) => return Some(self.set_errno(errno)),
+        };
+
+        match std::fs::rename(old_path, new_path) {
+            Ok(_) => Some(0),
+            Err(err) => Some(self.set_errno(io_errno(&err))),
+        }
+    }
+
+    fn sys_get_cmdline(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let buf = read_guest_u32(cpu, args)?;
+        let len = read_guest_u32(cpu, args + 4)?;
+
+        let mut cmdline = self.cmdline.clone().into_bytes();
+        cmdline.push(0);
+
+        if cmdline.len() > len as usize {
+            return Some(self.set_errno(EINVAL));
+        }
+
+        write_guest_bytes(cpu, buf, &cmdline)?;
+        write_guest_u32(cpu, args + 4, cmdline.len() as CpuReg - 1)?;
+
+        Some(0)
+    }
+
+    fn sys_heapinfo(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let block = read_guest_u32(cpu, args)?;
+
+        // Heap base, heap limit, stack base, stack limit: zero lets the
+        // C library pick its own defaults
+        for i in 0..4 {
+            write_guest_u32(cpu, block + i * 4, 0)?;
+        }
+
+        Some(0)
+    }
+
+    fn sys_elapsed(&mut self, cpu: &mut cpu::Cpu, args: CpuReg) -> Option<CpuReg> {
+        let ticks = util::timebase_since_program_start();
+
+        write_guest_u32(cpu, args, ticks as CpuReg)?;
+        write_guest_u32(cpu, args + 4, (ticks >> 32) as CpuReg)?;
+
+        Some(0)
+    }
+
+    fn handle_op(&mut self, cpu: &mut cpu::Cpu, op: CpuReg, args: CpuReg) -> Option<CpuReg> {
+        match op {
+            SYS_OPEN => self.sys_open(cpu, args),
+            SYS_CLOSE => self.sys_close(cpu, args),
+            SYS_WRITEC => {
+                let c = read_guest_bytes(cpu, args, 1)?;
+
+                std::io::stdout().write_all(&c).unwrap();
+
+                Some(0)
+            }
+            SYS_WRITE0 => {
+                let s = read_guest_cstr(cpu, args)?;
+
+                std::io::stdout().write_all(&s).unwrap();
+
+                Some(0)
+            }
+            SYS_WRITE => self.sys_write(cpu, args),
+            SYS_READ => self.sys_read(cpu, args),
+            SYS_READC => Some(read_console_byte() as CpuReg),
+            SYS_ISERROR => {
+                let status = read_guest_u32(cpu, args)?;
+
+                Some(((status as i32) < 0) as CpuReg)
+            }
+            SYS_ISTTY => self.sys_istty(cpu, args),
+            SYS_SEEK => self.sys_seek(cpu, args),
+            SYS_FLEN => self.sys_flen(cpu, args),
+            SYS_TMPNAM => self.sys_tmpnam(cpu, args),
+            SYS_REMOVE => self.sys_remove(cpu, args),
+            SYS_RENAME => self.sys_rename(cpu, args),
+            SYS_CLOCK => {
+                let centiseconds =
+                    util::timebase_since_program_start() / (CPU_TIMEBASE_FREQ as u64 / 100);
+
+                Some(centiseconds as CpuReg)
+            }
+            SYS_TIME => {
+                let secs = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_secs())
+                    .unwrap_or(0);
+
+                Some(secs as CpuReg)
+            }
+            // Running host commands on behalf of the guest is not allowed
+            SYS_SYSTEM => Some(self.set_errno(EACCES)),
+            SYS_ERRNO => Some(self.errno as CpuReg),
+            SYS_GET_CMDLINE => self.sys_get_cmdline(cpu, args),
+            SYS_HEAPINFO => self.sys_heapinfo(cpu, args),
+            SYS_ELAPSED => self.sys_elapsed(cpu, args),
+            SYS_TICKFREQ => Some(CPU_TIMEBASE_FREQ),
+            _ => {
+                println!("Unhandled semihosting operation {:#x}", op);
+
+                Some(self.set_errno(EINVAL))
+            }
+        }
+    }
+}
+
+fn exit(exit_code: i32) -> ! {
+    std::io::stdout().flush().unwrap();
+
+    syscon::request_poweroff(exit_code);
+    cpu::get_cpu().exception = Exception::Poweroff;
+    ReturnableImpl::throw();
+}
+
+// Services the operation in a0 with the parameter block pointed to by a1,
+// the result is written back to a0
+pub fn handle_call(cpu: &mut cpu::Cpu) {
+    let op = cpu.regs[RegName::A0 as usize];
+    let args = cpu.regs[RegName::A1 as usize];
+
+    // On 32-bit targets SYS_EXIT passes the reason code directly instead of a pointer
+    if op == SYS_EXIT {
+        exit(if args == ADP_STOPPED_APPLICATION_EXIT {
+            0
+        } else {
+            1
+        });
+    }
+
+    if op == SYS_EXIT_EXTENDED {
+        let reason = read_guest_u32(cpu, args).unwrap_or(0);
+        let subcode = read_guest_u32(cpu, args + 4).unwrap_or(1);
+
+        exit(if reason == ADP_STOPPED_APPLICATION_EXIT {
+            subcode as i32
+        } else {
+            1
+        });
+    }
+
+    let mut semihosting = SEMIHOSTING.lock().unwrap();
+    let semihosting = semihosting.as_mut().unwrap();
+
+    let ret = semihosting
+        .handle_op(cpu, op, args)
+        .unwrap_or(SEMIHOSTING_ERR);
+
+    cpu.regs[RegName::A0 as usize] = ret;
+}
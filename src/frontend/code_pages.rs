@@ -1,50 +1,107 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use hashbrown::HashMap;
 
 use crate::backend::common::HostEncodedInsn;
+use crate::bus::BusType;
 use crate::xmem::{self, AllocationError, PageState};
 
+pub const JIT_CACHE_DEFAULT_MB: usize = 256;
+
+static JIT_CACHE_LIMIT: AtomicUsize = AtomicUsize::new(JIT_CACHE_DEFAULT_MB << 20);
+
+pub fn set_cache_limit_mb(mb: usize) {
+    JIT_CACHE_LIMIT.store(mb << 20, Ordering::Release);
+}
+
+pub fn cache_limit() -> usize {
+    JIT_CACHE_LIMIT.load(Ordering::Acquire)
+}
+
+struct CodePageEntry {
+    xmem: xmem::CodePage,
+    // Physical guest page the translation belongs to
+    gpfn: BusType,
+    last_used: u64,
+}
+
 pub struct CodePages {
-    xmem: HashMap<usize, xmem::CodePage>,
+    xmem: HashMap<usize, CodePageEntry>,
+    // last_used -> index, oldest first
+    lru: BTreeMap<u64, usize>,
     idx: usize,
+    use_counter: u64,
 }
 
 impl CodePages {
     pub fn new() -> CodePages {
         CodePages {
             xmem: HashMap::new(),
+            lru: BTreeMap::new(),
             idx: 0,
+            use_counter: 0,
         }
     }
 
     pub fn get_code_page(&mut self, idx: usize) -> &mut xmem::CodePage {
-        self.xmem.get_mut(&idx).unwrap()
+        &mut self.xmem.get_mut(&idx).unwrap().xmem
     }
 
-    pub fn alloc_code_page(&mut self) -> (&mut xmem::CodePage, usize) {
+    pub fn alloc_code_page(&mut self, gpfn: BusType) -> (&mut xmem::CodePage, usize) {
         let idx = self.idx;
         self.idx += 1;
+        self.use_counter += 1;
+
+        let entry = CodePageEntry {
+            xmem: xmem::CodePage::new(),
+            gpfn,
+            last_used: self.use_counter,
+        };
+
+        self.xmem.insert(idx, entry);
+        self.lru.insert(self.use_counter, idx);
 
-        let xmem = xmem::CodePage::new();
-        self.xmem.insert(idx, xmem);
+        (&mut self.xmem.get_mut(&idx).unwrap().xmem, idx)
+    }
+
+    // Called whenever the exec loop enters a translation, which is what the
+    // eviction order is based on
+    pub fn touch(&mut self, idx: usize) {
+        let Some(entry) = self.xmem.get_mut(&idx) else {
+            return;
+        };
+
+        // Already the most recent one, which is what tight loops keep hitting
+        if entry.last_used == self.use_counter {
+            return;
+        }
+
+        self.use_counter += 1;
+
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.use_counter, idx);
 
-        (self.xmem.get_mut(&idx).unwrap(), idx)
+        entry.last_used = self.use_counter;
     }
 
     pub fn apply_insn(&mut self, idx: usize, insn: HostEncodedInsn) -> Result<(), AllocationError> {
-        self.xmem.get_mut(&idx).unwrap().push(insn.as_slice())
+        self.get_code_page(idx).push(insn.as_slice())
     }
 
     pub fn remove_code_page(&mut self, idx: usize) {
-        self.xmem.get_mut(&idx).unwrap().dealloc();
-        self.xmem.remove(&idx);
+        self.get_code_page(idx).dealloc();
+
+        let entry = self.xmem.remove(&idx).unwrap();
+        self.lru.remove(&entry.last_used);
     }
 
     pub fn mark_all_pages(&mut self, state: PageState) {
-        for xmem in self.xmem.iter_mut() {
+        for entry in self.xmem.values_mut() {
             match state {
-                PageState::ReadWrite => xmem.1.mark_rw().unwrap(),
-                PageState::ReadExecute => xmem.1.mark_rx().unwrap(),
-                PageState::Invalid => xmem.1.mark_invalid().unwrap(),
+                PageState::ReadWrite => entry.xmem.mark_rw().unwrap(),
+                PageState::ReadExecute => entry.xmem.mark_rx().unwrap(),
+                PageState::Invalid => entry.xmem.mark_invalid().unwrap(),
             }
         }
     }
@@ -53,11 +110,24 @@ impl CodePages {
         self.xmem.len()
     }
 
+    // Host memory committed to translations
+    pub fn size(&self) -> usize {
+        xmem::committed_size()
+    }
+
+    // Translation that was entered the longest time ago and its guest page
+    pub fn least_recently_used(&self) -> Option<(usize, BusType)> {
+        self.lru
+            .first_key_value()
+            .map(|(_, idx)| (*idx, self.xmem[idx].gpfn))
+    }
+
     pub fn cleanup(&mut self) {
-        for xmem in self.xmem.iter_mut() {
-            xmem.1.dealloc();
+        for entry in self.xmem.values_mut() {
+            entry.xmem.dealloc();
         }
 
         self.xmem.clear();
+        self.lru.clear();
     }
 }
@@ -15,7 +15,7 @@ pub fn decode_csr(insn: u32) -> DecodeRet {
                     let funct7 = ((insn >> 25) & 0b1111111) as u8;
 
                     let einsn = match funct7 {
-                        0b0001001 => CsrImpl::emit_sfence_vma(),
+                        0b0001001 => CsrImpl::emit_sfence_vma(rs1, ((insn >> 20) & 0x1f) as u8),
                         _ => Err(JitError::InvalidInstruction(insn)),
                     };
 
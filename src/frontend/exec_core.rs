@@ -3,15 +3,13 @@ use crate::backend::{
     BackendCore, FastmemHandleType, ReturnStatus, ReturnableHandler, ReturnableImpl,
 };
 use crate::bus::dtb::DTB_BEGIN_ADDR;
-use crate::bus::mmu::{AccessType, Mmu};
+use crate::bus::mmu::{self, AccessType, Mmu};
 use crate::bus::{self, tlb, BusType};
 use crate::cpu::{self, csr, CpuReg};
 use crate::cpu::{trap, RegName};
 pub use crate::frontend::parse_core::*;
 use crate::xmem::PageState;
 
-use super::insn_lookup::InsnMappingData;
-
 pub struct ExecCore {
     parse_core: ParseCore,
 }
@@ -26,13 +24,17 @@ impl ExecCore {
     fn get_jit_ptr(&mut self) -> *mut u8 {
         let cpu = cpu::get_cpu();
 
+        if !cpu.dma_dirty_gpfns.is_empty() {
+            self.parse_core.invalidate_dma_pages();
+        }
+
         if let Some(int) = trap::has_pending_interrupt(cpu) {
             trap::handle_interrupt(int, cpu);
 
             if cpu.pending_interrupt_number as u64 != 0 {
-                bus::get_bus()
-                    .get_plic()
-                    .update_pending(cpu.pending_interrupt_number as u64);
+                if let Some(plic) = bus::get_bus().get_plic() {
+                    plic.update_pending(cpu.pending_interrupt_number as u64);
+                }
             }
         }
 
@@ -63,6 +65,8 @@ impl ExecCore {
             next_phys_pc.unwrap()
         };
 
+        cpu.current_phys_page = next_phys_pc & RV_PAGE_MASK as BusType;
+
         let mut insn_data = cpu.insn_map.get_by_guest_idx(next_phys_pc);
         if insn_data.is_none() {
             self.parse_core.parse_gpfn(None).unwrap();
@@ -70,7 +74,11 @@ impl ExecCore {
             insn_data = cpu.insn_map.get_by_guest_idx(next_phys_pc);
         }
 
-        insn_data.unwrap().host_ptr
+        let insn_data = insn_data.unwrap();
+
+        self.parse_core.touch(insn_data.jit_block_idx);
+
+        insn_data.host_ptr
     }
 
     pub fn exec_loop(&mut self, core_id: CpuReg, initial_pc: CpuReg) {
@@ -91,7 +99,14 @@ impl ExecCore {
             cpu.jump_count = 0;
             cpu.next_pc = 0;
 
-            let ret = if cpu.mmu.is_active() {
+            // Loads and stores go through the TLB probe whenever they are translated or
+            // PMP has to see them, the untranslated path goes straight to fastmem
+            let data_mode = mmu::access_mode(cpu, AccessType::Load);
+            let translated = cpu.mmu.is_active() && data_mode != csr::MppMode::Machine;
+
+            tlb::select_tlb(translated, data_mode);
+
+            let ret = if cpu.mmu.is_active() || cpu.pmp.restricts(data_mode) {
                 ReturnableImpl::handle(|| unsafe {
                     BackendCoreImpl::call_jit_ptr(host_ptr);
                 })
@@ -114,21 +129,7 @@ impl ExecCore {
                     }
                 }
                 ReturnStatus::ReturnAccessViolation => {
-                    let mut guest_exception_pc: Option<&InsnMappingData> = None;
-                    let likely_offset = BackendCoreImpl::fastmem_violation_likely_offset();
-                    let likely_offset_lower = likely_offset - 4;
-                    let likely_offset_upper = likely_offset + 4;
-
-                    let addr = ret.exception_address as *mut u8;
-
-                    for i in likely_offset_lower..likely_offset_upper {
-                        let exc = cpu.insn_map.get_by_host_ptr(addr.wrapping_sub(i));
-
-                        if exc.is_some() {
-                            guest_exception_pc = Some(exc.unwrap());
-                            break;
-                        }
-                    }
+                    let guest_exception_pc = cpu.insn_map.get_by_host_addr(ret.exception_address);
 
                     if guest_exception_pc.is_none() {
                         println!(
@@ -169,7 +170,8 @@ impl ExecCore {
                 }
             }
 
-            if self.handle_guest_exception() {
+            // Devices polled from the tick thread can power the machine off as well
+            if self.handle_guest_exception() || bus::syscon::should_stop() {
                 return;
             }
         }
@@ -189,12 +191,17 @@ impl ExecCore {
 
         match cpu.exception {
             cpu::Exception::MmuStateUpdate => {
+                // The TLB keeps its entries, sfence.vma already flushed what it named
                 cpu.next_pc = cpu.c_exception_pc as CpuReg + INSN_SIZE as CpuReg;
-                tlb::get_current_tlb().flush();
             }
             cpu::Exception::BlockExit => {
                 cpu.next_pc = cpu.c_exception_pc as CpuReg;
             }
+            cpu::Exception::HotBlock => {
+                // Resumes at the back-edge, which enters the new trace if there is one
+                cpu.next_pc = cpu.c_exception_pc as CpuReg;
+                self.parse_core.compile_trace(cpu.next_pc);
+            }
             cpu::Exception::ForwardJumpFault(pc) => {
                 // We'll enter here both on unmapped jumps and missaligned jumps
                 // In the case of missaligned jumps, we'll forward the exception
@@ -209,18 +216,36 @@ impl ExecCore {
                     cpu.next_pc = pc;
                 }
             }
-            cpu::Exception::InvalidateJitBlock(gpfn, should_reparse) => {
-                self.parse_core.invalidate(gpfn, should_reparse);
+            cpu::Exception::InvalidateJitBlock(addr) => {
+                self.parse_core.invalidate(addr);
                 cpu.next_pc = cpu.c_exception_pc as CpuReg + INSN_SIZE as CpuReg;
             }
+            cpu::Exception::StaleInsn => {
+                // The guest wrote over this instruction since it was translated
+                cpu.next_pc = cpu.c_exception_pc as CpuReg;
+                self.parse_core.retranslate(cpu.next_pc);
+            }
             cpu::Exception::FastmemViolation => {}
-            cpu::Exception::DiscardJitBlock(_pc) => {
-                // If a mmu drops execute permission on a page, we can discard the jit block
-                unimplemented!()
+            cpu::Exception::DiscardJitBlock(phys_gpfn) => {
+                // The sfence.vma or satp write remapped the page it ran from or took
+                // execute permission away, the next fetch goes through the new mapping
+                cpu.next_pc = cpu.c_exception_pc as CpuReg + INSN_SIZE as CpuReg;
+                self.parse_core.discard(phys_gpfn);
             }
             cpu::Exception::Wfi => {
                 std::thread::sleep(std::time::Duration::from_millis(1));
 
+                // Idle time moves guest time on as if the hart kept running
+                if cpu::count_progress() {
+                    let progress = cpu::hart_progress(cpu.core_id as usize);
+
+                    progress.store(
+                        progress.load(std::sync::atomic::Ordering::Relaxed)
+                            + cpu::CPU_NOMINAL_IPS / 1000,
+                        std::sync::atomic::Ordering::Relaxed,
+                    );
+                }
+
                 cpu.next_pc = cpu.c_exception_pc as CpuReg + INSN_SIZE as CpuReg;
                 cpu.csr.write_bit_sstatus(csr::bits::SIE, true); // For some reason Linux is doing WFI inside compat_sys_ppoll_time64 where interrupts are disabled, no idea why
             }
@@ -232,7 +257,7 @@ impl ExecCore {
                 cpu.next_pc = cpu.c_exception_pc as CpuReg + INSN_SIZE as CpuReg;
             }
             cpu::Exception::Mret | cpu::Exception::Sret => {}
-            cpu::Exception::Reboot => {
+            cpu::Exception::Reboot | cpu::Exception::Poweroff => {
                 return true;
             }
             cpu::Exception::None => {
@@ -259,7 +284,7 @@ pub fn exec_core_thread(cpu_core_idx: usize, initial_pc: CpuReg) {
         let bus = bus::get_bus();
         let cpu = unsafe { &mut *(cpu as *mut cpu::Cpu) };
 
-        while !bus::syscon::should_reboot() {
+        while !bus::syscon::should_stop() {
             std::thread::sleep(std::time::Duration::from_millis(5));
 
             bus.tick_async(cpu);
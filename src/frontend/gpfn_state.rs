@@ -1,24 +1,33 @@
 use crate::{
     bus,
     cpu::{self, CpuReg},
-    frontend::exec_core::RV_PAGE_OFFSET_MASK,
+    frontend::exec_core::{INSN_SIZE, RV_PAGE_OFFSET_MASK, RV_PAGE_SIZE},
     xmem::{PageAllocator, PageState},
 };
 
+const STALE_WORDS: usize = RV_PAGE_SIZE / INSN_SIZE / u64::BITS as usize;
+
 pub struct GpfnState {
     pub addr: CpuReg,
     state: PageState,
+    // Instructions whose translation was patched out after the guest wrote over them
+    stale: [u64; STALE_WORDS],
 }
 
 impl GpfnState {
     pub fn new(addr: CpuReg, state: PageState) -> GpfnState {
-        GpfnState { addr, state }
+        GpfnState {
+            addr,
+            state,
+            stale: [0; STALE_WORDS],
+        }
     }
 
     pub fn default() -> GpfnState {
         GpfnState {
             addr: 0,
             state: PageState::ReadWrite,
+            stale: [0; STALE_WORDS],
         }
     }
 
@@ -32,6 +41,28 @@ impl GpfnState {
     pub fn get_state(&self) -> PageState {
         self.state
     }
+
+    pub fn is_stale(&self, offset: CpuReg) -> bool {
+        let word = offset as usize / INSN_SIZE;
+
+        self.stale[word / 64] & (1 << (word % 64)) != 0
+    }
+
+    pub fn mark_stale(&mut self, offset: CpuReg) {
+        let word = offset as usize / INSN_SIZE;
+
+        self.stale[word / 64] |= 1 << (word % 64);
+    }
+
+    // Whether a store of size bytes at addr changes an instruction that still has a
+    // live translation. Anything else in the page is data as far as the jit cares
+    pub fn is_code_write(&self, addr: CpuReg, size: usize) -> bool {
+        let first = addr as usize & RV_PAGE_OFFSET_MASK;
+        let last = std::cmp::min(first + size, RV_PAGE_SIZE) - 1;
+
+        (first / INSN_SIZE..=last / INSN_SIZE)
+            .any(|word| !self.is_stale((word * INSN_SIZE) as CpuReg))
+    }
 }
 
 pub struct GpfnStateSet {
@@ -86,6 +117,14 @@ impl GpfnStateSet {
         self.gpfn_set.get_mut(&gpfn)
     }
 
+    pub fn mark_stale(&mut self, phys_addr: CpuReg) {
+        let gpfn = phys_addr & !(RV_PAGE_OFFSET_MASK as CpuReg);
+
+        if let Some(gpfn_state) = self.gpfn_set.get_mut(&gpfn) {
+            gpfn_state.mark_stale(phys_addr & RV_PAGE_OFFSET_MASK as CpuReg);
+        }
+    }
+
     pub fn set_gpfn_state(&mut self, gpfn: CpuReg, state: PageState) {
         if let Some(gpfn_state) = self.gpfn_set.get_mut(&gpfn) {
             gpfn_state.set_state(state);
@@ -1,35 +1,65 @@
 use crate::bus::BusType;
 use hashbrown::HashMap;
+use std::collections::BTreeMap;
 
 use super::exec_core::{RV_PAGE_MASK, RV_PAGE_SIZE};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct InsnMappingData {
     pub host_ptr: *mut u8,
+    // One past the last host byte emitted for the instruction
+    pub host_end_ptr: *mut u8,
     pub guest_idx: BusType,
     pub jit_block_idx: usize,
 }
 
 pub struct InsnData {
     mapping: HashMap<BusType, InsnMappingData>,
+    // Host start address -> guest address, for attributing host faults
+    host_index: BTreeMap<usize, BusType>,
+    // First tier code copied into traces, keyed by host start address
+    trace_index: BTreeMap<usize, InsnMappingData>,
 }
 
 impl InsnData {
     pub fn new() -> InsnData {
         InsnData {
             mapping: HashMap::new(),
+            host_index: BTreeMap::new(),
+            trace_index: BTreeMap::new(),
         }
     }
 
-    pub fn add_mapping(&mut self, guest_idx: BusType, host_ptr: *mut u8, jit_block_idx: usize) {
-        self.mapping.insert(
+    pub fn add_mapping(
+        &mut self,
+        guest_idx: BusType,
+        host_ptr: *mut u8,
+        host_end_ptr: *mut u8,
+        jit_block_idx: usize,
+    ) {
+        let old = self.mapping.insert(
             guest_idx,
             InsnMappingData {
                 host_ptr,
+                host_end_ptr,
                 guest_idx,
                 jit_block_idx,
             },
         );
+
+        if let Some(old) = old {
+            self.remove_host_index(&old);
+        }
+
+        self.host_index.insert(host_ptr as usize, guest_idx);
+    }
+
+    fn remove_host_index(&mut self, mapping: &InsnMappingData) {
+        let host_addr = mapping.host_ptr as usize;
+
+        if self.host_index.get(&host_addr) == Some(&mapping.guest_idx) {
+            self.host_index.remove(&host_addr);
+        }
     }
 
     pub fn get_by_guest_idx(&self, guest_idx: BusType) -> Option<&InsnMappingData> {
@@ -37,17 +67,58 @@ impl InsnData {
     }
 
     pub fn get_by_host_ptr(&self, host_ptr: *mut u8) -> Option<&InsnMappingData> {
-        for (_, mapping) in self.mapping.iter() {
-            if mapping.host_ptr == host_ptr {
-                return Some(mapping);
+        let guest_idx = self.host_index.get(&(host_ptr as usize))?;
+
+        self.mapping.get(guest_idx)
+    }
+
+    // The instruction whose host code contains host_addr
+    pub fn get_by_host_addr(&self, host_addr: usize) -> Option<InsnMappingData> {
+        if let Some((_, mapping)) = self.trace_index.range(..=host_addr).next_back() {
+            if host_addr < mapping.host_end_ptr as usize {
+                return Some(*mapping);
             }
         }
 
-        None
+        let (_, guest_idx) = self.host_index.range(..=host_addr).next_back()?;
+
+        let mapping = self.mapping.get(guest_idx)?;
+
+        if host_addr < mapping.host_end_ptr as usize {
+            Some(*mapping)
+        } else {
+            None
+        }
+    }
+
+    // Traces only need their host ranges, they are never looked up by guest address
+    pub fn add_trace_range(
+        &mut self,
+        guest_idx: BusType,
+        host_ptr: *mut u8,
+        host_end_ptr: *mut u8,
+        jit_block_idx: usize,
+    ) {
+        self.trace_index.insert(
+            host_ptr as usize,
+            InsnMappingData {
+                host_ptr,
+                host_end_ptr,
+                guest_idx,
+                jit_block_idx,
+            },
+        );
+    }
+
+    pub fn remove_trace_ranges(&mut self, jit_block_idx: usize) {
+        self.trace_index
+            .retain(|_, mapping| mapping.jit_block_idx != jit_block_idx);
     }
 
     pub fn remove_by_guest_idx(&mut self, guest_idx: BusType) {
-        self.mapping.remove(&guest_idx);
+        if let Some(mapping) = self.mapping.remove(&guest_idx) {
+            self.remove_host_index(&mapping);
+        }
     }
 
     pub fn remove_by_guest_region(&mut self, guest_start: BusType, guest_end: BusType) {
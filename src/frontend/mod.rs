@@ -1,10 +1,12 @@
-mod code_pages;
+pub mod code_pages;
 pub mod exec_core;
 pub mod gpfn_state;
 pub mod insn_lookup;
 pub mod parse_core;
+pub mod trace;
 
 mod csr;
 mod rva;
+mod rvb;
 mod rvi;
 mod rvm;
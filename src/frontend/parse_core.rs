@@ -1,12 +1,16 @@
 use crate::backend::common as JitCommon;
 use crate::backend::common::BackendCore;
+use crate::backend::common::Csr;
 use crate::backend::common::HostEncodedInsn;
+use crate::backend::CsrImpl;
 
 use crate::backend::target::core::BackendCoreImpl;
+use crate::backend::target::trace::TraceEmitter;
 use crate::bus::bus;
 use crate::bus::bus::BusType;
-use crate::bus::mmu::AccessType;
+use crate::bus::mmu::{AccessType, Mmu};
 use crate::cpu;
+use crate::cpu::semihosting;
 use crate::cpu::CpuReg;
 use crate::cpu::Exception;
 use crate::xmem::AllocationError;
@@ -14,11 +18,15 @@ use crate::xmem::CodePage;
 
 use crate::frontend::csr;
 use crate::frontend::rva;
+use crate::frontend::rvb;
 use crate::frontend::rvi;
 use crate::frontend::rvm;
 use crate::xmem::PageState;
 
-use super::code_pages::CodePages;
+use hashbrown::HashMap;
+
+use super::code_pages::{self, CodePages};
+use super::trace::{self, TraceOp, Traces};
 
 pub const INSN_SIZE: usize = 4; // Unlikely for rvc to be supported
 pub const INSN_SIZE_BITS: usize = INSN_SIZE * 8;
@@ -33,25 +41,214 @@ pub const RV_PAGE_MASK: usize = !RV_PAGE_OFFSET_MASK;
 
 pub type DecoderFn = fn(u32) -> JitCommon::DecodeRet;
 
+// Pages retranslated at least this often because of code writes are reported on exit
+const SMC_REPORT_THRESHOLD: u32 = 16;
+
 pub struct ParseCore {
     code_pages: CodePages,
+    traces: Traces,
+    // Physical page -> times it was translated again after the guest wrote over its code
+    smc_retranslations: HashMap<BusType, u32>,
 }
 
 impl ParseCore {
     pub fn new() -> ParseCore {
         ParseCore {
             code_pages: CodePages::new(),
+            traces: Traces::new(),
+            smc_retranslations: HashMap::new(),
         }
     }
 
-    pub fn invalidate(&mut self, gpfn: CpuReg, should_reparse: bool) {
+    // A store landed on translated code at addr. Only the instructions it overlaps
+    // are patched out, the rest of the page keeps running from the old translation
+    pub fn invalidate(&mut self, addr: CpuReg) {
+        let cpu = cpu::get_cpu();
+        let bus = bus::get_bus();
+
+        let phys_addr = bus
+            .translate(addr, &mut cpu.mmu, AccessType::Store)
+            .expect("Failed to translate address for invalidation");
+
+        let phys_gpfn = phys_addr & RV_PAGE_MASK as BusType;
+
+        if !cpu.gpfn_state.contains_gpfn(phys_gpfn) {
+            return;
+        }
+
+        // Device backed code (flash) can change under a command, it gets parsed again on use
+        if !bus.is_dram_addr(phys_gpfn) {
+            self.release_phys_page(phys_gpfn);
+            return;
+        }
+
+        for idx in self.traces.invalidate_page(phys_gpfn) {
+            cpu.insn_map.remove_trace_ranges(idx);
+            self.code_pages.remove_code_page(idx);
+        }
+
+        // The exact store size is not known here, the word after an unaligned
+        // store is patched out as well
+        let first = phys_addr & !(INSN_SIZE as BusType - 1);
+        let last = (phys_addr + INSN_SIZE as BusType - 1) & !(INSN_SIZE as BusType - 1);
+
+        for phys_pc in [first, last] {
+            if phys_pc & RV_PAGE_MASK as BusType != phys_gpfn {
+                continue;
+            }
+
+            if !self.patch_out_insn(phys_pc) {
+                *self.smc_retranslations.entry(phys_gpfn).or_insert(0) += 1;
+
+                self.release_phys_page(phys_gpfn);
+                return;
+            }
+        }
+    }
+
+    // Points the start of the instruction at an exit that translates its page again,
+    // fails when its host code is too short to hold the jump
+    fn patch_out_insn(&mut self, phys_pc: BusType) -> bool {
+        let cpu = cpu::get_cpu();
+
+        let offset = phys_pc & RV_PAGE_OFFSET_MASK as BusType;
+
+        let is_stale = cpu
+            .gpfn_state
+            .get_gpfn_state(phys_pc & RV_PAGE_MASK as BusType)
+            .is_some_and(|state| state.is_stale(offset));
+
+        if is_stale {
+            return true;
+        }
+
+        let Some(mapping) = cpu.insn_map.get_by_guest_idx(phys_pc).copied() else {
+            return true;
+        };
+
+        let code_page = self.code_pages.get_code_page(mapping.jit_block_idx);
+
+        let jump = BackendCoreImpl::emit_jump(mapping.host_ptr, code_page.as_end_ptr());
+
+        if jump.size() > mapping.host_end_ptr as usize - mapping.host_ptr as usize {
+            return false;
+        }
+
+        let saved_offset = cpu.current_gpfn_offset;
+        cpu.current_gpfn_offset = offset;
+
+        let exit = BackendCoreImpl::emit_ret_with_exception(Exception::StaleInsn);
+
+        cpu.current_gpfn_offset = saved_offset;
+
+        code_page.mark_rw().unwrap();
+        code_page.push(exit.as_slice()).expect("Out of memory");
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(jump.as_slice().as_ptr(), mapping.host_ptr, jump.size());
+        }
+
+        code_page.mark_rx().unwrap();
+
+        cpu.gpfn_state.mark_stale(phys_pc);
+
+        true
+    }
+
+    // Replaces the translation of the page holding pc with a fresh one, after the
+    // guest wrote over code in it
+    pub fn retranslate(&mut self, pc: CpuReg) {
         let cpu = cpu::get_cpu();
         let bus = bus::get_bus();
 
         let phys_gpfn = bus
-            .translate(gpfn << RV_PAGE_SHIFT, &mut cpu.mmu, AccessType::Fetch)
+            .translate(pc & RV_PAGE_MASK as CpuReg, &mut cpu.mmu, AccessType::Fetch)
             .expect("Failed to translate gpfn for invalidation");
 
+        *self.smc_retranslations.entry(phys_gpfn).or_insert(0) += 1;
+
+        if cpu.gpfn_state.contains_gpfn(phys_gpfn) {
+            self.drop_phys_page(phys_gpfn);
+        }
+
+        self.parse_gpfn(Some(pc >> RV_PAGE_SHIFT as CpuReg))
+            .expect("Failed to parse page after invalidation");
+    }
+
+    // Drops the translation of a page no longer mapped executable where it ran from
+    pub fn discard(&mut self, phys_gpfn: BusType) {
+        let cpu = cpu::get_cpu();
+
+        if cpu.gpfn_state.contains_gpfn(phys_gpfn) {
+            self.release_phys_page(phys_gpfn);
+        }
+    }
+
+    // Drops the translation and gives the guest page its regular protection back
+    fn release_phys_page(&mut self, phys_gpfn: CpuReg) {
+        let bus = bus::get_bus();
+
+        self.drop_phys_page(phys_gpfn);
+
+        // Code outside of RAM lives in a device (flash), its page is made inaccessible
+        // again so accesses keep going through the device until it gets executed
+        if !bus.is_dram_addr(phys_gpfn) {
+            crate::xmem::PageAllocator::mark_page(phys_gpfn as *mut u8, 1, PageState::Invalid)
+                .expect("Failed to mark guest page as invalid after invalidation");
+        } else {
+            crate::xmem::PageAllocator::mark_page(phys_gpfn as *mut u8, 1, PageState::ReadWrite)
+                .expect("Failed to mark guest page as readwrite after invalidation");
+        }
+    }
+
+    // Keeps the translations within the --jit-cache-mb budget by throwing away
+    // the least recently entered ones, they get parsed again on their next use
+    fn evict_translations(&mut self) {
+        let cpu = cpu::get_cpu();
+        let limit = code_pages::cache_limit();
+
+        while self.code_pages.size() >= limit {
+            let Some((idx, gpfn)) = self.code_pages.least_recently_used() else {
+                break;
+            };
+
+            if let Some(head_phys) = self.traces.owner_of(idx) {
+                self.drop_trace(head_phys);
+                continue;
+            }
+
+            let mapped = cpu
+                .insn_map
+                .get_by_guest_idx(gpfn)
+                .is_some_and(|mapping| mapping.jit_block_idx == idx);
+
+            if mapped {
+                self.release_phys_page(gpfn);
+            } else {
+                self.code_pages.remove_code_page(idx);
+            }
+        }
+    }
+
+    pub fn touch(&mut self, idx: usize) {
+        self.code_pages.touch(idx);
+    }
+
+    // Pages written by device DMA are already writable, they only need their
+    // translations dropped so the next fetch parses them again
+    pub fn invalidate_dma_pages(&mut self) {
+        let cpu = cpu::get_cpu();
+
+        while let Some(phys_gpfn) = cpu.dma_dirty_gpfns.pop() {
+            if cpu.insn_map.get_by_guest_idx(phys_gpfn).is_some() {
+                self.drop_phys_page(phys_gpfn);
+            }
+        }
+    }
+
+    fn drop_phys_page(&mut self, phys_gpfn: CpuReg) {
+        let cpu = cpu::get_cpu();
+
         cpu.gpfn_state.remove_gpfn(phys_gpfn);
 
         let idx: usize = cpu
@@ -64,12 +261,18 @@ impl ParseCore {
 
         cpu.insn_map.remove_by_guest_page(phys_gpfn);
 
-        if should_reparse {
-            self.parse_gpfn(Some(gpfn))
-                .expect("Failed to parse page after invalidation");
-        } else {
-            crate::xmem::PageAllocator::mark_page(phys_gpfn as *mut u8, 1, PageState::ReadWrite)
-                .expect("Failed to mark guest page as readwrite after invalidation");
+        for idx in self.traces.drop_page(phys_gpfn) {
+            cpu.insn_map.remove_trace_ranges(idx);
+            self.code_pages.remove_code_page(idx);
+        }
+    }
+
+    fn drop_trace(&mut self, head_phys: BusType) {
+        let cpu = cpu::get_cpu();
+
+        if let Some(idx) = self.traces.remove_trace(head_phys) {
+            cpu.insn_map.remove_trace_ranges(idx);
+            self.code_pages.remove_code_page(idx);
         }
     }
 
@@ -84,20 +287,22 @@ impl ParseCore {
 
         assert!((gpfn as usize) << RV_PAGE_SHIFT < BusType::MAX as usize);
 
+        let base_addr = bus
+            .translate(gpfn << RV_PAGE_SHIFT, &mut cpu.mmu, AccessType::Load)
+            .unwrap() as BusType;
+
+        self.evict_translations();
+
         let code_page: &mut CodePage;
         let code_page_idx: usize;
 
         unsafe {
             let self_mut = self as *mut Self;
-            let (code_page_, code_page_idx_) = (*self_mut).code_pages.alloc_code_page();
+            let (code_page_, code_page_idx_) = (*self_mut).code_pages.alloc_code_page(base_addr);
             code_page = code_page_;
             code_page_idx = code_page_idx_;
         }
 
-        let base_addr = bus
-            .translate(gpfn << RV_PAGE_SHIFT, &mut cpu.mmu, AccessType::Load)
-            .unwrap() as BusType;
-
         cpu.gpfn_state.add_gpfn(base_addr as CpuReg);
 
         cpu.current_gpfn = base_addr >> RV_PAGE_SHIFT as BusType;
@@ -159,62 +364,225 @@ impl ParseCore {
         Ok(())
     }
 
-    fn decode_single(
-        &mut self,
-        code_page: &mut CodePage,
-        code_page_idx: usize,
-        insn: u32,
-        current_address: BusType,
-    ) -> Result<(), JitCommon::JitError> {
-        static DECODERS: [DecoderFn; 4] = [
+    // The semihosting sequence is only recognised when all three instructions
+    // live in the same page, so the neighbours can be fetched without faulting
+    fn is_semihosting_call(insn: u32, current_address: BusType) -> bool {
+        if insn != semihosting::SEMIHOSTING_EBREAK_INSN || !semihosting::is_enabled() {
+            return false;
+        }
+
+        let page_offset = current_address as usize & RV_PAGE_OFFSET_MASK;
+
+        if page_offset < INSN_SIZE || page_offset + INSN_SIZE * 2 > RV_PAGE_SIZE {
+            return false;
+        }
+
+        let bus = bus::get_bus();
+        let insn_size = INSN_SIZE as BusType;
+
+        let entry = bus.fetch_nommu(current_address - insn_size, INSN_SIZE_BITS as BusType);
+        let exit = bus.fetch_nommu(current_address + insn_size, INSN_SIZE_BITS as BusType);
+
+        entry == Ok(semihosting::SEMIHOSTING_ENTRY_INSN)
+            && exit == Ok(semihosting::SEMIHOSTING_EXIT_INSN)
+    }
+
+    fn decode_insn(insn: u32, current_address: BusType) -> JitCommon::DecodeRet {
+        static DECODERS: [DecoderFn; 5] = [
             rvi::decode_rvi,
             rvm::decode_rvm,
             csr::decode_csr,
             rva::decode_rva,
+            rvb::decode_rvb,
         ];
 
         let mut out_res: JitCommon::DecodeRet = Err(JitCommon::JitError::InvalidInstruction(insn));
 
+        if Self::is_semihosting_call(insn, current_address) {
+            out_res = CsrImpl::emit_semihosting_call();
+        } else {
+            for decode in &DECODERS {
+                let result = decode(insn);
+                if let Err(JitCommon::JitError::InvalidInstruction(_)) = result {
+                    continue;
+                } else {
+                    out_res = result;
+                    break;
+                }
+            }
+        }
+
+        match out_res {
+            Ok(insn_res) => Ok(insn_res),
+            Err(JitCommon::JitError::InvalidInstruction(_)) => Ok(
+                BackendCoreImpl::emit_ret_with_exception(Exception::IllegalInstruction(insn)),
+            ),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn decode_single(
+        &mut self,
+        code_page: &mut CodePage,
+        code_page_idx: usize,
+        insn: u32,
+        current_address: BusType,
+    ) -> Result<(), JitCommon::JitError> {
         let cpu = cpu::get_cpu();
 
         let host_insn_ptr = code_page.as_end_ptr();
-        cpu.jit_current_ptr = host_insn_ptr;
 
-        for decode in &DECODERS {
-            let result = decode(insn);
-            if let Err(JitCommon::JitError::InvalidInstruction(_)) = result {
-                continue;
-            } else {
-                out_res = result;
-                break;
-            }
-        }
+        // Loop back-edges count themselves, jumps into them enter the counter first
+        let threshold = trace::hot_threshold();
 
-        let insn_res: HostEncodedInsn;
+        if trace::is_back_edge(insn, current_address) {
+            let body_insns = trace::loop_body_insns(insn);
 
-        match out_res {
-            Ok(insn_res_unwrapped) => {
-                insn_res = insn_res_unwrapped;
-            }
-            Err(JitCommon::JitError::InvalidInstruction(_)) => {
-                insn_res =
-                    BackendCoreImpl::emit_ret_with_exception(Exception::IllegalInstruction(insn));
-            }
-            _ => {
-                return Err(out_res.err().unwrap());
+            if threshold != 0 {
+                let site = self.traces.add_site(current_address);
+
+                code_page
+                    .push(TraceEmitter::emit_site_check(site, threshold, body_insns).as_slice())
+                    .expect("Out of memory");
+
+                site.resume_ptr = code_page.as_end_ptr() as usize;
+            } else if cpu::count_progress() {
+                code_page
+                    .push(TraceEmitter::emit_progress_count(body_insns).as_slice())
+                    .expect("Out of memory");
             }
         }
 
+        // Other branches count which side they take for trace formation
+        let profile = (threshold != 0 && trace::is_side_branch(insn, current_address)).then(|| {
+            let profile: *mut _ = self.traces.add_branch(current_address);
+
+            code_page
+                .push(TraceEmitter::emit_branch_executed(profile).as_slice())
+                .expect("Out of memory");
+
+            profile
+        });
+
+        cpu.jit_current_ptr = code_page.as_end_ptr();
+
+        let insn_res: HostEncodedInsn = Self::decode_insn(insn, current_address)?;
+
         code_page.push(insn_res.as_slice()).expect("Out of memory");
 
-        let cpu = cpu::get_cpu();
+        if let Some(profile) = profile {
+            code_page
+                .push(TraceEmitter::emit_branch_not_taken(profile).as_slice())
+                .expect("Out of memory");
+        }
 
-        cpu.insn_map
-            .add_mapping(current_address, host_insn_ptr, code_page_idx);
+        cpu.insn_map.add_mapping(
+            current_address,
+            host_insn_ptr,
+            code_page.as_end_ptr(),
+            code_page_idx,
+        );
 
         Ok(())
     }
 
+    // Second tier: recompiles the loop closing at the hot back-edge at head_pc
+    // into one trace. Failing to form one leaves the first tier translation as is
+    pub fn compile_trace(&mut self, head_pc: CpuReg) {
+        let cpu = cpu::get_cpu();
+        let bus = bus::get_bus();
+
+        self.evict_translations();
+
+        let Ok(head_phys) = bus.translate(head_pc, &mut cpu.mmu, AccessType::Fetch) else {
+            return;
+        };
+
+        let (site, resume_ptr) = match self.traces.get_site(head_phys) {
+            Some(site) if site.trace_ptr == 0 => (site as *mut _, site.resume_ptr),
+            _ => return,
+        };
+
+        let Some(trace) = trace::form_trace(&self.traces, head_pc, head_phys, cpu.mmu.is_active())
+        else {
+            return;
+        };
+
+        let saved = (
+            cpu.current_gpfn,
+            cpu.current_guest_page,
+            cpu.current_gpfn_offset,
+        );
+
+        let (code_page, code_page_idx) = self
+            .code_pages
+            .alloc_code_page(head_phys & RV_PAGE_MASK as BusType);
+
+        let mut emitter = TraceEmitter::new(&trace, site);
+
+        let trace_ptr = code_page.as_end_ptr();
+
+        code_page
+            .push(emitter.emit_prologue(trace_ptr, resume_ptr).as_slice())
+            .expect("Out of memory");
+
+        for op in &trace.ops {
+            let TraceOp::Guest { insn, pc, phys } = *op else {
+                let host_ptr = code_page.as_end_ptr();
+
+                code_page
+                    .push(emitter.emit_op(op, host_ptr).as_slice())
+                    .expect("Out of memory");
+
+                continue;
+            };
+
+            code_page
+                .push(emitter.emit_guest_enter().as_slice())
+                .expect("Out of memory");
+
+            cpu.current_gpfn = pc >> RV_PAGE_SHIFT as CpuReg;
+            cpu.current_guest_page = pc & RV_PAGE_MASK as CpuReg;
+            cpu.current_gpfn_offset = pc & RV_PAGE_OFFSET_MASK as CpuReg;
+
+            let host_ptr = code_page.as_end_ptr();
+            cpu.jit_current_ptr = host_ptr;
+
+            let insn_res = Self::decode_insn(insn, phys).expect("Trace holds an undecodable insn");
+
+            code_page.push(insn_res.as_slice()).expect("Out of memory");
+
+            cpu.insn_map
+                .add_trace_range(phys, host_ptr, code_page.as_end_ptr(), code_page_idx);
+
+            // Stores leave every guest register alone
+            let rd = match cpu::OpType::from_u32(insn & 0x7f) {
+                cpu::OpType::S => 0,
+                _ => ((insn >> 7) & 0b11111) as u8,
+            };
+
+            code_page
+                .push(emitter.emit_guest_leave(rd).as_slice())
+                .expect("Out of memory");
+        }
+
+        code_page.mark_rx().unwrap();
+
+        (
+            cpu.current_gpfn,
+            cpu.current_guest_page,
+            cpu.current_gpfn_offset,
+        ) = saved;
+
+        if !self
+            .traces
+            .install(&trace, head_phys, code_page_idx, trace_ptr as usize)
+        {
+            cpu.insn_map.remove_trace_ranges(code_page_idx);
+            self.code_pages.remove_code_page(code_page_idx);
+        }
+    }
+
     pub fn get_exec_ptr(&mut self, idx: usize) -> *mut u8 {
         self.code_pages.get_code_page(idx).as_ptr()
     }
@@ -228,6 +596,22 @@ impl ParseCore {
     }
 
     pub fn cleanup(&mut self) {
+        let mut thrashing: Vec<(BusType, u32)> = self
+            .smc_retranslations
+            .iter()
+            .filter(|(_, count)| **count >= SMC_REPORT_THRESHOLD)
+            .map(|(page, count)| (*page, *count))
+            .collect();
+
+        thrashing.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+        for (page, count) in thrashing {
+            println!(
+                "JIT: page {:#x} was translated again {} times after writes to its code",
+                page, count
+            );
+        }
+
         self.code_pages.cleanup();
     }
 }
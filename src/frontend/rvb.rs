@@ -0,0 +1,67 @@
+use crate::{backend::*, cpu::OpType};
+
+pub fn decode_rvb(insn: u32) -> DecodeRet {
+    // Zba, Zbb, Zbc and Zbs, only the RV32 encodings
+
+    let opcode = insn & 0x7f;
+
+    let rd = ((insn >> 7) & 0b11111) as u8;
+    let funct3 = ((insn >> 12) & 0b111) as u8;
+    let rs1 = ((insn >> 15) & 0b11111) as u8;
+    let rs2 = ((insn >> 20) & 0b11111) as u8;
+    let funct7 = ((insn >> 25) & 0b1111111) as u8;
+
+    match OpType::from_u32(opcode) {
+        OpType::R => match (funct7, funct3) {
+            (0b0010000, 0b010) => RvbImpl::emit_sh1add(rd, rs1, rs2),
+            (0b0010000, 0b100) => RvbImpl::emit_sh2add(rd, rs1, rs2),
+            (0b0010000, 0b110) => RvbImpl::emit_sh3add(rd, rs1, rs2),
+
+            (0b0100000, 0b111) => RvbImpl::emit_andn(rd, rs1, rs2),
+            (0b0100000, 0b110) => RvbImpl::emit_orn(rd, rs1, rs2),
+            (0b0100000, 0b100) => RvbImpl::emit_xnor(rd, rs1, rs2),
+            (0b0000101, 0b110) => RvbImpl::emit_max(rd, rs1, rs2),
+            (0b0000101, 0b111) => RvbImpl::emit_maxu(rd, rs1, rs2),
+            (0b0000101, 0b100) => RvbImpl::emit_min(rd, rs1, rs2),
+            (0b0000101, 0b101) => RvbImpl::emit_minu(rd, rs1, rs2),
+            (0b0000100, 0b100) if rs2 == 0 => RvbImpl::emit_zext_h(rd, rs1),
+            (0b0110000, 0b001) => RvbImpl::emit_rol(rd, rs1, rs2),
+            (0b0110000, 0b101) => RvbImpl::emit_ror(rd, rs1, rs2),
+
+            (0b0000101, 0b001) => RvbImpl::emit_clmul(rd, rs1, rs2),
+            (0b0000101, 0b011) => RvbImpl::emit_clmulh(rd, rs1, rs2),
+            (0b0000101, 0b010) => RvbImpl::emit_clmulr(rd, rs1, rs2),
+
+            (0b0100100, 0b001) => RvbImpl::emit_bclr(rd, rs1, rs2),
+            (0b0100100, 0b101) => RvbImpl::emit_bext(rd, rs1, rs2),
+            (0b0110100, 0b001) => RvbImpl::emit_binv(rd, rs1, rs2),
+            (0b0010100, 0b001) => RvbImpl::emit_bset(rd, rs1, rs2),
+            _ => Err(JitError::InvalidInstruction(insn)),
+        },
+        OpType::I => {
+            // rs2 holds shamt or selects the unary op
+            let shamt = rs2;
+
+            match (funct7, funct3) {
+                (0b0110000, 0b001) => match rs2 {
+                    0b00000 => RvbImpl::emit_clz(rd, rs1),
+                    0b00001 => RvbImpl::emit_ctz(rd, rs1),
+                    0b00010 => RvbImpl::emit_cpop(rd, rs1),
+                    0b00100 => RvbImpl::emit_sext_b(rd, rs1),
+                    0b00101 => RvbImpl::emit_sext_h(rd, rs1),
+                    _ => Err(JitError::InvalidInstruction(insn)),
+                },
+                (0b0110000, 0b101) => RvbImpl::emit_rori(rd, rs1, shamt),
+                (0b0010100, 0b101) if rs2 == 0b00111 => RvbImpl::emit_orc_b(rd, rs1),
+                (0b0110100, 0b101) if rs2 == 0b11000 => RvbImpl::emit_rev8(rd, rs1),
+
+                (0b0100100, 0b001) => RvbImpl::emit_bclri(rd, rs1, shamt),
+                (0b0100100, 0b101) => RvbImpl::emit_bexti(rd, rs1, shamt),
+                (0b0110100, 0b001) => RvbImpl::emit_binvi(rd, rs1, shamt),
+                (0b0010100, 0b001) => RvbImpl::emit_bseti(rd, rs1, shamt),
+                _ => Err(JitError::InvalidInstruction(insn)),
+            }
+        }
+        _ => Err(JitError::InvalidInstruction(insn)),
+    }
+}
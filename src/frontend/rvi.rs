@@ -16,9 +16,8 @@ pub fn decode_rvi(insn: u32) -> DecodeRet {
             match funct3 {
                 0b000 => RviImpl::emit_addi(rd, rs1, imm),
                 0b001 => {
-                    let imm = imm & 0b111111;
-
-                    if imm & (1 << 5) != 0 {
+                    // Zbb and Zbs use the rest of funct7
+                    if insn >> 25 != 0 {
                         return Err(JitError::InvalidInstruction(insn));
                     }
 
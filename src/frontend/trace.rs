@@ -0,0 +1,766 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use hashbrown::HashMap;
+
+use crate::backend::common::JumpCond;
+use crate::bus::bus::{self, BusType};
+use crate::cpu::{self, CpuReg, OpType};
+use crate::util::sign_extend;
+
+use super::exec_core::{INSN_SIZE, INSN_SIZE_BITS, RV_PAGE_MASK, RV_PAGE_OFFSET_MASK};
+use super::rvb;
+
+pub const JIT_HOT_THRESHOLD_DEFAULT: u32 = 1000;
+
+// Guest instructions followed before giving up on closing the loop
+const TRACE_MAX_INSNS: usize = 256;
+
+// has_pending_interrupt stays set while interrupts are masked, checking it on
+// every iteration would leave the trace on every iteration as well
+pub const TRACE_INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+static HOT_THRESHOLD: AtomicU32 = AtomicU32::new(JIT_HOT_THRESHOLD_DEFAULT);
+
+// 0 keeps everything in the first tier
+pub fn set_hot_threshold(threshold: u32) {
+    HOT_THRESHOLD.store(threshold, Ordering::Release);
+}
+
+pub fn hot_threshold() -> u32 {
+    HOT_THRESHOLD.load(Ordering::Acquire)
+}
+
+// Lives next to every loop back-edge of the first tier. The translated branch
+// counts itself here and jumps into trace_ptr once a trace is installed
+#[repr(C)]
+pub struct TraceSite {
+    pub counter: u32,
+    // Iterations of the trace left until it looks for pending interrupts again
+    pub interrupt_countdown: u32,
+    pub trace_ptr: usize,
+    // First tier code of the branch itself, right after the counter
+    pub resume_ptr: usize,
+}
+
+// Lives next to every other conditional branch of the first tier, which counts
+// how often it ran and fell through. Traces follow the side it took more often
+#[repr(C)]
+pub struct BranchProfile {
+    pub executed: u64,
+    pub not_taken: u64,
+}
+
+impl BranchProfile {
+    pub fn mostly_taken(&self) -> bool {
+        self.executed - self.not_taken > self.not_taken
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceOperand {
+    Reg(u8),
+    Imm(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceAluOp {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceExit {
+    pub pc: CpuReg,
+    // First tier code to continue in, the exec loop takes over without it
+    pub host_ptr: Option<*mut u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceOp {
+    Li {
+        rd: u8,
+        imm: u32,
+    },
+    Alu {
+        op: TraceAluOp,
+        rd: u8,
+        rs1: TraceOperand,
+        rs2: TraceOperand,
+    },
+    // Translated by the first tier, guest registers are kept in memory around it
+    Guest {
+        insn: u32,
+        pc: CpuReg,
+        phys: BusType,
+    },
+    // Keeps current_gpfn right for the first tier code and exceptions
+    SetPage {
+        page: CpuReg,
+    },
+    ExitIf {
+        cond: JumpCond,
+        rs1: TraceOperand,
+        rs2: TraceOperand,
+        exit: TraceExit,
+    },
+    Exit(TraceExit),
+    Loop,
+}
+
+pub struct Trace {
+    pub head_pc: CpuReg,
+    pub ops: Vec<TraceOp>,
+    // Guest instructions followed around the loop once
+    pub insns: u32,
+    // Physical pages the trace was formed from or leaves into, with their generation
+    pub pages: Vec<(BusType, u32)>,
+    pub multi_page: bool,
+}
+
+struct TraceEntry {
+    code_page_idx: usize,
+    pages: Vec<(BusType, u32)>,
+}
+
+pub struct Traces {
+    // Keyed by the physical address of the back-edge
+    sites: BTreeMap<BusType, Box<TraceSite>>,
+    branches: BTreeMap<BusType, Box<BranchProfile>>,
+    traces: HashMap<BusType, TraceEntry>,
+    // Code page -> back-edge of the trace compiled into it
+    owners: HashMap<usize, BusType>,
+    // Bumped whenever the translation of a physical page is dropped
+    generations: HashMap<BusType, u32>,
+}
+
+impl Traces {
+    pub fn new() -> Traces {
+        Traces {
+            sites: BTreeMap::new(),
+            branches: BTreeMap::new(),
+            traces: HashMap::new(),
+            owners: HashMap::new(),
+            generations: HashMap::new(),
+        }
+    }
+
+    pub fn add_site(&mut self, phys_pc: BusType) -> &mut TraceSite {
+        let site = Box::new(TraceSite {
+            counter: 0,
+            interrupt_countdown: TRACE_INTERRUPT_CHECK_INTERVAL,
+            trace_ptr: 0,
+            resume_ptr: 0,
+        });
+
+        self.sites.insert(phys_pc, site);
+        self.sites.get_mut(&phys_pc).unwrap()
+    }
+
+    pub fn add_branch(&mut self, phys_pc: BusType) -> &mut BranchProfile {
+        let profile = Box::new(BranchProfile {
+            executed: 0,
+            not_taken: 0,
+        });
+
+        self.branches.insert(phys_pc, profile);
+        self.branches.get_mut(&phys_pc).unwrap()
+    }
+
+    pub fn mostly_taken(&self, phys_pc: BusType) -> bool {
+        self.branches
+            .get(&phys_pc)
+            .is_some_and(|profile| profile.mostly_taken())
+    }
+
+    pub fn get_site(&mut self, phys_pc: BusType) -> Option<&mut TraceSite> {
+        self.sites.get_mut(&phys_pc).map(|site| site.as_mut())
+    }
+
+    pub fn has_trace(&self, phys_pc: BusType) -> bool {
+        self.sites
+            .get(&phys_pc)
+            .is_some_and(|site| site.trace_ptr != 0)
+    }
+
+    pub fn generation(&self, page: BusType) -> u32 {
+        self.generations.get(&page).copied().unwrap_or(0)
+    }
+
+    // Refuses traces formed against a translation that was dropped since
+    pub fn install(
+        &mut self,
+        trace: &Trace,
+        head_phys: BusType,
+        code_page_idx: usize,
+        ptr: usize,
+    ) -> bool {
+        let current = trace
+            .pages
+            .iter()
+            .all(|(page, generation)| self.generation(*page) == *generation);
+
+        let Some(site) = self.sites.get_mut(&head_phys) else {
+            return false;
+        };
+
+        if !current {
+            return false;
+        }
+
+        site.trace_ptr = ptr;
+
+        self.owners.insert(code_page_idx, head_phys);
+        self.traces.insert(
+            head_phys,
+            TraceEntry {
+                code_page_idx,
+                pages: trace.pages.clone(),
+            },
+        );
+
+        true
+    }
+
+    // Code page of the trace, its back-edge counts from zero again
+    pub fn remove_trace(&mut self, head_phys: BusType) -> Option<usize> {
+        let entry = self.traces.remove(&head_phys)?;
+
+        self.owners.remove(&entry.code_page_idx);
+
+        if let Some(site) = self.sites.get_mut(&head_phys) {
+            site.counter = 0;
+            site.trace_ptr = 0;
+        }
+
+        Some(entry.code_page_idx)
+    }
+
+    pub fn owner_of(&self, code_page_idx: usize) -> Option<BusType> {
+        self.owners.get(&code_page_idx).copied()
+    }
+
+    // Called when the first tier translation of a page goes away. Returns the
+    // code pages of the traces that depended on it
+    pub fn drop_page(&mut self, page: BusType) -> Vec<usize> {
+        let page = page & RV_PAGE_MASK as BusType;
+
+        let code_pages = self.invalidate_page(page);
+
+        let page_end = page as u64 + (RV_PAGE_OFFSET_MASK + 1) as u64;
+
+        self.sites
+            .retain(|pc, _| (*pc as u64) < page as u64 || (*pc as u64) >= page_end);
+        self.branches
+            .retain(|pc, _| (*pc as u64) < page as u64 || (*pc as u64) >= page_end);
+
+        code_pages
+    }
+
+    // Drops the traces built from a page whose first tier translation stays,
+    // its back-edges keep their sites
+    pub fn invalidate_page(&mut self, page: BusType) -> Vec<usize> {
+        let page = page & RV_PAGE_MASK as BusType;
+
+        *self.generations.entry(page).or_insert(0) += 1;
+
+        let stale: Vec<BusType> = self
+            .traces
+            .iter()
+            .filter(|(_, entry)| entry.pages.iter().any(|(p, _)| *p == page))
+            .map(|(head, _)| *head)
+            .collect();
+
+        stale
+            .into_iter()
+            .filter_map(|head| self.remove_trace(head))
+            .collect()
+    }
+}
+
+// The first tier counts these, a hot one starts a trace
+pub fn is_back_edge(insn: u32, pc: BusType) -> bool {
+    let imm = match OpType::from_u32(insn & 0x7f) {
+        OpType::B if branch_cond(insn).is_some() => b_imm(insn),
+        OpType::JAL => j_imm(insn),
+        _ => return false,
+    };
+
+    let offset = (pc as usize & RV_PAGE_OFFSET_MASK) as i32;
+
+    imm < 0 && offset + imm >= 0
+}
+
+// Conditional branches that are not back-edges, the first tier profiles them
+pub fn is_side_branch(insn: u32, pc: BusType) -> bool {
+    matches!(OpType::from_u32(insn & 0x7f), OpType::B)
+        && branch_cond(insn).is_some()
+        && !is_back_edge(insn, pc)
+}
+
+// Guest instructions from the target of a back-edge up to and including it
+pub fn loop_body_insns(insn: u32) -> u32 {
+    let imm = match OpType::from_u32(insn & 0x7f) {
+        OpType::JAL => j_imm(insn),
+        _ => b_imm(insn),
+    };
+
+    (-imm) as u32 / INSN_SIZE as u32 + 1
+}
+
+fn rd(insn: u32) -> u8 {
+    ((insn >> 7) & 0b11111) as u8
+}
+
+fn rs1(insn: u32) -> u8 {
+    ((insn >> 15) & 0b11111) as u8
+}
+
+fn rs2(insn: u32) -> u8 {
+    ((insn >> 20) & 0b11111) as u8
+}
+
+fn funct3(insn: u32) -> u8 {
+    ((insn >> 12) & 0b111) as u8
+}
+
+fn funct7(insn: u32) -> u8 {
+    ((insn >> 25) & 0b1111111) as u8
+}
+
+fn i_imm(insn: u32) -> u32 {
+    sign_extend((insn >> 20) as i32, 12) as u32
+}
+
+fn b_imm(insn: u32) -> i32 {
+    let imm = ((insn & 0xf00) >> 7)
+        | ((insn & 0x7e000000) >> 20)
+        | ((insn & 0x80) << 4)
+        | ((insn >> 31) << 12);
+
+    sign_extend(imm as i32, 13) as i32
+}
+
+fn j_imm(insn: u32) -> i32 {
+    let imm = ((insn & 0x80000000) >> 11)
+        | ((insn & 0x7fe00000) >> 20)
+        | ((insn & 0x00100000) >> 9)
+        | (insn & 0x000ff000);
+
+    sign_extend(imm as i32, 21) as i32
+}
+
+fn branch_cond(insn: u32) -> Option<JumpCond> {
+    match funct3(insn) {
+        0b000 => Some(JumpCond::Equal),
+        0b001 => Some(JumpCond::NotEqual),
+        0b100 => Some(JumpCond::LessThan),
+        0b101 => Some(JumpCond::GreaterThanEqual),
+        0b110 => Some(JumpCond::LessThanUnsigned),
+        0b111 => Some(JumpCond::GreaterThanEqualUnsigned),
+        _ => None,
+    }
+}
+
+pub fn inverse_cond(cond: JumpCond) -> JumpCond {
+    match cond {
+        JumpCond::Equal => JumpCond::NotEqual,
+        JumpCond::NotEqual => JumpCond::Equal,
+        JumpCond::LessThan => JumpCond::GreaterThanEqual,
+        JumpCond::GreaterThanEqual => JumpCond::LessThan,
+        JumpCond::LessThanUnsigned => JumpCond::GreaterThanEqualUnsigned,
+        JumpCond::GreaterThanEqualUnsigned => JumpCond::LessThanUnsigned,
+        _ => unreachable!(),
+    }
+}
+
+fn eval_cond(cond: JumpCond, a: u32, b: u32) -> bool {
+    match cond {
+        JumpCond::Equal => a == b,
+        JumpCond::NotEqual => a != b,
+        JumpCond::LessThan => (a as i32) < (b as i32),
+        JumpCond::GreaterThanEqual => (a as i32) >= (b as i32),
+        JumpCond::LessThanUnsigned => a < b,
+        JumpCond::GreaterThanEqualUnsigned => a >= b,
+        _ => unreachable!(),
+    }
+}
+
+fn fold(op: TraceAluOp, a: u32, b: u32) -> u32 {
+    match op {
+        TraceAluOp::Add => a.wrapping_add(b),
+        TraceAluOp::Sub => a.wrapping_sub(b),
+        TraceAluOp::Sll => a << (b & 0x1f),
+        TraceAluOp::Slt => ((a as i32) < (b as i32)) as u32,
+        TraceAluOp::Sltu => (a < b) as u32,
+        TraceAluOp::Xor => a ^ b,
+        TraceAluOp::Srl => a >> (b & 0x1f),
+        TraceAluOp::Sra => ((a as i32) >> (b & 0x1f)) as u32,
+        TraceAluOp::Or => a | b,
+        TraceAluOp::And => a & b,
+    }
+}
+
+fn alu_op(insn: u32) -> Option<(TraceAluOp, bool)> {
+    let op = match (OpType::from_u32(insn & 0x7f), funct3(insn), funct7(insn)) {
+        (OpType::I, 0b000, _) => TraceAluOp::Add,
+        (OpType::I, 0b010, _) => TraceAluOp::Slt,
+        (OpType::I, 0b011, _) => TraceAluOp::Sltu,
+        (OpType::I, 0b100, _) => TraceAluOp::Xor,
+        (OpType::I, 0b110, _) => TraceAluOp::Or,
+        (OpType::I, 0b111, _) => TraceAluOp::And,
+        (OpType::I, 0b001, 0b0000000) => TraceAluOp::Sll,
+        (OpType::I, 0b101, 0b0000000) => TraceAluOp::Srl,
+        (OpType::I, 0b101, 0b0100000) => TraceAluOp::Sra,
+        (OpType::R, 0b000, 0b0000000) => TraceAluOp::Add,
+        (OpType::R, 0b000, 0b0100000) => TraceAluOp::Sub,
+        (OpType::R, 0b001, 0b0000000) => TraceAluOp::Sll,
+        (OpType::R, 0b010, 0b0000000) => TraceAluOp::Slt,
+        (OpType::R, 0b011, 0b0000000) => TraceAluOp::Sltu,
+        (OpType::R, 0b100, 0b0000000) => TraceAluOp::Xor,
+        (OpType::R, 0b101, 0b0000000) => TraceAluOp::Srl,
+        (OpType::R, 0b101, 0b0100000) => TraceAluOp::Sra,
+        (OpType::R, 0b110, 0b0000000) => TraceAluOp::Or,
+        (OpType::R, 0b111, 0b0000000) => TraceAluOp::And,
+        _ => return None,
+    };
+
+    Some((op, matches!(OpType::from_u32(insn & 0x7f), OpType::I)))
+}
+
+// Loads, stores, the M extension and bitmanip keep their first tier translation
+fn is_guest_op(insn: u32) -> bool {
+    match OpType::from_u32(insn & 0x7f) {
+        OpType::L => matches!(funct3(insn), 0b000 | 0b001 | 0b010 | 0b100 | 0b101),
+        OpType::S => matches!(funct3(insn), 0b000..=0b010),
+        OpType::R if funct7(insn) == 0b0000001 => true,
+        OpType::R | OpType::I => rvb::decode_rvb(insn).is_ok(),
+        _ => false,
+    }
+}
+
+struct TraceFormer<'a> {
+    traces: &'a Traces,
+    // Register values known at this point of the trace
+    consts: [Option<u32>; 32],
+    // Known values that were not written back yet
+    pending: [bool; 32],
+    ops: Vec<TraceOp>,
+    // Virtual page -> physical page of every page the trace went through
+    page_map: Vec<(CpuReg, BusType)>,
+    pages: Vec<(BusType, u32)>,
+    page: CpuReg,
+    paging: bool,
+}
+
+impl<'a> TraceFormer<'a> {
+    fn operand(&self, reg: u8) -> TraceOperand {
+        match self.consts[reg as usize] {
+            Some(value) => TraceOperand::Imm(value),
+            None => TraceOperand::Reg(reg),
+        }
+    }
+
+    fn set_const(&mut self, rd: u8, value: u32) {
+        if rd != 0 {
+            self.consts[rd as usize] = Some(value);
+            self.pending[rd as usize] = true;
+        }
+    }
+
+    fn set_unknown(&mut self, rd: u8) {
+        if rd != 0 {
+            self.consts[rd as usize] = None;
+            self.pending[rd as usize] = false;
+        }
+    }
+
+    // Writes back the folded constants, everything after may read registers
+    // from memory or leave the trace
+    fn materialize(&mut self) {
+        for rd in 1..32 {
+            if self.pending[rd] {
+                self.pending[rd] = false;
+                self.ops.push(TraceOp::Li {
+                    rd: rd as u8,
+                    imm: self.consts[rd].unwrap(),
+                });
+            }
+        }
+    }
+
+    fn add_page(&mut self, page: CpuReg, phys_page: BusType) {
+        if !self.page_map.iter().any(|(virt, _)| *virt == page) {
+            self.page_map.push((page, phys_page));
+        }
+
+        if !self.pages.iter().any(|(phys, _)| *phys == phys_page) {
+            self.pages
+                .push((phys_page, self.traces.generation(phys_page)));
+        }
+    }
+
+    // Physical page of a virtual one, only known for the pages the trace went
+    // through or, without paging, for any translated page
+    fn phys_page(&self, page: CpuReg) -> Option<BusType> {
+        if let Some((_, phys)) = self.page_map.iter().find(|(virt, _)| *virt == page) {
+            return Some(*phys);
+        }
+
+        if self.paging {
+            return None;
+        }
+
+        let translated = cpu::get_cpu().insn_map.get_by_guest_idx(page).is_some();
+
+        translated.then_some(page)
+    }
+
+    fn exit_to(&mut self, pc: CpuReg) -> TraceExit {
+        let page = pc & RV_PAGE_MASK as CpuReg;
+
+        let host_ptr = self.phys_page(page).and_then(|phys_page| {
+            let offset = pc & RV_PAGE_OFFSET_MASK as CpuReg;
+
+            cpu::get_cpu()
+                .insn_map
+                .get_by_guest_idx(phys_page | offset)
+                .map(|mapping| (phys_page, mapping.host_ptr))
+        });
+
+        match host_ptr {
+            Some((phys_page, host_ptr)) => {
+                self.add_page(page, phys_page);
+
+                TraceExit {
+                    pc,
+                    host_ptr: Some(host_ptr),
+                }
+            }
+            None => TraceExit { pc, host_ptr: None },
+        }
+    }
+
+    fn exit(&mut self, pc: CpuReg) {
+        self.materialize();
+
+        let exit = self.exit_to(pc);
+
+        self.ops.push(TraceOp::Exit(exit));
+    }
+
+    // Moves on to pc, false when the trace has to stop before it
+    fn enter(&mut self, pc: CpuReg) -> bool {
+        let page = pc & RV_PAGE_MASK as CpuReg;
+
+        if page == self.page {
+            return true;
+        }
+
+        // Pages are only chained without paging, where the mapping is fixed
+        let phys_page = match self.phys_page(page) {
+            Some(phys_page) if !self.paging || page == self.page_map[0].0 => phys_page,
+            _ => return false,
+        };
+
+        self.add_page(page, phys_page);
+        self.ops.push(TraceOp::SetPage { page });
+        self.page = page;
+
+        true
+    }
+}
+
+// Follows the hot loop from its back-edge at head_pc until it gets back there,
+// taking the side other branches took more often in the first tier. Side exits
+// go back to the first tier, which enters the trace again on the next back-edge
+pub fn form_trace(
+    traces: &Traces,
+    head_pc: CpuReg,
+    head_phys: BusType,
+    paging: bool,
+) -> Option<Trace> {
+    let bus = bus::get_bus();
+
+    let head_page = head_pc & RV_PAGE_MASK as CpuReg;
+
+    let mut former = TraceFormer {
+        traces,
+        consts: [None; 32],
+        pending: [false; 32],
+        ops: Vec::new(),
+        page_map: Vec::new(),
+        pages: Vec::new(),
+        page: head_page,
+        paging,
+    };
+
+    former.consts[0] = Some(0);
+    former.add_page(head_page, head_phys & RV_PAGE_MASK as BusType);
+
+    let mut pc = head_pc;
+    let mut insns = 0;
+
+    loop {
+        if insns > 0 && pc == head_pc {
+            former.materialize();
+
+            if former.page != head_page {
+                former.ops.push(TraceOp::SetPage { page: head_page });
+            }
+
+            former.ops.push(TraceOp::Loop);
+
+            break;
+        }
+
+        if insns == TRACE_MAX_INSNS || !former.enter(pc) {
+            former.exit(pc);
+            break;
+        }
+
+        let phys_page = former.phys_page(former.page).unwrap();
+        let phys = phys_page | (pc & RV_PAGE_OFFSET_MASK as CpuReg);
+
+        let insn = match bus.fetch_nommu(phys, INSN_SIZE_BITS as BusType) {
+            Ok(insn) => insn,
+            Err(_) => {
+                former.exit(pc);
+                break;
+            }
+        };
+
+        insns += 1;
+
+        let next_pc = pc.wrapping_add(INSN_SIZE as CpuReg);
+
+        if let Some((op, is_imm)) = alu_op(insn) {
+            let rs1 = former.operand(rs1(insn));
+            let rs2 = if is_imm {
+                let imm = i_imm(insn);
+
+                match op {
+                    TraceAluOp::Sll | TraceAluOp::Srl | TraceAluOp::Sra => {
+                        TraceOperand::Imm(imm & 0x1f)
+                    }
+                    _ => TraceOperand::Imm(imm),
+                }
+            } else {
+                former.operand(rs2(insn))
+            };
+
+            if let (TraceOperand::Imm(a), TraceOperand::Imm(b)) = (rs1, rs2) {
+                former.set_const(rd(insn), fold(op, a, b));
+            } else if rd(insn) != 0 {
+                former.set_unknown(rd(insn));
+                former.ops.push(TraceOp::Alu {
+                    op,
+                    rd: rd(insn),
+                    rs1,
+                    rs2,
+                });
+            }
+
+            pc = next_pc;
+            continue;
+        }
+
+        match OpType::from_u32(insn & 0x7f) {
+            OpType::U => {
+                former.set_const(rd(insn), insn & 0xfffff000);
+                pc = next_pc;
+            }
+            OpType::AUIPC => {
+                former.set_const(rd(insn), pc.wrapping_add(insn & 0xfffff000));
+                pc = next_pc;
+            }
+            OpType::FENCE if funct3(insn) == 0 => {
+                pc = next_pc;
+            }
+            OpType::JAL => {
+                former.set_const(rd(insn), next_pc);
+                pc = pc.wrapping_add(j_imm(insn) as CpuReg);
+            }
+            OpType::B if branch_cond(insn).is_some() => {
+                let cond = branch_cond(insn).unwrap();
+                let target = pc.wrapping_add(b_imm(insn) as CpuReg);
+
+                let rs1 = former.operand(rs1(insn));
+                let rs2 = former.operand(rs2(insn));
+
+                if let (TraceOperand::Imm(a), TraceOperand::Imm(b)) = (rs1, rs2) {
+                    pc = if eval_cond(cond, a, b) {
+                        target
+                    } else {
+                        next_pc
+                    };
+                    continue;
+                }
+
+                // Another loop whose trace is entered through its own back-edge
+                if pc != head_pc && traces.has_trace(phys) {
+                    former.exit(pc);
+                    break;
+                }
+
+                former.materialize();
+
+                // The head keeps looping on the taken side. Other branches only
+                // follow a taken side going forward, backwards it would be an
+                // inner loop without a trace of its own
+                let follow_taken = pc == head_pc || (b_imm(insn) > 0 && traces.mostly_taken(phys));
+
+                let (cond, exit_pc, follow_pc) = if follow_taken {
+                    (inverse_cond(cond), next_pc, target)
+                } else {
+                    (cond, target, next_pc)
+                };
+
+                let exit = former.exit_to(exit_pc);
+
+                former.ops.push(TraceOp::ExitIf {
+                    cond,
+                    rs1,
+                    rs2,
+                    exit,
+                });
+
+                pc = follow_pc;
+            }
+            _ if is_guest_op(insn) => {
+                former.materialize();
+                former.ops.push(TraceOp::Guest { insn, pc, phys });
+
+                if !matches!(OpType::from_u32(insn & 0x7f), OpType::S) {
+                    former.set_unknown(rd(insn));
+                }
+
+                pc = next_pc;
+            }
+            _ => {
+                former.exit(pc);
+                break;
+            }
+        }
+    }
+
+    if former.ops.last() != Some(&TraceOp::Loop) {
+        return None;
+    }
+
+    Some(Trace {
+        head_pc,
+        ops: former.ops,
+        insns: insns as u32,
+        pages: former.pages,
+        multi_page: former.page_map.len() > 1,
+    })
+}
@@ -10,9 +10,18 @@ mod window;
 mod xmem;
 
 use clap::Parser;
+use std::io::Write;
 
 use backend::csr::init_backend_csr;
 use bus::{
+    aclint::TimerKind,
+    aplic::AiaMode,
+    goldfish_rtc::RtcConfig,
+    htif::HtifConfig,
+    imsic::ImsicLevel,
+    ns16550::UartConfig,
+    pci::PciDevice,
+    pflash::{PflashConfig, PFLASH_ADDR},
     ram::RAM_BEGIN_ADDR,
     ramfb::RAMFB_BEGIN_ADDR,
     syscon::{SYSCON_ADDR, SYSCON_POWEROFF, SYSCON_REBOOT, SYSCON_SIZE},
@@ -20,12 +29,20 @@ use bus::{
 };
 use cpu::{csr, CPU_INTC_PHANDLE, CPU_TIMEBASE_FREQ};
 use frontend::exec_core::ExecCoreThreadPool;
+use window::capture::{FbDumpConfig, FrameGrabber};
+use window::record::RecordConfig;
 
 use crate::bus::BusDevice;
 
 use vm_fdt::FdtWriter;
 
-fn create_dtb(ram_origin: u32, ram_size: u32, has_fb: bool) -> Vec<u8> {
+fn create_dtb(
+    ram_origin: u32,
+    ram_size: u32,
+    has_fb: bool,
+    aia: AiaMode,
+    cmdline: &str,
+) -> Vec<u8> {
     let mut fdt: FdtWriter = FdtWriter::new().unwrap();
 
     let root_node = fdt.begin_node("").unwrap();
@@ -36,7 +53,9 @@ fn create_dtb(ram_origin: u32, ram_size: u32, has_fb: bool) -> Vec<u8> {
 
     let chosen_node = fdt.begin_node("chosen").unwrap();
 
-    let bootargs = if has_fb {
+    let bootargs = if !cmdline.is_empty() {
+        cmdline
+    } else if has_fb {
         "fbcon=nodefer fbcon=map:0"
     } else {
         "earlycon=sbi console=ttyS0"
@@ -66,7 +85,29 @@ fn create_dtb(ram_origin: u32, ram_size: u32, has_fb: bool) -> Vec<u8> {
     fdt.property_u32("reg", 0x0).unwrap();
     fdt.property_string("status", "okay").unwrap();
     fdt.property_string("compatible", "riscv").unwrap();
-    fdt.property_string("riscv,isa", "rv32imasu").unwrap();
+    let isa = if aia != AiaMode::None {
+        "rv32imasu_zba_zbb_zbc_zbs_smaia_ssaia_svadu"
+    } else {
+        "rv32imasu_zba_zbb_zbc_zbs_svadu"
+    };
+
+    let mut isa_extensions: Vec<String> = vec![
+        "i", "m", "a", "zicsr", "zifencei", "zba", "zbb", "zbc", "zbs",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+
+    if aia != AiaMode::None {
+        isa_extensions.extend(["smaia".into(), "ssaia".into()]);
+    }
+
+    isa_extensions.push("svadu".into());
+
+    fdt.property_string("riscv,isa", isa).unwrap();
+    fdt.property_string("riscv,isa-base", "rv32i").unwrap();
+    fdt.property_string_list("riscv,isa-extensions", isa_extensions)
+        .unwrap();
     fdt.property_string("mmu-type", "riscv,sv32").unwrap();
 
     // Begin syscon node
@@ -141,14 +182,46 @@ fn create_dtb(ram_origin: u32, ram_size: u32, has_fb: bool) -> Vec<u8> {
     fdt.finish().unwrap()
 }
 
-fn init_bus(
-    mut rom: Vec<u8>,
+// Everything the command line decided about the machine, consumed by init_bus
+struct MachineConfig {
+    rom: Vec<u8>,
     ram_size: usize,
     width: usize,
     height: usize,
     bpp: usize,
     using_fb: bool,
-) {
+    rtc: RtcConfig,
+    htif_config: Option<HtifConfig>,
+    timer: TimerKind,
+    aia: AiaMode,
+    pci_devices: Vec<Box<dyn PciDevice>>,
+    pci_hotplug_devices: Vec<Box<dyn PciDevice>>,
+    pflash: Option<bus::pflash::Pflash>,
+    fw_cfg: bus::fw_cfg::FwCfg,
+    cmdline: String,
+    uarts: Vec<bus::ns16550::Ns16550>,
+}
+
+fn init_bus(config: MachineConfig) {
+    let MachineConfig {
+        mut rom,
+        ram_size,
+        width,
+        height,
+        bpp,
+        using_fb,
+        rtc,
+        htif_config,
+        timer,
+        aia,
+        pci_devices,
+        pci_hotplug_devices,
+        pflash,
+        mut fw_cfg,
+        cmdline,
+        uarts,
+    } = config;
+
     assert!(ram_size >= rom.len());
 
     let bus = bus::bus::get_bus();
@@ -162,13 +235,45 @@ fn init_bus(
 
     bus.add_device(Box::new(ram));
 
-    let ns16550 = bus::ns16550::Ns16550::new();
+    if let Some(pflash) = pflash {
+        bus.add_device(Box::new(pflash));
+    }
+
+    if let Some(htif_config) = htif_config {
+        let htif = bus::htif::Htif::new(htif_config);
+
+        bus.add_device(Box::new(htif));
+    }
+
+    let ns16550 = bus::ns16550::Ns16550::new(&UartConfig::console()).unwrap();
 
     bus.add_device(Box::new(ns16550));
 
-    let plic = bus::plic::Plic::new();
+    for uart in uarts {
+        bus.add_device(Box::new(uart));
+    }
+
+    match aia {
+        AiaMode::None => {
+            let plic = bus::plic::Plic::new();
+
+            bus.add_plic(plic);
+        }
+        AiaMode::Aplic => {
+            let aplic = bus::aplic::Aplic::new(false);
 
-    bus.add_device(Box::new(plic));
+            bus.add_aplic(aplic);
+        }
+        AiaMode::AplicImsic => {
+            let aplic = bus::aplic::Aplic::new(true);
+            let imsic_m = bus::imsic::Imsic::new(ImsicLevel::Machine);
+            let imsic_s = bus::imsic::Imsic::new(ImsicLevel::Supervisor);
+
+            bus.add_aplic(aplic);
+            bus.add_device(Box::new(imsic_m));
+            bus.add_device(Box::new(imsic_s));
+        }
+    }
 
     let mut ramfb = bus::ramfb::RamFB::new(width, height, bpp, using_fb);
     let fb_ptr = ramfb.get_fb_ptr();
@@ -177,11 +282,40 @@ fn init_bus(
 
     bus.add_device(Box::new(ramfb));
 
-    let clint = bus::clint::Clint::new();
+    match timer {
+        TimerKind::Clint => {
+            let clint = bus::clint::Clint::new();
 
-    bus.add_device(Box::new(clint));
+            bus.add_device(Box::new(clint));
+        }
+        TimerKind::Aclint => {
+            let mswi = bus::aclint::AclintMswi::new();
+            let mtimer = bus::aclint::AclintMtimer::new();
+            let sswi = bus::aclint::AclintSswi::new();
+
+            bus.add_device(Box::new(mswi));
+            bus.add_device(Box::new(mtimer));
+            bus.add_device(Box::new(sswi));
+        }
+    }
+
+    let rtc = bus::goldfish_rtc::GoldfishRtc::new(rtc);
 
-    let dtb = create_dtb(RAM_BEGIN_ADDR, ram_size as u32, using_fb);
+    bus.add_device(Box::new(rtc));
+
+    if !pci_devices.is_empty() || !pci_hotplug_devices.is_empty() {
+        let pci_host = bus::pci::PciHost::new(pci_devices, pci_hotplug_devices);
+
+        bus.add_device(Box::new(pci_host));
+    }
+
+    if using_fb {
+        fw_cfg.add_ramfb();
+    }
+
+    bus.add_device(Box::new(fw_cfg));
+
+    let dtb = create_dtb(RAM_BEGIN_ADDR, ram_size as u32, using_fb, aia, &cmdline);
 
     let dtb = bus::dtb::Dtb::new(&dtb);
 
@@ -195,12 +329,31 @@ fn init_bus(
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, help = "Path to BIOS (firmware) image")]
+    #[arg(
+        short,
+        long,
+        default_value = "",
+        help = "Path to BIOS (firmware) image, boots from the pflash when omitted"
+    )]
     bios: String,
 
     #[arg(short, long, default_value = "", help = "Path to Linux kernel image")]
     kernel: String,
 
+    #[arg(
+        long,
+        default_value = "",
+        help = "Path to an initial ramdisk, passed to the firmware through fw_cfg"
+    )]
+    initrd: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Kernel command line, replaces the default bootargs"
+    )]
+    append: String,
+
     #[arg(short, long, default_value_t = 64, help = "Memory size in MiB")]
     memory: usize,
 
@@ -232,10 +385,149 @@ struct Args {
         help = "Scale factor for the graphical output (1, 2, 4, 8, 16, 32)"
     )]
     scale: usize,
+
+    #[arg(
+        long,
+        default_value = "base=utc",
+        help = "Real-time clock base (base=utc, base=localtime or base=<YYYY-MM-DDTHH:MM:SS>) and source (clock=host or clock=vm)"
+    )]
+    rtc: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "HTIF addresses (tohost=<addr>[,fromhost=<addr>]), taken from the ELF symbols when the BIOS is an ELF image"
+    )]
+    htif: String,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Enable RISC-V semihosting (slli x0, x0, 0x1f; ebreak; srai x0, x0, 7)"
+    )]
+    semihosting: bool,
+
+    #[arg(
+        long,
+        default_value = ".",
+        help = "Host directory that semihosting file operations are confined to"
+    )]
+    semihosting_root: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Command line the guest reads with SYS_GET_CMDLINE"
+    )]
+    semihosting_cmdline: String,
+
+    #[arg(
+        long,
+        default_value_t = frontend::code_pages::JIT_CACHE_DEFAULT_MB,
+        help = "Host memory in MiB for translated code, the least recently used translations are dropped beyond it"
+    )]
+    jit_cache_mb: usize,
+
+    #[arg(
+        long,
+        default_value_t = frontend::trace::JIT_HOT_THRESHOLD_DEFAULT,
+        help = "Times a loop runs before it gets recompiled into a trace, 0 disables traces"
+    )]
+    jit_hot_threshold: u32,
+
+    #[arg(long, default_value = "clint", help = "Timer device (clint or aclint)")]
+    timer: String,
+
+    #[arg(
+        long,
+        default_value = "none",
+        help = "Advanced Interrupt Architecture (none, aplic or aplic-imsic)"
+    )]
+    aia: String,
+
+    #[arg(
+        long,
+        help = "PCI device (virtio-blk,file=<path>[,readonly], nvme,file=<path>[,readonly] or virtio-rng), append ,hotplug to plug it in later with CTRL + A, then P"
+    )]
+    device: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "CFI parallel flash image (<path>[,readonly][,cmdset=intel|amd]), written back on program and erase"
+    )]
+    pflash: String,
+
+    #[arg(
+        long = "fw-cfg",
+        help = "Extra fw_cfg file (name=opt/<name>,file=<path> or name=opt/<name>,string=<value>)"
+    )]
+    fw_cfg: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Extra serial port (null, file:<path> or tcp:<port>)[,addr=<addr>][,irq=<irq>], defaults to the next 0x1000 after 0x10000000 and IRQ 12 onwards"
+    )]
+    serial: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = "",
+        help = "Save the framebuffer to this file when the guest powers off (.png or .ppm), works with --nographic"
+    )]
+    screenshot_on_exit: String,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "",
+        help = "Periodically dump the framebuffer (<dir>[,interval=<ms>][,format=png|ppm]), works with --nographic"
+    )]
+    fb_dump: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Serve the framebuffer over VNC on <addr>:<port> (e.g. 127.0.0.1:5900), works with --nographic"
+    )]
+    vnc: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = "",
+        help = "Record the framebuffer as a Y4M video (<file>[,fps=<n>]) paced by guest time, works with --nographic"
+    )]
+    record_video: String,
 }
 
 fn run_emulator(args: &Args) {
-    let rom = util::read_file(&args.bios);
+    let pflash_config = if args.pflash.is_empty() {
+        None
+    } else {
+        let config = PflashConfig::parse(&args.pflash);
+
+        if let Err(err) = &config {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        Some(config.unwrap())
+    };
+
+    if args.bios.is_empty() && pflash_config.is_none() {
+        println!("Either a BIOS image or a pflash image is required");
+        std::process::exit(1);
+    }
+
+    // Without a BIOS the reset vector is at the start of the flash and the
+    // firmware executes in place
+    let rom = if args.bios.is_empty() {
+        Ok(Vec::new())
+    } else {
+        util::read_file(&args.bios)
+    };
 
     if rom.is_err() {
         println!("Failed to read bios file: {}", args.bios);
@@ -244,6 +536,47 @@ fn run_emulator(args: &Args) {
 
     let mut rom = rom.unwrap();
 
+    let ram_size = util::size_mib(args.memory);
+
+    let mut entry = if args.bios.is_empty() {
+        PFLASH_ADDR
+    } else {
+        RAM_BEGIN_ADDR
+    };
+    let mut htif_config = None;
+
+    if util::Elf::is_elf(&rom) {
+        let elf = util::Elf::parse(&rom).and_then(|elf| {
+            let image = elf.to_image(RAM_BEGIN_ADDR, ram_size)?;
+
+            Ok((elf, image))
+        });
+
+        if let Err(err) = &elf {
+            println!("Failed to load bios ELF: {}", err);
+            std::process::exit(1);
+        }
+
+        let (elf, image) = elf.unwrap();
+
+        rom = image;
+        entry = elf.entry;
+        htif_config = HtifConfig::from_elf(&elf);
+    }
+
+    if !args.htif.is_empty() {
+        let config = HtifConfig::parse(&args.htif);
+
+        if let Err(err) = &config {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        htif_config = Some(config.unwrap());
+    }
+
+    let mut fw_cfg = bus::fw_cfg::FwCfg::new(ram_size);
+
     if !args.kernel.is_empty() {
         let kernel = util::read_file(&args.kernel);
 
@@ -252,14 +585,162 @@ fn run_emulator(args: &Args) {
             std::process::exit(1);
         }
 
-        let mut kernel = kernel.unwrap();
+        let kernel = kernel.unwrap();
 
         rom.resize(util::size_mib(4), 0);
-        rom.append(&mut kernel);
+        rom.extend_from_slice(&kernel);
+
+        fw_cfg.add_kernel(kernel);
     }
 
-    let ram_size = util::size_mib(args.memory);
-    let using_fb = !args.nographic;
+    if !args.initrd.is_empty() {
+        let initrd = util::read_file(&args.initrd);
+
+        if initrd.is_err() {
+            println!("Failed to read initrd file: {}", args.initrd);
+            std::process::exit(1);
+        }
+
+        fw_cfg.add_initrd(initrd.unwrap());
+    }
+
+    if !args.append.is_empty() {
+        fw_cfg.add_cmdline(&args.append);
+    }
+
+    for arg in &args.fw_cfg {
+        let blob = bus::fw_cfg::FwCfgBlob::parse(arg)
+            .and_then(|blob| fw_cfg.add_file(&blob.name, blob.data, None));
+
+        if let Err(err) = &blob {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let rtc = RtcConfig::parse(&args.rtc);
+
+    if let Err(err) = &rtc {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+
+    let rtc = rtc.unwrap();
+
+    let timer = TimerKind::parse(&args.timer);
+
+    if let Err(err) = &timer {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+
+    let timer = timer.unwrap();
+
+    let aia = AiaMode::parse(&args.aia);
+
+    if let Err(err) = &aia {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+
+    let aia = aia.unwrap();
+
+    let mut pci_devices = Vec::new();
+    let mut pci_hotplug_devices = Vec::new();
+
+    for arg in &args.device {
+        let device = bus::pci::parse_device(arg);
+
+        if let Err(err) = &device {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        let (device, hotplug) = device.unwrap();
+
+        if hotplug {
+            pci_hotplug_devices.push(device);
+        } else {
+            pci_devices.push(device);
+        }
+    }
+
+    let pflash = pflash_config.map(|config| {
+        let pflash = bus::pflash::Pflash::new(&config);
+
+        if let Err(err) = &pflash {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        pflash.unwrap()
+    });
+
+    let mut uarts = Vec::new();
+
+    for (i, arg) in args.serial.iter().enumerate() {
+        let uart =
+            UartConfig::parse(arg, i + 1).and_then(|config| bus::ns16550::Ns16550::new(&config));
+
+        if let Err(err) = &uart {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        uarts.push(uart.unwrap());
+    }
+
+    if args.jit_cache_mb == 0 {
+        println!("jit-cache-mb must be at least 1");
+        std::process::exit(1);
+    }
+
+    frontend::code_pages::set_cache_limit_mb(args.jit_cache_mb);
+    frontend::trace::set_hot_threshold(args.jit_hot_threshold);
+
+    if args.semihosting {
+        if let Err(err) = cpu::semihosting::init(&args.semihosting_root, &args.semihosting_cmdline)
+        {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let fb_dump = if args.fb_dump.is_empty() {
+        None
+    } else {
+        let config = FbDumpConfig::parse(&args.fb_dump);
+
+        if let Err(err) = &config {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        Some(config.unwrap())
+    };
+
+    let record_video = if args.record_video.is_empty() {
+        None
+    } else {
+        let config = RecordConfig::parse(&args.record_video);
+
+        if let Err(err) = &config {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        Some(config.unwrap())
+    };
+
+    // The recorder paces itself by how far the guest got
+    cpu::set_count_progress(record_video.is_some());
+
+    // Headless captures, recordings and VNC still need the guest to draw into the framebuffer
+    let using_fb = !args.nographic
+        || fb_dump.is_some()
+        || !args.screenshot_on_exit.is_empty()
+        || !args.vnc.is_empty()
+        || record_video.is_some();
 
     util::init();
     init_backend_csr();
@@ -271,11 +752,48 @@ fn run_emulator(args: &Args) {
     let height = args.height;
     let bpp = 32;
 
-    init_bus(rom, ram_size, width, height, bpp, using_fb);
+    init_bus(MachineConfig {
+        rom,
+        ram_size,
+        width,
+        height,
+        bpp,
+        using_fb,
+        rtc,
+        htif_config,
+        timer,
+        aia,
+        pci_devices,
+        pci_hotplug_devices,
+        pflash,
+        fw_cfg,
+        cmdline: args.append.clone(),
+        uarts,
+    });
+
+    let exec_thread_pool = ExecCoreThreadPool::new(entry, 1);
+
+    let grabber = FrameGrabber::new(RAMFB_BEGIN_ADDR as *mut u8, width, height);
+
+    if !args.vnc.is_empty() {
+        if let Err(err) = window::vnc::start(&args.vnc, grabber) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
 
-    let exec_thread_pool = ExecCoreThreadPool::new(RAM_BEGIN_ADDR, 1);
+    let fb_dump_thread = fb_dump.map(|config| window::capture::spawn_fb_dump(config, grabber));
 
-    if using_fb {
+    let record_thread = record_video.map(|config| {
+        if let Err(err) = window::record::open(&config, width, height) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+
+        window::record::spawn_recorder(grabber)
+    });
+
+    if !args.nographic {
         let mut window =
             window::window::Window::new(RAMFB_BEGIN_ADDR as *mut u8, width, height, args.scale);
 
@@ -284,6 +802,20 @@ fn run_emulator(args: &Args) {
 
     exec_thread_pool.join();
 
+    if let Some(thread) = fb_dump_thread {
+        thread.join().unwrap();
+    }
+
+    if let Some(thread) = record_thread {
+        thread.join().unwrap();
+    }
+
+    if !args.screenshot_on_exit.is_empty() && !bus::syscon::should_reboot() {
+        if let Err(err) = grabber.save(&args.screenshot_on_exit) {
+            println!("{}", err);
+        }
+    }
+
     bus::cleanup();
     csr::cleanup_csr();
     tlb::cleanup_asid_tlb();
@@ -299,7 +831,11 @@ fn main() {
         if bus::syscon::should_reboot() {
             bus::syscon::clear_should_reboot();
         } else {
-            return;
+            break;
         }
     }
+
+    std::io::stdout().flush().unwrap();
+
+    std::process::exit(bus::syscon::get_exit_code());
 }
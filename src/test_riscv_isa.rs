@@ -15,72 +15,35 @@ use std::thread;
 use std::time::Duration;
 
 use backend::csr::init_backend_csr;
-use bus::{ram::RAM_BEGIN_ADDR, BusType};
+use bus::htif::{Htif, HtifConfig, HTIF_DEFAULT_TOHOST_ADDR};
+use bus::tlb;
+use bus::{ram::RAM_BEGIN_ADDR, BusDevice, BusType};
 use cpu::Exception;
 use frontend::exec_core::ExecCoreThreadPool;
 use std::path::PathBuf;
 use std::process::Output;
 
-const TOHOSTADDR: BusType = 0x01000000;
-
-struct ToHost;
-
-impl bus::BusDevice for ToHost {
-    fn load(&mut self, _addr: BusType, _size: BusType) -> Result<BusType, Exception> {
-        Ok(0)
-    }
-
-    fn store(&mut self, addr: BusType, _data: BusType, _size: BusType) -> Result<(), Exception> {
-        if addr >= TOHOSTADDR + 4 {
-            // Ignore fromhost
-            return Ok(());
-        }
-
-        let cpu = cpu::get_cpu();
-
-        let a0 = if cpu.regs[cpu::RegName::A0 as usize] == 0 {
-            0
-        } else {
-            cpu.regs[cpu::RegName::A0 as usize] >> 1
-        };
-        let gp = cpu.regs[cpu::RegName::Gp as usize];
-
-        println!("tohost a0: {}, gp {}", a0, gp);
-
-        std::process::exit(if a0 == 0 && gp == 1 { 0 } else { 1 });
-    }
+fn init_bus(mut rom: Vec<u8>, ram_size: usize, htif_config: HtifConfig) {
+    assert!(ram_size >= rom.len());
 
-    fn get_begin_addr(&self) -> BusType {
-        TOHOSTADDR
-    }
+    rom.resize(ram_size, 0);
 
-    fn get_end_addr(&self) -> BusType {
-        TOHOSTADDR + 16
-    }
+    let bus = bus::bus::get_bus();
 
-    fn get_ptr(&mut self, _addr: BusType) -> Result<*mut u8, Exception> {
-        Ok(std::ptr::null_mut())
-    }
+    let mut ram = bus::ram::Ram::new(rom);
+    let ram_ptr = ram.get_ptr(RAM_BEGIN_ADDR).unwrap();
 
-    fn tick_core_local(&mut self) {}
-    fn tick_from_main_thread(&mut self) {}
-    fn tick_async(&mut self, _cpu: &mut cpu::Cpu) -> Option<u32> {
-        None
-    }
+    bus.set_ram_ptr(ram_ptr, ram.get_end_addr() as usize);
 
-    fn describe_fdt(&self, _fdt: &mut vm_fdt::FdtWriter) {}
-}
+    bus.add_device(Box::new(ram));
 
-fn init_bus(mut rom: Vec<u8>, ram_size: usize) {
-    assert!(ram_size >= rom.len());
+    let htif = Htif::new(htif_config);
 
-    rom.resize(ram_size, 0);
+    bus.add_device(Box::new(htif));
 
-    let ram = bus::ram::Ram::new(rom);
-    let tohost = ToHost {};
+    let clint = bus::clint::Clint::new();
 
-    bus::bus::get_bus().add_device(Box::new(ram));
-    bus::bus::get_bus().add_device(Box::new(tohost));
+    bus.add_device(Box::new(clint));
 }
 
 fn timeout_thread() {
@@ -105,7 +68,29 @@ fn main() {
         std::process::exit(1);
     }
 
-    let rom = util::read_file(&argv[1]).unwrap();
+    let mut rom = util::read_file(&argv[1]).unwrap();
+
+    let mut entry = RAM_BEGIN_ADDR;
+    let mut htif_config = HtifConfig::new(HTIF_DEFAULT_TOHOST_ADDR, None);
+
+    if util::Elf::is_elf(&rom) {
+        let elf = util::Elf::parse(&rom).and_then(|elf| {
+            let image = elf.to_image(RAM_BEGIN_ADDR, ram_size)?;
+
+            Ok((elf, image))
+        });
+
+        if let Err(err) = &elf {
+            println!("Failed to load ELF: {}", err);
+            std::process::exit(1);
+        }
+
+        let (elf, image) = elf.unwrap();
+
+        rom = image;
+        entry = elf.entry;
+        htif_config = HtifConfig::from_elf(&elf).unwrap_or(htif_config);
+    }
 
     if argv.len() == 3 && argv[2] == "timeout" {
         timeout_thread();
@@ -113,14 +98,22 @@ fn main() {
 
     util::init();
     init_backend_csr();
+    tlb::asid_tlb_init();
 
-    init_bus(rom.clone(), ram_size);
+    init_bus(rom, ram_size, htif_config);
 
-    let exec_thread_pool = ExecCoreThreadPool::new(RAM_BEGIN_ADDR, 1);
+    let exec_thread_pool = ExecCoreThreadPool::new(entry, 1);
 
     exec_thread_pool.join();
 
-    std::process::exit(1); // It's only valid to exit from the tohost device
+    std::io::stdout().flush().unwrap();
+
+    // It's only valid to exit from the htif device
+    if !bus::syscon::should_poweroff() {
+        std::process::exit(1);
+    }
+
+    std::process::exit(bus::syscon::get_exit_code());
 }
 
 fn get_least_one_file(files: &[&str]) -> Option<String> {
@@ -231,6 +224,26 @@ fn test_rva() {
     run_tests_from_directory("testbins/rv32ua/bin/", NOSKIP);
 }
 
+#[test]
+fn test_zba() {
+    run_tests_from_directory("testbins/rv32uzba/bin/", NOSKIP);
+}
+
+#[test]
+fn test_zbb() {
+    run_tests_from_directory("testbins/rv32uzbb/bin/", NOSKIP);
+}
+
+#[test]
+fn test_zbc() {
+    run_tests_from_directory("testbins/rv32uzbc/bin/", NOSKIP);
+}
+
+#[test]
+fn test_zbs() {
+    run_tests_from_directory("testbins/rv32uzbs/bin/", NOSKIP);
+}
+
 #[test]
 fn test_rvmi() {
     run_tests_from_directory("testbins/rv32mi/bin/", NOSKIP);
@@ -240,3 +253,8 @@ fn test_rvmi() {
 fn test_rvsi() {
     run_tests_from_directory("testbins/rv32si/bin/", NOSKIP);
 }
+
+#[test]
+fn test_misc() {
+    run_tests_from_directory("testbins/misc/bin/", NOSKIP);
+}
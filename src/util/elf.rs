@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+pub struct ElfSegment {
+    pub paddr: u32,
+    pub mem_size: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<ElfSegment>,
+    symbols: HashMap<String, u32>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(format!("ELF: read out of bounds at {:#x}", offset))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(format!("ELF: read out of bounds at {:#x}", offset))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Option<String> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&c| c == 0)?;
+
+    String::from_utf8(bytes[..len].to_vec()).ok()
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Elf, String> {
+        if !Self::is_elf(data) || data.len() < EHDR_SIZE {
+            return Err("ELF: invalid header".to_string());
+        }
+
+        if data[4] != ELFCLASS32 || data[5] != ELFDATA2LSB {
+            return Err("ELF: only little endian 32-bit images are supported".to_string());
+        }
+
+        if read_u16(data, 18)? != EM_RISCV {
+            return Err("ELF: not a RISC-V image".to_string());
+        }
+
+        let entry = read_u32(data, 24)?;
+        let phoff = read_u32(data, 28)? as usize;
+        let shoff = read_u32(data, 32)? as usize;
+        let phnum = read_u16(data, 44)? as usize;
+        let shnum = read_u16(data, 48)? as usize;
+
+        let mut segments = Vec::new();
+
+        for i in 0..phnum {
+            let phdr = phoff + i * PHDR_SIZE;
+
+            if read_u32(data, phdr)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(data, phdr + 4)? as usize;
+            let paddr = read_u32(data, phdr + 12)?;
+            let file_size = read_u32(data, phdr + 16)? as usize;
+            let mem_size = read_u32(data, phdr + 20)?;
+
+            if file_size > mem_size as usize {
+                return Err(format!(
+                    "ELF: segment at {:#x} has more file than memory bytes",
+                    paddr
+                ));
+            }
+
+            let seg_data = offset
+                .checked_add(file_size)
+                .and_then(|end| data.get(offset..end))
+                .ok_or("ELF: segment out of bounds".to_string())?;
+
+            segments.push(ElfSegment {
+                paddr,
+                mem_size,
+                data: seg_data.to_vec(),
+            });
+        }
+
+        let mut symbols = HashMap::new();
+
+        for i in 0..shnum {
+            let shdr = shoff + i * SHDR_SIZE;
+
+            if read_u32(data, shdr + 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let sym_offset = read_u32(data, shdr + 16)? as usize;
+            let sym_size = read_u32(data, shdr + 20)? as usize;
+            let strtab_idx = read_u32(data, shdr + 24)? as usize;
+            let strtab_offset = read_u32(data, shoff + strtab_idx * SHDR_SIZE + 16)? as usize;
+
+            for sym in (sym_offset..sym_offset + sym_size).step_by(SYM_SIZE) {
+                let name_offset = read_u32(data, sym)? as usize;
+                let value = read_u32(data, sym + 4)?;
+
+                if let Some(name) = read_str(data, strtab_offset + name_offset) {
+                    if !name.is_empty() {
+                        symbols.insert(name, value);
+                    }
+                }
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    pub fn get_symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    // Flattens the loadable segments into a memory image starting at base_addr
+    pub fn to_image(&self, base_addr: u32, max_size: usize) -> Result<Vec<u8>, String> {
+        let mut image = Vec::new();
+
+        for segment in &self.segments {
+            if segment.mem_size == 0 {
+                continue;
+            }
+
+            if segment.paddr < base_addr {
+                return Err(format!(
+                    "ELF: segment at {:#x} is below {:#x}",
+                    segment.paddr, base_addr
+                ));
+            }
+
+            let begin = (segment.paddr - base_addr) as usize;
+            let end = begin + segment.mem_size as usize;
+
+            if end > max_size {
+                return Err(format!(
+                    "ELF: segment at {:#x} does not fit in memory",
+                    segment.paddr
+                ));
+            }
+
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+
+            image[begin..begin + segment.data.len()].copy_from_slice(&segment.data);
+        }
+
+        Ok(image)
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+
+// Pixels are 0x00RRGGBB, one u32 per pixel in row order
+
+pub fn encode_ppm(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+
+    out.reserve(width * height * 3);
+
+    for pixel in &pixels[..width * height] {
+        out.extend_from_slice(&pixel.to_be_bytes()[1..]);
+    }
+
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = out.len();
+
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+
+    let crc = crc32(&out[start..]);
+
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// The image data goes into stored deflate blocks, which keeps the encoder
+// trivial at the cost of file size
+pub fn encode_png(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    const MAX_STORED_BLOCK: usize = 0xffff;
+
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+
+    for row in pixels[..width * height].chunks(width) {
+        // No filter
+        raw.push(0);
+
+        for pixel in row {
+            raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(MAX_STORED_BLOCK).peekable();
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel RGB, default compression, filtering and no interlacing
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+
+    out
+}
+
+// The format follows the extension, anything but .ppm is written as PNG
+pub fn save_image(path: &str, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    let data = if path.to_ascii_lowercase().ends_with(".ppm") {
+        encode_ppm(width, height, pixels)
+    } else {
+        encode_png(width, height, pixels)
+    };
+
+    std::fs::write(path, data)
+}
+
+pub fn y4m_header(width: usize, height: usize, fps: u32) -> String {
+    format!("YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n", width, height, fps)
+}
+
+// One 4:4:4 frame, BT.601 limited range
+pub fn encode_y4m_frame(width: usize, height: usize, pixels: &[u32], out: &mut Vec<u8>) {
+    let len = width * height;
+
+    out.clear();
+    out.extend_from_slice(b"FRAME\n");
+
+    let start = out.len();
+
+    out.resize(start + len * 3, 0);
+
+    let (y_plane, chroma) = out[start..].split_at_mut(len);
+    let (u_plane, v_plane) = chroma.split_at_mut(len);
+
+    for (i, pixel) in pixels[..len].iter().enumerate() {
+        let r = ((pixel >> 16) & 0xff) as i32;
+        let g = ((pixel >> 8) & 0xff) as i32;
+        let b = (pixel & 0xff) as i32;
+
+        y_plane[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        u_plane[i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        v_plane[i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+}
@@ -2,3 +2,6 @@ pub mod util;
 pub use util::*;
 pub mod insn;
 pub use insn::EncodedInsn;
+pub mod elf;
+pub use elf::Elf;
+pub mod image;
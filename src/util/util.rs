@@ -101,12 +101,59 @@ pub fn timebase_since_program_start() -> u64 {
     time_since_start
 }
 
+// Offset applied on top of the host timebase so the guest can write mtime
+static MTIME_OFFSET: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+pub fn get_mtime() -> u64 {
+    timebase_since_program_start()
+        .wrapping_add(MTIME_OFFSET.load(std::sync::atomic::Ordering::Acquire))
+}
+
+pub fn set_mtime(mtime: u64) {
+    let offset = mtime.wrapping_sub(timebase_since_program_start());
+
+    MTIME_OFFSET.store(offset, std::sync::atomic::Ordering::Release);
+}
+
 pub fn timebase_estimate_cycles() -> u64 {
     let timebase = timebase_since_program_start();
 
     max(timebase * CPU_TIMEBASE_FREQ as u64, 1)
 }
 
+#[cfg(unix)]
+pub fn host_utc_offset_secs() -> i64 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+
+        tm.tm_gmtoff as i64
+    }
+}
+
+#[cfg(windows)]
+pub fn host_utc_offset_secs() -> i64 {
+    use winapi::um::timezoneapi::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+
+    unsafe {
+        let mut tzi: TIME_ZONE_INFORMATION = std::mem::zeroed();
+
+        let bias = if GetTimeZoneInformation(&mut tzi) == TIME_ZONE_ID_DAYLIGHT {
+            tzi.Bias + tzi.DaylightBias
+        } else {
+            tzi.Bias + tzi.StandardBias
+        };
+
+        -(bias as i64) * 60
+    }
+}
+
 pub fn fdt_node_addr_helper(name: &str, addr: u32) -> String {
     format!("{}@{:x}", name, addr)
 }
@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::bus::{self, ramfb};
+use crate::util::image;
+
+const FB_DUMP_DEFAULT_INTERVAL_MS: u64 = 1000;
+const FB_DUMP_POLL_MS: u64 = 10;
+
+// Keeps the dump numbering going across guest reboots
+static FB_DUMP_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+pub fn abgr_to_bgr0(pixel: u32) -> u32 {
+    ((pixel & 0x00ff_0000) >> 16) | (pixel & 0x0000_ff00) | ((pixel & 0x0000_00ff) << 16)
+}
+
+// Reads the RamFB contents as 0x00RRGGBB pixels, shared by the window and the
+// headless capture paths
+#[derive(Clone, Copy)]
+pub struct FrameGrabber {
+    fb_slice: &'static [u32],
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FrameGrabber {
+    pub fn new(fb_ptr: *mut u8, width: usize, height: usize) -> Self {
+        let fb_slice =
+            unsafe { std::slice::from_raw_parts(fb_ptr as *const u32, width * height + 1) };
+
+        Self {
+            fb_slice,
+            width,
+            height,
+        }
+    }
+
+    pub fn grab(&self, out: &mut [u32]) {
+        if let Some(config) = ramfb::get_guest_config() {
+            self.grab_guest_fb(&config, out);
+        } else {
+            let pixels = self.width * self.height;
+
+            for (dst, &src) in out[..pixels].iter_mut().zip(&self.fb_slice[..pixels]) {
+                *dst = abgr_to_bgr0(src);
+            }
+        }
+    }
+
+    // The guest configured framebuffer is clipped to the output size
+    fn grab_guest_fb(&self, config: &ramfb::RamFBGuestConfig, out: &mut [u32]) {
+        let width = config.width.min(self.width);
+        let height = config.height.min(self.height);
+
+        let is_xrgb = config.fourcc == ramfb::DRM_FORMAT_XRGB8888
+            || config.fourcc == ramfb::DRM_FORMAT_ARGB8888;
+
+        out.fill(0);
+
+        for y in 0..height {
+            let line = unsafe {
+                std::slice::from_raw_parts((config.ptr + y * config.stride) as *const u32, width)
+            };
+
+            for x in 0..width {
+                out[y * self.width + x] = if is_xrgb {
+                    line[x] & 0x00ff_ffff
+                } else {
+                    abgr_to_bgr0(line[x])
+                };
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut pixels = vec![0; self.width * self.height];
+
+        self.grab(&mut pixels);
+
+        image::save_image(path, self.width, self.height, &pixels)
+            .map_err(|err| format!("Failed to write {}: {}", path, err))
+    }
+}
+
+pub struct FbDumpConfig {
+    pub dir: String,
+    pub interval: Duration,
+    pub ppm: bool,
+}
+
+impl FbDumpConfig {
+    // <dir>[,interval=<ms>][,format=png|ppm]
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let mut parts = arg.split(',');
+
+        let dir = parts.next().unwrap_or("");
+
+        if dir.is_empty() {
+            return Err("fb-dump: missing output directory".to_string());
+        }
+
+        let mut config = FbDumpConfig {
+            dir: dir.to_string(),
+            interval: Duration::from_millis(FB_DUMP_DEFAULT_INTERVAL_MS),
+            ppm: false,
+        };
+
+        for part in parts {
+            if let Some(value) = part.strip_prefix("interval=") {
+                let ms = value.parse::<u64>().ok().filter(|ms| *ms > 0);
+
+                if ms.is_none() {
+                    return Err(format!("fb-dump: invalid interval {}", value));
+                }
+
+                config.interval = Duration::from_millis(ms.unwrap());
+            } else if let Some(value) = part.strip_prefix("format=") {
+                config.ppm = match value {
+                    "png" => false,
+                    "ppm" => true,
+                    _ => return Err(format!("fb-dump: unknown format {}", value)),
+                };
+            } else {
+                return Err(format!("fb-dump: unknown option {}", part));
+            }
+        }
+
+        if let Err(err) = std::fs::create_dir_all(&config.dir) {
+            return Err(format!("fb-dump: failed to create {}: {}", config.dir, err));
+        }
+
+        Ok(config)
+    }
+}
+
+// Writes <dir>/frame-NNNNNN.<ext> every interval until the guest stops
+pub fn spawn_fb_dump(config: FbDumpConfig, grabber: FrameGrabber) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let ext = if config.ppm { "ppm" } else { "png" };
+        let mut next_dump = Instant::now();
+
+        while !bus::syscon::should_stop() {
+            if Instant::now() < next_dump {
+                std::thread::sleep(Duration::from_millis(FB_DUMP_POLL_MS));
+
+                continue;
+            }
+
+            next_dump += config.interval;
+
+            let frame = FB_DUMP_FRAME.fetch_add(1, Ordering::Relaxed);
+            let path = format!("{}/frame-{:06}.{}", config.dir, frame, ext);
+
+            if let Err(err) = grabber.save(&path) {
+                println!("{}", err);
+
+                break;
+            }
+        }
+    })
+}
+
+// Picks the first free screenshot-N.png in the current directory
+pub fn next_screenshot_path() -> String {
+    let mut index = 0;
+
+    loop {
+        let path = format!("screenshot-{}.png", index);
+
+        if !std::path::Path::new(&path).exists() {
+            return path;
+        }
+
+        index += 1;
+    }
+}
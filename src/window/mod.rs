@@ -1,4 +1,7 @@
+pub mod capture;
 pub mod console;
+pub mod record;
+pub mod vnc;
 pub mod window;
 
 #[cfg(windows)]
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::capture::FrameGrabber;
+use crate::bus;
+use crate::cpu::{self, CPU_NOMINAL_IPS, CPU_TIMEBASE_FREQ};
+use crate::util::image;
+
+const RECORD_DEFAULT_FPS: u32 = 30;
+const RECORD_MAX_FPS: u32 = 240;
+const RECORD_POLL_MS: u64 = 5;
+
+pub struct RecordConfig {
+    pub path: String,
+    pub fps: u32,
+}
+
+impl RecordConfig {
+    // <file>[,fps=<n>]
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let mut parts = arg.split(',');
+
+        let path = parts.next().unwrap_or("");
+
+        if path.is_empty() {
+            return Err("record-video: missing output file".to_string());
+        }
+
+        let mut config = RecordConfig {
+            path: path.to_string(),
+            fps: RECORD_DEFAULT_FPS,
+        };
+
+        for part in parts {
+            if let Some(value) = part.strip_prefix("fps=") {
+                let fps = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|fps| *fps > 0 && *fps <= RECORD_MAX_FPS);
+
+                if fps.is_none() {
+                    return Err(format!("record-video: invalid fps {}", value));
+                }
+
+                config.fps = fps.unwrap();
+            } else {
+                return Err(format!("record-video: unknown option {}", part));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+struct VideoRecorder {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    // Guest timebase ticks per frame
+    frame_ticks: u64,
+    next_frame: Option<u64>,
+    pixels: Vec<u32>,
+    frame: Vec<u8>,
+}
+
+// Opened on the first boot and kept across guest reboots so one file covers
+// the whole session
+static RECORDER: Mutex<Option<VideoRecorder>> = Mutex::new(None);
+
+pub fn open(config: &RecordConfig, width: usize, height: usize) -> Result<(), String> {
+    let mut recorder = RECORDER.lock().unwrap();
+
+    if recorder.is_some() {
+        return Ok(());
+    }
+
+    let file = File::create(&config.path)
+        .map_err(|err| format!("record-video: failed to create {}: {}", config.path, err))?;
+
+    let mut file = BufWriter::new(file);
+
+    file.write_all(image::y4m_header(width, height, config.fps).as_bytes())
+        .map_err(|err| format!("record-video: failed to write {}: {}", config.path, err))?;
+
+    *recorder = Some(VideoRecorder {
+        file,
+        width,
+        height,
+        frame_ticks: CPU_TIMEBASE_FREQ as u64 / config.fps as u64,
+        next_frame: None,
+        pixels: vec![0; width * height],
+        frame: Vec::new(),
+    });
+
+    Ok(())
+}
+
+// Guest time in timebase ticks by the progress of the boot hart, unlike mtime
+// it only moves on as far as the guest got to run
+fn guest_ticks() -> u64 {
+    cpu::hart_progress(0).load(Ordering::Relaxed) / (CPU_NOMINAL_IPS / CPU_TIMEBASE_FREQ as u64)
+}
+
+impl VideoRecorder {
+    // Emits one frame per elapsed slot of guest time, repeating the current
+    // frame when the recorder fell behind so playback speed follows the guest
+    fn record(&mut self, grabber: &FrameGrabber) -> std::io::Result<()> {
+        let now = guest_ticks();
+
+        let next_frame = *self.next_frame.get_or_insert(now);
+
+        if now < next_frame {
+            return Ok(());
+        }
+
+        let slots = (now - next_frame) / self.frame_ticks + 1;
+
+        self.next_frame = Some(next_frame + slots * self.frame_ticks);
+
+        grabber.grab(&mut self.pixels);
+
+        image::encode_y4m_frame(self.width, self.height, &self.pixels, &mut self.frame);
+
+        for _ in 0..slots {
+            self.file.write_all(&self.frame)?;
+        }
+
+        // Keeps the file playable when the emulator is killed with CTRL + A, then X
+        self.file.flush()
+    }
+
+    fn wait_ticks(&self) -> u64 {
+        self.next_frame
+            .map_or(0, |next| next.saturating_sub(guest_ticks()))
+    }
+}
+
+pub fn spawn_recorder(grabber: FrameGrabber) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut guard = RECORDER.lock().unwrap();
+
+        let Some(recorder) = guard.as_mut() else {
+            return;
+        };
+
+        while !bus::syscon::should_stop() {
+            if let Err(err) = recorder.record(&grabber) {
+                println!("record-video: {}", err);
+
+                *guard = None;
+
+                return;
+            }
+
+            let wait_us = recorder.wait_ticks() * 1_000_000 / CPU_TIMEBASE_FREQ as u64;
+
+            std::thread::sleep(Duration::from_micros(
+                wait_us.clamp(1, RECORD_POLL_MS * 1000),
+            ));
+        }
+    })
+}
@@ -0,0 +1,558 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+
+use super::capture::FrameGrabber;
+use super::window::send_key_state;
+use crate::bus::ns16550::write_char_cb;
+
+const RFB_VERSION: &[u8; 12] = b"RFB 003.008\n";
+const RFB_SECURITY_NONE: u8 = 1;
+const RFB_DESKTOP_NAME: &str = "RISCVBox";
+
+const RFB_ENCODING_RAW: i32 = 0;
+const RFB_ENCODING_RRE: i32 = 2;
+
+const RFB_MSG_SET_PIXEL_FORMAT: u8 = 0;
+const RFB_MSG_SET_ENCODINGS: u8 = 2;
+const RFB_MSG_FB_UPDATE_REQUEST: u8 = 3;
+const RFB_MSG_KEY_EVENT: u8 = 4;
+const RFB_MSG_POINTER_EVENT: u8 = 5;
+const RFB_MSG_CLIENT_CUT_TEXT: u8 = 6;
+
+const RFB_MSG_FB_UPDATE: u8 = 0;
+
+const VNC_TILE_SIZE: usize = 16;
+const VNC_FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+const XK_BACKSPACE: u32 = 0xff08;
+const XK_TAB: u32 = 0xff09;
+const XK_RETURN: u32 = 0xff0d;
+const XK_ESCAPE: u32 = 0xff1b;
+const XK_LEFT: u32 = 0xff51;
+const XK_UP: u32 = 0xff52;
+const XK_RIGHT: u32 = 0xff53;
+const XK_DOWN: u32 = 0xff54;
+const XK_KP_ENTER: u32 = 0xff8d;
+const XK_CONTROL_L: u32 = 0xffe3;
+const XK_CONTROL_R: u32 = 0xffe4;
+const XK_DELETE: u32 = 0xffff;
+
+// The listener outlives guest reboots, just like the TCP serial ports
+static VNC_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct PixelFormat {
+    bpp: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    // Matches the 0x00RRGGBB pixels FrameGrabber produces
+    fn native() -> Self {
+        Self {
+            bpp: 32,
+            depth: 24,
+            big_endian: false,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        }
+    }
+
+    fn from_bytes(data: &[u8; 16]) -> Self {
+        Self {
+            bpp: data[0],
+            depth: data[1],
+            big_endian: data[2] != 0,
+            true_colour: data[3] != 0,
+            red_max: u16::from_be_bytes([data[4], data[5]]),
+            green_max: u16::from_be_bytes([data[6], data[7]]),
+            blue_max: u16::from_be_bytes([data[8], data[9]]),
+            red_shift: data[10],
+            green_shift: data[11],
+            blue_shift: data[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut data = [0u8; 16];
+
+        data[0] = self.bpp;
+        data[1] = self.depth;
+        data[2] = self.big_endian as u8;
+        data[3] = self.true_colour as u8;
+        data[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        data[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        data[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        data[10] = self.red_shift;
+        data[11] = self.green_shift;
+        data[12] = self.blue_shift;
+
+        data
+    }
+
+    fn is_supported(&self) -> bool {
+        self.true_colour && matches!(self.bpp, 8 | 16 | 32)
+    }
+
+    fn pack(&self, pixel: u32, out: &mut Vec<u8>) {
+        let scale = |value: u32, max: u16| (value * max as u32 + 127) / 255;
+
+        let value = (scale((pixel >> 16) & 0xff, self.red_max) << self.red_shift)
+            | (scale((pixel >> 8) & 0xff, self.green_max) << self.green_shift)
+            | (scale(pixel & 0xff, self.blue_max) << self.blue_shift);
+
+        match (self.bpp, self.big_endian) {
+            (8, _) => out.push(value as u8),
+            (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+// Only what the update loop cares about, input is handled by the reader
+enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    UpdateRequest { incremental: bool, rect: Rect },
+}
+
+fn read_u8(stream: &mut TcpStream) -> std::io::Result<u8> {
+    let mut data = [0u8; 1];
+
+    stream.read_exact(&mut data)?;
+
+    Ok(data[0])
+}
+
+fn read_u16(stream: &mut TcpStream) -> std::io::Result<u16> {
+    let mut data = [0u8; 2];
+
+    stream.read_exact(&mut data)?;
+
+    Ok(u16::from_be_bytes(data))
+}
+
+fn read_u32(stream: &mut TcpStream) -> std::io::Result<u32> {
+    let mut data = [0u8; 4];
+
+    stream.read_exact(&mut data)?;
+
+    Ok(u32::from_be_bytes(data))
+}
+
+fn skip(stream: &mut TcpStream, len: usize) -> std::io::Result<()> {
+    let copied = std::io::copy(&mut stream.take(len as u64), &mut std::io::sink())?;
+
+    if copied != len as u64 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(())
+}
+
+// Same input path as the window, characters go to the console UART and
+// letters and space also go to the keyboard queue
+struct KeyState {
+    ctrl: bool,
+}
+
+impl KeyState {
+    fn key_event(&mut self, keysym: u32, down: bool) {
+        if keysym == XK_CONTROL_L || keysym == XK_CONTROL_R {
+            self.ctrl = down;
+
+            return;
+        }
+
+        if let Some(key) = char::from_u32(keysym).filter(|c| c.is_ascii_alphabetic() || *c == ' ') {
+            send_key_state(key.to_ascii_lowercase() as u8, down);
+        }
+
+        if !down {
+            return;
+        }
+
+        let sequence: &[u8] = match keysym {
+            XK_BACKSPACE => b"\x7f",
+            XK_TAB => b"\t",
+            XK_RETURN | XK_KP_ENTER => b"\r",
+            XK_ESCAPE => b"\x1b",
+            XK_UP => b"\x1b[A",
+            XK_DOWN => b"\x1b[B",
+            XK_RIGHT => b"\x1b[C",
+            XK_LEFT => b"\x1b[D",
+            XK_DELETE => b"\x1b[3~",
+            0x20..=0x7e => {
+                let c = keysym as u8;
+
+                if self.ctrl && c.is_ascii_alphabetic() {
+                    write_char_cb(c & 0x1f);
+                } else {
+                    write_char_cb(c);
+                }
+
+                return;
+            }
+            _ => return,
+        };
+
+        for c in sequence {
+            write_char_cb(*c);
+        }
+    }
+}
+
+fn reader_thread(mut stream: TcpStream, tx: Sender<ClientMessage>) -> std::io::Result<()> {
+    let mut keys = KeyState { ctrl: false };
+
+    loop {
+        match read_u8(&mut stream)? {
+            RFB_MSG_SET_PIXEL_FORMAT => {
+                let mut data = [0u8; 19];
+
+                stream.read_exact(&mut data)?;
+
+                let format = PixelFormat::from_bytes(data[3..].try_into().unwrap());
+
+                let _ = tx.send(ClientMessage::SetPixelFormat(format));
+            }
+            RFB_MSG_SET_ENCODINGS => {
+                read_u8(&mut stream)?;
+
+                let count = read_u16(&mut stream)?;
+                let mut encodings = Vec::with_capacity(count as usize);
+
+                for _ in 0..count {
+                    encodings.push(read_u32(&mut stream)? as i32);
+                }
+
+                let _ = tx.send(ClientMessage::SetEncodings(encodings));
+            }
+            RFB_MSG_FB_UPDATE_REQUEST => {
+                let incremental = read_u8(&mut stream)? != 0;
+
+                let rect = Rect {
+                    x: read_u16(&mut stream)? as usize,
+                    y: read_u16(&mut stream)? as usize,
+                    width: read_u16(&mut stream)? as usize,
+                    height: read_u16(&mut stream)? as usize,
+                };
+
+                let _ = tx.send(ClientMessage::UpdateRequest { incremental, rect });
+            }
+            RFB_MSG_KEY_EVENT => {
+                let down = read_u8(&mut stream)? != 0;
+
+                read_u16(&mut stream)?;
+
+                let keysym = read_u32(&mut stream)?;
+
+                keys.key_event(keysym, down);
+            }
+            // There is no pointing device to forward to yet
+            RFB_MSG_POINTER_EVENT => skip(&mut stream, 5)?,
+            RFB_MSG_CLIENT_CUT_TEXT => {
+                skip(&mut stream, 3)?;
+
+                let len = read_u32(&mut stream)?;
+
+                skip(&mut stream, len as usize)?;
+            }
+            _ => return Err(std::io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+struct VncClient {
+    stream: TcpStream,
+    grabber: FrameGrabber,
+    format: PixelFormat,
+    rre: bool,
+    // What the client is known to be showing
+    shown: Vec<u32>,
+    current: Vec<u32>,
+}
+
+impl VncClient {
+    fn handshake(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(RFB_VERSION)?;
+
+        let mut version = [0u8; 12];
+
+        self.stream.read_exact(&mut version)?;
+
+        if &version[..4] != b"RFB " {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+
+        let minor = std::str::from_utf8(&version[8..11])
+            .ok()
+            .and_then(|minor| minor.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        if minor >= 7 {
+            self.stream.write_all(&[1, RFB_SECURITY_NONE])?;
+
+            if read_u8(&mut self.stream)? != RFB_SECURITY_NONE {
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+
+            // 3.7 only sends a result for failures
+            if minor >= 8 {
+                self.stream.write_all(&0u32.to_be_bytes())?;
+            }
+        } else {
+            self.stream
+                .write_all(&(RFB_SECURITY_NONE as u32).to_be_bytes())?;
+        }
+
+        // Shared flag, every client gets its own view anyway
+        read_u8(&mut self.stream)?;
+
+        let mut init = Vec::new();
+
+        init.extend_from_slice(&(self.grabber.width as u16).to_be_bytes());
+        init.extend_from_slice(&(self.grabber.height as u16).to_be_bytes());
+        init.extend_from_slice(&self.format.to_bytes());
+        init.extend_from_slice(&(RFB_DESKTOP_NAME.len() as u32).to_be_bytes());
+        init.extend_from_slice(RFB_DESKTOP_NAME.as_bytes());
+
+        self.stream.write_all(&init)
+    }
+
+    // Tiles inside the request that differ from what the client shows,
+    // neighbours on the same tile row are merged into one rectangle
+    fn dirty_rects(&self, request: Rect, incremental: bool) -> Vec<Rect> {
+        let width = self.grabber.width;
+        let x_end = (request.x + request.width).min(width);
+        let y_end = (request.y + request.height).min(self.grabber.height);
+
+        let mut rects = Vec::new();
+
+        for y in (request.y..y_end).step_by(VNC_TILE_SIZE) {
+            let tile_height = VNC_TILE_SIZE.min(y_end - y);
+            let mut run: Option<Rect> = None;
+
+            for x in (request.x..x_end).step_by(VNC_TILE_SIZE) {
+                let tile_width = VNC_TILE_SIZE.min(x_end - x);
+
+                let dirty = !incremental
+                    || (y..y + tile_height).any(|row| {
+                        let line = row * width + x..row * width + x + tile_width;
+
+                        self.current[line.clone()] != self.shown[line]
+                    });
+
+                if dirty {
+                    match &mut run {
+                        Some(rect) => rect.width += tile_width,
+                        None => {
+                            run = Some(Rect {
+                                x,
+                                y,
+                                width: tile_width,
+                                height: tile_height,
+                            })
+                        }
+                    }
+                } else if let Some(rect) = run.take() {
+                    rects.push(rect);
+                }
+            }
+
+            rects.extend(run);
+        }
+
+        rects
+    }
+
+    fn encode_rect(&mut self, rect: Rect, out: &mut Vec<u8>) {
+        let width = self.grabber.width;
+
+        out.extend_from_slice(&(rect.x as u16).to_be_bytes());
+        out.extend_from_slice(&(rect.y as u16).to_be_bytes());
+        out.extend_from_slice(&(rect.width as u16).to_be_bytes());
+        out.extend_from_slice(&(rect.height as u16).to_be_bytes());
+
+        let first = self.current[rect.y * width + rect.x];
+
+        let solid = (rect.y..rect.y + rect.height).all(|row| {
+            self.current[row * width + rect.x..row * width + rect.x + rect.width]
+                .iter()
+                .all(|pixel| *pixel == first)
+        });
+
+        if solid && self.rre {
+            // A background colour without any subrectangles
+            out.extend_from_slice(&RFB_ENCODING_RRE.to_be_bytes());
+            out.extend_from_slice(&0u32.to_be_bytes());
+
+            self.format.pack(first, out);
+        } else {
+            out.extend_from_slice(&RFB_ENCODING_RAW.to_be_bytes());
+
+            for row in rect.y..rect.y + rect.height {
+                for pixel in &self.current[row * width + rect.x..row * width + rect.x + rect.width]
+                {
+                    self.format.pack(*pixel, out);
+                }
+            }
+        }
+
+        for row in rect.y..rect.y + rect.height {
+            let line = row * width + rect.x..row * width + rect.x + rect.width;
+
+            self.shown[line.clone()].copy_from_slice(&self.current[line]);
+        }
+    }
+
+    // Returns false while there is nothing new to send for an incremental request
+    fn send_update(&mut self, request: Rect, incremental: bool) -> std::io::Result<bool> {
+        self.grabber.grab(&mut self.current);
+
+        let rects = self.dirty_rects(request, incremental);
+
+        if rects.is_empty() && incremental {
+            return Ok(false);
+        }
+
+        let mut out = vec![RFB_MSG_FB_UPDATE, 0];
+
+        out.extend_from_slice(&(rects.len() as u16).to_be_bytes());
+
+        for rect in rects {
+            self.encode_rect(rect, &mut out);
+        }
+
+        self.stream.write_all(&out)?;
+
+        Ok(true)
+    }
+
+    fn update_loop(&mut self, rx: Receiver<ClientMessage>) -> std::io::Result<()> {
+        let mut pending: Option<(Rect, bool)> = None;
+        let mut last_grab = Instant::now() - VNC_FRAME_INTERVAL;
+
+        loop {
+            match rx.recv_timeout(VNC_FRAME_INTERVAL) {
+                Ok(ClientMessage::SetPixelFormat(format)) => {
+                    if !format.is_supported() {
+                        println!("vnc: client requested an unsupported pixel format");
+
+                        return Err(std::io::ErrorKind::Unsupported.into());
+                    }
+
+                    self.format = format;
+                }
+                Ok(ClientMessage::SetEncodings(encodings)) => {
+                    self.rre = encodings.contains(&RFB_ENCODING_RRE);
+                }
+                Ok(ClientMessage::UpdateRequest { incremental, rect }) => {
+                    // A full request overrides a pending incremental one
+                    let incremental = incremental && pending.map_or(true, |(_, inc)| inc);
+
+                    pending = Some((rect, incremental));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            let Some((rect, incremental)) = pending else {
+                continue;
+            };
+
+            if incremental && last_grab.elapsed() < VNC_FRAME_INTERVAL {
+                continue;
+            }
+
+            last_grab = Instant::now();
+
+            if self.send_update(rect, incremental)? {
+                pending = None;
+            }
+        }
+    }
+}
+
+fn client_thread(stream: TcpStream, grabber: FrameGrabber) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let pixels = grabber.width * grabber.height;
+
+    let mut client = VncClient {
+        stream: stream.try_clone()?,
+        grabber,
+        format: PixelFormat::native(),
+        rre: false,
+        shown: vec![0; pixels],
+        current: vec![0; pixels],
+    };
+
+    client.handshake()?;
+
+    let (tx, rx) = channel::unbounded();
+
+    std::thread::spawn(move || {
+        let _ = reader_thread(stream, tx);
+    });
+
+    let result = client.update_loop(rx);
+
+    // Also unblocks the reader thread
+    let _ = client.stream.shutdown(std::net::Shutdown::Both);
+
+    result
+}
+
+// Serves the framebuffer on <addr>:<port>, e.g. 127.0.0.1:5900
+pub fn start(addr: &str, grabber: FrameGrabber) -> Result<(), String> {
+    if VNC_STARTED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind(addr)
+        .map_err(|err| format!("vnc: failed to listen on {}: {}", addr, err))?;
+
+    VNC_STARTED.store(true, Ordering::Release);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+
+            std::thread::spawn(move || {
+                let _ = client_thread(stream, grabber);
+            });
+        }
+    });
+
+    Ok(())
+}
@@ -1,8 +1,9 @@
+use super::capture::{self, FrameGrabber};
 use crate::bus::{
     self,
     ns16550::{write_char_cb, write_char_kbd},
 };
-use minifb::{self, Key};
+use minifb::{self, Key, KeyRepeat};
 
 struct UartCB;
 
@@ -13,26 +14,27 @@ impl minifb::InputCallback for UartCB {
 
     fn set_key_state(&mut self, key: Key, state: bool) {
         if key >= Key::A && key <= Key::Z || key == Key::Space {
-            let mut key = key as u32;
-
-            if key == Key::Space as u32 {
-                key = b' ' as u32;
+            let key = if key == Key::Space {
+                b' '
             } else {
-                key = key - Key::A as u32 + b'a' as u32;
-            }
-
-            key = if state { key } else { key | 0x80 };
+                (key as u32 - Key::A as u32) as u8 + b'a'
+            };
 
-            write_char_kbd(key as u8);
+            send_key_state(key, state);
         }
     }
 }
 
+// Lowercase letters and space, the high bit marks a release
+pub fn send_key_state(key: u8, pressed: bool) {
+    write_char_kbd(if pressed { key } else { key | 0x80 });
+}
+
 pub struct Window {
     window: minifb::Window,
     width: usize,
     height: usize,
-    fb_slice: &'static [u32],
+    grabber: FrameGrabber,
     framebuffer: Vec<u32>,
 }
 
@@ -55,16 +57,13 @@ impl Window {
 
         window.set_target_fps(60);
 
-        let fb_slice =
-            unsafe { std::slice::from_raw_parts(fb_ptr as *const u32, width * height + 1) };
-
         window.set_input_callback(Box::new(UartCB {}));
 
         let mut this = Self {
             window,
             width,
             height,
-            fb_slice,
+            grabber: FrameGrabber::new(fb_ptr, width, height),
             framebuffer: vec![0; width * height * 4],
         };
 
@@ -75,14 +74,11 @@ impl Window {
     }
 
     pub fn event_loop(&mut self) {
-        while self.window.is_open() && !bus::syscon::should_reboot() {
-            // Convert ABGR to BGR0
-            for i in 0..self.width * self.height {
-                let pixel = self.fb_slice[i];
-
-                self.framebuffer[i] = ((pixel & 0x00ff_0000) >> 16)
-                    | (pixel & 0x0000_ff00)
-                    | ((pixel & 0x0000_00ff) << 16);
+        while self.window.is_open() && !bus::syscon::should_stop() {
+            self.grabber.grab(&mut self.framebuffer);
+
+            if self.window.is_key_pressed(Key::F12, KeyRepeat::No) {
+                self.save_screenshot();
             }
 
             self.window
@@ -95,6 +91,15 @@ impl Window {
         }
     }
 
+    fn save_screenshot(&self) {
+        let path = capture::next_screenshot_path();
+
+        match self.grabber.save(&path) {
+            Ok(()) => println!("Saved screenshot to {}", path),
+            Err(err) => println!("{}", err),
+        }
+    }
+
     #[cfg(windows)]
     fn set_icon(&mut self) {
         use winapi::{
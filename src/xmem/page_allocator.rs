@@ -48,6 +48,35 @@ pub mod win32_page_allocator {
         }
     }
 
+    // Address space only, pages become usable after commit_pages
+    pub fn reserve_pages(npages: usize) -> Result<*mut u8, AllocationError> {
+        let size = npages * PAGE_SIZE;
+
+        unsafe {
+            let ptr = VirtualAlloc(ptr::null_mut(), size, MEM_RESERVE, PAGE_NOACCESS) as *mut u8;
+
+            if ptr == ptr::null_mut() {
+                Err(AllocationError::OutOfMemory)
+            } else {
+                Ok(ptr)
+            }
+        }
+    }
+
+    pub fn commit_pages(ptr: *mut u8, npages: usize) -> Result<(), AllocationError> {
+        let size = npages * PAGE_SIZE;
+
+        unsafe {
+            let res = VirtualAlloc(ptr as *mut _, size, MEM_COMMIT, PAGE_READWRITE);
+
+            if res.is_null() {
+                Err(AllocationError::OutOfMemory)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
     pub fn free_pages(ptr: *mut u8, npages: usize) {
         let size = npages * PAGE_SIZE;
 
@@ -142,6 +171,42 @@ pub mod posix_page_allocator {
         }
     }
 
+    // Address space only, pages become usable after commit_pages
+    pub fn reserve_pages(npages: usize) -> Result<*mut u8, AllocationError> {
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                npages * PAGE_SIZE,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE,
+                -1,
+                0,
+            );
+
+            if ptr == libc::MAP_FAILED {
+                Err(AllocationError::OutOfMemory)
+            } else {
+                Ok(ptr as *mut u8)
+            }
+        }
+    }
+
+    pub fn commit_pages(ptr: *mut u8, npages: usize) -> Result<(), AllocationError> {
+        let result = unsafe {
+            libc::mprotect(
+                ptr as *mut _,
+                npages * PAGE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+            )
+        };
+
+        if result != 0 {
+            Err(AllocationError::OutOfMemory)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn free_pages(ptr: *mut u8, npages: usize) {
         unsafe {
             libc::munmap(ptr as *mut _, npages * PAGE_SIZE);
@@ -1,3 +1,6 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::util;
 use crate::xmem::PageAllocator;
 
 pub struct CodePage {
@@ -9,9 +12,27 @@ pub struct CodePage {
 
 const INITIAL_NPAGES: usize = 16;
 
+// Address space reserved per guest page, only the used part gets committed.
+// Translations never move since they hold rip relative references
+const MAX_NPAGES: usize = 512;
+
+// Host memory committed to all code pages, kept up to date as they grow and go
+static COMMITTED_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn committed_size() -> usize {
+    COMMITTED_SIZE.load(Ordering::Acquire)
+}
+
 impl CodePage {
     pub fn new() -> Self {
-        let ptr = PageAllocator::allocate_pages(INITIAL_NPAGES).unwrap();
+        let ptr = PageAllocator::reserve_pages(MAX_NPAGES).unwrap();
+
+        PageAllocator::commit_pages(ptr, INITIAL_NPAGES).unwrap();
+
+        COMMITTED_SIZE.fetch_add(
+            INITIAL_NPAGES * PageAllocator::get_page_size(),
+            Ordering::AcqRel,
+        );
 
         CodePage {
             ptr,
@@ -21,21 +42,37 @@ impl CodePage {
         }
     }
 
-    pub fn push(&mut self, data: &[u8]) -> Result<(), AllocationError> {
+    fn grow(&mut self, size: usize) -> Result<(), AllocationError> {
         let page_size = PageAllocator::get_page_size();
 
-        if self.offset + data.len() > self.npages * page_size {
-            panic!("CodePage: Resize not supported");
+        let npages = std::cmp::max(util::align_up(size, page_size) / page_size, self.npages * 2);
+        let npages = std::cmp::min(npages, MAX_NPAGES);
+
+        if npages * page_size < size {
+            return Err(AllocationError::OutOfMemory);
+        }
+
+        let grown_ptr = unsafe { self.ptr.add(self.npages * page_size) };
+        let grown_npages = npages - self.npages;
 
-            // let npages = std::cmp::max(
-            //     util::align_up(self.offset + data.len(), page_size) / page_size,
-            //     self.npages * 2, // A logarithmic growth strategy may be better
-            // );
+        PageAllocator::commit_pages(grown_ptr, grown_npages)?;
 
-            // let new_ptr = PageAllocator::realloc_pages(self.ptr, self.npages, npages).unwrap();
+        COMMITTED_SIZE.fetch_add(grown_npages * page_size, Ordering::AcqRel);
 
-            // self.ptr = new_ptr;
-            // self.npages = npages;
+        if self.state != PageState::ReadWrite {
+            PageAllocator::mark_page(grown_ptr, grown_npages, self.state)?;
+        }
+
+        self.npages = npages;
+
+        Ok(())
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<(), AllocationError> {
+        let page_size = PageAllocator::get_page_size();
+
+        if self.offset + data.len() > self.npages * page_size {
+            self.grow(self.offset + data.len())?;
         }
 
         unsafe {
@@ -90,7 +127,12 @@ impl CodePage {
     }
 
     pub fn dealloc(&mut self) {
-        PageAllocator::free_pages(self.ptr, self.npages);
+        PageAllocator::free_pages(self.ptr, MAX_NPAGES);
+
+        COMMITTED_SIZE.fetch_sub(
+            self.npages * PageAllocator::get_page_size(),
+            Ordering::AcqRel,
+        );
 
         self.ptr = std::ptr::null_mut();
         self.npages = 0;